@@ -0,0 +1,423 @@
+use crate::error::{Error, Result};
+use crate::math::*;
+
+/// How values are interpolated between two keys.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Holds each key's value until the next key
+    Step,
+    /// Moves at a constant rate
+    Linear,
+    /// Eases in and out of each key, moving fastest halfway between them
+    Smooth,
+}
+
+impl Interpolation {
+    /// Maps how far through a segment between two keys a time is, from 0 to 1, to how far the
+    /// value has moved from the first key to the second.
+    pub fn ease(self, t: f64) -> f64 {
+        match self {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => t,
+            Interpolation::Smooth => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// Checks that key times are finite and in increasing order.
+fn check_key_times<I: Iterator<Item = f64>>(times: I) -> Result<()> {
+    let mut last = f64::NEG_INFINITY;
+    let mut count = 0;
+    for time in times {
+        if !time.is_finite() {
+            return Err(Error::InvalidParameter("key times must be finite"));
+        }
+        if time <= last {
+            return Err(Error::InvalidParameter(
+                "keys must be in increasing order of time",
+            ));
+        }
+        last = time;
+        count += 1;
+    }
+    if count == 0 {
+        return Err(Error::InvalidParameter("animation needs at least one key"));
+    }
+    Ok(())
+}
+
+/// Finds where `time` falls among keys with the given times, which must be in increasing
+/// order, returning the index of the key at or before it and how far it is towards the next
+/// key, from 0 to 1. Times before the first key or after the last are clamped to them, with a
+/// fraction of 0.
+fn locate<K, F: Fn(&K) -> f64>(keys: &[K], time: f64, key_time: F) -> (usize, f64) {
+    let next = keys.partition_point(|key| key_time(key) <= time);
+    if next == 0 {
+        return (0, 0.0);
+    }
+    if next == keys.len() {
+        return (next - 1, 0.0);
+    }
+    let (start, end) = (key_time(&keys[next - 1]), key_time(&keys[next]));
+    (next - 1, ((time - start) / (end - start)).clamp(0.0, 1.0))
+}
+
+/// A value that can be interpolated between keys.
+pub trait Lerp: Copy {
+    /// Returns the value `t` of the way from this value to `other`.
+    fn lerp(self, other: Self, t: f64) -> Self;
+}
+
+impl Lerp for f64 {
+    fn lerp(self, other: f64, t: f64) -> f64 {
+        (1.0 - t) * self + t * other
+    }
+}
+
+impl Lerp for Vec3 {
+    fn lerp(self, other: Vec3, t: f64) -> Vec3 {
+        (1.0 - t) * self + t * other
+    }
+}
+
+/// A value that changes over time, interpolated between keys. Before the first key and after
+/// the last, the value holds still.
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keys: Vec<(f64, T)>,
+    interpolation: Interpolation,
+}
+
+impl<T: Lerp> Track<T> {
+    /// Creates a track through `keys`, pairs of times and values in increasing order of time.
+    pub fn new(keys: &[(f64, T)], interpolation: Interpolation) -> Result<Track<T>> {
+        check_key_times(keys.iter().map(|&(time, _)| time))?;
+        Ok(Track {
+            keys: keys.to_vec(),
+            interpolation,
+        })
+    }
+
+    /// Returns the value at `time`.
+    pub fn at(&self, time: f64) -> T {
+        let (idx, t) = locate(&self.keys, time, |&(time, _)| time);
+        let value = self.keys[idx].1;
+        if t == 0.0 {
+            return value;
+        }
+        value.lerp(self.keys[idx + 1].1, self.interpolation.ease(t))
+    }
+
+    /// Returns the values of the keys.
+    pub fn values(&self) -> impl Iterator<Item = T> + '_ {
+        self.keys.iter().map(|&(_, value)| value)
+    }
+}
+
+/// Changes to the camera's placement over time, overriding the options they're applied to.
+/// Any motion of the camera is applied on top.
+#[derive(Debug, Clone, Default)]
+pub struct CameraAnimation {
+    pub pos: Option<Track<Vec3>>,
+    pub target: Option<Track<Vec3>>,
+    /// The vertical field of view, in degrees
+    pub vert_fov: Option<Track<f64>>,
+}
+
+/// The number of steps each segment between keyframes is split into when bounding the motion
+/// of a box.
+const MOTION_BOUND_STEPS: u32 = 16;
+
+/// The pose of a moving object at a point in time, as a rotation about the origin followed by
+/// a translation.
+#[derive(Debug, Copy, Clone)]
+pub struct Keyframe {
+    pub time: f64,
+    pub translation: Vec3,
+    /// The axis of the rotation and its angle in degrees, counterclockwise as for
+    /// `Transform::rotate`
+    pub rotation: (Unit3, f64),
+}
+
+impl Keyframe {
+    /// Creates a keyframe that translates by `translation` without rotating.
+    pub fn translate(time: f64, translation: Vec3) -> Keyframe {
+        Keyframe {
+            time,
+            translation,
+            rotation: (
+                Unit3::from_unit_vec3(Vec3 {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                }),
+                0.0,
+            ),
+        }
+    }
+}
+
+/// A rotation represented by a unit quaternion, which can be interpolated smoothly.
+#[derive(Debug, Copy, Clone)]
+struct Quaternion {
+    w: f64,
+    v: Vec3,
+}
+
+impl Quaternion {
+    fn from_axis_angle(axis: Unit3, degrees: f64) -> Quaternion {
+        let (sin, cos) = (degrees.to_radians() / 2.0).sin_cos();
+        Quaternion {
+            w: cos,
+            v: sin * Vec3::from(axis),
+        }
+    }
+
+    fn dot(self, other: Quaternion) -> f64 {
+        self.w * other.w + self.v.dot(other.v)
+    }
+
+    /// Returns the angle of the smallest rotation that turns this rotation into `other`, in
+    /// radians.
+    fn angle_to(self, other: Quaternion) -> f64 {
+        2.0 * self.dot(other).abs().min(1.0).acos()
+    }
+
+    /// Interpolates at a constant rate along the smallest rotation from this rotation to
+    /// `other`.
+    fn slerp(self, other: Quaternion, t: f64) -> Quaternion {
+        // A quaternion and its negation are the same rotation, so pick the nearer of the two.
+        let (cos, sign) = match self.dot(other) {
+            cos if cos < 0.0 => (-cos, -1.0),
+            cos => (cos, 1.0),
+        };
+
+        let (a, b) = if cos > 1.0 - 1e-6 {
+            // Nearly equal rotations, where the sines below vanish
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+
+        let w = a * self.w + sign * b * other.w;
+        let v = a * self.v + sign * b * other.v;
+        let norm = (w * w + v.mag_squared()).sqrt();
+        Quaternion {
+            w: w / norm,
+            v: v / norm,
+        }
+    }
+
+    /// Returns the transform that applies the rotation and then translates by `translation`.
+    fn to_transform(self, translation: Vec3) -> Transform {
+        let (w, x, y, z) = (self.w, self.v.x, self.v.y, self.v.z);
+
+        Transform::rigid([
+            [
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - w * z),
+                2.0 * (x * z + w * y),
+                translation.x,
+            ],
+            [
+                2.0 * (x * y + w * z),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - w * x),
+                translation.y,
+            ],
+            [
+                2.0 * (x * z - w * y),
+                2.0 * (y * z + w * x),
+                1.0 - 2.0 * (x * x + y * y),
+                translation.z,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+}
+
+/// A transform that changes over time, moving between keyframes. Translations are
+/// interpolated along straight lines and rotations about a fixed axis, at rates set by the
+/// interpolation, which is linear unless changed. Before the first keyframe and after the
+/// last, the transform holds still.
+#[derive(Debug, Clone)]
+pub struct AnimatedTransform {
+    /// Applied before the motion, such as to scale the object
+    base: Transform,
+    keyframes: Vec<(f64, Vec3, Quaternion)>,
+    interpolation: Interpolation,
+}
+
+impl AnimatedTransform {
+    /// Creates a transform that applies `base` and then the motion described by `keyframes`,
+    /// which must be in increasing order of time. Each rotation is reached from the last by
+    /// the smallest rotation between them, so a spin of half a turn or more needs keyframes in
+    /// between.
+    pub fn new(base: Transform, keyframes: &[Keyframe]) -> Result<AnimatedTransform> {
+        check_key_times(keyframes.iter().map(|keyframe| keyframe.time))?;
+
+        Ok(AnimatedTransform {
+            base,
+            keyframes: keyframes
+                .iter()
+                .map(|keyframe| {
+                    let (axis, degrees) = keyframe.rotation;
+                    (
+                        keyframe.time,
+                        keyframe.translation,
+                        Quaternion::from_axis_angle(axis, degrees),
+                    )
+                })
+                .collect(),
+            interpolation: Interpolation::Linear,
+        })
+    }
+
+    /// Sets how the transform moves between keyframes.
+    pub fn with_interpolation(self, interpolation: Interpolation) -> AnimatedTransform {
+        AnimatedTransform {
+            interpolation,
+            ..self
+        }
+    }
+
+    /// Returns the transform at `time`.
+    pub fn at(&self, time: f64) -> Transform {
+        self.motion_at(time) * self.base
+    }
+
+    fn motion_at(&self, time: f64) -> Transform {
+        let (idx, t) = locate(&self.keyframes, time, |&(time, ..)| time);
+        let (translation, rotation) = if t == 0.0 {
+            let (_, translation, rotation) = self.keyframes[idx];
+            (translation, rotation)
+        } else {
+            let t = self.interpolation.ease(t);
+            interpolate(self.keyframes[idx], self.keyframes[idx + 1], t)
+        };
+        rotation.to_transform(translation)
+    }
+
+    /// Returns bounds that contain `bounds` as it's transformed at every point in time. Easing
+    /// only changes how fast the transform moves along its path, so the bounds are found along
+    /// the linear path.
+    pub fn motion_bounds(&self, bounds: &Aabb) -> Aabb {
+        if !bounds.is_finite() {
+            return Aabb::infinite();
+        }
+        let bounds = bounds.transform(&self.base);
+        let radius = bounds.corners().map(Vec3::mag).fold(0.0, f64::max);
+
+        let (_, translation, rotation) = self.keyframes[0];
+        let mut motion_bounds = bounds.transform(&rotation.to_transform(translation));
+        for pair in self.keyframes.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+
+            // Points sweep along arcs between the steps, which bulge out of the boxes at
+            // either end by less than this.
+            let step = start.2.angle_to(end.2) / f64::from(MOTION_BOUND_STEPS);
+            let bulge = radius * step * step / 2.0;
+            let pad = Vec3 {
+                x: bulge,
+                y: bulge,
+                z: bulge,
+            };
+
+            for i in 0..=MOTION_BOUND_STEPS {
+                let t = f64::from(i) / f64::from(MOTION_BOUND_STEPS);
+                let (translation, rotation) = interpolate(start, end, t);
+                let step_bounds = bounds.transform(&rotation.to_transform(translation));
+                motion_bounds = motion_bounds.union(Aabb {
+                    min: step_bounds.min - pad,
+                    max: step_bounds.max + pad,
+                });
+            }
+        }
+        motion_bounds
+    }
+}
+
+/// Returns the translation and rotation `t` of the way between two keyframes.
+fn interpolate(
+    (_, start_translation, start_rotation): (f64, Vec3, Quaternion),
+    (_, end_translation, end_rotation): (f64, Vec3, Quaternion),
+    t: f64,
+) -> (Vec3, Quaternion) {
+    (
+        start_translation.lerp(end_translation, t),
+        start_rotation.slerp(end_rotation, t),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    fn track(interpolation: Interpolation) -> Track<f64> {
+        Track::new(&[(0.0, 0.0), (2.0, 10.0), (3.0, 4.0)], interpolation).unwrap()
+    }
+
+    #[test]
+    fn track_interpolation() {
+        let linear = track(Interpolation::Linear);
+        // Keys are hit exactly, and held before the first and after the last.
+        for &(time, value) in &[(-1.0, 0.0), (0.0, 0.0), (2.0, 10.0), (3.0, 4.0), (5.0, 4.0)] {
+            assert_close(linear.at(time), value);
+        }
+        assert_close(linear.at(1.0), 5.0);
+        assert_close(linear.at(2.5), 7.0);
+
+        let step = track(Interpolation::Step);
+        assert_close(step.at(1.9), 0.0);
+        assert_close(step.at(2.0), 10.0);
+        assert_close(step.at(2.9), 10.0);
+
+        let smooth = track(Interpolation::Smooth);
+        assert_close(smooth.at(1.0), 5.0);
+        assert_close(smooth.at(0.5), 10.0 * 0.15625);
+        assert_close(smooth.at(2.0), 10.0);
+
+        let moving = Track::new(
+            &[
+                (0.0, Vec3::default()),
+                (
+                    4.0,
+                    Vec3 {
+                        x: 4.0,
+                        y: -8.0,
+                        z: 2.0,
+                    },
+                ),
+            ],
+            Interpolation::Linear,
+        )
+        .unwrap();
+        let halfway = moving.at(2.0);
+        assert_close(halfway.x, 2.0);
+        assert_close(halfway.y, -4.0);
+        assert_close(halfway.z, 1.0);
+    }
+
+    #[test]
+    fn invalid_tracks() {
+        let new = |keys: &[(f64, f64)]| Track::new(keys, Interpolation::Linear);
+        assert!(new(&[]).is_err());
+        assert!(new(&[(1.0, 0.0), (1.0, 1.0)]).is_err());
+        assert!(new(&[(2.0, 0.0), (1.0, 1.0)]).is_err());
+        assert!(new(&[(f64::NAN, 0.0)]).is_err());
+        assert_close(new(&[(3.0, 7.0)]).unwrap().at(-10.0), 7.0);
+    }
+}
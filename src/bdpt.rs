@@ -0,0 +1,1006 @@
+use std::f64;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::bsdf::Bsdf;
+use crate::light::bounding_sphere;
+use crate::math::*;
+use crate::renderer::{rgb_bsdf, Camera, IntersectionInfo, RenderOptions, Scene, SHADOW_TOLERANCE};
+use crate::stats::{self, Counter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+/// A point where a camera or light subpath starts or scatters. See "Robust Monte Carlo Methods
+/// for Light Transport Simulation" (Veach, 1997), chapter 10.
+#[derive(Copy, Clone)]
+struct Vertex {
+    kind: VertexKind,
+    point: Vec3,
+    /// The true surface normal, facing the previous vertex on surfaces, for vertices that lie
+    /// on a surface
+    normal: Option<Unit3>,
+    /// The normal used for shading, on the same side as `normal`
+    shading_normal: Option<Unit3>,
+    /// The direction towards the previous vertex
+    wo: Unit3,
+    bsdf: Option<Bsdf>,
+    /// The light that a light vertex belongs to, or that a surface vertex is the surface of
+    light: Option<usize>,
+    /// The radiance that a surface emits towards the previous vertex
+    emitted: Vec3,
+    /// The throughput of the subpath up to and including the vertex
+    beta: Vec3,
+    /// Whether the path scattered specularly here, so that it can't be connected to
+    delta: bool,
+    /// Whether the vertex is a light at a single point or direction, which can't be hit
+    delta_light: bool,
+    /// Whether the vertex is on a light infinitely far away
+    infinite: bool,
+    /// The probability density of the vertex with respect to area, as sampled by its subpath
+    pdf_fwd: f64,
+    /// The probability density of the vertex with respect to area, were it sampled by a
+    /// subpath travelling the other way
+    pdf_rev: f64,
+}
+
+impl Vertex {
+    fn new(kind: VertexKind, point: Vec3, beta: Vec3) -> Vertex {
+        Vertex {
+            kind,
+            point,
+            normal: None,
+            shading_normal: None,
+            wo: Unit3::from_unit_vec3(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+            bsdf: None,
+            light: None,
+            emitted: Vec3::default(),
+            beta,
+            delta: false,
+            delta_light: false,
+            infinite: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(info: &IntersectionInfo, wo: Unit3, light: Option<usize>, beta: Vec3) -> Vertex {
+        let material = info.prim.material();
+        let emitted = if material.two_sided || !info.inside {
+            material.emittance.eval(info.uv, info.point)
+        } else {
+            Vec3::default()
+        };
+        Vertex {
+            normal: Some(info.normal),
+            shading_normal: Some(info.shading_normal),
+            wo,
+            bsdf: Some(rgb_bsdf(info)),
+            light,
+            emitted,
+            ..Vertex::new(VertexKind::Surface, info.point, beta)
+        }
+    }
+
+    fn light(light: usize, point: Vec3, normal: Option<Unit3>, beta: Vec3) -> Vertex {
+        Vertex {
+            normal,
+            shading_normal: normal,
+            light: Some(light),
+            ..Vertex::new(VertexKind::Light, point, beta)
+        }
+    }
+
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Camera => true,
+            VertexKind::Light => !self.infinite,
+            VertexKind::Surface => self.bsdf.is_some_and(|bsdf| !bsdf.is_specular()),
+        }
+    }
+
+    /// Returns the fraction of light scattered at a surface vertex between the previous vertex
+    /// and `next`, or the other way if `importance` is set, excluding the cosine term.
+    fn f(&self, next: &Vertex, importance: bool) -> Vec3 {
+        let (bsdf, wi) = match (self.bsdf, (next.point - self.point).try_to_unit()) {
+            (Some(bsdf), Some(wi)) => (bsdf, wi),
+            _ => return Vec3::default(),
+        };
+        let value = bsdf.f(self.wo, wi);
+        if importance {
+            self.shading_correction(wi) * value
+        } else {
+            value
+        }
+    }
+
+    /// Corrects light traced from a light source for the asymmetry that shading normals
+    /// introduce when it scatters from `wo` to `wi`.
+    fn shading_correction(&self, wi: Unit3) -> f64 {
+        match (self.normal, self.shading_normal) {
+            (Some(normal), Some(shading_normal)) => {
+                shading_correction(normal, shading_normal, self.wo, wi)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Converts a probability density of the direction from the vertex to `next` into a
+    /// density of `next` with respect to area.
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        if next.infinite {
+            return pdf;
+        }
+        let offset = next.point - self.point;
+        let dist_squared = offset.mag_squared();
+        let dir = match offset.try_to_unit() {
+            Some(dir) => dir,
+            None => return 0.0,
+        };
+        let cos_theta = next
+            .normal
+            .map_or(1.0, |normal| Vec3::from(normal).dot(dir.into()).abs());
+        pdf * cos_theta / dist_squared
+    }
+}
+
+/// Corrects light traced from a light source for the asymmetry that shading normals
+/// introduce when it scatters from `wo` to `wi` at a surface with the given true and shading
+/// normals. See Veach (1997), section 5.3.
+pub(crate) fn shading_correction(
+    normal: Unit3,
+    shading_normal: Unit3,
+    wo: Unit3,
+    wi: Unit3,
+) -> f64 {
+    let (normal, shading_normal) = (Vec3::from(normal), Vec3::from(shading_normal));
+    let wo = Vec3::from(wo);
+    let wi = Vec3::from(wi);
+    let denom = wo.dot(normal).abs() * wi.dot(shading_normal).abs();
+    if denom <= 0.0 {
+        return 0.0;
+    }
+    wo.dot(shading_normal).abs() * wi.dot(normal).abs() / denom
+}
+
+/// Light added to the image at arbitrary positions, such as by light subpaths, which can land
+/// on any pixel.
+pub(crate) struct Film {
+    width: u32,
+    height: u32,
+    // Each channel is an f64 stored as bits, so that threads can add to it atomically
+    splats: Vec<[AtomicU64; 3]>,
+}
+
+impl Film {
+    pub(crate) fn new(width: u32, height: u32) -> Film {
+        let zero = || AtomicU64::new(0.0f64.to_bits());
+        Film {
+            width,
+            height,
+            splats: (0..width as usize * height as usize)
+                .map(|_| [zero(), zero(), zero()])
+                .collect(),
+        }
+    }
+
+    pub(crate) fn add_splat(&self, (x, y): (f64, f64), value: Vec3) {
+        let x = (x as usize).min(self.width as usize - 1);
+        let y = (y as usize).min(self.height as usize - 1);
+        let pixel = &self.splats[y * self.width as usize + x];
+        for (channel, amount) in pixel.iter().zip(&[value.x, value.y, value.z]) {
+            let _ = channel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + amount).to_bits())
+            });
+        }
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> Vec3 {
+        let [x, y, z] = &self.splats[idx];
+        Vec3 {
+            x: f64::from_bits(x.load(Ordering::Relaxed)),
+            y: f64::from_bits(y.load(Ordering::Relaxed)),
+            z: f64::from_bits(z.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Picks lights in proportion to their power, to start light subpaths and photons from.
+pub(crate) struct LightDistribution {
+    cdf: Vec<f64>,
+    /// Whether each light is infinitely far away
+    infinite: Vec<bool>,
+}
+
+impl LightDistribution {
+    /// Lights without bounds, whose power depends on the size of the scene, are picked as
+    /// often as the average light with bounds.
+    pub(crate) fn new(scene: &Scene) -> LightDistribution {
+        let powers: Vec<_> = (0..scene.light_count())
+            .map(|idx| scene.light(idx).get().bounds().map(|bounds| bounds.power))
+            .collect();
+        let infinite = powers.iter().map(Option::is_none).collect();
+        let bounded: Vec<_> = powers.iter().flatten().collect();
+        let average = if bounded.is_empty() {
+            1.0
+        } else {
+            bounded.iter().copied().sum::<f64>() / bounded.len() as f64
+        };
+
+        let mut total = 0.0;
+        let mut cdf: Vec<_> = powers
+            .iter()
+            .map(|power| {
+                total += power.unwrap_or(average).max(0.0);
+                total
+            })
+            .collect();
+        if total > 0.0 {
+            for value in &mut cdf {
+                *value /= total;
+            }
+        } else {
+            cdf.clear();
+        }
+        LightDistribution { cdf, infinite }
+    }
+
+    /// Picks a light using `u` in `[0, 1)`, returning it along with the probability of
+    /// picking it.
+    pub(crate) fn sample(&self, u: f64) -> Option<(usize, f64)> {
+        let idx = self.cdf.partition_point(|&value| value <= u);
+        if idx < self.cdf.len() {
+            Some((idx, self.pmf(idx)))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn pmf(&self, idx: usize) -> f64 {
+        match idx {
+            0 => self.cdf.first().copied().unwrap_or(0.0),
+            _ => self
+                .cdf
+                .get(idx)
+                .map_or(0.0, |value| value - self.cdf[idx - 1]),
+        }
+    }
+}
+
+/// Where the camera is for a single sample, and the time the sample is traced at.
+struct View {
+    camera: Camera,
+    time: f64,
+}
+
+/// Estimates the light reaching the camera by tracing subpaths from the camera and from a
+/// light, and connecting every pair of their vertices. The estimates from each way of
+/// forming a path are combined with the power heuristic.
+struct Tracer<'s> {
+    scene: &'s Scene<'s>,
+    camera: &'s Camera,
+    max_depth: u32,
+    scene_bounds: Aabb,
+    /// The center and radius of a sphere around `scene_bounds`, which lights infinitely far
+    /// away shine on
+    scene_sphere: Option<(Vec3, f64)>,
+    lights: LightDistribution,
+    film: Film,
+}
+
+impl<'s> Tracer<'s> {
+    fn new(scene: &'s Scene<'s>, camera: &'s Camera, opts: &RenderOptions) -> Tracer<'s> {
+        let scene_bounds = scene.finite_bounds();
+        Tracer {
+            scene,
+            camera,
+            max_depth: opts.max_depth,
+            scene_bounds,
+            scene_sphere: bounding_sphere(&scene_bounds),
+            lights: LightDistribution::new(scene),
+            film: Film::new(opts.width, opts.height),
+        }
+    }
+
+    /// Returns the radiance arriving through the camera along `ray`, splatting light traced
+    /// from the lights that lands elsewhere onto the film. The whole sample is traced at the
+    /// ray's time.
+    fn sample<R: Rng + ?Sized>(&self, ray: &Ray, rng: &mut R) -> Vec3 {
+        let view = View {
+            camera: self.camera.at(ray.time),
+            time: ray.time,
+        };
+        let camera_path = self.camera_subpath(ray, &view.camera, rng);
+        let light_path = self.light_subpath(view.time, rng);
+
+        let mut radiance = Vec3::default();
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let edges = s + t - 1;
+                if edges == 0 || (s == 1 && t == 1) || edges > self.max_depth as usize {
+                    continue;
+                }
+                match self.connect(&light_path, &camera_path, s, t, &view, rng) {
+                    Some((value, Some(raster))) => self.film.add_splat(raster, value),
+                    Some((value, None)) => radiance = radiance + value,
+                    None => {}
+                }
+            }
+        }
+        radiance
+    }
+
+    fn camera_subpath<R: Rng + ?Sized>(
+        &self,
+        ray: &Ray,
+        camera: &Camera,
+        rng: &mut R,
+    ) -> Vec<Vertex> {
+        let unit = Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let mut path = vec![Vertex::new(VertexKind::Camera, camera.position(), unit)];
+        let pdf = camera.pdf(ray.dir);
+        self.random_walk(ray, unit, pdf, self.max_depth, false, &mut path, rng);
+        path
+    }
+
+    fn light_subpath<R: Rng + ?Sized>(&self, time: f64, rng: &mut R) -> Vec<Vertex> {
+        let mut path = Vec::new();
+        if self.max_depth == 0 {
+            return path;
+        }
+        let (idx, prob) = match self.lights.sample(rng.gen()) {
+            Some(picked) => picked,
+            None => return path,
+        };
+        let light = self.scene.light(idx);
+        let mut emission = match light
+            .get()
+            .sample_emission(&self.scene_bounds, &mut &mut *rng)
+        {
+            Some(emission) => emission,
+            None => return path,
+        };
+        emission.ray.time = time;
+        emission.radiance = self.scene.light_scale(idx, time) * emission.radiance;
+        if emission.pdf_pos <= 0.0
+            || emission.pdf_dir <= 0.0
+            || emission.radiance.mag_squared() <= 0.0
+        {
+            return path;
+        }
+
+        let infinite = self.lights.infinite[idx];
+        let mut vertex =
+            Vertex::light(idx, emission.ray.origin, emission.normal, emission.radiance);
+        vertex.delta_light = emission.delta;
+        vertex.infinite = infinite;
+        vertex.pdf_fwd = prob * emission.pdf_pos;
+        path.push(vertex);
+
+        let cos_theta = emission.normal.map_or(1.0, |normal| {
+            Vec3::from(normal).dot(emission.ray.dir.into()).abs()
+        });
+        let beta = cos_theta / (prob * emission.pdf_pos * emission.pdf_dir) * emission.radiance;
+        self.random_walk(
+            &emission.ray,
+            beta,
+            emission.pdf_dir,
+            self.max_depth - 1,
+            true,
+            &mut path,
+            rng,
+        );
+
+        // Light infinitely far away starts from a disk rather than a point, and can't be hit.
+        if infinite {
+            if let Some(first) = path.get_mut(1) {
+                first.pdf_fwd = emission.pdf_pos
+                    * first.normal.map_or(1.0, |normal| {
+                        Vec3::from(normal).dot(emission.ray.dir.into()).abs()
+                    });
+            }
+            path[0].pdf_fwd = 0.0;
+        }
+        path
+    }
+
+    /// Extends a subpath by up to `max_bounces` vertices, following `ray` and then directions
+    /// sampled from the BSDFs it hits. `pdf` is the probability density of the ray's direction
+    /// and `importance` is set for subpaths traced from lights.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<R: Rng + ?Sized>(
+        &self,
+        ray: &Ray,
+        mut beta: Vec3,
+        mut pdf: f64,
+        max_bounces: u32,
+        importance: bool,
+        path: &mut Vec<Vertex>,
+        rng: &mut R,
+    ) {
+        let mut ray = *ray;
+        for bounce in 0..max_bounces {
+            let info = match self.scene.intersect_surface(&ray) {
+                Some(info) => info,
+                None => return,
+            };
+            let wo = (-Vec3::from(ray.dir)).to_unit();
+            let light = self.scene.prim_light(info.prim_index);
+            let mut vertex = Vertex::surface(&info, wo, light, beta);
+            vertex.emitted = self.scene.emission_scale(&info) * vertex.emitted;
+            let prev = path.last().unwrap();
+            vertex.pdf_fwd = prev.convert_density(pdf, &vertex);
+
+            let bsdf = vertex.bsdf.unwrap();
+            let sample = match bsdf.sample(wo, rng) {
+                Some(sample) if bounce + 1 < max_bounces => sample,
+                _ => {
+                    path.push(vertex);
+                    return;
+                }
+            };
+
+            let mut weight = sample.weight;
+            if importance {
+                weight = vertex.shading_correction(sample.dir) * weight;
+            }
+            beta = beta.component_mul(weight);
+
+            let pdf_rev = if sample.specular {
+                vertex.delta = true;
+                pdf = 0.0;
+                0.0
+            } else {
+                pdf = sample.pdf;
+                bsdf.pdf(sample.dir, wo)
+            };
+            path.push(vertex);
+            let len = path.len();
+            path[len - 2].pdf_rev = path[len - 1].convert_density(pdf_rev, &path[len - 2]);
+
+            if beta.mag_squared() <= 0.0 {
+                return;
+            }
+            stats::add(Counter::BounceRays, 1);
+            ray = Ray {
+                origin: info.point,
+                dir: sample.dir,
+                time: ray.time,
+            };
+        }
+    }
+
+    /// Returns the fraction of light that travels between two points at `time`, ignoring any
+    /// media.
+    fn visibility<R: Rng + ?Sized>(&self, from: Vec3, to: Vec3, time: f64, rng: &mut R) -> Vec3 {
+        let offset = to - from;
+        let dir = match offset.try_to_unit() {
+            Some(dir) => dir,
+            None => return Vec3::default(),
+        };
+        let ray = Ray {
+            origin: from,
+            dir,
+            time,
+        };
+        let dist = (1.0 - SHADOW_TOLERANCE) * offset.mag();
+        self.scene.transmittance(&ray, dist, None, rng)
+    }
+
+    /// Returns the geometry term between two vertices, including whether they can see each
+    /// other at `time`.
+    fn geometry<R: Rng + ?Sized>(&self, a: &Vertex, b: &Vertex, time: f64, rng: &mut R) -> Vec3 {
+        let offset = b.point - a.point;
+        let dir = match offset.try_to_unit() {
+            Some(dir) => Vec3::from(dir),
+            None => return Vec3::default(),
+        };
+        let cos = |normal: Option<Unit3>| normal.map_or(1.0, |n| Vec3::from(n).dot(dir).abs());
+        let g = cos(a.shading_normal) * cos(b.shading_normal) / offset.mag_squared();
+        g * self.visibility(b.point, a.point, time, rng)
+    }
+
+    /// Estimates the light along the path formed by the first `s` vertices of the light
+    /// subpath and the first `t` of the camera subpath, weighted against the other ways it
+    /// could have been formed. Light traced onto the camera is returned along with the pixel
+    /// coordinates it lands on.
+    fn connect<R: Rng + ?Sized>(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+        view: &View,
+        rng: &mut R,
+    ) -> Option<(Vec3, Option<(f64, f64)>)> {
+        let camera = &view.camera;
+        let mut sampled = None;
+        let mut raster = None;
+
+        let value = if s == 0 {
+            // The camera subpath hit a light by itself.
+            let pt = &camera_path[t - 1];
+            pt.beta.component_mul(pt.emitted)
+        } else if t == 1 {
+            // Trace light to the camera.
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible() {
+                return None;
+            }
+            let offset = camera.position() - qs.point;
+            let dir = offset.try_to_unit()?;
+            let from_camera = (-Vec3::from(dir)).to_unit();
+            raster = Some(camera.project(from_camera)?);
+            let cos_camera = Vec3::from(from_camera).dot(camera.direction().into());
+            let pdf = offset.mag_squared() / cos_camera;
+            let camera = Vertex::new(
+                VertexKind::Camera,
+                camera.position(),
+                camera.importance(from_camera) / pdf
+                    * Vec3 {
+                        x: 1.0,
+                        y: 1.0,
+                        z: 1.0,
+                    },
+            );
+
+            let cos_theta = qs
+                .shading_normal
+                .map_or(1.0, |n| Vec3::from(n).dot(dir.into()).abs());
+            let mut value = cos_theta
+                * qs.beta
+                    .component_mul(qs.f(&camera, true))
+                    .component_mul(camera.beta);
+            if value.mag_squared() > 0.0 {
+                let visibility = self.visibility(camera.point, qs.point, view.time, rng);
+                value = value.component_mul(visibility);
+            }
+            sampled = Some(camera);
+            value
+        } else if s == 1 {
+            // Sample a point on a light to connect the camera subpath to.
+            let pt = &camera_path[t - 1];
+            if !pt.is_connectible() {
+                return None;
+            }
+            let (idx, prob) = self.lights.sample(rng.gen())?;
+            let sample = self
+                .scene
+                .light(idx)
+                .get()
+                .sample(pt.point, view.time, &mut &mut *rng)?;
+            let infinite = sample.dist.is_infinite();
+            let dist = if infinite {
+                2.0 * self.scene_sphere.map_or(1.0, |(_, radius)| radius)
+            } else {
+                sample.dist
+            };
+            let mut light = Vertex::light(
+                idx,
+                pt.point + dist * Vec3::from(sample.dir),
+                sample.normal,
+                self.scene.light_scale(idx, view.time) / prob * sample.radiance,
+            );
+            light.delta_light = sample.delta;
+            light.infinite = infinite;
+            light.pdf_fwd = self.pdf_light_origin(&light, pt);
+
+            let cos_theta = pt
+                .shading_normal
+                .map_or(1.0, |n| Vec3::from(n).dot(sample.dir.into()).abs());
+            let mut value = cos_theta
+                * pt.beta
+                    .component_mul(pt.f(&light, false))
+                    .component_mul(light.beta);
+            if value.mag_squared() > 0.0 {
+                let ray = Ray {
+                    origin: pt.point,
+                    dir: sample.dir,
+                    time: view.time,
+                };
+                let dist = (1.0 - SHADOW_TOLERANCE) * sample.dist;
+                value = value.component_mul(self.scene.transmittance(&ray, dist, None, rng));
+            }
+            sampled = Some(light);
+            value
+        } else {
+            // Connect the ends of both subpaths.
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return None;
+            }
+            let mut value = qs
+                .beta
+                .component_mul(qs.f(pt, true))
+                .component_mul(pt.f(qs, false))
+                .component_mul(pt.beta);
+            if value.mag_squared() > 0.0 {
+                value = value.component_mul(self.geometry(qs, pt, view.time, rng));
+            }
+            value
+        };
+
+        if value.mag_squared() <= 0.0 {
+            return None;
+        }
+        let weight = self.mis_weight(light_path, camera_path, sampled, s, t, camera);
+        Some((weight * value, raster))
+    }
+
+    /// Returns the weight of the path formed by connecting the subpaths with `s` and `t`
+    /// vertices, relative to all the other ways of forming it. `sampled` replaces the end of
+    /// a subpath of one vertex that was sampled specially to make the connection.
+    fn mis_weight(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<Vertex>,
+        s: usize,
+        t: usize,
+        camera: &Camera,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let mut light_path = light_path[..s].to_vec();
+        let mut camera_path = camera_path[..t].to_vec();
+        if let Some(vertex) = sampled {
+            if s == 1 {
+                light_path[0] = vertex;
+            } else {
+                camera_path[0] = vertex;
+            }
+        }
+
+        // Emissive surfaces that aren't sampled as lights can only be found by the camera, and
+        // light from infinitely far away can only reach the rest of the scene directly.
+        if (s == 0 && camera_path[t - 1].light.is_none())
+            || (s == 1 && !self.is_lit_by_subpaths(&light_path[0], camera_path[t - 1].point))
+        {
+            return 1.0;
+        }
+
+        // Find the densities of the vertices around the connection as sampled from the other
+        // side of it.
+        let pt = camera_path[t - 1];
+        let pt_minus = camera_path.get(t.wrapping_sub(2)).copied();
+        let qs = light_path.get(s.wrapping_sub(1)).copied();
+        let qs_minus = light_path.get(s.wrapping_sub(2)).copied();
+
+        camera_path[t - 1].pdf_rev = match &qs {
+            Some(qs) => self.pdf(qs, qs_minus.as_ref(), &pt, camera),
+            None => self.pdf_light_origin(&pt, pt_minus.as_ref().unwrap()),
+        };
+        if let Some(pt_minus) = &pt_minus {
+            camera_path[t - 2].pdf_rev = match &qs {
+                Some(qs) => self.pdf(&pt, Some(qs), pt_minus, camera),
+                None => self.pdf_light(&pt, pt_minus),
+            };
+        }
+        if let Some(qs) = &qs {
+            light_path[s - 1].pdf_rev = self.pdf(&pt, pt_minus.as_ref(), qs, camera);
+            light_path[s - 1].delta = false;
+        }
+        if let (Some(qs), Some(qs_minus)) = (&qs, &qs_minus) {
+            light_path[s - 2].pdf_rev = self.pdf(qs, Some(&pt), qs_minus, camera);
+        }
+        camera_path[t - 1].delta = false;
+
+        // Sum the ratios of the densities of the other strategies to this one, skipping those
+        // that would need to connect to a specular vertex or hit a light that can't be hit.
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera_path[i].pdf_rev) / remap(camera_path[i].pdf_fwd);
+            if !camera_path[i].delta && !camera_path[i - 1].delta {
+                sum += ratio * ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light_path[i].pdf_rev) / remap(light_path[i].pdf_fwd);
+            let delta_before = match i {
+                0 => light_path[0].delta_light,
+                _ => light_path[i - 1].delta,
+            };
+            if !light_path[i].delta && !delta_before {
+                sum += ratio * ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+
+    /// Returns whether light subpaths starting from `light` can reach `point` directly. Light
+    /// from infinitely far away starts from a disk facing it, in front of the scene's bounding
+    /// sphere, so it misses anything outside the disk's beam.
+    fn is_lit_by_subpaths(&self, light: &Vertex, point: Vec3) -> bool {
+        if !light.infinite {
+            return true;
+        }
+        let (center, radius) = match self.scene_sphere {
+            Some(sphere) => sphere,
+            None => return false,
+        };
+        let to_light = match (light.point - point).try_to_unit() {
+            Some(dir) => Vec3::from(dir),
+            None => return false,
+        };
+        let offset = point - center;
+        let along = offset.dot(to_light);
+        along <= radius && offset.mag_squared() - along * along <= radius * radius
+    }
+
+    /// Returns the probability density, with respect to area, of `vertex` sampling `next` when
+    /// it's reached from `prev`, with `camera` where the camera is.
+    fn pdf(&self, vertex: &Vertex, prev: Option<&Vertex>, next: &Vertex, camera: &Camera) -> f64 {
+        let dir = match (next.point - vertex.point).try_to_unit() {
+            Some(dir) => dir,
+            None => return 0.0,
+        };
+        let pdf = match vertex.kind {
+            VertexKind::Light => return self.pdf_light(vertex, next),
+            VertexKind::Camera => camera.pdf(dir),
+            VertexKind::Surface => {
+                let wo = prev.and_then(|prev| (prev.point - vertex.point).try_to_unit());
+                match (vertex.bsdf, wo) {
+                    (Some(bsdf), Some(wo)) => bsdf.pdf(wo, dir),
+                    _ => 0.0,
+                }
+            }
+        };
+        vertex.convert_density(pdf, next)
+    }
+
+    /// Returns the probability density, with respect to area, of a light subpath starting at
+    /// `vertex` on a light going on to `next`.
+    fn pdf_light(&self, vertex: &Vertex, next: &Vertex) -> f64 {
+        let offset = next.point - vertex.point;
+        let dir = match offset.try_to_unit() {
+            Some(dir) => dir,
+            None => return 0.0,
+        };
+        let pdf = if vertex.infinite {
+            match self.scene_sphere {
+                Some((_, radius)) => 1.0 / (f64::consts::PI * radius * radius),
+                None => return 0.0,
+            }
+        } else {
+            let light = match vertex.light {
+                Some(light) => self.scene.light(light),
+                None => return 0.0,
+            };
+            let (_, pdf_dir) =
+                light
+                    .get()
+                    .pdf_emission(vertex.point, vertex.normal, dir, &self.scene_bounds);
+            pdf_dir / offset.mag_squared()
+        };
+        let cos_theta = next
+            .normal
+            .map_or(1.0, |normal| Vec3::from(normal).dot(dir.into()).abs());
+        pdf * cos_theta
+    }
+
+    /// Returns the probability density, with respect to area, of a light subpath starting at
+    /// `vertex` on a light, heading towards `next`.
+    fn pdf_light_origin(&self, vertex: &Vertex, next: &Vertex) -> f64 {
+        if vertex.infinite {
+            // Only directional lights are infinitely far away, and they can't be hit.
+            return 0.0;
+        }
+        let (light, dir) = match (vertex.light, (next.point - vertex.point).try_to_unit()) {
+            (Some(light), Some(dir)) => (light, dir),
+            _ => return 0.0,
+        };
+        let (pdf_pos, _) = self.scene.light(light).get().pdf_emission(
+            vertex.point,
+            vertex.normal,
+            dir,
+            &self.scene_bounds,
+        );
+        self.lights.pmf(light) * pdf_pos
+    }
+}
+
+/// Renders the scene with bidirectional path tracing.
+pub(crate) fn render_to(scene: &Scene, camera: &Camera, pixels: &mut [Vec3], opts: &RenderOptions) {
+    let tracer = Tracer::new(scene, camera, opts);
+    let samples = f64::from(opts.samples_per_pixel);
+
+    pixels.par_iter_mut().enumerate().for_each(|(idx, pixel)| {
+        let x = (idx as u32) % opts.width;
+        let y = (idx as u32) / opts.width;
+
+        let mut rng = rand::thread_rng();
+
+        let total_sampled = (0..opts.samples_per_pixel)
+            .map(|_| {
+                let ray = camera.cast_ray(
+                    f64::from(x) + rng.gen::<f64>(),
+                    f64::from(y) + rng.gen::<f64>(),
+                    camera.sample_time(rng.gen()),
+                );
+                tracer.sample(&ray, &mut rng)
+            })
+            .fold(Vec3::default(), |a, b| a + b);
+
+        *pixel = total_sampled / samples;
+    });
+
+    // Every sample also traced a path from a light, which could land on any pixel.
+    for (idx, pixel) in pixels.iter_mut().enumerate() {
+        *pixel = *pixel + tracer.film.get(idx) / samples;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Quad;
+    use crate::renderer::{CameraOptions, Integrator, Material, Primitive};
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    /// A floor and a back wall, lit by a square light overhead.
+    fn room() -> Scene<'static> {
+        let gray = || Material::make_diffuse(vec3(0.5, 0.5, 0.5));
+        Scene::with_primitives(vec![
+            Primitive::new(
+                Quad::new(
+                    vec3(-5.0, 0.0, 5.0),
+                    vec3(10.0, 0.0, 0.0),
+                    vec3(0.0, 0.0, -10.0),
+                )
+                .unwrap(),
+                gray(),
+            ),
+            Primitive::new(
+                Quad::new(
+                    vec3(-5.0, 0.0, -2.0),
+                    vec3(10.0, 0.0, 0.0),
+                    vec3(0.0, 5.0, 0.0),
+                )
+                .unwrap(),
+                gray(),
+            ),
+            Primitive::new(
+                Quad::new(
+                    vec3(-1.0, 3.0, -1.0),
+                    vec3(2.0, 0.0, 0.0),
+                    vec3(0.0, 0.0, 2.0),
+                )
+                .unwrap(),
+                Material::make_light(vec3(5.0, 5.0, 5.0)),
+            ),
+        ])
+    }
+
+    fn options() -> RenderOptions {
+        RenderOptions {
+            camera_options: CameraOptions {
+                pos: vec3(0.0, 1.0, 4.0),
+                target: vec3(0.0, 0.5, 0.0),
+                up: vec3(0.0, 1.0, 0.0),
+                vert_fov: 60.0,
+                motion: None,
+                animation: None,
+            },
+            width: 64,
+            height: 64,
+            samples_per_pixel: 1,
+            max_depth: 8,
+            threads: 1,
+            spectral: false,
+            integrator: Integrator::Bidirectional,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+
+    /// Sums the weights of every way of forming the path from the camera through `points`,
+    /// the last of which must lie on the light.
+    fn total_weight(tracer: &Tracer, camera: &Camera, points: &[Vec3]) -> f64 {
+        let unit = vec3(1.0, 1.0, 1.0);
+
+        // The vertices as seen from the camera, ending on the light's surface
+        let mut vertices = vec![Vertex::new(VertexKind::Camera, camera.position(), unit)];
+        for &point in points {
+            let from = vertices.last().unwrap().point;
+            let ray = Ray {
+                origin: from,
+                dir: (point - from).to_unit(),
+                time: 0.0,
+            };
+            let info = tracer.scene.intersect_surface(&ray).unwrap();
+            assert!((info.point - point).mag() < 1e-6, "path is blocked");
+            let wo = (-Vec3::from(ray.dir)).to_unit();
+            let light = tracer.scene.prim_light(info.prim_index);
+            vertices.push(Vertex::surface(&info, wo, light, unit));
+        }
+        let end = *vertices.last().unwrap();
+        let light = Vertex::light(end.light.unwrap(), end.point, end.normal, unit);
+
+        // The density of each vertex as sampled from the camera and from the light
+        let n = vertices.len();
+        let mut from_camera = vec![0.0; n];
+        let mut from_light = vec![0.0; n];
+        for i in 1..n {
+            let prev = vertices.get(i.wrapping_sub(2));
+            from_camera[i] = tracer.pdf(&vertices[i - 1], prev, &vertices[i], camera);
+        }
+        from_light[n - 1] = tracer.pdf_light_origin(&light, &vertices[n - 2]);
+        from_light[n - 2] = tracer.pdf_light(&light, &vertices[n - 2]);
+        for i in (0..n - 2).rev() {
+            let prev = if i + 2 == n - 1 {
+                &light
+            } else {
+                &vertices[i + 2]
+            };
+            from_light[i] = tracer.pdf(&vertices[i + 1], Some(prev), &vertices[i], camera);
+        }
+
+        let camera_path: Vec<Vertex> = (0..n)
+            .map(|i| Vertex {
+                pdf_fwd: if i == 0 { 0.0 } else { from_camera[i] },
+                pdf_rev: from_light[i],
+                ..vertices[i]
+            })
+            .collect();
+        let light_path: Vec<Vertex> = (0..n)
+            .map(|j| {
+                let i = n - 1 - j;
+                let vertex = if j == 0 { light } else { vertices[i] };
+                Vertex {
+                    pdf_fwd: from_light[i],
+                    pdf_rev: from_camera[i],
+                    ..vertex
+                }
+            })
+            .collect();
+
+        // Light can't be traced onto the camera by chance, so every strategy has a camera
+        // vertex.
+        (0..n)
+            .map(|s| tracer.mis_weight(&light_path, &camera_path, None, s, n - s, camera))
+            .sum()
+    }
+
+    #[test]
+    fn mis_weights_sum_to_one() {
+        let scene = room();
+        let opts = options();
+        let camera = Camera::new(&opts.camera_options, opts.width, opts.height).unwrap();
+        let tracer = Tracer::new(&scene, &camera, &opts);
+
+        let floor = vec3(0.5, 0.0, 1.0);
+        let wall = vec3(0.3, 1.5, -2.0);
+        let light = vec3(0.2, 3.0, -0.5);
+        for points in [vec![floor, light], vec![floor, wall, light]] {
+            let total = total_weight(&tracer, &camera, &points);
+            assert!(
+                (total - 1.0).abs() < 1e-9,
+                "weights for {:?} sum to {}",
+                points,
+                total
+            );
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::f64;
+
+use rand::Rng;
+
+use crate::math::*;
+use crate::sample::*;
+
+/// The result of sampling a direction from a `Bsdf`.
+#[derive(Debug, Copy, Clone)]
+pub struct BsdfSample {
+    /// The direction light arrives from, pointing away from the surface
+    pub dir: Unit3,
+    /// The value of the BSDF times the cosine term, divided by the probability density
+    pub weight: Vec3,
+    /// The probability density of the direction, or its discrete probability if `specular`
+    pub pdf: f64,
+    /// Whether the direction was chosen from a perfectly specular lobe, which `eval` and `pdf`
+    /// don't account for
+    pub specular: bool,
+    /// Whether the direction passes through the surface
+    pub transmitted: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Lobes {
+    /// A diffuse lobe mixed with a glossy reflection, chosen with probability `reflectance`.
+    /// The glossy lobe spreads reflected light uniformly over a cone around the mirror
+    /// direction.
+    Standard {
+        albedo: Vec3,
+        reflectance: f64,
+        cos_alpha: f64,
+    },
+    /// A smooth interface between dielectrics, where `eta` is the ratio of the index of
+    /// refraction on the incident side to that on the far side.
+    Dielectric { eta: f64, tint: Vec3 },
+}
+
+/// Describes how light is scattered at a point on a surface.
+///
+/// All directions point away from the surface: `wo` towards where the light ends up, and `wi`
+/// towards where it came from.
+#[derive(Debug, Copy, Clone)]
+pub struct Bsdf {
+    /// The true surface normal, on the same side as the viewer
+    normal: Unit3,
+    /// The normal used for shading, on the same side as `normal`
+    shading_normal: Unit3,
+    lobes: Lobes,
+}
+
+impl Bsdf {
+    /// Creates the BSDF of the diffuse and glossy materials made by `Material`. `gloss` ranges
+    /// from a hemisphere-wide lobe at 0 to a perfect mirror at 1.
+    pub fn standard(
+        normal: Unit3,
+        shading_normal: Unit3,
+        albedo: Vec3,
+        reflectance: f64,
+        gloss: f64,
+    ) -> Bsdf {
+        let alpha = (1.0 - gloss) * f64::consts::FRAC_PI_2;
+        Bsdf {
+            normal,
+            shading_normal,
+            lobes: Lobes::Standard {
+                albedo,
+                reflectance: reflectance.clamp(0.0, 1.0),
+                cos_alpha: alpha.cos(),
+            },
+        }
+    }
+
+    /// Creates the BSDF of a smooth dielectric surface, where `eta` is the ratio of the index
+    /// of refraction on the viewer's side to that on the far side.
+    pub fn dielectric(normal: Unit3, shading_normal: Unit3, eta: f64, tint: Vec3) -> Bsdf {
+        Bsdf {
+            normal,
+            shading_normal,
+            lobes: Lobes::Dielectric { eta, tint },
+        }
+    }
+
+    /// Returns whether all of the BSDF's lobes are perfectly specular, so that `eval` is always
+    /// zero.
+    pub fn is_specular(&self) -> bool {
+        match self.lobes {
+            Lobes::Standard {
+                albedo,
+                reflectance,
+                cos_alpha,
+            } => {
+                reflectance > 0.0
+                    && is_mirror(cos_alpha)
+                    && (reflectance >= 1.0 || albedo.mag_squared() <= EPSILON)
+            }
+            Lobes::Dielectric { .. } => true,
+        }
+    }
+
+    /// Returns the probability that `sample` chooses a direction from a perfectly specular
+    /// lobe.
+    pub fn specular_probability(&self) -> f64 {
+        match self.lobes {
+            Lobes::Standard {
+                reflectance,
+                cos_alpha,
+                ..
+            } if is_mirror(cos_alpha) => reflectance,
+            Lobes::Standard { .. } => 0.0,
+            Lobes::Dielectric { .. } => 1.0,
+        }
+    }
+
+    fn reflect(&self, wo: Unit3) -> Vec3 {
+        let wo = Vec3::from(wo);
+        let normal = Vec3::from(self.shading_normal);
+        2.0 * wo.dot(normal) * normal - wo
+    }
+
+    /// Returns the value of the BSDF times the cosine of `wi` to the shading normal, excluding
+    /// any specular lobes.
+    pub fn eval(&self, wo: Unit3, wi: Unit3) -> Vec3 {
+        let cos_theta = Vec3::from(wi).dot(self.shading_normal.into());
+        cos_theta.max(0.0) * self.f(wo, wi)
+    }
+
+    /// Returns the value of the BSDF alone, excluding any specular lobes. Unlike `eval`, this
+    /// is symmetric in `wo` and `wi`, so it also describes light travelling the other way.
+    pub fn f(&self, wo: Unit3, wi: Unit3) -> Vec3 {
+        let (albedo, reflectance, cos_alpha) = match self.lobes {
+            Lobes::Standard {
+                albedo,
+                reflectance,
+                cos_alpha,
+            } => (albedo, reflectance, cos_alpha),
+            Lobes::Dielectric { .. } => return Vec3::default(),
+        };
+
+        // Directions below the true surface would leak light through it.
+        if !self.is_above(wo) || !self.is_above(wi) {
+            return Vec3::default();
+        }
+
+        let diffuse = (1.0 - reflectance) / f64::consts::PI * albedo;
+        let glossy = if !is_mirror(cos_alpha) && in_cone(self.reflect(wo), wi, cos_alpha) {
+            reflectance / (f64::consts::PI * (1.0 - cos_alpha * cos_alpha))
+        } else {
+            0.0
+        };
+
+        diffuse
+            + Vec3 {
+                x: glossy,
+                y: glossy,
+                z: glossy,
+            }
+    }
+
+    fn is_above(&self, dir: Unit3) -> bool {
+        Vec3::from(dir).dot(self.shading_normal.into()) > 0.0
+            && Vec3::from(dir).dot(self.normal.into()) > 0.0
+    }
+
+    /// Returns the probability density of `sample` choosing `wi`, excluding any specular lobes.
+    pub fn pdf(&self, wo: Unit3, wi: Unit3) -> f64 {
+        let (albedo, reflectance, cos_alpha) = match self.lobes {
+            Lobes::Standard {
+                albedo,
+                reflectance,
+                cos_alpha,
+            } => (albedo, reflectance, cos_alpha),
+            Lobes::Dielectric { .. } => return 0.0,
+        };
+
+        let cos_theta = Vec3::from(wi).dot(self.shading_normal.into());
+        let diffuse = if albedo.mag_squared() > EPSILON && cos_theta > 0.0 {
+            (1.0 - reflectance) * cos_theta / f64::consts::PI
+        } else {
+            0.0
+        };
+        let glossy = if !is_mirror(cos_alpha) && in_cone(self.reflect(wo), wi, cos_alpha) {
+            reflectance / (2.0 * f64::consts::PI * (1.0 - cos_alpha))
+        } else {
+            0.0
+        };
+
+        diffuse + glossy
+    }
+
+    /// Samples the direction light arrives from, given the direction it leaves in.
+    pub fn sample<R: Rng + ?Sized>(&self, wo: Unit3, rng: &mut R) -> Option<BsdfSample> {
+        match self.lobes {
+            Lobes::Standard {
+                albedo,
+                reflectance,
+                cos_alpha,
+            } => {
+                let dir = if reflectance > 0.0 && rng.gen::<f64>() < reflectance {
+                    let reflection = Unit3::from_unit_vec3(self.reflect(wo));
+                    if is_mirror(cos_alpha) {
+                        return self.sample_mirror(reflection, reflectance);
+                    }
+                    sample_uniform_cone(reflection, cos_alpha.acos(), rng)
+                } else if albedo.mag_squared() > EPSILON {
+                    sample_cos_weighted_hemisphere(self.shading_normal, rng)
+                } else {
+                    return None;
+                };
+
+                let pdf = self.pdf(wo, dir);
+                let value = self.eval(wo, dir);
+                if pdf <= 0.0 || value.mag_squared() <= 0.0 {
+                    return None;
+                }
+
+                Some(BsdfSample {
+                    dir,
+                    weight: value / pdf,
+                    pdf,
+                    specular: false,
+                    transmitted: false,
+                })
+            }
+            Lobes::Dielectric { eta, tint } => self.sample_dielectric(wo, eta, tint, rng),
+        }
+    }
+
+    fn sample_mirror(&self, dir: Unit3, reflectance: f64) -> Option<BsdfSample> {
+        let cos_theta = Vec3::from(dir).dot(self.shading_normal.into());
+        if cos_theta <= 0.0 || Vec3::from(dir).dot(self.normal.into()) <= 0.0 {
+            return None;
+        }
+
+        // The limit of the glossy lobe as it narrows, which reflects the cosine-weighted
+        // incoming light.
+        Some(BsdfSample {
+            dir,
+            weight: Vec3 {
+                x: cos_theta,
+                y: cos_theta,
+                z: cos_theta,
+            },
+            pdf: reflectance,
+            specular: true,
+            transmitted: false,
+        })
+    }
+
+    fn sample_dielectric<R: Rng + ?Sized>(
+        &self,
+        wo: Unit3,
+        eta: f64,
+        tint: Vec3,
+        rng: &mut R,
+    ) -> Option<BsdfSample> {
+        let wo = Vec3::from(wo);
+        let normal = Vec3::from(self.shading_normal);
+        let cos_i = wo.dot(normal);
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+
+        // Refract unless there's total internal reflection, or the Fresnel term chooses
+        // reflection.
+        let cos_t = (1.0 - sin2_t).max(0.0).sqrt();
+        let reflectance = if sin2_t < 1.0 {
+            fresnel_dielectric(cos_i, cos_t, eta)
+        } else {
+            1.0
+        };
+
+        if rng.gen::<f64>() >= reflectance {
+            Some(BsdfSample {
+                dir: (-eta * wo + (eta * cos_i - cos_t) * normal).to_unit(),
+                weight: tint,
+                pdf: 1.0 - reflectance,
+                specular: true,
+                transmitted: true,
+            })
+        } else {
+            Some(BsdfSample {
+                dir: (2.0 * cos_i * normal - wo).to_unit(),
+                weight: tint,
+                pdf: reflectance,
+                specular: true,
+                transmitted: false,
+            })
+        }
+    }
+}
+
+/// Returns whether a glossy lobe is narrow enough to be treated as a perfect mirror.
+fn is_mirror(cos_alpha: f64) -> bool {
+    1.0 - cos_alpha < EPSILON
+}
+
+fn in_cone(axis: Vec3, dir: Unit3, cos_alpha: f64) -> bool {
+    Vec3::from(dir).dot(axis) >= cos_alpha
+}
+
+/// Returns the fraction of unpolarized light reflected from a dielectric interface, given the
+/// cosines of the incident and transmitted angles and the ratio of the indices of refraction.
+fn fresnel_dielectric(cos_i: f64, cos_t: f64, eta: f64) -> f64 {
+    let parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn assert_vec_close(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).mag() < TOLERANCE * expected.mag().max(1.0),
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn standard_sample_weight_matches_eval_and_pdf() {
+        let normal = vec3(0.0, 0.0, 1.0).to_unit();
+        let shading_normal = vec3(0.1, 0.0, 1.0).to_unit();
+        let wo = vec3(-0.5, 0.2, 1.0).to_unit();
+        let albedo = vec3(0.8, 0.5, 0.2);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for &(reflectance, gloss) in &[(0.0, 0.0), (1.0, 0.7), (0.4, 0.9), (0.6, 0.0)] {
+            let bsdf = Bsdf::standard(normal, shading_normal, albedo, reflectance, gloss);
+            let mut sampled = 0;
+            for _ in 0..1000 {
+                if let Some(sample) = bsdf.sample(wo, &mut rng) {
+                    assert!(!sample.specular && !sample.transmitted);
+                    let pdf = bsdf.pdf(wo, sample.dir);
+                    assert!((sample.pdf - pdf).abs() < TOLERANCE * pdf);
+                    assert_vec_close(sample.weight, bsdf.eval(wo, sample.dir) / pdf);
+                    sampled += 1;
+                }
+            }
+            assert!(sampled > 500, "only {} samples succeeded", sampled);
+        }
+    }
+
+    #[test]
+    fn diffuse_sample_weight_is_albedo() {
+        // Cosine-weighted sampling cancels the whole Lambertian lobe.
+        let normal = vec3(0.0, 1.0, 0.0).to_unit();
+        let albedo = vec3(0.3, 0.6, 0.9);
+        let bsdf = Bsdf::standard(normal, normal, albedo, 0.0, 0.0);
+        let wo = vec3(1.0, 1.0, 0.0).to_unit();
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let sample = bsdf.sample(wo, &mut rng).unwrap();
+            assert_vec_close(sample.weight, albedo);
+        }
+    }
+
+    #[test]
+    fn mirror_sample_is_specular() {
+        let normal = vec3(0.0, 0.0, 1.0).to_unit();
+        let bsdf = Bsdf::standard(normal, normal, Vec3::default(), 1.0, 1.0);
+        assert!(bsdf.is_specular());
+
+        let wo = vec3(1.0, 0.0, 1.0).to_unit();
+        let sample = bsdf.sample(wo, &mut StdRng::seed_from_u64(2)).unwrap();
+        assert!(sample.specular);
+        assert_vec_close(sample.dir.into(), vec3(-1.0, 0.0, 1.0) / 2f64.sqrt());
+        assert_eq!(bsdf.eval(wo, sample.dir).mag_squared(), 0.0);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::math::*;
+use crate::stats::{self, Counter};
+
+const MAX_LEAF_SIZE: usize = 4;
+const BIN_COUNT: usize = 12;
+
+#[derive(Debug, Copy, Clone)]
+enum NodeKind {
+    Leaf { start: u32, count: u32 },
+    // The first child always immediately follows its parent.
+    Interior { second: u32, axis: u8 },
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+/// A bounding volume hierarchy over a set of indexed items, built with a binned surface area
+/// heuristic.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<u32>,
+}
+
+struct BuildItem {
+    bounds: Aabb,
+    centroid: Vec3,
+    index: u32,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over items with the given bounds. All bounds must be finite.
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let mut items: Vec<BuildItem> = bounds
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| {
+                debug_assert!(bounds.is_finite(), "Infinite bounds in BVH");
+                BuildItem {
+                    bounds: *bounds,
+                    centroid: bounds.centroid(),
+                    index: index as u32,
+                }
+            })
+            .collect();
+
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * items.len()),
+            indices: Vec::with_capacity(items.len()),
+        };
+
+        if !items.is_empty() {
+            bvh.build(&mut items);
+        }
+
+        bvh
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes
+            .first()
+            .map_or_else(Aabb::empty, |node| node.bounds)
+    }
+
+    fn build(&mut self, items: &mut [BuildItem]) {
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, item| acc.union(item.bounds));
+
+        let node_idx = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            kind: NodeKind::Leaf { start: 0, count: 0 },
+        });
+
+        let split = if items.len() > MAX_LEAF_SIZE {
+            find_split(items, &bounds)
+        } else {
+            None
+        };
+
+        match split {
+            Some((axis, mid)) => {
+                let (first, second) = items.split_at_mut(mid);
+                self.build(first);
+                let second_idx = self.nodes.len() as u32;
+                self.build(second);
+                self.nodes[node_idx].kind = NodeKind::Interior {
+                    second: second_idx,
+                    axis: axis as u8,
+                };
+            }
+            None => {
+                let start = self.indices.len() as u32;
+                self.indices.extend(items.iter().map(|item| item.index));
+                self.nodes[node_idx].kind = NodeKind::Leaf {
+                    start,
+                    count: items.len() as u32,
+                };
+            }
+        }
+    }
+
+    /// Finds the closest intersection of `ray` with the items in the hierarchy.
+    ///
+    /// `intersect` is invoked with the index of every item whose bounds may be hit before the
+    /// closest intersection found so far, and should return the distance to the item along with
+    /// any associated data.
+    pub fn intersect<T, F>(&self, ray: &Ray, mut intersect: F) -> Option<(f64, T)>
+    where
+        F: FnMut(usize) -> Option<(f64, T)>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = Vec3::from(ray.dir).recip();
+        let dir_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+
+        let mut closest: Option<(f64, T)> = None;
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        let mut visited = 0;
+        let mut tests = 0;
+
+        while let Some(node_idx) = stack.pop() {
+            visited += 1;
+            let node = &self.nodes[node_idx];
+            let max_dist = closest.as_ref().map_or(f64::INFINITY, |(dist, _)| *dist);
+            if node
+                .bounds
+                .intersect_range(ray, inv_dir, max_dist)
+                .is_none()
+            {
+                continue;
+            }
+
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    let start = start as usize;
+                    tests += count;
+                    for &index in &self.indices[start..start + count as usize] {
+                        if let Some((dist, data)) = intersect(index as usize) {
+                            let max_dist =
+                                closest.as_ref().map_or(f64::INFINITY, |(dist, _)| *dist);
+                            if dist < max_dist {
+                                closest = Some((dist, data));
+                            }
+                        }
+                    }
+                }
+                NodeKind::Interior { second, axis } => {
+                    // Visit the nearer child first.
+                    if dir_neg[axis as usize] {
+                        stack.push(node_idx + 1);
+                        stack.push(second as usize);
+                    } else {
+                        stack.push(second as usize);
+                        stack.push(node_idx + 1);
+                    }
+                }
+            }
+        }
+
+        stats::add(Counter::NodesVisited, visited);
+        stats::add(Counter::IntersectionTests, u64::from(tests));
+        closest
+    }
+}
+
+/// Finds the best split of `items` according to the surface area heuristic, partitioning them
+/// in place. Returns the split axis and the index of the first item in the second half, or
+/// `None` if the items should be kept in a single leaf.
+fn find_split(items: &mut [BuildItem], bounds: &Aabb) -> Option<(usize, usize)> {
+    let centroid_bounds = items
+        .iter()
+        .fold(Aabb::empty(), |acc, item| acc.include(item.centroid));
+
+    let mut best: Option<(f64, usize, usize)> = None;
+
+    for axis in 0..3 {
+        let min = centroid_bounds.min.axis(axis);
+        let extent = centroid_bounds.max.axis(axis) - min;
+        if extent <= EPSILON {
+            continue;
+        }
+
+        let bin_of = |item: &BuildItem| {
+            let bin = ((item.centroid.axis(axis) - min) / extent * BIN_COUNT as f64) as usize;
+            bin.min(BIN_COUNT - 1)
+        };
+
+        let mut bin_bounds = [Aabb::empty(); BIN_COUNT];
+        let mut bin_counts = [0usize; BIN_COUNT];
+        for item in items.iter() {
+            let bin = bin_of(item);
+            bin_bounds[bin] = bin_bounds[bin].union(item.bounds);
+            bin_counts[bin] += 1;
+        }
+
+        // Sweep from the right to find the cost of every suffix.
+        let mut right_areas = [0.0; BIN_COUNT];
+        let mut right_bounds = Aabb::empty();
+        for bin in (1..BIN_COUNT).rev() {
+            right_bounds = right_bounds.union(bin_bounds[bin]);
+            right_areas[bin] = right_bounds.surface_area();
+        }
+
+        let mut left_bounds = Aabb::empty();
+        let mut left_count = 0;
+        for bin in 0..BIN_COUNT - 1 {
+            left_bounds = left_bounds.union(bin_bounds[bin]);
+            left_count += bin_counts[bin];
+            let right_count = items.len() - left_count;
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = left_bounds.surface_area() * left_count as f64
+                + right_areas[bin + 1] * right_count as f64;
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, bin));
+            }
+        }
+    }
+
+    let (cost, axis, split_bin) = best?;
+
+    // Relative cost of a leaf vs. traversing an interior node with the proposed split
+    let leaf_cost = bounds.surface_area() * items.len() as f64;
+    if items.len() <= 2 * MAX_LEAF_SIZE && cost + bounds.surface_area() >= leaf_cost {
+        return None;
+    }
+
+    let min = centroid_bounds.min.axis(axis);
+    let extent = centroid_bounds.max.axis(axis) - min;
+    let mid = partition(items, |item| {
+        let bin = ((item.centroid.axis(axis) - min) / extent * BIN_COUNT as f64) as usize;
+        bin.min(BIN_COUNT - 1) <= split_bin
+    });
+
+    Some((axis, mid))
+}
+
+fn partition<T, F: Fn(&T) -> bool>(items: &mut [T], pred: F) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
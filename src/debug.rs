@@ -0,0 +1,309 @@
+use std::f64;
+
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::math::*;
+use crate::renderer::{Camera, RenderOptions, Scene};
+use crate::sample::sample_cos_weighted_hemisphere;
+use crate::stats::{self, Counter};
+
+/// How far ambient occlusion looks for occluders, as a fraction of the size of the scene's
+/// bounds.
+const OCCLUSION_DISTANCE: f64 = 0.1;
+
+/// A view of the scene's geometry rather than its lighting, for finding out why a scene looks
+/// wrong. Every view looks through interfaces to the surfaces behind them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugView {
+    /// Colors surfaces by their shading normal, mapping each component from `[-1, 1]` to
+    /// `[0, 1]`
+    Normal,
+    /// Shades surfaces from white nearby to black at the far side of the scene's bounds
+    Depth,
+    /// Colors surfaces by their texture coordinates, with u in red and v in green
+    Uv,
+    /// Gives each primitive a color of its own
+    PrimitiveId,
+    /// Colors pixels from blue to red by how many BVH nodes their rays visited, relative to
+    /// the most visited by any pixel
+    BvhHeatmap,
+    /// Shades surfaces by the fraction of the hemisphere above them that is open within a
+    /// short distance
+    AmbientOcclusion,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 6] = [
+        DebugView::Normal,
+        DebugView::Depth,
+        DebugView::Uv,
+        DebugView::PrimitiveId,
+        DebugView::BvhHeatmap,
+        DebugView::AmbientOcclusion,
+    ];
+
+    /// Returns a short lowercase name for the view.
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugView::Normal => "normal",
+            DebugView::Depth => "depth",
+            DebugView::Uv => "uv",
+            DebugView::PrimitiveId => "prim-id",
+            DebugView::BvhHeatmap => "bvh",
+            DebugView::AmbientOcclusion => "ao",
+        }
+    }
+
+    /// Looks up a view by the name `name` returns.
+    pub fn from_name(name: &str) -> Option<DebugView> {
+        DebugView::ALL
+            .iter()
+            .copied()
+            .find(|view| view.name() == name)
+    }
+}
+
+struct Viewer<'s> {
+    scene: &'s Scene<'s>,
+    view: DebugView,
+    /// The distance from the camera to the farthest corner of the scene's bounds
+    far: f64,
+    occlusion_distance: f64,
+}
+
+impl Viewer<'_> {
+    fn sample<R: Rng + ?Sized>(&self, ray: &Ray, rng: &mut R) -> Vec3 {
+        let visited = stats::get(Counter::NodesVisited);
+        let info = self.scene.intersect_surface(ray);
+        if self.view == DebugView::BvhHeatmap {
+            return gray((stats::get(Counter::NodesVisited) - visited) as f64);
+        }
+
+        let info = match info {
+            Some(info) => info,
+            None => return Vec3::default(),
+        };
+        match self.view {
+            DebugView::Normal => (Vec3::from(info.shading_normal) + gray(1.0)) / 2.0,
+            DebugView::Depth => {
+                let depth = (info.point - ray.origin).mag();
+                gray((1.0 - depth / self.far).clamp(0.0, 1.0))
+            }
+            DebugView::Uv => Vec3 {
+                x: info.uv.0,
+                y: info.uv.1,
+                z: 0.0,
+            },
+            DebugView::PrimitiveId => false_color(info.prim_index as u64),
+            DebugView::AmbientOcclusion => {
+                let ray = Ray {
+                    origin: info.point,
+                    dir: sample_cos_weighted_hemisphere(info.shading_normal, rng),
+                    time: ray.time,
+                };
+                let open = self
+                    .scene
+                    .intersect_surface(&ray)
+                    .is_none_or(|hit| (hit.point - info.point).mag() > self.occlusion_distance);
+                gray(if open { 1.0 } else { 0.0 })
+            }
+            DebugView::BvhHeatmap => unreachable!(),
+        }
+    }
+}
+
+fn gray(value: f64) -> Vec3 {
+    Vec3 {
+        x: value,
+        y: value,
+        z: value,
+    }
+}
+
+/// Returns a bright color that differs widely between nearby `id`s.
+fn false_color(id: u64) -> Vec3 {
+    let hash = (id + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let channel = |shift: u32| 0.2 + 0.8 * ((hash >> shift) & 0xff) as f64 / 255.0;
+    Vec3 {
+        x: channel(40),
+        y: channel(48),
+        z: channel(56),
+    }
+}
+
+/// Maps `t` in `[0, 1]` from blue through green to red.
+fn heat(t: f64) -> Vec3 {
+    let t = t.clamp(0.0, 1.0);
+    Vec3 {
+        x: (2.0 * t - 1.0).max(0.0),
+        y: 1.0 - (2.0 * t - 1.0).abs(),
+        z: (1.0 - 2.0 * t).max(0.0),
+    }
+}
+
+/// Renders `view` of the scene, averaging over `samples_per_pixel` rays through each pixel.
+pub(crate) fn render_to(
+    scene: &Scene,
+    camera: &Camera,
+    pixels: &mut [Vec3],
+    opts: &RenderOptions,
+    view: DebugView,
+) {
+    let bounds = scene.finite_bounds();
+    let far = bounds
+        .corners()
+        .map(|corner| (corner - camera.position()).mag())
+        .fold(0.0, f64::max);
+    let size = (bounds.max - bounds.min).mag();
+    let viewer = Viewer {
+        scene,
+        view,
+        far,
+        occlusion_distance: if size.is_finite() {
+            OCCLUSION_DISTANCE * size
+        } else {
+            f64::INFINITY
+        },
+    };
+    let samples = f64::from(opts.samples_per_pixel);
+
+    pixels.par_iter_mut().enumerate().for_each(|(idx, pixel)| {
+        let x = (idx as u32) % opts.width;
+        let y = (idx as u32) / opts.width;
+
+        let mut rng = rand::thread_rng();
+
+        let total_sampled = (0..opts.samples_per_pixel)
+            .map(|_| {
+                let ray = camera.cast_ray(
+                    f64::from(x) + rng.gen::<f64>(),
+                    f64::from(y) + rng.gen::<f64>(),
+                    camera.sample_time(rng.gen()),
+                );
+                viewer.sample(&ray, &mut rng)
+            })
+            .fold(Vec3::default(), |a, b| a + b);
+
+        *pixel = total_sampled / samples;
+    });
+
+    // Node counts are only meaningful relative to each other.
+    if view == DebugView::BvhHeatmap {
+        let most = pixels.iter().map(|pixel| pixel.x).fold(0.0, f64::max);
+        for pixel in pixels.iter_mut() {
+            *pixel = heat(if most > 0.0 { pixel.x / most } else { 0.0 });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Sphere;
+    use crate::renderer::{self, CameraOptions, Integrator, Material, Primitive};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn assert_vec_eq(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).mag() < 1e-12,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    fn sphere(center: Vec3) -> Primitive<'static> {
+        Primitive::new(
+            Sphere::new(center, 1.0).unwrap(),
+            Material::make_diffuse(vec3(0.5, 0.5, 0.5)),
+        )
+    }
+
+    #[test]
+    fn view_names_round_trip() {
+        for &view in DebugView::ALL.iter() {
+            assert_eq!(DebugView::from_name(view.name()), Some(view));
+        }
+        assert_eq!(DebugView::from_name("heatmap"), None);
+    }
+
+    #[test]
+    fn heat_runs_from_blue_to_red() {
+        assert_vec_eq(heat(0.0), vec3(0.0, 0.0, 1.0));
+        assert_vec_eq(heat(0.5), vec3(0.0, 1.0, 0.0));
+        assert_vec_eq(heat(1.0), vec3(1.0, 0.0, 0.0));
+        assert_vec_eq(heat(-3.0), heat(0.0));
+        assert_vec_eq(heat(7.0), heat(1.0));
+    }
+
+    #[test]
+    fn bvh_heatmap_is_relative_to_busiest_pixel() {
+        let scene = Scene::with_primitives(
+            (0..8)
+                .map(|idx| sphere(vec3(f64::from(idx) * 2.5 - 9.0, 0.0, 0.0)))
+                .collect(),
+        );
+        let opts = RenderOptions {
+            camera_options: CameraOptions {
+                pos: vec3(0.0, 0.0, 20.0),
+                target: vec3(0.0, 0.0, 0.0),
+                up: vec3(0.0, 1.0, 0.0),
+                vert_fov: 40.0,
+                motion: None,
+                animation: None,
+            },
+            width: 32,
+            height: 24,
+            samples_per_pixel: 1,
+            max_depth: 1,
+            threads: 1,
+            spectral: false,
+            integrator: Integrator::Debug(DebugView::BvhHeatmap),
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        };
+        let pixels = renderer::render(&scene, &opts).unwrap();
+
+        // Every pixel is on the scale, and the busiest is at its top.
+        for &pixel in pixels.iter() {
+            assert!((pixel.x + pixel.y + pixel.z - 1.0).abs() < 1e-12);
+        }
+        let hottest = pixels.iter().map(|pixel| pixel.x).fold(0.0, f64::max);
+        assert_eq!(hottest, 1.0);
+    }
+
+    #[test]
+    fn occlusion_only_counts_nearby_surfaces() {
+        // Two spheres with a narrow gap between them, looking at the near side of one.
+        let scene = Scene::with_primitives(vec![
+            sphere(vec3(0.0, 0.0, 0.0)),
+            sphere(vec3(2.1, 0.0, 0.0)),
+        ]);
+        let ray = Ray {
+            origin: vec3(1.05, 0.0, 5.0),
+            dir: vec3(-0.05, 0.0, -5.0).to_unit(),
+            time: 0.0,
+        };
+        let occlusion = |distance: f64| {
+            let viewer = Viewer {
+                scene: &scene,
+                view: DebugView::AmbientOcclusion,
+                far: 10.0,
+                occlusion_distance: distance,
+            };
+            let mut rng = StdRng::seed_from_u64(0);
+            let open: f64 = (0..200).map(|_| viewer.sample(&ray, &mut rng).x).sum();
+            open / 200.0
+        };
+
+        assert_eq!(occlusion(1e-3), 1.0);
+        let occluded = occlusion(1.0);
+        assert!(occluded > 0.0 && occluded < 0.9, "{} open", occluded);
+    }
+}
@@ -0,0 +1,301 @@
+use rayon::prelude::*;
+
+use crate::error::{Error, Result};
+use crate::img::pixel_count;
+use crate::math::*;
+
+/// The number of filtering passes, each spreading its kernel twice as far as the last.
+const ITERATIONS: u32 = 5;
+
+/// The weights of the taps of the kernel along each axis, from a cubic B-spline.
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// How far apart the luminance of two neighbors may be, at a strength of 1, before they stop
+/// being averaged together, in standard deviations of the luminance around the pixel. Noisier
+/// pixels, and fireflies especially, are blended more as a result, and the limit narrows as
+/// passes remove noise.
+const LUMINANCE_SIGMA: f64 = 4.0;
+const NORMAL_SIGMA: f64 = 0.3;
+const ALBEDO_SIGMA: f64 = 0.1;
+/// How much the depth may change for each pixel of distance, relative to the depth itself.
+const DEPTH_SIGMA: f64 = 0.02;
+
+/// Albedo channels darker than this are left out of demodulation, where dividing by them would
+/// only amplify noise.
+const MIN_ALBEDO: f64 = 1e-3;
+
+/// The first surfaces seen through each pixel of an image, as rendered by the albedo, normal
+/// and depth auxiliary outputs, which tell the denoiser where the edges in the image are.
+pub struct Features<'a> {
+    pub albedo: &'a [Vec3],
+    pub normal: &'a [Vec3],
+    pub depth: &'a [Vec3],
+}
+
+/// Returns a denoised copy of `pixels`, filtered with an edge-avoiding à-trous wavelet
+/// transform guided by `features`. See "Edge-Avoiding À-Trous Wavelet Transform for fast
+/// Global Illumination Filtering" (Dammertz et al., 2010).
+///
+/// `strength` scales how different in luminance neighboring pixels may be and still be blended,
+/// where 0 leaves the image unchanged. The lighting is filtered separately from the albedo, so
+/// that textures stay sharp.
+///
+/// The features are the `Albedo`, `Normal` and `Depth` auxiliary outputs, which only the path
+/// and guided integrators render, so images from the other integrators can't be denoised.
+pub fn denoise(
+    pixels: &[Vec3],
+    features: &Features,
+    width: u32,
+    height: u32,
+    strength: f64,
+) -> Result<Box<[Vec3]>> {
+    let expected = pixel_count(width, height)?;
+    let buffers = [pixels, features.albedo, features.normal, features.depth];
+    if let Some(buffer) = buffers.iter().find(|buffer| buffer.len() != expected) {
+        return Err(Error::BufferSize {
+            expected,
+            actual: buffer.len(),
+        });
+    }
+    if !(strength >= 0.0 && strength.is_finite()) {
+        return Err(Error::InvalidParameter(
+            "denoising strength must be nonnegative and finite",
+        ));
+    }
+    if strength == 0.0 {
+        return Ok(pixels.into());
+    }
+
+    let albedo: Vec<_> = features
+        .albedo
+        .iter()
+        .map(|&albedo| divisor(albedo))
+        .collect();
+    let mut lighting: Vec<_> = pixels
+        .iter()
+        .zip(&albedo)
+        .map(|(&pixel, &albedo)| div(pixel, albedo))
+        .collect();
+
+    let filter = Filter {
+        features,
+        width: width as usize,
+        height: height as usize,
+    };
+    let mut filtered = vec![Vec3::default(); expected];
+    for iteration in 0..ITERATIONS {
+        let deviations = filter.deviations(&lighting);
+        filtered
+            .par_chunks_mut(filter.width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let sigma = strength * LUMINANCE_SIGMA * deviations[y * filter.width + x];
+                    *pixel = filter.apply(&lighting, x, y, 1 << iteration, sigma);
+                }
+            });
+        std::mem::swap(&mut lighting, &mut filtered);
+    }
+
+    Ok(lighting
+        .iter()
+        .zip(&albedo)
+        .map(|(&lighting, &albedo)| lighting.component_mul(albedo))
+        .collect())
+}
+
+struct Filter<'f> {
+    features: &'f Features<'f>,
+    width: usize,
+    height: usize,
+}
+
+impl Filter<'_> {
+    /// Returns the standard deviation of the luminance in the 3x3 block around each pixel.
+    fn deviations(&self, lighting: &[Vec3]) -> Vec<f64> {
+        (0..lighting.len())
+            .into_par_iter()
+            .map(|idx| {
+                let (x, y) = (idx % self.width, idx / self.width);
+                let mut count = 0.0;
+                let mut sum = 0.0;
+                let mut sum_squares = 0.0;
+                for ty in y.saturating_sub(1)..(y + 2).min(self.height) {
+                    for tx in x.saturating_sub(1)..(x + 2).min(self.width) {
+                        let value = luminance(lighting[ty * self.width + tx]);
+                        count += 1.0;
+                        sum += value;
+                        sum_squares += value * value;
+                    }
+                }
+                let mean = sum / count;
+                (sum_squares / count - mean * mean).max(0.0).sqrt()
+            })
+            .collect()
+    }
+
+    /// Returns the weighted average of the taps around the pixel at `(x, y)` that lie `step`
+    /// pixels apart, leaving out those that differ from it in luminance, by more than `sigma`,
+    /// or in features.
+    fn apply(&self, lighting: &[Vec3], x: usize, y: usize, step: usize, sigma: f64) -> Vec3 {
+        let center = y * self.width + x;
+        let mut total = Vec3::default();
+        let mut total_weight = 0.0;
+
+        for (dy, ky) in KERNEL.iter().enumerate() {
+            let ty = y as isize + (dy as isize - 2) * step as isize;
+            if ty < 0 || ty >= self.height as isize {
+                continue;
+            }
+            for (dx, kx) in KERNEL.iter().enumerate() {
+                let tx = x as isize + (dx as isize - 2) * step as isize;
+                if tx < 0 || tx >= self.width as isize {
+                    continue;
+                }
+
+                let tap = ty as usize * self.width + tx as usize;
+                let distance = f64::hypot((tx - x as isize) as f64, (ty - y as isize) as f64);
+                let weight = kx
+                    * ky
+                    * luminance_similarity(lighting[center], lighting[tap], sigma)
+                    * similarity(
+                        self.features.albedo[center],
+                        self.features.albedo[tap],
+                        ALBEDO_SIGMA,
+                    )
+                    * similarity(
+                        self.features.normal[center],
+                        self.features.normal[tap],
+                        NORMAL_SIGMA,
+                    )
+                    * self.depth_similarity(center, tap, distance);
+                total = total + weight * lighting[tap];
+                total_weight += weight;
+            }
+        }
+
+        // The center tap always has some weight, so this never divides by zero.
+        total / total_weight
+    }
+
+    fn depth_similarity(&self, center: usize, tap: usize, distance: f64) -> f64 {
+        let depth = self.features.depth[center].x;
+        let other = self.features.depth[tap].x;
+        if depth == other {
+            return 1.0;
+        }
+        let change = (depth - other).abs() / (DEPTH_SIGMA * depth * distance);
+        if change.is_finite() {
+            (-change).exp()
+        } else {
+            0.0
+        }
+    }
+}
+
+fn luminance_similarity(a: Vec3, b: Vec3, sigma: f64) -> f64 {
+    let difference = (luminance(a) - luminance(b)).abs();
+    if difference == 0.0 {
+        1.0
+    } else {
+        (-difference / sigma).exp()
+    }
+}
+
+fn luminance(color: Vec3) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+fn similarity(a: Vec3, b: Vec3, sigma: f64) -> f64 {
+    (-(a - b).mag_squared() / (sigma * sigma)).exp()
+}
+
+/// Returns what to divide the lighting at a pixel by to remove its albedo.
+fn divisor(albedo: Vec3) -> Vec3 {
+    let channel = |value: f64| if value < MIN_ALBEDO { 1.0 } else { value };
+    Vec3 {
+        x: channel(albedo.x),
+        y: channel(albedo.y),
+        z: channel(albedo.z),
+    }
+}
+
+fn div(value: Vec3, by: Vec3) -> Vec3 {
+    value.component_mul(by.recip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(value: f64) -> Vec3 {
+        Vec3 {
+            x: value,
+            y: value,
+            z: value,
+        }
+    }
+
+    fn assert_unchanged(pixels: &[Vec3], features: &Features, width: u32, height: u32) {
+        let denoised = denoise(pixels, features, width, height, 1.0).unwrap();
+        for (denoised, pixel) in denoised.iter().zip(pixels) {
+            assert!(
+                (*denoised - *pixel).mag() < 1e-9,
+                "expected {:?}, got {:?}",
+                pixel,
+                denoised
+            );
+        }
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let (width, height) = (37, 23);
+        let count = (width * height) as usize;
+        let normal = vec![
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0
+            };
+            count
+        ];
+        let depth = vec![gray(2.0); count];
+
+        let albedo = vec![gray(0.5); count];
+        let features = Features {
+            albedo: &albedo,
+            normal: &normal,
+            depth: &depth,
+        };
+        assert_unchanged(&vec![gray(0.3); count], &features, width, height);
+
+        // Constant lighting stays constant under a texture, which keeps its detail.
+        let albedo: Vec<Vec3> = (0..count)
+            .map(|idx| gray((1 + idx % 7) as f64 / 8.0))
+            .collect();
+        let pixels: Vec<Vec3> = albedo.iter().map(|&albedo| 0.6 * albedo).collect();
+        let features = Features {
+            albedo: &albedo,
+            ..features
+        };
+        assert_unchanged(&pixels, &features, width, height);
+    }
+
+    #[test]
+    fn mismatched_buffers_are_rejected() {
+        let buffer = vec![gray(1.0); 12];
+        let features = Features {
+            albedo: &buffer,
+            normal: &buffer,
+            depth: &buffer[..11],
+        };
+        assert!(matches!(
+            denoise(&buffer, &features, 4, 3, 1.0),
+            Err(Error::BufferSize {
+                expected: 12,
+                actual: 11
+            })
+        ));
+    }
+}
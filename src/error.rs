@@ -0,0 +1,80 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A scene object was constructed with an out-of-range parameter.
+    InvalidParameter(&'static str),
+    /// The camera options do not describe a valid view.
+    DegenerateCamera(&'static str),
+    /// A caller-provided buffer does not match the dimensions of the image.
+    BufferSize {
+        expected: usize,
+        actual: usize,
+    },
+    /// An input file could not be parsed.
+    Format(String),
+    ThreadPool(rayon::ThreadPoolBuildError),
+    Io(io::Error),
+    Encoding(png::EncodingError),
+    Decoding(png::DecodingError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidParameter(msg) => write!(f, "invalid parameter: {}", msg),
+            Error::DegenerateCamera(msg) => write!(f, "degenerate camera: {}", msg),
+            Error::BufferSize { expected, actual } => write!(
+                f,
+                "buffer size mismatch: expected {} elements, got {}",
+                expected, actual
+            ),
+            Error::Format(msg) => write!(f, "malformed input: {}", msg),
+            Error::ThreadPool(err) => write!(f, "failed to create thread pool: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Encoding(err) => write!(f, "encoding error: {}", err),
+            Error::Decoding(err) => write!(f, "decoding error: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::ThreadPool(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Encoding(err) => Some(err),
+            Error::Decoding(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(err: rayon::ThreadPoolBuildError) -> Error {
+        Error::ThreadPool(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(err: png::EncodingError) -> Error {
+        Error::Encoding(err)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(err: png::DecodingError) -> Error {
+        Error::Decoding(err)
+    }
+}
@@ -1,8 +1,139 @@
+use std::f64;
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+
+use crate::animation::AnimatedTransform;
+use crate::error::{Error, Result};
 use crate::math::*;
+use crate::sample::Basis;
+use crate::texture::Uv;
+
+/// Describes the point at which a ray hits a geometric object.
+#[derive(Debug, Copy, Clone)]
+pub struct Hit {
+    /// The distance along the ray at which the hit occurs.
+    pub dist: f64,
+    /// The outward-facing surface normal at the hit point.
+    pub normal: Unit3,
+    /// The normal used for shading, which may be interpolated and so differ from the true
+    /// surface normal. Always in the same hemisphere as `normal`.
+    pub shading_normal: Unit3,
+    /// The texture coordinates of the hit point.
+    pub uv: Uv,
+    /// The partial derivative of the hit point with respect to `u`.
+    pub dpdu: Vec3,
+    /// The partial derivative of the hit point with respect to `v`.
+    pub dpdv: Vec3,
+}
+
+/// An interval along a ray inside a solid object, delimited by the hits at which the ray enters
+/// and exits it. The normals at both hits point out of the solid.
+#[derive(Debug, Copy, Clone)]
+pub struct Span {
+    pub enter: Hit,
+    pub exit: Hit,
+}
+
+impl Hit {
+    /// Creates a hit whose shading normal is the true surface normal.
+    pub fn new(dist: f64, normal: Unit3, uv: Uv, dpdu: Vec3, dpdv: Vec3) -> Hit {
+        Hit {
+            dist,
+            normal,
+            shading_normal: normal,
+            uv,
+            dpdu,
+            dpdv,
+        }
+    }
+}
+
+/// Pairs each crossing into a solid with the exit that follows it, given crossings in
+/// increasing order of distance along with whether each one enters the solid. Crossings that
+/// don't fit, such as a tangent root found only once, are dropped.
+fn pair_crossings<I: IntoIterator<Item = (Hit, bool)>>(crossings: I) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut enter = None;
+    for (hit, entering) in crossings {
+        if entering {
+            // A later entry supersedes an earlier one, which can only have been a tangent.
+            enter = Some(hit);
+        } else if let Some(enter) = enter.take() {
+            spans.push(Span { enter, exit: hit });
+        }
+    }
+    spans
+}
+
+pub trait Geom: Send + Sync {
+    /// Returns the closest intersection of `ray` with the object, ignoring any intersections
+    /// behind the ray origin.
+    fn intersect(&self, ray: &Ray) -> Option<Hit>;
+
+    /// Returns the bounds of the object, which may be infinite.
+    fn bounds(&self) -> Aabb;
+
+    /// Returns whether the object is a closed solid, with a well-defined interior.
+    fn is_solid(&self) -> bool {
+        false
+    }
+
+    /// Returns every interval along the (infinite) line containing `ray` during which the line
+    /// is inside the object, in increasing order of distance. Distances may be negative or
+    /// infinite. Returns `None` if the object is not a solid.
+    fn spans(&self, _ray: &Ray) -> Option<Vec<Span>> {
+        None
+    }
+
+    /// Returns the surface area of the object, if points can be sampled on its surface. Only
+    /// objects that support sampling can be area lights; emissive materials on any others are
+    /// ignored when sampling lights, though they still glow when hit.
+    fn area(&self) -> Option<f64> {
+        None
+    }
+
+    /// Picks a point distributed uniformly over the surface of the object by area. Returns
+    /// `None` if the object doesn't support sampling.
+    fn sample_surface(&self, _rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        None
+    }
+}
+
+/// A point on the surface of an object.
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceSample {
+    pub point: Vec3,
+    /// The true surface normal, pointing outwards for solids
+    pub normal: Unit3,
+    pub uv: Uv,
+}
 
-pub trait Geom: Sync {
-    fn intersect(&self, ray: &Ray) -> Option<f64>;
-    fn normal_at(&self, point: Vec3) -> Unit3;
+/// Shared geometry can be used directly, without placing it with an `Instance`.
+impl<G: Geom + ?Sized> Geom for Arc<G> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        (**self).intersect(ray)
+    }
+
+    fn bounds(&self) -> Aabb {
+        (**self).bounds()
+    }
+
+    fn is_solid(&self) -> bool {
+        (**self).is_solid()
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        (**self).spans(ray)
+    }
+
+    fn area(&self) -> Option<f64> {
+        (**self).area()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        (**self).sample_surface(rng)
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -12,9 +143,13 @@ pub struct Sphere {
 }
 
 impl Sphere {
-    pub fn new(center: Vec3, radius: f64) -> Sphere {
-        assert!(radius > 0.0, "Invalid sphere radius");
-        Sphere { center, radius }
+    pub fn new(center: Vec3, radius: f64) -> Result<Sphere> {
+        if !radius.is_finite() || radius <= 0.0 {
+            return Err(Error::InvalidParameter(
+                "sphere radius must be positive and finite",
+            ));
+        }
+        Ok(Sphere { center, radius })
     }
 
     pub fn center(&self) -> Vec3 {
@@ -26,8 +161,9 @@ impl Sphere {
     }
 }
 
-impl Geom for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<f64> {
+impl Sphere {
+    /// Returns the distances at which the line containing `ray` enters and exits the sphere.
+    fn roots(&self, ray: &Ray) -> Option<(f64, f64)> {
         // t^2 + 2t * (origin - center) . dir + |origin - center|^2 - r^2 = 0
         // Divided by 2 here for stability
         let oc = ray.origin - self.center;
@@ -37,8 +173,11 @@ impl Geom for Sphere {
             return None;
         }
         let radical = discriminant.sqrt();
-        let t1 = -b - radical;
-        let t2 = -b + radical;
+        Some((-b - radical, -b + radical))
+    }
+
+    fn intersect_dist(&self, ray: &Ray) -> Option<f64> {
+        let (t1, t2) = self.roots(ray)?;
 
         // Prefer intersections closer to the origin first (but always ignore those behind the ray).
 
@@ -61,4 +200,1783 @@ impl Geom for Sphere {
         );
         outward.to_unit()
     }
+
+    fn hit_at(&self, ray: &Ray, dist: f64) -> Hit {
+        let normal = self.normal_at(ray.interp(dist));
+
+        // u runs counterclockwise around the y axis starting from +x, v from the bottom pole
+        // to the top.
+        let phi = (-normal.z())
+            .atan2(normal.x())
+            .rem_euclid(2.0 * f64::consts::PI);
+        let theta = normal.y().clamp(-1.0, 1.0).acos();
+        let uv = (phi / (2.0 * f64::consts::PI), 1.0 - theta / f64::consts::PI);
+
+        let local = self.radius * Vec3::from(normal);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let dpdu = 2.0
+            * f64::consts::PI
+            * Vec3 {
+                x: local.z,
+                y: 0.0,
+                z: -local.x,
+            };
+        let dpdv = -f64::consts::PI
+            * Vec3 {
+                x: local.y * cos_phi,
+                y: -self.radius * theta.sin(),
+                z: -local.y * sin_phi,
+            };
+
+        Hit::new(dist, normal, uv, dpdu, dpdv)
+    }
+}
+
+impl Geom for Sphere {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.intersect_dist(ray).map(|dist| self.hit_at(ray, dist))
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        Some(
+            self.roots(ray)
+                .map(|(t1, t2)| Span {
+                    enter: self.hit_at(ray, t1),
+                    exit: self.hit_at(ray, t2),
+                })
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    fn bounds(&self) -> Aabb {
+        let extent = Vec3 {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Aabb {
+            min: self.center - extent,
+            max: self.center + extent,
+        }
+    }
+
+    fn area(&self) -> Option<f64> {
+        Some(4.0 * f64::consts::PI * self.radius * self.radius)
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let z: f64 = rng.gen_range(-1.0, 1.0);
+        let phi = rng.gen_range(0.0, 2.0 * f64::consts::PI);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let dir = Vec3 {
+            x: r * phi.cos(),
+            y: r * phi.sin(),
+            z,
+        };
+
+        // Find the texture coordinates of the point from a ray cast outwards from the center.
+        let hit = self.hit_at(
+            &Ray {
+                origin: self.center,
+                dir: Unit3::from_unit_vec3(dir),
+                time: 0.0,
+            },
+            self.radius,
+        );
+        Some(SurfaceSample {
+            point: self.center + self.radius * dir,
+            normal: hit.normal,
+            uv: hit.uv,
+        })
+    }
+}
+
+/// A local coordinate frame whose z axis is aligned with the axis of a primitive.
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    origin: Vec3,
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl Frame {
+    fn new(origin: Vec3, axis: Unit3) -> Frame {
+        let basis = Basis::from_normal(axis);
+        Frame {
+            origin,
+            x: basis.x,
+            y: basis.y,
+            z: basis.z,
+        }
+    }
+
+    fn vec_to_local(&self, v: Vec3) -> Vec3 {
+        Vec3 {
+            x: v.dot(self.x),
+            y: v.dot(self.y),
+            z: v.dot(self.z),
+        }
+    }
+
+    fn vec_to_world(&self, v: Vec3) -> Vec3 {
+        v.x * self.x + v.y * self.y + v.z * self.z
+    }
+
+    /// Returns the origin and direction of `ray` in local coordinates. Distances along the ray
+    /// are preserved.
+    fn ray_to_local(&self, ray: &Ray) -> (Vec3, Vec3) {
+        (
+            self.vec_to_local(ray.origin - self.origin),
+            self.vec_to_local(ray.dir.into()),
+        )
+    }
+
+    /// Rotates a normal into world space. The frame is orthonormal, so the length is kept.
+    fn normal_to_world(&self, n: Unit3) -> Unit3 {
+        Unit3::from_unit_vec3(self.vec_to_world(n.into()))
+    }
+
+    /// Returns the world-space bounds of a box specified in local coordinates.
+    fn bounds(&self, local: Aabb) -> Aabb {
+        Aabb::from_points(local.corners().map(|p| self.origin + self.vec_to_world(p)))
+    }
+}
+
+/// Returns the bounds of a disk with the specified center, normal and radius.
+fn disk_bounds(center: Vec3, normal: Unit3, radius: f64) -> Aabb {
+    let extent = |n: f64| radius * (1.0 - n * n).max(0.0).sqrt();
+    let extent = Vec3 {
+        x: extent(normal.x()),
+        y: extent(normal.y()),
+        z: extent(normal.z()),
+    };
+    Aabb {
+        min: center - extent,
+        max: center + extent,
+    }
+}
+
+/// Returns the angle of `(x, y)` counterclockwise from the x axis, in `[0, 2pi)`.
+fn azimuth(x: f64, y: f64) -> f64 {
+    y.atan2(x).rem_euclid(2.0 * f64::consts::PI)
+}
+
+/// Intersects the local-space line with the disk of the specified radius centered at the origin
+/// of the `z = height` plane, returning the distance to the hit (which may be negative).
+fn intersect_local_disk(origin: Vec3, dir: Vec3, height: f64, radius: f64) -> Option<f64> {
+    if dir.z.abs() < EPSILON {
+        return None;
+    }
+    let dist = (height - origin.z) / dir.z;
+    let x = origin.x + dist * dir.x;
+    let y = origin.y + dist * dir.y;
+    if x * x + y * y <= radius * radius {
+        Some(dist)
+    } else {
+        None
+    }
+}
+
+/// Returns the closest of `hits` in front of the ray origin.
+fn closest_hit<I: IntoIterator<Item = Hit>>(hits: I) -> Option<Hit> {
+    hits.into_iter()
+        .filter(|hit| hit.dist > EPSILON && hit.dist.is_finite())
+        .min_by(|a, b| a.dist.total_cmp(&b.dist))
+}
+
+/// Computes the span of a line through a convex solid from all of the points at which it
+/// crosses the solid's boundary.
+fn convex_span<I: IntoIterator<Item = Hit>>(hits: I) -> Vec<Span> {
+    let mut enter: Option<Hit> = None;
+    let mut exit: Option<Hit> = None;
+    for hit in hits {
+        if enter.is_none_or(|enter| hit.dist < enter.dist) {
+            enter = Some(hit);
+        }
+        if exit.is_none_or(|exit| hit.dist > exit.dist) {
+            exit = Some(hit);
+        }
+    }
+
+    match (enter, exit) {
+        (Some(enter), Some(exit)) if enter.dist < exit.dist => vec![Span { enter, exit }],
+        _ => vec![],
+    }
+}
+
+/// Builds a hit on a disk lying in a plane perpendicular to the z axis of `frame`, with planar
+/// texture coordinates mapping the disk into the unit square.
+fn local_disk_hit(
+    frame: &Frame,
+    origin: Vec3,
+    dir: Vec3,
+    dist: f64,
+    radius: f64,
+    normal: Vec3,
+) -> Hit {
+    let p = origin + dist * dir;
+    Hit::new(
+        dist,
+        Unit3::from_unit_vec3(normal),
+        (0.5 + p.x / (2.0 * radius), 0.5 + p.y / (2.0 * radius)),
+        2.0 * radius * frame.x,
+        2.0 * radius * frame.y,
+    )
+}
+
+/// An infinite plane. When used as a solid, its interior is the half-space behind its normal.
+#[derive(Copy, Clone)]
+pub struct Plane {
+    frame: Frame,
+}
+
+impl Plane {
+    /// Creates a plane through `point` with the specified normal. Texture coordinates are the
+    /// world-space offsets from `point` along two axes within the plane.
+    pub fn new(point: Vec3, normal: Vec3) -> Result<Plane> {
+        let normal = normal
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter("plane normal must be nonzero"))?;
+        Ok(Plane {
+            frame: Frame::new(point, normal),
+        })
+    }
+
+    pub fn point(&self) -> Vec3 {
+        self.frame.origin
+    }
+
+    pub fn normal(&self) -> Unit3 {
+        Unit3::from_unit_vec3(self.frame.z)
+    }
+}
+
+impl Geom for Plane {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        if dir.z.abs() < EPSILON {
+            return None;
+        }
+
+        let dist = -origin.z / dir.z;
+        if dist <= EPSILON {
+            return None;
+        }
+
+        let p = origin + dist * dir;
+        Some(Hit::new(
+            dist,
+            self.normal(),
+            (p.x, p.y),
+            self.frame.x,
+            self.frame.y,
+        ))
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb::infinite()
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        let hit_at =
+            |dist: f64| Hit::new(dist, self.normal(), (0.0, 0.0), self.frame.x, self.frame.y);
+
+        if dir.z.abs() < EPSILON {
+            return Some(if origin.z < 0.0 {
+                vec![Span {
+                    enter: hit_at(f64::NEG_INFINITY),
+                    exit: hit_at(f64::INFINITY),
+                }]
+            } else {
+                vec![]
+            });
+        }
+
+        let dist = -origin.z / dir.z;
+        let p = origin + dist * dir;
+        let crossing = Hit::new(dist, self.normal(), (p.x, p.y), self.frame.x, self.frame.y);
+        Some(vec![if dir.z > 0.0 {
+            Span {
+                enter: hit_at(f64::NEG_INFINITY),
+                exit: crossing,
+            }
+        } else {
+            Span {
+                enter: crossing,
+                exit: hit_at(f64::INFINITY),
+            }
+        }])
+    }
+}
+
+/// A flat, one-sided disk.
+#[derive(Copy, Clone)]
+pub struct Disk {
+    frame: Frame,
+    radius: f64,
+}
+
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: f64) -> Result<Disk> {
+        if radius.is_nan() || radius <= 0.0 {
+            return Err(Error::InvalidParameter("disk radius must be positive"));
+        }
+        let normal = normal
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter("disk normal must be nonzero"))?;
+        Ok(Disk {
+            frame: Frame::new(center, normal),
+            radius,
+        })
+    }
+}
+
+impl Geom for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        intersect_local_disk(origin, dir, 0.0, self.radius)
+            .filter(|&dist| dist > EPSILON)
+            .map(|dist| local_disk_hit(&self.frame, origin, dir, dist, self.radius, self.frame.z))
+    }
+
+    fn bounds(&self) -> Aabb {
+        disk_bounds(
+            self.frame.origin,
+            Unit3::from_unit_vec3(self.frame.z),
+            self.radius,
+        )
+    }
+
+    fn area(&self) -> Option<f64> {
+        Some(f64::consts::PI * self.radius * self.radius)
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let r = self.radius * rng.gen::<f64>().sqrt();
+        let phi = rng.gen_range(0.0, 2.0 * f64::consts::PI);
+        let (x, y) = (r * phi.cos(), r * phi.sin());
+        Some(SurfaceSample {
+            point: self.frame.origin + x * self.frame.x + y * self.frame.y,
+            normal: Unit3::from_unit_vec3(self.frame.z),
+            uv: (0.5 + x / (2.0 * self.radius), 0.5 + y / (2.0 * self.radius)),
+        })
+    }
+}
+
+/// A flat parallelogram spanned by two edges from one of its corners. Its normal follows the
+/// right-hand rule from the first edge to the second, and texture coordinates run from 0 to 1
+/// along each edge.
+#[derive(Copy, Clone)]
+pub struct Quad {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    normal: Unit3,
+    /// The cross product of the edges divided by its squared magnitude, for finding texture
+    /// coordinates
+    dual: Vec3,
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> Result<Quad> {
+        let cross = edge_u.cross(edge_v);
+        let normal = cross.try_to_unit().ok_or(Error::InvalidParameter(
+            "quad edges must be nonzero and not parallel",
+        ))?;
+        Ok(Quad {
+            corner,
+            edge_u,
+            edge_v,
+            normal,
+            dual: cross / cross.mag_squared(),
+        })
+    }
+
+    pub fn corner(&self) -> Vec3 {
+        self.corner
+    }
+
+    pub fn edge_u(&self) -> Vec3 {
+        self.edge_u
+    }
+
+    pub fn edge_v(&self) -> Vec3 {
+        self.edge_v
+    }
+
+    pub fn normal(&self) -> Unit3 {
+        self.normal
+    }
+
+    pub fn area(&self) -> f64 {
+        self.edge_u.cross(self.edge_v).mag()
+    }
+}
+
+impl Geom for Quad {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let normal = Vec3::from(self.normal);
+        let denom = normal.dot(ray.dir.into());
+        if denom.abs() < EPSILON {
+            return None;
+        }
+
+        let dist = normal.dot(self.corner - ray.origin) / denom;
+        if dist <= EPSILON {
+            return None;
+        }
+
+        let offset = ray.interp(dist) - self.corner;
+        let u = self.dual.dot(offset.cross(self.edge_v));
+        let v = self.dual.dot(self.edge_u.cross(offset));
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+
+        Some(Hit::new(
+            dist,
+            self.normal,
+            (u, v),
+            self.edge_u,
+            self.edge_v,
+        ))
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb::from_points(vec![
+            self.corner,
+            self.corner + self.edge_u,
+            self.corner + self.edge_v,
+            self.corner + self.edge_u + self.edge_v,
+        ])
+    }
+
+    fn area(&self) -> Option<f64> {
+        Some(self.area())
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let (u, v) = (rng.gen(), rng.gen());
+        Some(SurfaceSample {
+            point: self.corner + u * self.edge_u + v * self.edge_v,
+            normal: self.normal,
+            uv: (u, v),
+        })
+    }
+}
+
+/// An axis-aligned box.
+#[derive(Copy, Clone)]
+pub struct Cuboid {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Cuboid {
+    pub fn new(min: Vec3, max: Vec3) -> Result<Cuboid> {
+        if !(0..3).all(|axis| min.axis(axis) < max.axis(axis)) {
+            return Err(Error::InvalidParameter(
+                "box minimum must be less than its maximum along every axis",
+            ));
+        }
+        Ok(Cuboid { min, max })
+    }
+
+    pub fn min(&self) -> Vec3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.max
+    }
+
+    /// Returns the entry and exit distances along the (infinite) line containing `ray`, along
+    /// with the axes of the faces crossed at each.
+    fn slabs(&self, ray: &Ray) -> Option<((f64, usize), (f64, usize))> {
+        let mut near = (f64::NEG_INFINITY, 0);
+        let mut far = (f64::INFINITY, 0);
+
+        for axis in 0..3 {
+            let origin = ray.origin.axis(axis);
+            let dir = Vec3::from(ray.dir).axis(axis);
+            let (min, max) = (self.min.axis(axis), self.max.axis(axis));
+
+            if dir.abs() < EPSILON {
+                // Parallel to the slab: either always inside it or never.
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+
+            let t0 = (min - origin) / dir;
+            let t1 = (max - origin) / dir;
+            let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+
+            if t0 > near.0 {
+                near = (t0, axis);
+            }
+            if t1 < far.0 {
+                far = (t1, axis);
+            }
+            if near.0 > far.0 {
+                return None;
+            }
+        }
+
+        Some((near, far))
+    }
+
+    fn face_hit(&self, ray: &Ray, dist: f64, axis: usize) -> Hit {
+        let p = ray.interp(dist);
+        let center = (self.min + self.max) / 2.0;
+        let sign = if p.axis(axis) > center.axis(axis) {
+            1.0
+        } else {
+            -1.0
+        };
+
+        let unit = |axis: usize| Vec3 {
+            x: if axis == 0 { 1.0 } else { 0.0 },
+            y: if axis == 1 { 1.0 } else { 0.0 },
+            z: if axis == 2 { 1.0 } else { 0.0 },
+        };
+
+        // Texture coordinates span each face, along the next two axes in cyclic order.
+        let u_axis = (axis + 1) % 3;
+        let v_axis = (axis + 2) % 3;
+        let extent = self.max - self.min;
+        let uv = (
+            (p.axis(u_axis) - self.min.axis(u_axis)) / extent.axis(u_axis),
+            (p.axis(v_axis) - self.min.axis(v_axis)) / extent.axis(v_axis),
+        );
+
+        Hit::new(
+            dist,
+            Unit3::from_unit_vec3(sign * unit(axis)),
+            uv,
+            extent.axis(u_axis) * unit(u_axis),
+            extent.axis(v_axis) * unit(v_axis),
+        )
+    }
+}
+
+impl Geom for Cuboid {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let ((near, near_axis), (far, far_axis)) = self.slabs(ray)?;
+        if near > EPSILON {
+            Some(self.face_hit(ray, near, near_axis))
+        } else if far > EPSILON {
+            Some(self.face_hit(ray, far, far_axis))
+        } else {
+            None
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        Some(
+            self.slabs(ray)
+                .map(|((near, near_axis), (far, far_axis))| Span {
+                    enter: self.face_hit(ray, near, near_axis),
+                    exit: self.face_hit(ray, far, far_axis),
+                })
+                .into_iter()
+                .collect(),
+        )
+    }
+}
+
+/// A cylinder capped at both ends.
+#[derive(Copy, Clone)]
+pub struct Cylinder {
+    frame: Frame,
+    height: f64,
+    radius: f64,
+}
+
+impl Cylinder {
+    /// Creates a cylinder whose axis runs from the center of its base to the center of its top.
+    pub fn new(base: Vec3, top: Vec3, radius: f64) -> Result<Cylinder> {
+        if radius.is_nan() || radius <= 0.0 {
+            return Err(Error::InvalidParameter("cylinder radius must be positive"));
+        }
+        let axis = (top - base)
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter("cylinder must have nonzero height"))?;
+        Ok(Cylinder {
+            frame: Frame::new(base, axis),
+            height: (top - base).mag(),
+            radius,
+        })
+    }
+
+    fn side_hit(&self, origin: Vec3, dir: Vec3, dist: f64) -> Hit {
+        let p = origin + dist * dir;
+        let phi = azimuth(p.x, p.y);
+        Hit::new(
+            dist,
+            // The point is `radius` from the axis, so however thin the cylinder, this is close
+            // to unit length.
+            self.frame.normal_to_world(
+                Vec3 {
+                    x: p.x / self.radius,
+                    y: p.y / self.radius,
+                    z: 0.0,
+                }
+                .to_unit(),
+            ),
+            (phi / (2.0 * f64::consts::PI), p.z / self.height),
+            self.frame.vec_to_world(
+                2.0 * f64::consts::PI
+                    * Vec3 {
+                        x: -p.y,
+                        y: p.x,
+                        z: 0.0,
+                    },
+            ),
+            self.height * self.frame.z,
+        )
+    }
+}
+
+impl Cylinder {
+    /// Returns every point at which the local-space line crosses the surface of the cylinder.
+    fn boundary_hits(&self, origin: Vec3, dir: Vec3) -> Vec<Hit> {
+        let mut hits = Vec::with_capacity(4);
+
+        let a = dir.x * dir.x + dir.y * dir.y;
+        if a > EPSILON {
+            let b = 2.0 * (origin.x * dir.x + origin.y * dir.y);
+            let c = origin.x * origin.x + origin.y * origin.y - self.radius * self.radius;
+            if let Some((t1, t2)) = solve_quadratic(a, b, c) {
+                for &t in [t1, t2].iter() {
+                    let z = origin.z + t * dir.z;
+                    if (0.0..=self.height).contains(&z) {
+                        hits.push(self.side_hit(origin, dir, t));
+                    }
+                }
+            }
+        }
+
+        for &(height, normal) in [(0.0, -self.frame.z), (self.height, self.frame.z)].iter() {
+            if let Some(dist) = intersect_local_disk(origin, dir, height, self.radius) {
+                hits.push(local_disk_hit(
+                    &self.frame,
+                    origin,
+                    dir,
+                    dist,
+                    self.radius,
+                    normal,
+                ));
+            }
+        }
+
+        hits
+    }
+}
+
+impl Geom for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        closest_hit(self.boundary_hits(origin, dir))
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        Some(convex_span(self.boundary_hits(origin, dir)))
+    }
+
+    fn bounds(&self) -> Aabb {
+        let axis = Unit3::from_unit_vec3(self.frame.z);
+        disk_bounds(self.frame.origin, axis, self.radius).union(disk_bounds(
+            self.frame.origin + self.height * self.frame.z,
+            axis,
+            self.radius,
+        ))
+    }
+}
+
+/// A cone capped at its base.
+#[derive(Copy, Clone)]
+pub struct Cone {
+    frame: Frame,
+    height: f64,
+    radius: f64,
+}
+
+impl Cone {
+    /// Creates a cone with the specified base radius, whose axis runs from the center of its
+    /// base to its apex.
+    pub fn new(base: Vec3, apex: Vec3, radius: f64) -> Result<Cone> {
+        if radius.is_nan() || radius <= 0.0 {
+            return Err(Error::InvalidParameter("cone radius must be positive"));
+        }
+        let axis = (apex - base)
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter("cone must have nonzero height"))?;
+        Ok(Cone {
+            frame: Frame::new(base, axis),
+            height: (apex - base).mag(),
+            radius,
+        })
+    }
+
+    fn side_hit(&self, origin: Vec3, dir: Vec3, dist: f64) -> Hit {
+        let p = origin + dist * dir;
+        let slope = self.radius / self.height;
+        let phi = azimuth(p.x, p.y);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+
+        // The gradient of x^2 + y^2 - slope^2 * (h - z)^2, simplified using the fact that the
+        // point lies on the surface.
+        let radial = (p.x * p.x + p.y * p.y).sqrt();
+        let normal = Vec3 {
+            x: p.x,
+            y: p.y,
+            z: slope * radial,
+        }
+        .try_to_unit()
+        .map_or(Unit3::from_unit_vec3(self.frame.z), |n| {
+            self.frame.normal_to_world(n)
+        });
+
+        Hit::new(
+            dist,
+            normal,
+            (phi / (2.0 * f64::consts::PI), p.z / self.height),
+            self.frame.vec_to_world(
+                2.0 * f64::consts::PI
+                    * Vec3 {
+                        x: -p.y,
+                        y: p.x,
+                        z: 0.0,
+                    },
+            ),
+            self.frame.vec_to_world(Vec3 {
+                x: -self.radius * cos_phi,
+                y: -self.radius * sin_phi,
+                z: self.height,
+            }),
+        )
+    }
+}
+
+impl Cone {
+    /// Returns every point at which the local-space line crosses the surface of the cone.
+    fn boundary_hits(&self, origin: Vec3, dir: Vec3) -> Vec<Hit> {
+        let mut hits = Vec::with_capacity(3);
+
+        // x^2 + y^2 = k^2 * (h - z)^2
+        let k2 = (self.radius / self.height).powi(2);
+        let dz = self.height - origin.z;
+        let a = dir.x * dir.x + dir.y * dir.y - k2 * dir.z * dir.z;
+        let b = 2.0 * (origin.x * dir.x + origin.y * dir.y + k2 * dz * dir.z);
+        let c = origin.x * origin.x + origin.y * origin.y - k2 * dz * dz;
+        if let Some((t1, t2)) = solve_quadratic(a, b, c) {
+            for &t in [t1, t2].iter() {
+                let z = origin.z + t * dir.z;
+                if (0.0..=self.height).contains(&z) {
+                    hits.push(self.side_hit(origin, dir, t));
+                }
+            }
+        }
+
+        if let Some(dist) = intersect_local_disk(origin, dir, 0.0, self.radius) {
+            hits.push(local_disk_hit(
+                &self.frame,
+                origin,
+                dir,
+                dist,
+                self.radius,
+                -self.frame.z,
+            ));
+        }
+
+        hits
+    }
+}
+
+impl Geom for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        closest_hit(self.boundary_hits(origin, dir))
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        Some(convex_span(self.boundary_hits(origin, dir)))
+    }
+
+    fn bounds(&self) -> Aabb {
+        disk_bounds(
+            self.frame.origin,
+            Unit3::from_unit_vec3(self.frame.z),
+            self.radius,
+        )
+        .include(self.frame.origin + self.height * self.frame.z)
+    }
+}
+
+/// A torus, formed by sweeping a circle of radius `minor_radius` around a circle of radius
+/// `major_radius`.
+#[derive(Copy, Clone)]
+pub struct Torus {
+    frame: Frame,
+    major_radius: f64,
+    minor_radius: f64,
+}
+
+impl Torus {
+    /// Creates a torus centered at `center`, which is symmetric about `axis`.
+    pub fn new(center: Vec3, axis: Vec3, major_radius: f64, minor_radius: f64) -> Result<Torus> {
+        if minor_radius.is_nan()
+            || minor_radius <= 0.0
+            || major_radius.is_nan()
+            || major_radius <= minor_radius
+        {
+            return Err(Error::InvalidParameter(
+                "torus radii must satisfy 0 < minor radius < major radius",
+            ));
+        }
+        let axis = axis
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter("torus axis must be nonzero"))?;
+        Ok(Torus {
+            frame: Frame::new(center, axis),
+            major_radius,
+            minor_radius,
+        })
+    }
+}
+
+impl Torus {
+    /// Returns the distances at which the local-space line crosses the surface of the torus,
+    /// in increasing order.
+    fn roots(&self, origin: Vec3, dir: Vec3) -> Vec<f64> {
+        let (big_r, small_r) = (self.major_radius, self.minor_radius);
+
+        // Move the origin up to the bounding sphere first, as the quartic loses precision
+        // quickly with distance.
+        let outer = big_r + small_r;
+        let b = origin.dot(dir);
+        let discriminant = b * b - origin.mag_squared() + outer * outer;
+        if discriminant < 0.0 {
+            return vec![];
+        }
+        let shift = -b - discriminant.sqrt();
+        let o = origin + shift * dir;
+
+        let f = o.dot(dir);
+        let e = o.mag_squared() - big_r * big_r - small_r * small_r;
+        let four_r2 = 4.0 * big_r * big_r;
+        solve_quartic(
+            4.0 * f,
+            4.0 * f * f + 2.0 * e + four_r2 * dir.z * dir.z,
+            4.0 * f * e + 2.0 * four_r2 * o.z * dir.z,
+            e * e - four_r2 * (small_r * small_r - o.z * o.z),
+        )
+        .into_iter()
+        .map(|t| t + shift)
+        .collect()
+    }
+
+    fn hit_at(&self, origin: Vec3, dir: Vec3, dist: f64) -> Hit {
+        let p = origin + dist * dir;
+        let phi = azimuth(p.x, p.y);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+
+        // Offset from the center of the tube
+        let tube = p - self.major_radius
+            * Vec3 {
+                x: cos_phi,
+                y: sin_phi,
+                z: 0.0,
+            };
+        let radial = tube.x * cos_phi + tube.y * sin_phi;
+        let theta = tube.z.atan2(radial).rem_euclid(2.0 * f64::consts::PI);
+        let (sin_theta, cos_theta) = theta.sin_cos();
+
+        Hit::new(
+            dist,
+            tube.try_to_unit()
+                .map_or(Unit3::from_unit_vec3(self.frame.z), |n| {
+                    self.frame.normal_to_world(n)
+                }),
+            (
+                phi / (2.0 * f64::consts::PI),
+                theta / (2.0 * f64::consts::PI),
+            ),
+            self.frame.vec_to_world(
+                2.0 * f64::consts::PI
+                    * Vec3 {
+                        x: -p.y,
+                        y: p.x,
+                        z: 0.0,
+                    },
+            ),
+            self.frame.vec_to_world(
+                2.0 * f64::consts::PI
+                    * self.minor_radius
+                    * Vec3 {
+                        x: -sin_theta * cos_phi,
+                        y: -sin_theta * sin_phi,
+                        z: cos_theta,
+                    },
+            ),
+        )
+    }
+}
+
+impl Geom for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        self.roots(origin, dir)
+            .into_iter()
+            .find(|&t| t > EPSILON)
+            .map(|dist| self.hit_at(origin, dir, dist))
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        let (origin, dir) = self.frame.ray_to_local(ray);
+        // A grazing ray may report its tangent root once rather than twice, so classify each
+        // root by the direction in which it crosses the surface.
+        Some(pair_crossings(self.roots(origin, dir).into_iter().map(
+            |dist| {
+                let hit = self.hit_at(origin, dir, dist);
+                let entering = Vec3::from(hit.normal).dot(ray.dir.into()) < 0.0;
+                (hit, entering)
+            },
+        )))
+    }
+
+    fn bounds(&self) -> Aabb {
+        let outer = self.major_radius + self.minor_radius;
+        self.frame.bounds(Aabb {
+            min: Vec3 {
+                x: -outer,
+                y: -outer,
+                z: -self.minor_radius,
+            },
+            max: Vec3 {
+                x: outer,
+                y: outer,
+                z: self.minor_radius,
+            },
+        })
+    }
+}
+
+/// A transformed reference to shared geometry.
+///
+/// Rays are mapped into the object space of the underlying geometry for intersection, and
+/// normals are mapped back into world space. Animated instances are transformed as they are at
+/// the time of each ray.
+///
+/// Points can only be sampled on an instance's surface when its transform is static and scales
+/// equally in every direction, so that uniformly distributed points stay uniform. Other
+/// instances can't be used as area lights.
+#[derive(Clone)]
+pub struct Instance<'a> {
+    geom: Arc<dyn Geom + 'a>,
+    transform: Transform,
+    motion: Option<AnimatedTransform>,
+}
+
+impl<'a> Instance<'a> {
+    pub fn new(geom: Arc<dyn Geom + 'a>, transform: Transform) -> Instance<'a> {
+        Instance {
+            geom,
+            transform,
+            motion: None,
+        }
+    }
+
+    /// Creates an instance that moves over time.
+    pub fn animated(geom: Arc<dyn Geom + 'a>, motion: AnimatedTransform) -> Instance<'a> {
+        Instance {
+            geom,
+            transform: Transform::identity(),
+            motion: Some(motion),
+        }
+    }
+
+    pub fn geom(&self) -> &dyn Geom {
+        self.geom.as_ref()
+    }
+
+    /// Returns the transform at `time`.
+    pub fn transform(&self, time: f64) -> Transform {
+        match &self.motion {
+            Some(motion) => motion.at(time),
+            None => self.transform,
+        }
+    }
+
+    fn hit_to_world(transform: &Transform, hit: Hit, scale: f64) -> Hit {
+        Hit {
+            dist: hit.dist / scale,
+            normal: transform.apply_normal(hit.normal),
+            shading_normal: transform.apply_normal(hit.shading_normal),
+            uv: hit.uv,
+            dpdu: transform.apply_vector(hit.dpdu),
+            dpdv: transform.apply_vector(hit.dpdv),
+        }
+    }
+}
+
+impl<'a> Geom for Instance<'a> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let transform = self.transform(ray.time);
+        let (local_ray, scale) = transform.inverse().apply_ray(ray);
+        self.geom
+            .intersect(&local_ray)
+            .map(|hit| Instance::hit_to_world(&transform, hit, scale))
+    }
+
+    fn bounds(&self) -> Aabb {
+        match &self.motion {
+            Some(motion) => motion.motion_bounds(&self.geom.bounds()),
+            None => self.geom.bounds().transform(&self.transform),
+        }
+    }
+
+    fn is_solid(&self) -> bool {
+        self.geom.is_solid()
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        let transform = self.transform(ray.time);
+        let (local_ray, scale) = transform.inverse().apply_ray(ray);
+        let spans = self.geom.spans(&local_ray)?;
+        Some(
+            spans
+                .into_iter()
+                .map(|span| Span {
+                    enter: Instance::hit_to_world(&transform, span.enter, scale),
+                    exit: Instance::hit_to_world(&transform, span.exit, scale),
+                })
+                .collect(),
+        )
+    }
+
+    fn area(&self) -> Option<f64> {
+        if self.motion.is_some() {
+            return None;
+        }
+        let scale = self.transform.similarity_scale()?;
+        Some(self.geom.area()? * scale * scale)
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        if self.motion.is_some() || self.transform.similarity_scale().is_none() {
+            return None;
+        }
+        let sample = self.geom.sample_surface(rng)?;
+        Some(SurfaceSample {
+            point: self.transform.apply_point(sample.point),
+            normal: self.transform.apply_normal(sample.normal),
+            uv: sample.uv,
+        })
+    }
+}
+
+/// The boolean operation combining the two children of a `Csg` node.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CsgOp {
+    /// Points inside either child.
+    Union,
+    /// Points inside both children.
+    Intersection,
+    /// Points inside the first child but not the second.
+    Difference,
+}
+
+impl CsgOp {
+    fn contains(self, inside_a: bool, inside_b: bool) -> bool {
+        match self {
+            CsgOp::Union => inside_a || inside_b,
+            CsgOp::Intersection => inside_a && inside_b,
+            CsgOp::Difference => inside_a && !inside_b,
+        }
+    }
+}
+
+/// A solid formed by a boolean operation on two other solids. Nodes can be nested to build up
+/// more complex shapes.
+pub struct Csg<'a> {
+    op: CsgOp,
+    a: Box<dyn Geom + 'a>,
+    b: Box<dyn Geom + 'a>,
+}
+
+impl<'a> Csg<'a> {
+    /// Combines two solids. Fails if either child has no well-defined interior.
+    pub fn new<A: Geom + 'a, B: Geom + 'a>(op: CsgOp, a: A, b: B) -> Result<Csg<'a>> {
+        if !a.is_solid() || !b.is_solid() {
+            return Err(Error::InvalidParameter("CSG operands must be solids"));
+        }
+
+        Ok(Csg {
+            op,
+            a: Box::new(a),
+            b: Box::new(b),
+        })
+    }
+
+    pub fn union<A: Geom + 'a, B: Geom + 'a>(a: A, b: B) -> Result<Csg<'a>> {
+        Csg::new(CsgOp::Union, a, b)
+    }
+
+    pub fn intersection<A: Geom + 'a, B: Geom + 'a>(a: A, b: B) -> Result<Csg<'a>> {
+        Csg::new(CsgOp::Intersection, a, b)
+    }
+
+    pub fn difference<A: Geom + 'a, B: Geom + 'a>(a: A, b: B) -> Result<Csg<'a>> {
+        Csg::new(CsgOp::Difference, a, b)
+    }
+
+    pub fn op(&self) -> CsgOp {
+        self.op
+    }
+
+    /// Returns the points along the line containing `ray` at which it crosses the boundary of
+    /// the combined solid, in increasing order of distance, along with whether each crossing
+    /// enters it.
+    fn boundary(&self, ray: &Ray) -> Vec<(Hit, bool)> {
+        let spans_a = self.a.spans(ray).unwrap_or_default();
+        let spans_b = self.b.spans(ray).unwrap_or_default();
+
+        // Each event is a crossing of one child's boundary: (hit, is from `a`, is an entry).
+        let mut events: Vec<(Hit, bool, bool)> = Vec::new();
+        for (spans, from_a) in [(spans_a, true), (spans_b, false)] {
+            for span in spans {
+                events.push((span.enter, from_a, true));
+                events.push((span.exit, from_a, false));
+            }
+        }
+        // Spans of unbounded solids may start or end at infinity, and a degenerate child can
+        // produce NaN, which `total_cmp` sorts after every real crossing.
+        events.sort_by(|x, y| x.0.dist.total_cmp(&y.0.dist));
+
+        let (mut inside_a, mut inside_b) = (false, false);
+        let mut boundary = Vec::new();
+        for (mut hit, from_a, entering) in events {
+            let was_inside = self.op.contains(inside_a, inside_b);
+            if from_a {
+                inside_a = entering;
+            } else {
+                inside_b = entering;
+            }
+
+            let inside = self.op.contains(inside_a, inside_b);
+            if inside != was_inside {
+                // The surface of a subtracted solid faces into it.
+                if self.op == CsgOp::Difference && !from_a {
+                    hit.normal = (-Vec3::from(hit.normal)).to_unit();
+                    hit.shading_normal = (-Vec3::from(hit.shading_normal)).to_unit();
+                }
+                boundary.push((hit, inside));
+            }
+        }
+
+        boundary
+    }
+}
+
+impl<'a> Geom for Csg<'a> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Crossings of planes may lie at infinity.
+        self.boundary(ray)
+            .into_iter()
+            .map(|(hit, _)| hit)
+            .find(|hit| hit.dist > EPSILON && hit.dist.is_finite())
+    }
+
+    fn bounds(&self) -> Aabb {
+        let (a, b) = (self.a.bounds(), self.b.bounds());
+        match self.op {
+            CsgOp::Union => a.union(b),
+            CsgOp::Intersection => a.intersection(b),
+            CsgOp::Difference => a,
+        }
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray) -> Option<Vec<Span>> {
+        Some(pair_crossings(self.boundary(ray)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::Keyframe;
+
+    const TOLERANCE: f64 = 1e-6;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn ray(origin: Vec3, dir: Vec3) -> Ray {
+        Ray {
+            origin,
+            dir: dir.to_unit(),
+            time: 0.0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    fn assert_vec_close(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).mag() < TOLERANCE,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    fn assert_hit(geom: &dyn Geom, ray: &Ray, dist: f64, normal: Vec3) {
+        let hit = geom.intersect(ray).expect("expected a hit");
+        assert_close(hit.dist, dist);
+        assert_vec_close(hit.normal.into(), normal);
+    }
+
+    /// Tangent rays may or may not register a hit, but any hit must be well-formed and lie on
+    /// the grazed point.
+    fn assert_tangent(geom: &dyn Geom, ray: &Ray, point: Vec3) {
+        if let Some(hit) = geom.intersect(ray) {
+            assert!(hit.dist.is_finite());
+            assert_vec_close(ray.interp(hit.dist), point);
+        }
+    }
+
+    #[test]
+    fn sphere_hits() {
+        let sphere = Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap();
+        assert_hit(
+            &sphere,
+            &ray(vec3(0.0, 0.0, 5.0), vec3(0.0, 0.0, -1.0)),
+            4.0,
+            vec3(0.0, 0.0, 1.0),
+        );
+        assert_hit(
+            &sphere,
+            &ray(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)),
+            1.0,
+            vec3(1.0, 0.0, 0.0),
+        );
+        assert!(sphere
+            .intersect(&ray(vec3(1.0 + TOLERANCE, 0.0, 5.0), vec3(0.0, 0.0, -1.0)))
+            .is_none());
+        assert_tangent(
+            &sphere,
+            &ray(vec3(1.0, 0.0, 5.0), vec3(0.0, 0.0, -1.0)),
+            vec3(1.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn plane_hits() {
+        let plane = Plane::new(vec3(0.0, -1.0, 0.0), vec3(0.0, 1.0, 0.0)).unwrap();
+        assert_hit(
+            &plane,
+            &ray(vec3(3.0, 1.0, 2.0), vec3(0.0, -1.0, 0.0)),
+            2.0,
+            vec3(0.0, 1.0, 0.0),
+        );
+        // From below, the normal still faces up.
+        assert_hit(
+            &plane,
+            &ray(vec3(0.0, -3.0, 0.0), vec3(0.0, 1.0, 0.0)),
+            2.0,
+            vec3(0.0, 1.0, 0.0),
+        );
+        assert!(plane
+            .intersect(&ray(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert!(plane
+            .intersect(&ray(vec3(0.0, -1.0, 0.0), vec3(0.0, 0.0, 1.0)))
+            .is_none());
+        assert!(plane
+            .intersect(&ray(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)))
+            .is_none());
+    }
+
+    #[test]
+    fn disk_hits() {
+        let disk = Disk::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 2.0).unwrap();
+        assert_hit(
+            &disk,
+            &ray(vec3(1.0, 1.0, 3.0), vec3(0.0, 0.0, -1.0)),
+            3.0,
+            vec3(0.0, 0.0, 1.0),
+        );
+        assert!(disk
+            .intersect(&ray(vec3(2.0 + TOLERANCE, 0.0, 3.0), vec3(0.0, 0.0, -1.0)))
+            .is_none());
+        assert!(disk
+            .intersect(&ray(vec3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert!(disk
+            .intersect(&ray(vec3(1.0, 1.0, 3.0), vec3(0.0, 0.0, 1.0)))
+            .is_none());
+        assert!(disk
+            .intersect(&ray(vec3(1.0, 1.0, 0.0), vec3(0.0, 0.6, -0.8)))
+            .is_none());
+        assert_tangent(
+            &disk,
+            &ray(vec3(2.0, 0.0, 3.0), vec3(0.0, 0.0, -1.0)),
+            vec3(2.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn quad_hits() {
+        let quad = Quad::new(
+            vec3(-1.0, -1.0, 0.0),
+            vec3(2.0, 0.0, 0.0),
+            vec3(1.0, 2.0, 0.0),
+        )
+        .unwrap();
+        assert_hit(
+            &quad,
+            &ray(vec3(1.0, 0.5, 2.0), vec3(0.0, 0.0, -1.0)),
+            2.0,
+            vec3(0.0, 0.0, 1.0),
+        );
+        let hit = quad
+            .intersect(&ray(vec3(0.5, 0.0, -1.0), vec3(0.0, 0.0, 1.0)))
+            .unwrap();
+        assert_close(hit.uv.0, 0.5);
+        assert_close(hit.uv.1, 0.5);
+        assert!(quad
+            .intersect(&ray(vec3(-0.9, 0.5, 2.0), vec3(0.0, 0.0, -1.0)))
+            .is_none());
+        assert!(quad
+            .intersect(&ray(vec3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+    }
+
+    #[test]
+    fn cuboid_hits() {
+        let cuboid = Cuboid::new(vec3(-1.0, -2.0, -3.0), vec3(1.0, 2.0, 3.0)).unwrap();
+        assert_hit(
+            &cuboid,
+            &ray(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, -1.0)),
+            7.0,
+            vec3(0.0, 0.0, 1.0),
+        );
+        assert_hit(
+            &cuboid,
+            &ray(vec3(0.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+            2.0,
+            vec3(0.0, -1.0, 0.0),
+        );
+        assert!(cuboid
+            .intersect(&ray(vec3(1.0 + TOLERANCE, 0.0, 10.0), vec3(0.0, 0.0, -1.0)))
+            .is_none());
+        assert!(cuboid
+            .intersect(&ray(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, 1.0)))
+            .is_none());
+        // Grazing along an edge
+        assert_tangent(
+            &cuboid,
+            &ray(vec3(1.0, 2.0, 10.0), vec3(0.0, 0.0, -1.0)),
+            vec3(1.0, 2.0, 3.0),
+        );
+    }
+
+    #[test]
+    fn cylinder_hits() {
+        let cylinder = Cylinder::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 2.0, 0.0), 1.0).unwrap();
+        assert_hit(
+            &cylinder,
+            &ray(vec3(-5.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0)),
+            4.0,
+            vec3(-1.0, 0.0, 0.0),
+        );
+        assert_hit(
+            &cylinder,
+            &ray(vec3(0.5, 5.0, 0.0), vec3(0.0, -1.0, 0.0)),
+            3.0,
+            vec3(0.0, 1.0, 0.0),
+        );
+        assert_hit(
+            &cylinder,
+            &ray(vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0)),
+            1.0,
+            vec3(0.0, -1.0, 0.0),
+        );
+        assert_hit(
+            &cylinder,
+            &ray(vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0)),
+            1.0,
+            vec3(0.0, 0.0, 1.0),
+        );
+        assert!(cylinder
+            .intersect(&ray(vec3(-5.0, 1.0, 1.0 + TOLERANCE), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert!(cylinder
+            .intersect(&ray(vec3(-5.0, 2.0 + TOLERANCE, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert_tangent(
+            &cylinder,
+            &ray(vec3(-5.0, 1.0, 1.0), vec3(1.0, 0.0, 0.0)),
+            vec3(0.0, 1.0, 1.0),
+        );
+    }
+
+    #[test]
+    fn cone_hits() {
+        let cone = Cone::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 2.0, 0.0), 1.0).unwrap();
+        let slant = vec3(-2.0, 1.0, 0.0).to_unit();
+        assert_hit(
+            &cone,
+            &ray(vec3(-5.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0)),
+            4.5,
+            slant.into(),
+        );
+        assert_hit(
+            &cone,
+            &ray(vec3(0.0, -3.0, 0.0), vec3(0.0, 1.0, 0.0)),
+            3.0,
+            vec3(0.0, -1.0, 0.0),
+        );
+        assert_hit(
+            &cone,
+            &ray(vec3(0.0, 1.0, 0.0), vec3(0.0, 1.0, 0.0)),
+            1.0,
+            vec3(0.0, 1.0, 0.0),
+        );
+        assert_hit(
+            &cone,
+            &ray(vec3(0.0, 0.5, 0.0), vec3(0.0, -1.0, 0.0)),
+            0.5,
+            vec3(0.0, -1.0, 0.0),
+        );
+        // Just above the apex
+        assert!(cone
+            .intersect(&ray(vec3(-5.0, 2.0 + TOLERANCE, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        // Just outside the slanted side, at mid-height
+        assert!(cone
+            .intersect(&ray(vec3(-5.0, 1.0, 0.5 + TOLERANCE), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert_tangent(
+            &cone,
+            &ray(vec3(-5.0, 1.0, 0.5), vec3(1.0, 0.0, 0.0)),
+            vec3(0.0, 1.0, 0.5),
+        );
+    }
+
+    #[test]
+    fn torus_hits() {
+        let torus = Torus::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 2.0, 0.5).unwrap();
+        assert_hit(
+            &torus,
+            &ray(vec3(-10.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)),
+            7.5,
+            vec3(-1.0, 0.0, 0.0),
+        );
+        assert_hit(
+            &torus,
+            &ray(vec3(2.0, 0.0, 5.0), vec3(0.0, 0.0, -1.0)),
+            4.5,
+            vec3(0.0, 0.0, 1.0),
+        );
+        // From inside the tube
+        assert_hit(
+            &torus,
+            &ray(vec3(0.0, 2.0, 0.0), vec3(0.0, 1.0, 0.0)),
+            0.5,
+            vec3(0.0, 1.0, 0.0),
+        );
+        // From the hole, hitting the inner side of the tube
+        assert_hit(
+            &torus,
+            &ray(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)),
+            1.5,
+            vec3(-1.0, 0.0, 0.0),
+        );
+        // Straight through the hole
+        assert!(torus
+            .intersect(&ray(vec3(0.0, 0.0, 5.0), vec3(0.0, 0.0, -1.0)))
+            .is_none());
+        assert!(torus
+            .intersect(&ray(vec3(-10.0, 0.0, 0.5 + TOLERANCE), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert_tangent(
+            &torus,
+            &ray(vec3(-10.0, 2.5, 0.0), vec3(1.0, 0.0, 0.0)),
+            vec3(0.0, 2.5, 0.0),
+        );
+    }
+
+    #[test]
+    fn csg_hits() {
+        let sphere = || Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap();
+        let cube = || Cuboid::new(vec3(0.0, -2.0, -2.0), vec3(2.0, 2.0, 2.0)).unwrap();
+        let along_x = ray(vec3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0));
+
+        let union = Csg::union(sphere(), cube()).unwrap();
+        assert_hit(&union, &along_x, 4.0, vec3(-1.0, 0.0, 0.0));
+        let spans = union.spans(&along_x).unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_close(spans[0].exit.dist, 7.0);
+
+        let intersection = Csg::intersection(sphere(), cube()).unwrap();
+        assert_hit(&intersection, &along_x, 5.0, vec3(-1.0, 0.0, 0.0));
+        assert_close(intersection.bounds().min.x, 0.0);
+
+        // The hole left by the sphere faces inwards.
+        let difference = Csg::difference(cube(), sphere()).unwrap();
+        assert_hit(&difference, &along_x, 6.0, vec3(-1.0, 0.0, 0.0));
+        assert_hit(
+            &difference,
+            &ray(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)),
+            1.0,
+            vec3(0.0, -1.0, 0.0),
+        );
+
+        // Tunnel through a cube along its axis, from inside the tunnel.
+        let tunnel = Csg::difference(
+            cube(),
+            Cylinder::new(vec3(-1.0, 0.0, 0.0), vec3(3.0, 0.0, 0.0), 0.5).unwrap(),
+        )
+        .unwrap();
+        assert!(tunnel.intersect(&along_x).is_none());
+        assert_hit(
+            &tunnel,
+            &ray(vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)),
+            0.5,
+            vec3(0.0, 0.0, -1.0),
+        );
+
+        // Nested and transformed operands
+        let nested = Csg::difference(
+            Instance::new(Arc::new(union), Transform::translate(vec3(0.0, 0.0, 10.0))),
+            Plane::new(vec3(0.0, 0.0, 10.0), vec3(1.0, 0.0, 0.0)).unwrap(),
+        )
+        .unwrap();
+        assert_hit(
+            &nested,
+            &ray(vec3(-5.0, 0.0, 10.0), vec3(1.0, 0.0, 0.0)),
+            5.0,
+            vec3(-1.0, 0.0, 0.0),
+        );
+
+        // Starting inside a half-space, the plane's exit at infinity isn't a hit.
+        let half_space = || Plane::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)).unwrap();
+        let down = ray(vec3(0.0, 0.0, -1.0), vec3(0.0, 0.0, -1.0));
+        let with_sphere =
+            Csg::union(half_space(), Sphere::new(vec3(0.0, 0.0, 5.0), 1.0).unwrap()).unwrap();
+        assert!(with_sphere.intersect(&down).is_none());
+        let spans = with_sphere.spans(&down).unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[1].exit.dist, f64::INFINITY);
+        let hollowed = Csg::difference(half_space(), sphere()).unwrap();
+        let in_hole = |dir| ray(vec3(0.0, 0.0, -0.5), dir);
+        assert_hit(
+            &hollowed,
+            &in_hole(vec3(0.0, 0.0, -1.0)),
+            0.5,
+            vec3(0.0, 0.0, 1.0),
+        );
+        assert!(hollowed.intersect(&in_hole(vec3(0.0, 0.0, 1.0))).is_none());
+
+        assert!(Csg::union(
+            sphere(),
+            Disk::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), 1.0).unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn animated_instance_hits() {
+        let spin = |time, x, degrees| Keyframe {
+            time,
+            translation: vec3(x, 0.0, 0.0),
+            rotation: (vec3(0.0, 1.0, 0.0).to_unit(), degrees),
+        };
+        let motion = AnimatedTransform::new(
+            Transform::identity(),
+            &[spin(0.0, 0.0, 0.0), spin(1.0, 4.0, 90.0)],
+        )
+        .unwrap();
+        let cube: Arc<dyn Geom> =
+            Arc::new(Cuboid::new(vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0)).unwrap());
+        let instance = Instance::animated(cube.clone(), motion.clone());
+
+        let at = |time| Ray {
+            time,
+            ..ray(vec3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0))
+        };
+        assert_hit(&instance, &at(0.0), 4.0, vec3(-1.0, 0.0, 0.0));
+        assert_hit(&instance, &at(1.0), 8.0, vec3(-1.0, 0.0, 0.0));
+        // Halfway, an edge of the cube faces the ray.
+        let hit = instance.intersect(&at(0.5)).unwrap();
+        assert_close(hit.dist, 7.0 - f64::consts::SQRT_2);
+        // The motion holds still outside the keyframes.
+        assert_hit(&instance, &at(-1.0), 4.0, vec3(-1.0, 0.0, 0.0));
+        assert_hit(&instance, &at(2.0), 8.0, vec3(-1.0, 0.0, 0.0));
+
+        // The bounds cover the cube throughout its motion.
+        let bounds = instance.bounds();
+        for i in 0..=100 {
+            let time = f64::from(i) / 100.0;
+            for corner in cube.bounds().transform(&motion.at(time)).corners() {
+                let inside = (bounds.min.x..=bounds.max.x).contains(&corner.x)
+                    && (bounds.min.y..=bounds.max.y).contains(&corner.y)
+                    && (bounds.min.z..=bounds.max.z).contains(&corner.z);
+                assert!(inside, "{:?} outside {:?}", corner, bounds);
+            }
+        }
+
+        assert!(AnimatedTransform::new(Transform::identity(), &[]).is_err());
+        assert!(AnimatedTransform::new(
+            Transform::identity(),
+            &[spin(1.0, 0.0, 0.0), spin(0.0, 0.0, 0.0)]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn instance_sampling() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let sphere: Arc<dyn Geom> = Arc::new(Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap());
+        let placed = Instance::new(
+            sphere.clone(),
+            Transform::translate(vec3(0.0, 3.0, 0.0))
+                * Transform::rotate_x(30.0)
+                * Transform::uniform_scale(2.0).unwrap(),
+        );
+        assert_close(placed.area().unwrap(), 16.0 * f64::consts::PI);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let sample = placed.sample_surface(&mut rng).unwrap();
+            let offset = sample.point - vec3(0.0, 3.0, 0.0);
+            assert_close(offset.mag(), 2.0);
+            assert_vec_close(sample.normal.into(), offset / 2.0);
+        }
+
+        // Stretched and moving instances would need a varying density, so can't be sampled.
+        let stretched = Instance::new(
+            sphere.clone(),
+            Transform::scale(vec3(1.0, 2.0, 1.0)).unwrap(),
+        );
+        assert!(stretched.area().is_none());
+        assert!(stretched.sample_surface(&mut rng).is_none());
+        let moving = Instance::animated(
+            sphere,
+            AnimatedTransform::new(
+                Transform::identity(),
+                &[Keyframe::translate(0.0, vec3(0.0, 0.0, 0.0))],
+            )
+            .unwrap(),
+        );
+        assert!(moving.area().is_none());
+    }
+
+    #[test]
+    fn extreme_scales_keep_normals() {
+        let thin = Cylinder::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 1e-12).unwrap();
+        let hit = thin
+            .intersect(&ray(vec3(-1e-6, 0.0, 0.5), vec3(1.0, 0.0, 0.0)))
+            .unwrap();
+        assert_vec_close(hit.normal.into(), vec3(-1.0, 0.0, 0.0));
+
+        let sphere: Arc<dyn Geom> = Arc::new(Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap());
+        let huge = Instance::new(sphere, Transform::uniform_scale(1e10).unwrap());
+        let hit = huge
+            .intersect(&ray(vec3(-3e10, 0.0, 0.0), vec3(1.0, 0.0, 0.0)))
+            .unwrap();
+        assert_vec_close(hit.normal.into(), vec3(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        for radius in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                Sphere::new(vec3(0.0, 0.0, 0.0), radius),
+                Err(Error::InvalidParameter(_))
+            ));
+        }
+        assert!(Plane::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0)).is_err());
+        assert!(Quad::new(
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(2.0, 0.0, 0.0)
+        )
+        .is_err());
+        assert!(Cuboid::new(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 1.0)).is_err());
+        assert!(Cylinder::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0), 1.0).is_err());
+        assert!(Cone::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), -1.0).is_err());
+        assert!(Torus::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), 1.0, 2.0).is_err());
+    }
+
+    #[test]
+    fn closest_hit_ignores_non_finite_distances() {
+        let hit = |dist: f64| {
+            let normal = vec3(0.0, 0.0, 1.0).to_unit();
+            Hit::new(
+                dist,
+                normal,
+                (0.0, 0.0),
+                vec3(1.0, 0.0, 0.0),
+                vec3(0.0, 1.0, 0.0),
+            )
+        };
+        let closest = closest_hit(vec![hit(f64::NAN), hit(3.0), hit(f64::INFINITY), hit(2.0)]);
+        assert_close(closest.unwrap().dist, 2.0);
+        assert!(closest_hit(vec![hit(f64::NAN), hit(f64::INFINITY)]).is_none());
+    }
 }
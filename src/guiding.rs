@@ -0,0 +1,546 @@
+use std::f64;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::Rng;
+
+use crate::math::*;
+
+/// The fraction of a directional tree's energy above which a quadrant is subdivided.
+const SUBDIVISION_THRESHOLD: f64 = 0.01;
+/// The deepest a directional tree is subdivided.
+const MAX_DIRECTION_DEPTH: u32 = 20;
+/// Scales the number of records that a spatial leaf can collect before it's split.
+const SPATIAL_THRESHOLD: f64 = 12000.0;
+/// The deepest the spatial tree is subdivided.
+const MAX_SPATIAL_DEPTH: u32 = 48;
+
+/// An f64 that threads can add to concurrently.
+#[derive(Debug, Default)]
+struct AtomicF64(AtomicU64);
+
+impl Clone for AtomicF64 {
+    fn clone(&self) -> AtomicF64 {
+        AtomicF64::new(self.load())
+    }
+}
+
+impl AtomicF64 {
+    fn new(value: f64) -> AtomicF64 {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, amount: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + amount).to_bits())
+            });
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct QuadNode {
+    /// The energy arriving within each quadrant, numbered left to right and then top to
+    /// bottom
+    sums: [AtomicF64; 4],
+    /// The node subdividing each quadrant, or 0 if it's a leaf
+    children: [u32; 4],
+}
+
+impl QuadNode {
+    fn sums(&self) -> [f64; 4] {
+        let [a, b, c, d] = &self.sums;
+        [a.load(), b.load(), c.load(), d.load()]
+    }
+
+    fn total(&self) -> f64 {
+        self.sums().iter().sum()
+    }
+}
+
+/// A quadtree over the square that directions are mapped to by cylindrical coordinates, which
+/// preserve area. Each quadrant holds the energy arriving from within it, so that directions
+/// can be sampled in proportion to it.
+#[derive(Debug, Clone)]
+struct DirectionTree {
+    nodes: Vec<QuadNode>,
+}
+
+impl DirectionTree {
+    fn new() -> DirectionTree {
+        DirectionTree {
+            nodes: vec![QuadNode::default()],
+        }
+    }
+
+    fn total(&self) -> f64 {
+        self.nodes[0].total()
+    }
+
+    /// Returns a tree with the same quadrants, holding no energy.
+    fn cleared(&self) -> DirectionTree {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| QuadNode {
+                children: node.children,
+                ..QuadNode::default()
+            })
+            .collect();
+        DirectionTree { nodes }
+    }
+
+    /// Adds energy arriving from `dir` to every quadrant containing it.
+    fn record(&self, dir: Unit3, amount: f64) {
+        let (mut u, mut v) = dir_to_square(dir);
+        let mut node = &self.nodes[0];
+        loop {
+            let quadrant = pick_quadrant(&mut u, &mut v);
+            node.sums[quadrant].add(amount);
+            match node.children[quadrant] {
+                0 => return,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+    }
+
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Unit3 {
+        let (mut u, mut v) = (rng.gen::<f64>(), rng.gen::<f64>());
+        let mut origin = (0.0, 0.0);
+        let mut size = 1.0;
+        let mut node = &self.nodes[0];
+
+        // Descend into quadrants in proportion to their energy, reusing `u` and `v` to pick
+        // them and then to place the point within the final quadrant.
+        loop {
+            let sums = node.sums();
+            let (right, new_u) = pick(u, sums[0] + sums[2], sums[1] + sums[3]);
+            let (lower, new_v) = if right {
+                pick(v, sums[1], sums[3])
+            } else {
+                pick(v, sums[0], sums[2])
+            };
+            u = new_u;
+            v = new_v;
+
+            size /= 2.0;
+            let quadrant = usize::from(right) + 2 * usize::from(lower);
+            origin = (
+                origin.0 + size * f64::from(u8::from(right)),
+                origin.1 + size * f64::from(u8::from(lower)),
+            );
+            match node.children[quadrant] {
+                0 => break,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+
+        square_to_dir(origin.0 + size * u, origin.1 + size * v)
+    }
+
+    fn pdf(&self, dir: Unit3) -> f64 {
+        let (mut u, mut v) = dir_to_square(dir);
+        let mut node = &self.nodes[0];
+        let mut density = 1.0;
+        loop {
+            let quadrant = pick_quadrant(&mut u, &mut v);
+            let total = node.total();
+            if total <= 0.0 {
+                return 0.0;
+            }
+            density *= 4.0 * node.sums[quadrant].load() / total;
+            match node.children[quadrant] {
+                0 => break,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+
+        // The cylindrical mapping stretches the square evenly over the sphere.
+        density / (4.0 * f64::consts::PI)
+    }
+
+    /// Builds a tree from the energy recorded in this one, subdividing quadrants that hold
+    /// much of it and merging those that hold little.
+    fn refined(&self) -> DirectionTree {
+        let total = self.total();
+        let mut refined = DirectionTree { nodes: Vec::new() };
+        self.refine_node(Some(0), self.nodes[0].sums(), total, 1, &mut refined.nodes);
+        refined
+    }
+
+    fn refine_node(
+        &self,
+        source: Option<u32>,
+        sums: [f64; 4],
+        total: f64,
+        depth: u32,
+        nodes: &mut Vec<QuadNode>,
+    ) -> u32 {
+        let idx = nodes.len();
+        nodes.push(QuadNode::default());
+        for (quadrant, &sum) in sums.iter().enumerate() {
+            nodes[idx].sums[quadrant] = AtomicF64::new(sum);
+            if total <= 0.0 || sum / total <= SUBDIVISION_THRESHOLD || depth >= MAX_DIRECTION_DEPTH
+            {
+                continue;
+            }
+
+            // A quadrant that wasn't subdivided is assumed to have held its energy evenly.
+            let child = source
+                .map(|source| self.nodes[source as usize].children[quadrant])
+                .filter(|&child| child != 0);
+            let child_sums = match child {
+                Some(child) => self.nodes[child as usize].sums(),
+                None => [sum / 4.0; 4],
+            };
+            let child_idx = self.refine_node(child, child_sums, total, depth + 1, nodes);
+            nodes[idx].children[quadrant] = child_idx;
+        }
+        idx as u32
+    }
+}
+
+/// Picks between two parts with the given weights using `u` in `[0, 1)`, returning whether
+/// the second was picked along with `u` remapped to `[0, 1)` within it.
+fn pick(u: f64, first: f64, second: f64) -> (bool, f64) {
+    let x = u * (first + second);
+    let (second_picked, remapped) = if x < first || second <= 0.0 {
+        (false, x / first)
+    } else {
+        (true, (x - first) / second)
+    };
+    (second_picked, remapped.clamp(0.0, 1.0 - f64::EPSILON))
+}
+
+/// Picks the quadrant of the unit square containing `(u, v)`, and maps the point into it.
+fn pick_quadrant(u: &mut f64, v: &mut f64) -> usize {
+    let right = *u >= 0.5;
+    let lower = *v >= 0.5;
+    *u = (2.0 * *u - f64::from(u8::from(right))).clamp(0.0, 1.0);
+    *v = (2.0 * *v - f64::from(u8::from(lower))).clamp(0.0, 1.0);
+    usize::from(right) + 2 * usize::from(lower)
+}
+
+fn dir_to_square(dir: Unit3) -> (f64, f64) {
+    let cos_theta = dir.z().clamp(-1.0, 1.0);
+    let mut phi = dir.y().atan2(dir.x());
+    if phi < 0.0 {
+        phi += 2.0 * f64::consts::PI;
+    }
+    ((cos_theta + 1.0) / 2.0, phi / (2.0 * f64::consts::PI))
+}
+
+fn square_to_dir(u: f64, v: f64) -> Unit3 {
+    let cos_theta = 2.0 * u - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * f64::consts::PI * v;
+    Vec3 {
+        x: sin_theta * phi.cos(),
+        y: sin_theta * phi.sin(),
+        z: cos_theta,
+    }
+    .to_unit()
+}
+
+/// What has been learned about the light arriving within a region of space.
+pub(crate) struct GuideRegion {
+    /// Learned in previous passes, and sampled from in this one
+    sampling: DirectionTree,
+    /// Collects the light found in this pass
+    recording: DirectionTree,
+    records: AtomicU64,
+}
+
+impl GuideRegion {
+    fn new(sampling: DirectionTree) -> GuideRegion {
+        GuideRegion {
+            recording: sampling.cleared(),
+            sampling,
+            records: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether any light has been learned about to guide paths with.
+    pub(crate) fn is_trained(&self) -> bool {
+        self.sampling.total() > 0.0
+    }
+
+    /// Samples a direction in proportion to the light learned to arrive from it.
+    pub(crate) fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Unit3 {
+        self.sampling.sample(rng)
+    }
+
+    /// Returns the probability density of `sample` choosing `dir`.
+    pub(crate) fn pdf(&self, dir: Unit3) -> f64 {
+        self.sampling.pdf(dir)
+    }
+
+    /// Records `radiance` arriving from `dir`, which a path sampled with probability density
+    /// `pdf`.
+    pub(crate) fn record(&self, dir: Unit3, radiance: Vec3, pdf: f64) {
+        let amount = (radiance.x + radiance.y + radiance.z) / 3.0 / pdf;
+        if amount.is_finite() && amount >= 0.0 {
+            self.recording.record(dir, amount);
+        }
+        self.records.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+enum SpatialNode {
+    /// Splits its bounds in half along the axis its depth picks
+    Interior {
+        children: [u32; 2],
+    },
+    Leaf(u32),
+}
+
+/// Learns where light arrives from throughout the scene, so that paths can be guided towards
+/// it. A binary tree divides space, and each region holds a quadtree over directions. The trees
+/// are refined between passes, each of which should trace more samples than the last. See
+/// "Practical Path Guiding for Efficient Light-Transport Simulation" (Müller et al., 2017).
+pub(crate) struct Guide {
+    bounds: Aabb,
+    nodes: Vec<SpatialNode>,
+    regions: Vec<GuideRegion>,
+}
+
+impl Guide {
+    /// Creates a guide that hasn't learned anything, over a region of space. Points outside
+    /// the bounds share what's learned near them.
+    pub(crate) fn new(bounds: Aabb) -> Guide {
+        Guide {
+            bounds,
+            nodes: vec![SpatialNode::Leaf(0)],
+            regions: vec![GuideRegion::new(DirectionTree::new())],
+        }
+    }
+
+    /// Returns the region containing `point`.
+    pub(crate) fn region(&self, point: Vec3) -> &GuideRegion {
+        let mut bounds = self.bounds;
+        let mut node = &self.nodes[0];
+        let mut depth = 0;
+        loop {
+            match *node {
+                SpatialNode::Interior { children } => {
+                    let axis = depth % 3;
+                    let mid = bounds.centroid().axis(axis);
+                    let second = point.axis(axis) >= mid;
+                    bounds = half(bounds, axis, second);
+                    node = &self.nodes[children[usize::from(second)] as usize];
+                    depth += 1;
+                }
+                SpatialNode::Leaf(region) => return &self.regions[region as usize],
+            }
+        }
+    }
+
+    /// Builds a guide from what was recorded during a pass that traced `samples` samples per
+    /// pixel. Regions that recorded many paths are split, so that what they learn can vary
+    /// more finely.
+    pub(crate) fn refined(&self, samples: u32) -> Guide {
+        let mut refined = Guide {
+            bounds: self.bounds,
+            nodes: Vec::new(),
+            regions: Vec::new(),
+        };
+        let threshold = SPATIAL_THRESHOLD * f64::from(samples).sqrt();
+        self.refine_node(0, 0, threshold, &mut refined);
+        refined
+    }
+
+    fn refine_node(&self, idx: usize, depth: u32, threshold: f64, refined: &mut Guide) -> u32 {
+        match self.nodes[idx] {
+            SpatialNode::Interior { children } => {
+                let node = refined.nodes.len();
+                refined
+                    .nodes
+                    .push(SpatialNode::Interior { children: [0; 2] });
+                let first = self.refine_node(children[0] as usize, depth + 1, threshold, refined);
+                let second = self.refine_node(children[1] as usize, depth + 1, threshold, refined);
+                refined.nodes[node] = SpatialNode::Interior {
+                    children: [first, second],
+                };
+                node as u32
+            }
+            SpatialNode::Leaf(region) => {
+                let region = &self.regions[region as usize];
+                let records = region.records.load(Ordering::Relaxed) as f64;
+                let learned = if region.recording.total() > 0.0 {
+                    region.recording.refined()
+                } else {
+                    region.sampling.clone()
+                };
+                split_region(&learned, records, depth, threshold, refined)
+            }
+        }
+    }
+}
+
+/// Adds nodes for a region that recorded `records` paths, splitting it in half until each
+/// part would have recorded no more than `threshold`.
+fn split_region(
+    learned: &DirectionTree,
+    records: f64,
+    depth: u32,
+    threshold: f64,
+    refined: &mut Guide,
+) -> u32 {
+    let node = refined.nodes.len();
+    if records > threshold && depth < MAX_SPATIAL_DEPTH {
+        refined
+            .nodes
+            .push(SpatialNode::Interior { children: [0; 2] });
+        let first = split_region(learned, records / 2.0, depth + 1, threshold, refined);
+        let second = split_region(learned, records / 2.0, depth + 1, threshold, refined);
+        refined.nodes[node] = SpatialNode::Interior {
+            children: [first, second],
+        };
+    } else {
+        refined
+            .nodes
+            .push(SpatialNode::Leaf(refined.regions.len() as u32));
+        refined.regions.push(GuideRegion::new(learned.clone()));
+    }
+    node as u32
+}
+
+/// Returns one half of `bounds`, split at its center along `axis`.
+fn half(bounds: Aabb, axis: usize, second: bool) -> Aabb {
+    let mid = bounds.centroid();
+    let mut min = [bounds.min.x, bounds.min.y, bounds.min.z];
+    let mut max = [bounds.max.x, bounds.max.y, bounds.max.z];
+    if second {
+        min[axis] = mid.axis(axis);
+    } else {
+        max[axis] = mid.axis(axis);
+    }
+    Aabb {
+        min: Vec3 {
+            x: min[0],
+            y: min[1],
+            z: min[2],
+        },
+        max: Vec3 {
+            x: max[0],
+            y: max[1],
+            z: max[2],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// The number of cells along each side of the square that densities are integrated over.
+    /// Fine enough that no quadrant in the tests' trees straddles a cell.
+    const GRID: usize = 256;
+
+    /// A tree that has learned of light arriving from everywhere, but mostly from around +z.
+    fn lobe_tree() -> DirectionTree {
+        let mut rng = StdRng::seed_from_u64(0x91);
+        let record = |tree: &DirectionTree, rng: &mut StdRng| {
+            for _ in 0..2000 {
+                let uniform = square_to_dir(rng.gen(), rng.gen());
+                tree.record(uniform, 1.0);
+                let lobe = square_to_dir(1.0 - 0.05 * rng.gen::<f64>(), rng.gen());
+                tree.record(lobe, 3.0);
+            }
+        };
+        let tree = DirectionTree::new();
+        record(&tree, &mut rng);
+        let refined = tree.refined();
+        record(&refined, &mut rng);
+        refined
+    }
+
+    /// Integrates the tree's density over each cell of the square.
+    fn cell_probabilities(tree: &DirectionTree) -> Vec<f64> {
+        let cell_area = 4.0 * f64::consts::PI / (GRID * GRID) as f64;
+        let mut probabilities = Vec::with_capacity(GRID * GRID);
+        for row in 0..GRID {
+            for col in 0..GRID {
+                let u = (col as f64 + 0.5) / GRID as f64;
+                let v = (row as f64 + 0.5) / GRID as f64;
+                probabilities.push(tree.pdf(square_to_dir(u, v)) * cell_area);
+            }
+        }
+        probabilities
+    }
+
+    #[test]
+    fn quadrants_round_trip() {
+        for i in 0..16 {
+            for j in 0..16 {
+                let (u, v) = ((i as f64 + 0.3) / 16.0, (j as f64 + 0.7) / 16.0);
+                let (mut inner_u, mut inner_v) = (u, v);
+                let quadrant = pick_quadrant(&mut inner_u, &mut inner_v);
+                let (right, lower) = (quadrant % 2, quadrant / 2);
+                assert_eq!(
+                    (right, lower),
+                    (usize::from(u >= 0.5), usize::from(v >= 0.5))
+                );
+                assert!((0.5 * (right as f64 + inner_u) - u).abs() < 1e-12);
+                assert!((0.5 * (lower as f64 + inner_v) - v).abs() < 1e-12);
+
+                let (mapped_u, mapped_v) = dir_to_square(square_to_dir(u, v));
+                assert!((mapped_u - u).abs() < 1e-9 && (mapped_v - v).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let tree = lobe_tree();
+        assert!(tree.nodes.len() > 1, "tree wasn't subdivided");
+        let total: f64 = cell_probabilities(&tree).iter().sum();
+        assert!((total - 1.0).abs() < 1e-9, "pdf integrates to {}", total);
+
+        assert_eq!(DirectionTree::new().pdf(square_to_dir(0.5, 0.5)), 0.0);
+    }
+
+    #[test]
+    fn samples_follow_pdf() {
+        let tree = lobe_tree();
+        let mut rng = StdRng::seed_from_u64(0x5a);
+
+        // Compare how often samples land in each block of cells with the density's integral.
+        let blocks = 8;
+        let cells = cell_probabilities(&tree);
+        let mut expected = vec![0.0; blocks * blocks];
+        for (idx, probability) in cells.iter().enumerate() {
+            let (row, col) = (idx / GRID, idx % GRID);
+            expected[row * blocks / GRID * blocks + col * blocks / GRID] += probability;
+        }
+
+        let count = 200_000;
+        let mut found = vec![0.0; blocks * blocks];
+        let mut inverse_pdf = 0.0;
+        for _ in 0..count {
+            let dir = tree.sample(&mut rng);
+            let (u, v) = dir_to_square(dir);
+            let block = |x: f64| ((x * blocks as f64) as usize).min(blocks - 1);
+            found[block(v) * blocks + block(u)] += 1.0 / count as f64;
+            inverse_pdf += 1.0 / tree.pdf(dir) / count as f64;
+        }
+
+        for (found, expected) in found.iter().zip(&expected) {
+            assert!(
+                (found - expected).abs() < 0.005,
+                "expected {}, found {}",
+                expected,
+                found
+            );
+        }
+        // Every direction can be sampled, so the inverse density averages to the sphere's area.
+        let sphere = 4.0 * f64::consts::PI;
+        assert!((inverse_pdf - sphere).abs() < 0.02 * sphere);
+    }
+}
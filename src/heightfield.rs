@@ -0,0 +1,394 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::geom::{Geom, Hit};
+use crate::img;
+use crate::math::*;
+use crate::mesh::intersect_triangle;
+
+/// The range of heights within a block of cells, at one level of the min-max pyramid.
+struct Level {
+    columns: usize,
+    rows: usize,
+    ranges: Vec<(f64, f64)>,
+}
+
+/// The (column, row) grid coordinates of the vertices of a triangle.
+type Triangle = [(usize, usize); 3];
+
+/// A terrain surface defined by a regular grid of heights.
+///
+/// The grid lies in the xz plane, spanning from the origin to `size.x` along x and `size.z`
+/// along z, with columns running along x and rows along z. Heights are scaled by `size.y`. Each
+/// cell of the grid is split into two triangles, which are found by descending a pyramid of
+/// min-max height bounds rather than through a general-purpose hierarchy.
+pub struct Heightfield {
+    columns: usize,
+    rows: usize,
+    cell_size: (f64, f64),
+    size: Vec3,
+    heights: Vec<f64>,
+    normals: Vec<Unit3>,
+    levels: Vec<Level>,
+}
+
+impl Heightfield {
+    /// Creates a heightfield from `columns * rows` heights in row-major order.
+    pub fn new(columns: usize, rows: usize, heights: Vec<f64>, size: Vec3) -> Result<Heightfield> {
+        if columns < 2 || rows < 2 {
+            return Err(Error::InvalidParameter(
+                "heightfield must have at least 2 rows and columns",
+            ));
+        }
+        let expected = columns
+            .checked_mul(rows)
+            .ok_or(Error::InvalidParameter("heightfield is too large"))?;
+        if heights.len() != expected {
+            return Err(Error::BufferSize {
+                expected,
+                actual: heights.len(),
+            });
+        }
+        if heights.iter().any(|h| !h.is_finite()) {
+            return Err(Error::InvalidParameter("heights must be finite"));
+        }
+        if (0..3).any(|axis| size.axis(axis).is_nan() || size.axis(axis) <= 0.0) {
+            return Err(Error::InvalidParameter("heightfield size must be positive"));
+        }
+
+        let heights: Vec<f64> = heights.into_iter().map(|h| h * size.y).collect();
+        let cell_size = (size.x / (columns - 1) as f64, size.z / (rows - 1) as f64);
+
+        // Estimate the normal at each sample from the slope between its neighbours.
+        let height = |col: usize, row: usize| heights[row * columns + col];
+        let mut normals = Vec::with_capacity(heights.len());
+        for row in 0..rows {
+            for col in 0..columns {
+                let (left, right) = (col.saturating_sub(1), (col + 1).min(columns - 1));
+                let (back, front) = (row.saturating_sub(1), (row + 1).min(rows - 1));
+                let dhdx = (height(right, row) - height(left, row))
+                    / ((right - left) as f64 * cell_size.0);
+                let dhdz = (height(col, front) - height(col, back))
+                    / ((front - back) as f64 * cell_size.1);
+                normals.push(
+                    Vec3 {
+                        x: -dhdx,
+                        y: 1.0,
+                        z: -dhdz,
+                    }
+                    .to_unit(),
+                );
+            }
+        }
+
+        let mut levels = vec![Level {
+            columns: columns - 1,
+            rows: rows - 1,
+            ranges: (0..rows - 1)
+                .flat_map(|row| (0..columns - 1).map(move |col| (col, row)))
+                .map(|(col, row)| {
+                    let corners = [
+                        height(col, row),
+                        height(col + 1, row),
+                        height(col, row + 1),
+                        height(col + 1, row + 1),
+                    ];
+                    (
+                        corners.iter().cloned().fold(f64::INFINITY, f64::min),
+                        corners.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                    )
+                })
+                .collect(),
+        }];
+
+        while levels.last().is_some_and(|l| l.columns > 1 || l.rows > 1) {
+            let prev = levels.last().unwrap();
+            let (level_columns, level_rows) = (prev.columns.div_ceil(2), prev.rows.div_ceil(2));
+            let mut ranges = Vec::with_capacity(level_columns * level_rows);
+            for row in 0..level_rows {
+                for col in 0..level_columns {
+                    let mut range = (f64::INFINITY, f64::NEG_INFINITY);
+                    for child_row in 2 * row..(2 * row + 2).min(prev.rows) {
+                        for child_col in 2 * col..(2 * col + 2).min(prev.columns) {
+                            let (lo, hi) = prev.ranges[child_row * prev.columns + child_col];
+                            range = (range.0.min(lo), range.1.max(hi));
+                        }
+                    }
+                    ranges.push(range);
+                }
+            }
+            levels.push(Level {
+                columns: level_columns,
+                rows: level_rows,
+                ranges,
+            });
+        }
+
+        Ok(Heightfield {
+            columns,
+            rows,
+            cell_size,
+            size,
+            heights,
+            normals,
+            levels,
+        })
+    }
+
+    /// Loads the heights from a grayscale PNG, ideally with 16 bits per sample. The top row of
+    /// the image becomes the row at `z = 0`. Color images are converted to their luminance.
+    pub fn load_png<P: AsRef<Path>>(path: P, size: Vec3) -> Result<Heightfield> {
+        let image = img::read_png(path)?;
+        let heights = image.pixels().map(|pixel| pixel.gray()).collect();
+        Heightfield::new(image.width, image.height, heights, size)
+    }
+
+    /// Loads the heights from a headerless file of `columns * rows` unsigned 16-bit
+    /// little-endian samples in row-major order.
+    pub fn load_raw<P: AsRef<Path>>(
+        path: P,
+        columns: usize,
+        rows: usize,
+        size: Vec3,
+    ) -> Result<Heightfield> {
+        let expected = columns
+            .checked_mul(rows)
+            .and_then(|count| count.checked_mul(2))
+            .ok_or_else(|| Error::Format("heightfield dimensions are too large".to_owned()))?;
+        let bytes = fs::read(path)?;
+        if bytes.len() != expected {
+            return Err(Error::BufferSize {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+
+        let heights = bytes
+            .chunks(2)
+            .map(|pair| f64::from(u16::from_le_bytes([pair[0], pair[1]])) / 65535.0)
+            .collect();
+        Heightfield::new(columns, rows, heights, size)
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn vertex(&self, col: usize, row: usize) -> Vec3 {
+        Vec3 {
+            x: col as f64 * self.cell_size.0,
+            y: self.heights[row * self.columns + col],
+            z: row as f64 * self.cell_size.1,
+        }
+    }
+
+    /// Returns the bounds of a node of the pyramid.
+    fn node_bounds(&self, level: usize, col: usize, row: usize) -> Aabb {
+        let info = &self.levels[level];
+        let (lo, hi) = info.ranges[row * info.columns + col];
+        let span = 1 << level;
+        let (cells_x, cells_z) = (self.columns - 1, self.rows - 1);
+
+        Aabb {
+            min: Vec3 {
+                x: (col * span) as f64 * self.cell_size.0,
+                y: lo,
+                z: (row * span) as f64 * self.cell_size.1,
+            },
+            max: Vec3 {
+                x: ((col + 1) * span).min(cells_x) as f64 * self.cell_size.0,
+                y: hi,
+                z: ((row + 1) * span).min(cells_z) as f64 * self.cell_size.1,
+            },
+        }
+    }
+
+    /// Returns the vertex indices of the two triangles in a cell, counterclockwise when seen
+    /// from above.
+    fn cell_triangles(&self, col: usize, row: usize) -> [Triangle; 2] {
+        let (c00, c10) = ((col, row), (col + 1, row));
+        let (c01, c11) = ((col, row + 1), (col + 1, row + 1));
+        [[c00, c01, c11], [c00, c11, c10]]
+    }
+
+    fn hit_at(&self, dist: f64, tri: Triangle, b1: f64, b2: f64) -> Hit {
+        let [p0, p1, p2] = tri.map(|(col, row)| self.vertex(col, row));
+        let geometric = (p1 - p0).cross(p2 - p0).to_unit();
+        let n = Vec3::from(geometric);
+
+        let b0 = 1.0 - b1 - b2;
+        let [n0, n1, n2] = tri.map(|(col, row)| Vec3::from(self.normals[row * self.columns + col]));
+        let shading_normal = match (b0 * n0 + b1 * n1 + b2 * n2).try_to_unit() {
+            Some(shading) if Vec3::from(shading).dot(n) > 0.0 => shading,
+            _ => geometric,
+        };
+
+        let point = b0 * p0 + b1 * p1 + b2 * p2;
+        Hit {
+            dist,
+            normal: geometric,
+            shading_normal,
+            uv: (point.x / self.size.x, point.z / self.size.z),
+            // The geometric normal always points upwards, so its y component is positive.
+            dpdu: self.size.x
+                * Vec3 {
+                    x: 1.0,
+                    y: -n.x / n.y,
+                    z: 0.0,
+                },
+            dpdv: self.size.z
+                * Vec3 {
+                    x: 0.0,
+                    y: -n.z / n.y,
+                    z: 1.0,
+                },
+        }
+    }
+}
+
+impl Geom for Heightfield {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let dir = Vec3::from(ray.dir);
+        let inv_dir = dir.recip();
+        let mut closest: Option<(f64, Triangle, f64, f64)> = None;
+
+        // Visit the children of each node nearest-first, so that the closest hit is usually
+        // found early and prunes the remaining nodes.
+        let near_x = if dir.x >= 0.0 { 0 } else { 1 };
+        let near_z = if dir.z >= 0.0 { 0 } else { 1 };
+        let child_order = [
+            (1 - near_x, 1 - near_z),
+            (near_x, 1 - near_z),
+            (1 - near_x, near_z),
+            (near_x, near_z),
+        ];
+
+        let mut stack = vec![(self.levels.len() - 1, 0, 0)];
+        while let Some((level, col, row)) = stack.pop() {
+            let max_dist = closest.map_or(f64::INFINITY, |(dist, ..)| dist);
+            if self
+                .node_bounds(level, col, row)
+                .intersect_range(ray, inv_dir, max_dist)
+                .is_none()
+            {
+                continue;
+            }
+
+            if level == 0 {
+                for tri in self.cell_triangles(col, row).iter() {
+                    let vertices = tri.map(|(col, row)| self.vertex(col, row));
+                    if let Some((dist, b1, b2)) = intersect_triangle(ray, vertices) {
+                        if closest.is_none_or(|(closest_dist, ..)| dist < closest_dist) {
+                            closest = Some((dist, *tri, b1, b2));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let children = &self.levels[level - 1];
+            for &(dx, dz) in child_order.iter() {
+                let (child_col, child_row) = (2 * col + dx, 2 * row + dz);
+                if child_col < children.columns && child_row < children.rows {
+                    stack.push((level - 1, child_col, child_row));
+                }
+            }
+        }
+
+        closest.map(|(dist, tri, b1, b2)| self.hit_at(dist, tri, b1, b2))
+    }
+
+    fn bounds(&self) -> Aabb {
+        let top = self.levels.last().unwrap();
+        let (lo, hi) = top.ranges[0];
+        Aabb {
+            min: Vec3 {
+                x: 0.0,
+                y: lo,
+                z: 0.0,
+            },
+            max: Vec3 {
+                x: self.size.x,
+                y: hi,
+                z: self.size.z,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-6;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn ray(origin: Vec3, dir: Vec3) -> Ray {
+        Ray {
+            origin,
+            dir: dir.to_unit(),
+            time: 0.0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn flat_grid_hits() {
+        // A 9x9 grid at half height, which lies in the plane y = 1.
+        let flat = Heightfield::new(9, 9, vec![0.5; 81], vec3(4.0, 2.0, 4.0)).unwrap();
+
+        let hit = flat
+            .intersect(&ray(vec3(1.3, 5.0, 2.7), vec3(0.0, -1.0, 0.0)))
+            .expect("expected a hit");
+        assert_close(hit.dist, 4.0);
+        assert_close(Vec3::from(hit.normal).y, 1.0);
+        assert_close(Vec3::from(hit.shading_normal).y, 1.0);
+        assert_close(hit.uv.0, 1.3 / 4.0);
+        assert_close(hit.uv.1, 2.7 / 4.0);
+
+        // An oblique ray crossing many cells before it lands
+        let hit = flat
+            .intersect(&ray(vec3(-1.0, 4.0, 0.5), vec3(1.0, -1.0, 1.0)))
+            .expect("expected a hit");
+        assert_close(hit.dist, 3.0 * 3f64.sqrt());
+
+        assert!(flat
+            .intersect(&ray(vec3(5.0, 5.0, 2.0), vec3(0.0, -1.0, 0.0)))
+            .is_none());
+        assert!(flat
+            .intersect(&ray(vec3(2.0, 0.0, 2.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+    }
+
+    #[test]
+    fn oversized_raw_file_is_rejected() {
+        assert!(matches!(
+            Heightfield::load_raw("missing.raw", usize::MAX, 2, vec3(1.0, 1.0, 1.0)),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_grid_is_rejected() {
+        assert!(matches!(
+            Heightfield::new(usize::MAX, 2, vec![0.0; 4], vec3(1.0, 1.0, 1.0)),
+            Err(Error::InvalidParameter(_))
+        ));
+    }
+}
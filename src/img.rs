@@ -1,9 +1,21 @@
+use std::fs::File;
 use std::io::Write;
+use std::path::Path;
 
+use crate::error::{Error, Result};
 use crate::math::Vec3;
 
+/// Returns the number of pixels in an image, failing if there are too many to index with `u32`s
+/// as rendering does.
+pub fn pixel_count(width: u32, height: u32) -> Result<usize> {
+    width
+        .checked_mul(height)
+        .map(|count| count as usize)
+        .ok_or(Error::InvalidParameter("image dimensions are too large"))
+}
+
 fn channel_to_raw(chan: f64) -> u8 {
-    (chan.max(0.0).min(1.0) * 255.0) as u8
+    (chan.clamp(0.0, 1.0) * 255.0) as u8
 }
 
 pub fn pixels_to_raw_rgb(pixels: &[Vec3]) -> Box<[u8]> {
@@ -23,12 +35,151 @@ pub fn write_png<W: Write>(
     raw_pixels: &[u8],
     width: u32,
     height: u32,
-) -> Result<(), png::EncodingError> {
-    assert_eq!(raw_pixels.len(), (width * height * 3) as usize);
+) -> Result<()> {
+    let expected = pixel_count(width, height)?
+        .checked_mul(3)
+        .ok_or(Error::InvalidParameter("image dimensions are too large"))?;
+    if raw_pixels.len() != expected {
+        return Err(Error::BufferSize {
+            expected,
+            actual: raw_pixels.len(),
+        });
+    }
 
     let mut enc = png::Encoder::new(writer, width, height);
     enc.set_color(png::ColorType::RGB);
     enc.set_depth(png::BitDepth::Eight);
 
-    enc.write_header()?.write_image_data(raw_pixels)
+    enc.write_header()?.write_image_data(raw_pixels)?;
+    Ok(())
+}
+
+/// Writes pixels as a Portable Float Map, which keeps values outside `[0, 1]` such as depths
+/// and normals.
+pub fn write_pfm<W: Write>(writer: &mut W, pixels: &[Vec3], width: u32, height: u32) -> Result<()> {
+    let expected = pixel_count(width, height)?;
+    if pixels.len() != expected {
+        return Err(Error::BufferSize {
+            expected,
+            actual: pixels.len(),
+        });
+    }
+
+    // A negative scale marks the samples as little-endian.
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    // Rows are stored from the bottom of the image up.
+    for row in pixels.chunks(width as usize).rev() {
+        for pixel in row {
+            for chan in &[pixel.x, pixel.y, pixel.z] {
+                writer.write_all(&(*chan as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A decoded PNG image, with samples normalized to `[0, 1]`.
+pub struct PngImage {
+    pub width: usize,
+    pub height: usize,
+    channels: usize,
+    samples: Vec<f64>,
+}
+
+impl PngImage {
+    /// Iterates over the pixels of the image in row-major order, starting from the top row.
+    pub fn pixels(&self) -> impl Iterator<Item = PngPixel<'_>> {
+        self.samples.chunks(self.channels).map(PngPixel)
+    }
+}
+
+pub struct PngPixel<'a>(&'a [f64]);
+
+impl<'a> PngPixel<'a> {
+    /// Returns the color of the pixel, expanding grayscale and dropping alpha.
+    pub fn rgb(&self) -> [f64; 3] {
+        match self.0.len() {
+            1 | 2 => [self.0[0]; 3],
+            _ => [self.0[0], self.0[1], self.0[2]],
+        }
+    }
+
+    /// Returns the gray level of the pixel, taking the luminance of color pixels.
+    pub fn gray(&self) -> f64 {
+        match self.0.len() {
+            1 | 2 => self.0[0],
+            _ => 0.2126 * self.0[0] + 0.7152 * self.0[1] + 0.0722 * self.0[2],
+        }
+    }
+}
+
+pub fn read_png<P: AsRef<Path>>(path: P) -> Result<PngImage> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    // Expand palettes and low bit depths to 8-bit samples.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info()?;
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf)?;
+
+    let (color_type, bit_depth) = reader.output_color_type();
+    let channels = match color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        png::ColorType::Indexed => {
+            return Err(Error::Format("unexpanded indexed PNG".to_owned()));
+        }
+    };
+
+    let samples = match bit_depth {
+        png::BitDepth::Sixteen => buf
+            .chunks(2)
+            .map(|bytes| f64::from(u16::from_be_bytes([bytes[0], bytes[1]])) / 65535.0)
+            .collect(),
+        _ => buf.iter().map(|&byte| f64::from(byte) / 255.0).collect(),
+    };
+
+    Ok(PngImage {
+        width: info.width as usize,
+        height: info.height as usize,
+        channels,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatched_buffers_are_rejected() {
+        let raw = pixels_to_raw_rgb(&[Vec3::default(); 6]);
+        assert!(write_png(&mut Vec::new(), &raw, 3, 2).is_ok());
+        assert!(matches!(
+            write_png(&mut Vec::new(), &raw, 2, 2),
+            Err(Error::BufferSize {
+                expected: 12,
+                actual: 18
+            })
+        ));
+        assert!(matches!(
+            write_pfm(&mut Vec::new(), &[Vec3::default(); 5], 3, 2),
+            Err(Error::BufferSize {
+                expected: 6,
+                actual: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        assert_eq!(pixel_count(1 << 16, (1 << 16) - 1).unwrap(), 0xffff_0000);
+        assert!(matches!(
+            pixel_count(1 << 16, 1 << 16),
+            Err(Error::InvalidParameter(_))
+        ));
+    }
 }
@@ -0,0 +1,159 @@
+use std::cmp::Ordering;
+use std::ops::Range;
+
+use crate::math::*;
+
+/// A balanced kd-tree over a set of points, each carrying some data, for finding the points
+/// near a location.
+pub struct KdTree<T> {
+    /// The points, ordered so that each subtree occupies a contiguous range with the point it
+    /// splits at in the middle
+    items: Vec<(Vec3, T)>,
+    /// The axis that each point splits its subtree along
+    axes: Vec<u8>,
+}
+
+impl<T> KdTree<T> {
+    /// Builds a tree over `items`, splitting each subtree at the median of its points along
+    /// the axis where they're most spread out. All points must be finite.
+    pub fn new(mut items: Vec<(Vec3, T)>) -> KdTree<T> {
+        let mut axes = vec![0; items.len()];
+        build(&mut items, &mut axes);
+        KdTree { items, axes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Invokes `visit` with every point within `radius` of `center`, along with its data.
+    pub fn for_each_within<F>(&self, center: Vec3, radius: f64, mut visit: F)
+    where
+        F: FnMut(Vec3, &T),
+    {
+        self.search(0..self.items.len(), center, radius, &mut visit);
+    }
+
+    fn search<F>(&self, range: Range<usize>, center: Vec3, radius: f64, visit: &mut F)
+    where
+        F: FnMut(Vec3, &T),
+    {
+        if range.is_empty() {
+            return;
+        }
+
+        let mid = range.start + range.len() / 2;
+        let (point, data) = &self.items[mid];
+        if (*point - center).mag_squared() <= radius * radius {
+            visit(*point, data);
+        }
+
+        // Only descend into the sides of the splitting plane that the sphere overlaps.
+        let axis = usize::from(self.axes[mid]);
+        let offset = center.axis(axis) - point.axis(axis);
+        if offset <= radius {
+            self.search(range.start..mid, center, radius, visit);
+        }
+        if offset >= -radius {
+            self.search(mid + 1..range.end, center, radius, visit);
+        }
+    }
+}
+
+fn build<T>(items: &mut [(Vec3, T)], axes: &mut [u8]) {
+    if items.len() <= 1 {
+        return;
+    }
+
+    let bounds = Aabb::from_points(items.iter().map(|(point, _)| *point));
+    let extent = bounds.max - bounds.min;
+    let axis = (0..3)
+        .max_by(|&a, &b| {
+            extent
+                .axis(a)
+                .partial_cmp(&extent.axis(b))
+                .unwrap_or(Ordering::Equal)
+        })
+        .unwrap_or(0);
+
+    let mid = items.len() / 2;
+    items.select_nth_unstable_by(mid, |(a, _), (b, _)| {
+        a.axis(axis)
+            .partial_cmp(&b.axis(axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[mid] = axis as u8;
+
+    let (below, rest) = items.split_at_mut(mid);
+    let (axes_below, axes_rest) = axes.split_at_mut(mid);
+    build(below, axes_below);
+    build(&mut rest[1..], &mut axes_rest[1..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_point(rng: &mut StdRng) -> Vec3 {
+        Vec3 {
+            x: rng.gen_range(-1.0, 1.0),
+            y: rng.gen_range(-1.0, 1.0),
+            z: rng.gen_range(-1.0, 1.0),
+        }
+    }
+
+    fn assert_matches_scan(points: &[Vec3], rng: &mut StdRng) {
+        let tree = KdTree::new(
+            points
+                .iter()
+                .copied()
+                .enumerate()
+                .map(|(i, p)| (p, i))
+                .collect(),
+        );
+        assert_eq!(tree.len(), points.len());
+
+        for _ in 0..200 {
+            let center = 1.2 * random_point(rng);
+            let radius = rng.gen_range(0.0, 0.6);
+
+            let mut found = Vec::new();
+            tree.for_each_within(center, radius, |point, &idx| {
+                assert_eq!((point - points[idx]).mag_squared(), 0.0);
+                found.push(idx);
+            });
+            found.sort_unstable();
+
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|&idx| (points[idx] - center).mag_squared() <= radius * radius)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn finds_same_points_as_scan() {
+        let mut rng = StdRng::seed_from_u64(0x4d);
+        let points: Vec<Vec3> = (0..1000).map(|_| random_point(&mut rng)).collect();
+        assert_matches_scan(&points, &mut rng);
+
+        // Points sharing coordinates tie at the medians they're split at.
+        let grid: Vec<Vec3> = (0..512)
+            .map(|i| Vec3 {
+                x: f64::from(i % 8) / 4.0 - 1.0,
+                y: f64::from(i / 8 % 8) / 4.0 - 1.0,
+                z: f64::from(i / 64) / 4.0 - 1.0,
+            })
+            .chain((0..20).map(|_| Vec3::default()))
+            .collect();
+        assert_matches_scan(&grid, &mut rng);
+
+        assert!(KdTree::<()>::new(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,26 @@
+pub mod animation;
+pub mod bdpt;
+pub mod bsdf;
+pub mod bvh;
+pub mod debug;
+pub mod denoise;
+pub mod error;
+pub mod geom;
+pub mod guiding;
+pub mod heightfield;
+pub mod img;
+pub mod kdtree;
+pub mod light;
+pub mod math;
+pub mod medium;
+pub mod mesh;
+pub mod mlt;
+pub mod renderer;
+pub mod sample;
+pub mod sdf;
+pub mod spectral;
+pub mod sppm;
+pub mod stats;
+pub mod texture;
+
+pub use error::{Error, Result};
@@ -0,0 +1,1195 @@
+use std::cmp::Ordering;
+use std::f64;
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+
+use crate::error::{Error, Result};
+use crate::geom::{Geom, Quad, SurfaceSample};
+use crate::math::*;
+use crate::mesh::Mesh;
+use crate::sample::*;
+
+/// Light arriving at a point from a sampled position on a light.
+#[derive(Debug, Copy, Clone)]
+pub struct LightSample {
+    /// The direction towards the light
+    pub dir: Unit3,
+    /// The distance to the light, which may be infinite
+    pub dist: f64,
+    /// The radiance arriving from the light, divided by the probability density of the sample
+    pub radiance: Vec3,
+    /// The probability density of the sample with respect to solid angle, or 1 for lights that
+    /// can only be reached by sampling them directly
+    pub pdf: f64,
+    /// Whether the light is infinitesimally small, so that it can't be hit by chance
+    pub delta: bool,
+    /// The surface normal at the sampled point, for area lights
+    pub normal: Option<Unit3>,
+}
+
+/// A ray of light leaving a light, for tracing paths outwards from it.
+#[derive(Debug, Copy, Clone)]
+pub struct EmissionSample {
+    /// The ray the light leaves along, at time 0 since lights don't move
+    pub ray: Ray,
+    /// The surface normal where the ray leaves, for area lights
+    pub normal: Option<Unit3>,
+    /// The radiance carried by the ray, or the intensity or irradiance for lights without area
+    pub radiance: Vec3,
+    /// The probability density of the ray's origin with respect to area, or 1 if there is only
+    /// one place it can start
+    pub pdf_pos: f64,
+    /// The probability density of the ray's direction with respect to solid angle, or 1 if
+    /// there is only one direction it can take
+    pub pdf_dir: f64,
+    /// Whether the light is a single point or direction, so that it can't be hit by chance
+    pub delta: bool,
+}
+
+/// A source of light that is sampled explicitly with shadow rays, rather than being found by
+/// tracing rays into the scene.
+///
+/// Colors are in linear RGB.
+pub trait Light: Send + Sync {
+    /// Samples the light arriving at `point` at `time`, ignoring anything in the way.
+    fn sample(&self, point: Vec3, time: f64, rng: &mut dyn RngCore) -> Option<LightSample>;
+
+    /// Returns the probability density, with respect to solid angle, of `sample` choosing the
+    /// direction `dir` from `point` at `time`. Lights that can't be hit by rays always return 0.
+    fn pdf(&self, _point: Vec3, _dir: Unit3, _time: f64) -> f64 {
+        0.0
+    }
+
+    /// Returns bounds on the light's position, orientation and power, or `None` if the light is
+    /// infinitely far away.
+    fn bounds(&self) -> Option<LightBounds>;
+
+    /// Samples a ray of light leaving the light. Lights infinitely far away emit rays from a
+    /// disk covering `scene_bounds`.
+    fn sample_emission(&self, scene_bounds: &Aabb, rng: &mut dyn RngCore)
+        -> Option<EmissionSample>;
+
+    /// Returns the probability densities of `sample_emission` choosing a ray leaving `point`
+    /// along `dir`, as the density of the origin followed by that of the direction. Lights
+    /// with a single origin or direction return 0 for it. `normal` is the surface normal at
+    /// `point`, for area lights.
+    fn pdf_emission(
+        &self,
+        point: Vec3,
+        normal: Option<Unit3>,
+        dir: Unit3,
+        scene_bounds: &Aabb,
+    ) -> (f64, f64);
+}
+
+/// Bounds on where a light is, which way it faces and how much it emits, for estimating how
+/// much it might contribute to the light arriving at a point. See "Importance Sampling of Many
+/// Lights with Adaptive Tree Splitting" (Conty Estevez and Kulla, 2018).
+#[derive(Debug, Copy, Clone)]
+pub struct LightBounds {
+    pub bounds: Aabb,
+    /// The axis of a cone bounding the light's surface normals, or the directions it emits in
+    pub axis: Unit3,
+    /// The angle from the axis to the edge of the cone of normals
+    pub normal_angle: f64,
+    /// The angle beyond the normals that light is emitted at, such as `pi / 2` for surfaces
+    pub emission_angle: f64,
+    /// The total power emitted, averaged over channels
+    pub power: f64,
+    /// Whether the light also emits backwards, against its normals
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    /// Returns bounds on both sets of lights.
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.power <= 0.0 {
+            return *other;
+        }
+        if other.power <= 0.0 {
+            return *self;
+        }
+
+        let (axis, normal_angle) = cone_union(
+            (self.axis, self.normal_angle),
+            (other.axis, other.normal_angle),
+        );
+        LightBounds {
+            bounds: self.bounds.union(other.bounds),
+            axis,
+            normal_angle,
+            emission_angle: self.emission_angle.max(other.emission_angle),
+            power: self.power + other.power,
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    /// Estimates how much light the bounded lights contribute at `point`, conservatively
+    /// assuming they are placed and oriented as favorably as the bounds allow. `normal` is the
+    /// normal of the surface receiving the light, if there is one.
+    pub fn importance(&self, point: Vec3, normal: Option<Unit3>) -> f64 {
+        if self.power <= 0.0 {
+            return 0.0;
+        }
+
+        let center = self.bounds.centroid();
+        let half_diagonal = (self.bounds.max - self.bounds.min).mag() / 2.0;
+        let offset = point - center;
+        // Don't let the estimate blow up for points within the bounds.
+        let dist_squared = offset.mag_squared().max(half_diagonal * half_diagonal);
+
+        // The angle subtended by the bounding sphere
+        let dist = offset.mag();
+        let bounds_angle = if dist <= half_diagonal {
+            f64::consts::PI
+        } else {
+            (half_diagonal / dist).asin()
+        };
+
+        // The smallest angle there could be between an emitting normal and the direction to
+        // the point
+        let to_point = offset.try_to_unit().map_or(0.0, |dir| {
+            let cos = Vec3::from(dir).dot(self.axis.into()).clamp(-1.0, 1.0);
+            let angle = cos.acos();
+            if self.two_sided {
+                angle.min(f64::consts::PI - angle)
+            } else {
+                angle
+            }
+        });
+        let emit_angle = (to_point - self.normal_angle - bounds_angle).max(0.0);
+        if emit_angle >= self.emission_angle {
+            return 0.0;
+        }
+
+        let mut importance = self.power * emit_angle.cos() / dist_squared;
+
+        // The smallest angle there could be between the light and the receiving normal
+        if let Some(normal) = normal {
+            let cos = offset
+                .try_to_unit()
+                .map_or(1.0, |dir| Vec3::from(dir).dot(normal.into()).abs());
+            let incident_angle = (cos.min(1.0).acos() - bounds_angle).max(0.0);
+            importance *= incident_angle.cos();
+        }
+
+        importance.max(0.0)
+    }
+}
+
+/// Returns a cone bounding two others, each given by its axis and the angle from the axis to its
+/// edge.
+fn cone_union(a: (Unit3, f64), b: (Unit3, f64)) -> (Unit3, f64) {
+    let (a, b) = if b.1 > a.1 { (b, a) } else { (a, b) };
+    let full = (a.0, f64::consts::PI);
+    if a.1 >= f64::consts::PI {
+        return full;
+    }
+
+    let between = Vec3::from(a.0).dot(b.0.into()).clamp(-1.0, 1.0).acos();
+    if (between + b.1).min(f64::consts::PI) <= a.1 {
+        return a;
+    }
+
+    // Rotate the wider cone's axis towards the other, to the middle of the combined spread.
+    let angle = (a.1 + between + b.1) / 2.0;
+    if angle >= f64::consts::PI {
+        return full;
+    }
+    let rotation = angle - a.1;
+    let perp =
+        match (Vec3::from(b.0) - Vec3::from(a.0).dot(b.0.into()) * Vec3::from(a.0)).try_to_unit() {
+            Some(perp) => Vec3::from(perp),
+            None => return full,
+        };
+    let axis = rotation.cos() * Vec3::from(a.0) + rotation.sin() * perp;
+    (axis.to_unit(), angle)
+}
+
+/// A hierarchy over the lights in a scene, for picking a light in proportion to an estimate of
+/// its contribution at a point.
+///
+/// Lights without bounds, such as directional lights, are left out of the hierarchy and should
+/// always be sampled.
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    /// The path from the root to each light's leaf, as a bit per level that is set when the path
+    /// takes the second child, along with the light's depth in the tree
+    trails: Vec<Option<(u64, u32)>>,
+    unbounded: Vec<usize>,
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    /// The light at a leaf, or the index of the second child of an interior node, whose first
+    /// child directly follows it
+    index: usize,
+    leaf: bool,
+}
+
+impl LightTree {
+    /// Builds a hierarchy over lights with the given bounds, identified by their indices.
+    pub fn new(bounds: &[Option<LightBounds>]) -> LightTree {
+        let mut tree = LightTree {
+            nodes: Vec::new(),
+            trails: vec![None; bounds.len()],
+            unbounded: Vec::new(),
+        };
+
+        let mut bounded = Vec::new();
+        for (idx, light_bounds) in bounds.iter().enumerate() {
+            match light_bounds {
+                Some(light_bounds) => bounded.push((idx, *light_bounds)),
+                None => tree.unbounded.push(idx),
+            }
+        }
+        if !bounded.is_empty() {
+            tree.build(&mut bounded, 0, 0);
+        }
+
+        tree
+    }
+
+    /// Adds nodes for the lights, returning their combined bounds.
+    fn build(
+        &mut self,
+        lights: &mut [(usize, LightBounds)],
+        trail: u64,
+        depth: u32,
+    ) -> LightBounds {
+        if let [(idx, bounds)] = *lights {
+            self.nodes.push(LightNode {
+                bounds,
+                index: idx,
+                leaf: true,
+            });
+            self.trails[idx] = Some((trail, depth));
+            return bounds;
+        }
+
+        // Split at the median along the longest axis of the lights' centers.
+        let centers = Aabb::from_points(lights.iter().map(|(_, bounds)| bounds.bounds.centroid()));
+        let extent = centers.max - centers.min;
+        let axis = (0..3)
+            .max_by(|&a, &b| {
+                extent
+                    .axis(a)
+                    .partial_cmp(&extent.axis(b))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap();
+        lights.sort_by(|(_, a), (_, b)| {
+            let a = a.bounds.centroid().axis(axis);
+            let b = b.bounds.centroid().axis(axis);
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        });
+        let (first, second) = lights.split_at_mut(lights.len() / 2);
+
+        let node = self.nodes.len();
+        self.nodes.push(LightNode {
+            bounds: first[0].1,
+            index: 0,
+            leaf: false,
+        });
+        let first_bounds = self.build(first, trail, depth + 1);
+        self.nodes[node].index = self.nodes.len();
+        let second_bounds = self.build(second, trail | 1 << depth, depth + 1);
+
+        let bounds = first_bounds.union(&second_bounds);
+        self.nodes[node].bounds = bounds;
+        bounds
+    }
+
+    /// Returns the lights that aren't in the hierarchy.
+    pub fn unbounded(&self) -> &[usize] {
+        &self.unbounded
+    }
+
+    /// Returns the probabilities of descending to each child of an interior node.
+    fn child_probs(&self, node: usize, point: Vec3, normal: Option<Unit3>) -> Option<(f64, f64)> {
+        let first = self.nodes[node + 1].bounds.importance(point, normal);
+        let second = self.nodes[self.nodes[node].index]
+            .bounds
+            .importance(point, normal);
+        let total = first + second;
+        if total > 0.0 {
+            Some((first / total, second / total))
+        } else {
+            None
+        }
+    }
+
+    /// Picks one of the lights in the hierarchy to illuminate `point`, using `u` in `[0, 1)`,
+    /// and returns it along with the probability of picking it.
+    pub fn sample(&self, point: Vec3, normal: Option<Unit3>, mut u: f64) -> Option<(usize, f64)> {
+        let mut node = 0;
+        let mut prob = 1.0;
+        loop {
+            let current = self.nodes.get(node)?;
+            if current.leaf {
+                let importance = current.bounds.importance(point, normal);
+                return if importance > 0.0 {
+                    Some((current.index, prob))
+                } else {
+                    None
+                };
+            }
+
+            let (first, second) = self.child_probs(node, point, normal)?;
+            if u < first {
+                u = (u / first).min(1.0 - f64::EPSILON);
+                prob *= first;
+                node += 1;
+            } else {
+                u = ((u - first) / second).min(1.0 - f64::EPSILON);
+                prob *= second;
+                node = current.index;
+            }
+        }
+    }
+
+    /// Returns the probability of `sample` picking the light with the given index.
+    pub fn pmf(&self, point: Vec3, normal: Option<Unit3>, light: usize) -> f64 {
+        let (trail, depth) = match self.trails.get(light) {
+            Some(Some(trail)) => *trail,
+            _ => return 0.0,
+        };
+
+        let mut node = 0;
+        let mut prob = 1.0;
+        for level in 0..depth {
+            let (first, second) = match self.child_probs(node, point, normal) {
+                Some(probs) => probs,
+                None => return 0.0,
+            };
+            if trail & 1 << level == 0 {
+                prob *= first;
+                node += 1;
+            } else {
+                prob *= second;
+                node = self.nodes[node].index;
+            }
+        }
+
+        if self.nodes[node].bounds.importance(point, normal) > 0.0 {
+            prob
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A light with an emitting surface, which is placed in the scene along with the light so that
+/// rays can also find it by chance.
+pub trait AreaLight: Light {
+    /// Returns the emitting surface.
+    fn geom(&self) -> Arc<dyn Geom>;
+
+    /// Returns the radiance leaving each point on the surface.
+    fn radiance(&self) -> Vec3;
+
+    /// Returns whether the surface emits from its back as well as its front.
+    fn is_two_sided(&self) -> bool;
+}
+
+fn check_color(color: Vec3, message: &'static str) -> Result<()> {
+    if (0..3).all(|axis| color.axis(axis) >= 0.0 && color.axis(axis).is_finite()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidParameter(message))
+    }
+}
+
+/// A light emitting equally in all directions from a single point.
+#[derive(Debug, Copy, Clone)]
+pub struct PointLight {
+    position: Vec3,
+    intensity: Vec3,
+}
+
+impl PointLight {
+    /// Creates a light with the given radiant intensity, so that a surface facing it at unit
+    /// distance receives `intensity` irradiance.
+    pub fn new(position: Vec3, intensity: Vec3) -> Result<PointLight> {
+        check_color(intensity, "light intensity must be finite and nonnegative")?;
+        Ok(PointLight {
+            position,
+            intensity,
+        })
+    }
+}
+
+/// Samples a light at a single point, whose intensity in the direction of `point` is given by
+/// `intensity`.
+fn sample_point(
+    position: Vec3,
+    point: Vec3,
+    intensity: impl FnOnce(Unit3) -> Vec3,
+) -> Option<LightSample> {
+    let offset = position - point;
+    let dist = offset.mag();
+    let dir = offset.try_to_unit()?;
+    let radiance = intensity(dir) / (dist * dist);
+    if radiance.mag_squared() <= 0.0 {
+        return None;
+    }
+
+    Some(LightSample {
+        dir,
+        dist,
+        radiance,
+        pdf: 1.0,
+        delta: true,
+        normal: None,
+    })
+}
+
+impl Light for PointLight {
+    fn sample(&self, point: Vec3, _time: f64, _rng: &mut dyn RngCore) -> Option<LightSample> {
+        sample_point(self.position, point, |_| self.intensity)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: Aabb::from_points(vec![self.position]),
+            axis: Unit3::from_unit_vec3(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+            normal_angle: f64::consts::PI,
+            emission_angle: f64::consts::FRAC_PI_2,
+            power: 4.0 * f64::consts::PI * average(self.intensity),
+            two_sided: false,
+        })
+    }
+
+    fn sample_emission(&self, _: &Aabb, rng: &mut dyn RngCore) -> Option<EmissionSample> {
+        // A cone as wide as a sphere, around any axis
+        let axis = Unit3::from_unit_vec3(Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        });
+        let dir = sample_uniform_cone(axis, f64::consts::PI, rng);
+        Some(EmissionSample {
+            ray: Ray {
+                origin: self.position,
+                dir,
+                time: 0.0,
+            },
+            normal: None,
+            radiance: self.intensity,
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (4.0 * f64::consts::PI),
+            delta: true,
+        })
+    }
+
+    fn pdf_emission(&self, _: Vec3, _: Option<Unit3>, _: Unit3, _: &Aabb) -> (f64, f64) {
+        (0.0, 1.0 / (4.0 * f64::consts::PI))
+    }
+}
+
+/// A point light that only shines within a cone, fading out towards its edge.
+#[derive(Debug, Copy, Clone)]
+pub struct SpotLight {
+    position: Vec3,
+    dir: Unit3,
+    intensity: Vec3,
+    cos_cone: f64,
+    cos_falloff: f64,
+}
+
+impl SpotLight {
+    /// Creates a light shining from `position` towards `target`. The light has its full
+    /// `intensity` up to `falloff_angle` degrees from its axis, and fades out smoothly to
+    /// nothing at `cone_angle` degrees.
+    pub fn new(
+        position: Vec3,
+        target: Vec3,
+        intensity: Vec3,
+        cone_angle: f64,
+        falloff_angle: f64,
+    ) -> Result<SpotLight> {
+        check_color(intensity, "light intensity must be finite and nonnegative")?;
+        let dir = (target - position)
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter(
+                "spot light target coincides with it",
+            ))?;
+        if cone_angle.is_nan() || cone_angle <= 0.0 || cone_angle > 180.0 {
+            return Err(Error::InvalidParameter(
+                "spot light cone angle must be between 0 and 180 degrees",
+            ));
+        }
+        if falloff_angle.is_nan() || falloff_angle < 0.0 || falloff_angle > cone_angle {
+            return Err(Error::InvalidParameter(
+                "spot light falloff angle must be between 0 and the cone angle",
+            ));
+        }
+
+        Ok(SpotLight {
+            position,
+            dir,
+            intensity,
+            cos_cone: cone_angle.to_radians().cos(),
+            cos_falloff: falloff_angle.to_radians().cos(),
+        })
+    }
+
+    /// Returns the fraction of the full intensity emitted at an angle to the axis.
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_falloff {
+            1.0
+        } else if cos_theta <= self.cos_cone {
+            0.0
+        } else {
+            let t = (cos_theta - self.cos_cone) / (self.cos_falloff - self.cos_cone);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+
+    /// Returns the probability density of emitted rays, which are spread uniformly over the
+    /// cone.
+    fn cone_pdf(&self) -> f64 {
+        1.0 / (2.0 * f64::consts::PI * (1.0 - self.cos_cone))
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, point: Vec3, _time: f64, _rng: &mut dyn RngCore) -> Option<LightSample> {
+        sample_point(self.position, point, |dir| {
+            let cos_theta = -Vec3::from(dir).dot(self.dir.into());
+            self.falloff(cos_theta) * self.intensity
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: Aabb::from_points(vec![self.position]),
+            axis: self.dir,
+            normal_angle: 0.0,
+            emission_angle: self.cos_cone.acos(),
+            power: 2.0 * f64::consts::PI * (1.0 - self.cos_cone) * average(self.intensity),
+            two_sided: false,
+        })
+    }
+
+    fn sample_emission(&self, _: &Aabb, rng: &mut dyn RngCore) -> Option<EmissionSample> {
+        let dir = sample_uniform_cone(self.dir, self.cos_cone.acos(), rng);
+        let cos_theta = Vec3::from(dir).dot(self.dir.into());
+        Some(EmissionSample {
+            ray: Ray {
+                origin: self.position,
+                dir,
+                time: 0.0,
+            },
+            normal: None,
+            radiance: self.falloff(cos_theta) * self.intensity,
+            pdf_pos: 1.0,
+            pdf_dir: self.cone_pdf(),
+            delta: true,
+        })
+    }
+
+    fn pdf_emission(&self, _: Vec3, _: Option<Unit3>, dir: Unit3, _: &Aabb) -> (f64, f64) {
+        if Vec3::from(dir).dot(self.dir.into()) >= self.cos_cone {
+            (0.0, self.cone_pdf())
+        } else {
+            (0.0, 0.0)
+        }
+    }
+}
+
+/// A light infinitely far away, such as the sun, whose light arrives everywhere from the same
+/// direction.
+#[derive(Debug, Copy, Clone)]
+pub struct DirectionalLight {
+    /// The direction towards the light
+    to_light: Unit3,
+    irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    /// Creates a light shining along `dir`, so that a surface facing it receives `irradiance`.
+    pub fn new(dir: Vec3, irradiance: Vec3) -> Result<DirectionalLight> {
+        check_color(
+            irradiance,
+            "light irradiance must be finite and nonnegative",
+        )?;
+        let to_light = (-dir)
+            .try_to_unit()
+            .ok_or(Error::InvalidParameter("light direction must be nonzero"))?;
+        Ok(DirectionalLight {
+            to_light,
+            irradiance,
+        })
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _point: Vec3, _time: f64, _rng: &mut dyn RngCore) -> Option<LightSample> {
+        Some(LightSample {
+            dir: self.to_light,
+            dist: f64::INFINITY,
+            radiance: self.irradiance,
+            pdf: 1.0,
+            delta: true,
+            normal: None,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    fn sample_emission(
+        &self,
+        scene_bounds: &Aabb,
+        rng: &mut dyn RngCore,
+    ) -> Option<EmissionSample> {
+        // Start rays from a disk facing the light, just outside the scene's bounding sphere.
+        let (center, radius) = bounding_sphere(scene_bounds)?;
+        let basis = Basis::from_normal(self.to_light);
+        let r = radius * rng.gen::<f64>().sqrt();
+        let phi = rng.gen_range(0.0, 2.0 * f64::consts::PI);
+        let origin = center
+            + radius * Vec3::from(self.to_light)
+            + r * phi.cos() * basis.x
+            + r * phi.sin() * basis.y;
+        Some(EmissionSample {
+            ray: Ray {
+                origin,
+                dir: (-Vec3::from(self.to_light)).to_unit(),
+                time: 0.0,
+            },
+            normal: None,
+            radiance: self.irradiance,
+            pdf_pos: 1.0 / (f64::consts::PI * radius * radius),
+            pdf_dir: 1.0,
+            delta: true,
+        })
+    }
+
+    fn pdf_emission(&self, _: Vec3, _: Option<Unit3>, _: Unit3, scene_bounds: &Aabb) -> (f64, f64) {
+        let pdf_pos = bounding_sphere(scene_bounds)
+            .map_or(0.0, |(_, radius)| 1.0 / (f64::consts::PI * radius * radius));
+        (pdf_pos, 0.0)
+    }
+}
+
+/// Returns the center and radius of a sphere enclosing `bounds`, if they are finite.
+pub fn bounding_sphere(bounds: &Aabb) -> Option<(Vec3, f64)> {
+    if !bounds.is_finite() {
+        return None;
+    }
+    let radius = ((bounds.max - bounds.min).mag() / 2.0).max(EPSILON);
+    Some((bounds.centroid(), radius))
+}
+
+/// A rectangle as seen from a point, for sampling points on it uniformly by solid angle. See
+/// "An Area-Preserving Parametrization for Spherical Rectangles" (Ureña et al., 2013).
+struct SphericalRect {
+    origin: Vec3,
+    /// A frame aligned with the rectangle's edges, with `z` pointing away from it
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+    /// The rectangle's extent in the frame around `origin`
+    x0: f64,
+    x1: f64,
+    y0: f64,
+    y1: f64,
+    z0: f64,
+    b0: f64,
+    b1: f64,
+    k: f64,
+    solid_angle: f64,
+}
+
+impl SphericalRect {
+    /// Returns `None` if the rectangle covers too small a solid angle to sample reliably.
+    fn new(quad: &Quad, origin: Vec3) -> Option<SphericalRect> {
+        let edge_u = quad.edge_u();
+        let edge_v = quad.edge_v();
+        let x = edge_u.to_unit().into();
+        let y = edge_v.to_unit().into();
+        let mut z = Vec3::from(quad.normal());
+
+        let offset = quad.corner() - origin;
+        let mut z0 = offset.dot(z);
+        if z0 > 0.0 {
+            z0 = -z0;
+            z = -z;
+        }
+        let x0 = offset.dot(x);
+        let y0 = offset.dot(y);
+        let x1 = x0 + edge_u.mag();
+        let y1 = y0 + edge_v.mag();
+
+        // Normals of the planes through the origin and each edge, and the angles between them
+        let corner = |x, y| Vec3 { x, y, z: z0 };
+        let n0 = corner(x0, y0).cross(corner(x1, y0)).try_to_unit()?.into();
+        let n1 = corner(x1, y0).cross(corner(x1, y1)).try_to_unit()?.into();
+        let n2 = corner(x1, y1).cross(corner(x0, y1)).try_to_unit()?.into();
+        let n3 = corner(x0, y1).cross(corner(x0, y0)).try_to_unit()?.into();
+        let angle = |a: Vec3, b: Vec3| (-a.dot(b)).clamp(-1.0, 1.0).acos();
+        let g0 = angle(n0, n1);
+        let g1 = angle(n1, n2);
+        let g2 = angle(n2, n3);
+        let g3 = angle(n3, n0);
+
+        let k = 2.0 * f64::consts::PI - g2 - g3;
+        let solid_angle = g0 + g1 - k;
+        if solid_angle.is_nan() || solid_angle <= EPSILON {
+            return None;
+        }
+
+        Some(SphericalRect {
+            origin,
+            x,
+            y,
+            z,
+            x0,
+            x1,
+            y0,
+            y1,
+            z0,
+            b0: n0.z,
+            b1: n2.z,
+            k,
+            solid_angle,
+        })
+    }
+
+    /// Maps a point in the unit square to a point on the rectangle.
+    fn sample(&self, u: f64, v: f64) -> Vec3 {
+        // Pick the x coordinate by the solid angle to its left
+        let au = u * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = (fu.signum() / (fu * fu + self.b0 * self.b0).sqrt()).clamp(-1.0, 1.0);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).sqrt()).clamp(self.x0, self.x1);
+
+        // and then the y coordinate along the resulting strip.
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + v * (h1 - h0);
+        let yv = if hv * hv < 1.0 - EPSILON {
+            hv * d / (1.0 - hv * hv).sqrt()
+        } else {
+            self.y1
+        };
+
+        self.origin + xu * self.x + yv * self.y + self.z0 * self.z
+    }
+}
+
+/// A rectangular area light, such as a softbox or window, which is sampled uniformly by the
+/// solid angle it covers.
+#[derive(Copy, Clone)]
+pub struct QuadLight {
+    quad: Quad,
+    radiance: Vec3,
+    two_sided: bool,
+}
+
+impl QuadLight {
+    /// Creates a light covering the rectangle spanned by two perpendicular edges from
+    /// `corner`. One-sided lights only emit on the side of the rectangle's normal, given by the
+    /// right-hand rule from `edge_u` to `edge_v`.
+    pub fn new(
+        corner: Vec3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+        radiance: Vec3,
+        two_sided: bool,
+    ) -> Result<QuadLight> {
+        check_color(radiance, "light radiance must be finite and nonnegative")?;
+        let quad = Quad::new(corner, edge_u, edge_v)?;
+        if edge_u.dot(edge_v).abs() > 1e-6 * edge_u.mag() * edge_v.mag() {
+            return Err(Error::InvalidParameter(
+                "quad light edges must be perpendicular",
+            ));
+        }
+
+        Ok(QuadLight {
+            quad,
+            radiance,
+            two_sided,
+        })
+    }
+
+    /// Returns whether `point` is on a side of the light that it emits towards.
+    fn faces(&self, point: Vec3) -> bool {
+        self.two_sided || (point - self.quad.corner()).dot(self.quad.normal().into()) > 0.0
+    }
+}
+
+impl Light for QuadLight {
+    fn sample(&self, point: Vec3, _time: f64, rng: &mut dyn RngCore) -> Option<LightSample> {
+        if !self.faces(point) {
+            return None;
+        }
+
+        let rect = SphericalRect::new(&self.quad, point)?;
+        let offset = rect.sample(rng.gen(), rng.gen()) - point;
+        Some(LightSample {
+            dir: offset.try_to_unit()?,
+            dist: offset.mag(),
+            radiance: self.radiance * rect.solid_angle,
+            pdf: 1.0 / rect.solid_angle,
+            delta: false,
+            normal: Some(self.quad.normal()),
+        })
+    }
+
+    fn pdf(&self, point: Vec3, dir: Unit3, time: f64) -> f64 {
+        if !self.faces(point)
+            || self
+                .quad
+                .intersect(&Ray {
+                    origin: point,
+                    dir,
+                    time,
+                })
+                .is_none()
+        {
+            return 0.0;
+        }
+
+        SphericalRect::new(&self.quad, point).map_or(0.0, |rect| 1.0 / rect.solid_angle)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: self.quad.bounds(),
+            axis: self.quad.normal(),
+            normal_angle: 0.0,
+            emission_angle: f64::consts::FRAC_PI_2,
+            power: surface_power(self.radiance, self.quad.area(), self.two_sided),
+            two_sided: self.two_sided,
+        })
+    }
+
+    fn sample_emission(&self, _: &Aabb, rng: &mut dyn RngCore) -> Option<EmissionSample> {
+        let surface = self.quad.sample_surface(rng)?;
+        sample_surface_emission(
+            &surface,
+            self.radiance,
+            self.quad.area(),
+            self.two_sided,
+            rng,
+        )
+    }
+
+    fn pdf_emission(&self, _: Vec3, _: Option<Unit3>, dir: Unit3, _: &Aabb) -> (f64, f64) {
+        surface_emission_pdf(self.quad.normal(), dir, self.quad.area(), self.two_sided)
+    }
+}
+
+impl AreaLight for QuadLight {
+    fn geom(&self) -> Arc<dyn Geom> {
+        Arc::new(self.quad)
+    }
+
+    fn radiance(&self) -> Vec3 {
+        self.radiance
+    }
+
+    fn is_two_sided(&self) -> bool {
+        self.two_sided
+    }
+}
+
+/// An area light in the shape of a triangle mesh, which is sampled uniformly by area.
+pub struct MeshLight {
+    mesh: Arc<Mesh>,
+    area: f64,
+    radiance: Vec3,
+    two_sided: bool,
+}
+
+impl MeshLight {
+    /// Creates a light covering a mesh. One-sided lights only emit from the front faces of
+    /// triangles.
+    pub fn new(mesh: Mesh, radiance: Vec3, two_sided: bool) -> Result<MeshLight> {
+        check_color(radiance, "light radiance must be finite and nonnegative")?;
+        let area = mesh.area().unwrap_or(0.0);
+        if area <= 0.0 {
+            return Err(Error::InvalidParameter("mesh light must have nonzero area"));
+        }
+
+        Ok(MeshLight {
+            mesh: Arc::new(mesh),
+            area,
+            radiance,
+            two_sided,
+        })
+    }
+
+    /// Returns a cone bounding the normals of the triangles.
+    fn normal_cone(&self) -> (Unit3, f64) {
+        let normals = self.mesh.triangles().iter().filter_map(|tri| {
+            let [p0, p1, p2] = tri.map(|idx| self.mesh.positions()[idx as usize]);
+            (p1 - p0).cross(p2 - p0).try_to_unit()
+        });
+        let sum = normals
+            .clone()
+            .fold(Vec3::default(), |sum, normal| sum + normal.into());
+        match sum.try_to_unit() {
+            Some(axis) => {
+                let cos_angle = normals
+                    .map(|normal| Vec3::from(normal).dot(axis.into()))
+                    .fold(1.0, f64::min);
+                (axis, cos_angle.clamp(-1.0, 1.0).acos())
+            }
+            None => (
+                Unit3::from_unit_vec3(Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                }),
+                f64::consts::PI,
+            ),
+        }
+    }
+}
+
+impl Light for MeshLight {
+    fn sample(&self, point: Vec3, _time: f64, rng: &mut dyn RngCore) -> Option<LightSample> {
+        let surface = self.mesh.sample_surface(rng)?;
+        let offset = surface.point - point;
+        let dist = offset.mag();
+        let dir = offset.try_to_unit()?;
+        let pdf = area_to_solid_angle(dir, dist, surface.normal, self.area, self.two_sided);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            dir,
+            dist,
+            radiance: self.radiance / pdf,
+            pdf,
+            delta: false,
+            normal: Some(surface.normal),
+        })
+    }
+
+    fn pdf(&self, point: Vec3, dir: Unit3, time: f64) -> f64 {
+        self.mesh
+            .intersect(&Ray {
+                origin: point,
+                dir,
+                time,
+            })
+            .map_or(0.0, |hit| {
+                area_to_solid_angle(dir, hit.dist, hit.normal, self.area, self.two_sided)
+            })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let (axis, normal_angle) = self.normal_cone();
+        Some(LightBounds {
+            bounds: self.mesh.bounds(),
+            axis,
+            normal_angle,
+            emission_angle: f64::consts::FRAC_PI_2,
+            power: surface_power(self.radiance, self.area, self.two_sided),
+            two_sided: self.two_sided,
+        })
+    }
+
+    fn sample_emission(&self, _: &Aabb, rng: &mut dyn RngCore) -> Option<EmissionSample> {
+        let surface = self.mesh.sample_surface(rng)?;
+        sample_surface_emission(&surface, self.radiance, self.area, self.two_sided, rng)
+    }
+
+    fn pdf_emission(&self, _: Vec3, normal: Option<Unit3>, dir: Unit3, _: &Aabb) -> (f64, f64) {
+        normal.map_or((0.0, 0.0), |normal| {
+            surface_emission_pdf(normal, dir, self.area, self.two_sided)
+        })
+    }
+}
+
+/// Converts the density of a point on a surface sampled uniformly by area into a density by
+/// solid angle as seen from a point `dist` away along `dir`. Returns 0 if the surface doesn't
+/// emit back along `dir`.
+pub fn area_to_solid_angle(
+    dir: Unit3,
+    dist: f64,
+    normal: Unit3,
+    area: f64,
+    two_sided: bool,
+) -> f64 {
+    let cos_theta = -Vec3::from(dir).dot(normal.into());
+    if cos_theta.abs() < EPSILON || (!two_sided && cos_theta < 0.0) {
+        return 0.0;
+    }
+    dist * dist / (cos_theta.abs() * area)
+}
+
+/// Samples a ray leaving a point sampled uniformly on a surface with the given area, in a
+/// cosine-weighted direction on a side of the surface that emits.
+pub fn sample_surface_emission(
+    surface: &SurfaceSample,
+    radiance: Vec3,
+    area: f64,
+    two_sided: bool,
+    rng: &mut dyn RngCore,
+) -> Option<EmissionSample> {
+    let side = if two_sided && rng.gen::<bool>() {
+        (-Vec3::from(surface.normal)).to_unit()
+    } else {
+        surface.normal
+    };
+    let dir = sample_cos_weighted_hemisphere(side, rng);
+    let (pdf_pos, pdf_dir) = surface_emission_pdf(surface.normal, dir, area, two_sided);
+    if pdf_dir <= 0.0 {
+        return None;
+    }
+    Some(EmissionSample {
+        ray: Ray {
+            origin: surface.point,
+            dir,
+            time: 0.0,
+        },
+        normal: Some(surface.normal),
+        radiance,
+        pdf_pos,
+        pdf_dir,
+        delta: false,
+    })
+}
+
+/// Returns the probability densities of `sample_surface_emission` choosing a ray leaving a
+/// surface with the given normal and area along `dir`.
+pub fn surface_emission_pdf(normal: Unit3, dir: Unit3, area: f64, two_sided: bool) -> (f64, f64) {
+    let cos_theta = Vec3::from(dir).dot(normal.into());
+    let pdf_dir = if two_sided {
+        cos_theta.abs() / (2.0 * f64::consts::PI)
+    } else {
+        cos_theta.max(0.0) / f64::consts::PI
+    };
+    (1.0 / area, pdf_dir)
+}
+
+/// Returns the power emitted by a surface of uniform radiance, averaged over channels.
+pub fn surface_power(radiance: Vec3, area: f64, two_sided: bool) -> f64 {
+    let sides = if two_sided { 2.0 } else { 1.0 };
+    sides * f64::consts::PI * area * average(radiance)
+}
+
+fn average(color: Vec3) -> f64 {
+    (color.x + color.y + color.z) / 3.0
+}
+
+impl AreaLight for MeshLight {
+    fn geom(&self) -> Arc<dyn Geom> {
+        self.mesh.clone()
+    }
+
+    fn radiance(&self) -> Vec3 {
+        self.radiance
+    }
+
+    fn is_two_sided(&self) -> bool {
+        self.two_sided
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn point_light_follows_inverse_square_law() {
+        let light = PointLight::new(vec3(1.0, 2.0, 3.0), vec3(4.0, 8.0, 0.0)).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        for &dist in &[0.5, 1.0, 2.0, 10.0] {
+            let sample = light
+                .sample(vec3(1.0, 2.0 - dist, 3.0), 0.0, &mut rng)
+                .unwrap();
+            assert!(sample.delta);
+            assert_close(sample.dist, dist);
+            assert_close(sample.dir.y(), 1.0);
+            assert_close(sample.radiance.x, 4.0 / (dist * dist));
+            assert_close(sample.radiance.y, 8.0 / (dist * dist));
+        }
+    }
+
+    #[test]
+    fn spot_light_falloff() {
+        let light = SpotLight::new(
+            Vec3::default(),
+            vec3(0.0, -1.0, 0.0),
+            vec3(1.0, 1.0, 1.0),
+            40.0,
+            25.0,
+        )
+        .unwrap();
+
+        let at = |degrees: f64| light.falloff(f64::to_radians(degrees).cos());
+        assert_close(at(0.0), 1.0);
+        assert_close(at(25.0), 1.0);
+        assert_close(at(40.0), 0.0);
+        assert_close(at(90.0), 0.0);
+        let edge = at(32.5);
+        assert!(edge > 0.0 && edge < 1.0);
+        assert!(at(30.0) > edge && edge > at(35.0));
+
+        // Inside the falloff angle, a point gets the full intensity.
+        let mut rng = StdRng::seed_from_u64(0);
+        let angle = f64::to_radians(20.0);
+        let point = 2.0 * vec3(angle.sin(), -angle.cos(), 0.0);
+        let sample = light.sample(point, 0.0, &mut rng).unwrap();
+        assert_close(sample.radiance.x, 0.25);
+        // Outside the cone, it gets nothing.
+        assert!(light.sample(vec3(1.0, 0.0, 0.0), 0.0, &mut rng).is_none());
+    }
+
+    #[test]
+    fn invalid_lights_are_rejected() {
+        let white = vec3(1.0, 1.0, 1.0);
+        let position = vec3(0.0, 1.0, 0.0);
+        let target = Vec3::default();
+        let spot = |target: Vec3, cone: f64, falloff: f64| {
+            SpotLight::new(position, target, white, cone, falloff)
+        };
+        for light in [
+            spot(position, 30.0, 10.0),
+            spot(target, 0.0, 0.0),
+            spot(target, 190.0, 10.0),
+            spot(target, f64::NAN, 10.0),
+            spot(target, 30.0, 40.0),
+            spot(target, 30.0, -1.0),
+            spot(target, 30.0, f64::NAN),
+        ] {
+            assert!(matches!(light, Err(Error::InvalidParameter(_))));
+        }
+
+        assert!(PointLight::new(position, vec3(-1.0, 0.0, 0.0)).is_err());
+        assert!(PointLight::new(position, vec3(f64::INFINITY, 0.0, 0.0)).is_err());
+        assert!(DirectionalLight::new(Vec3::default(), white).is_err());
+    }
+}
@@ -1,25 +1,40 @@
-mod geom;
-mod img;
-mod math;
-mod renderer;
-mod sample;
-
 use std::error;
+use std::f64;
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 use std::time::Instant;
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use structopt::StructOpt;
 
-use geom::Sphere;
-use math::Vec3;
-use renderer::*;
+use path_tracer::animation::{AnimatedTransform, CameraAnimation, Interpolation, Keyframe, Track};
+use path_tracer::debug::DebugView;
+use path_tracer::denoise::{denoise, Features};
+use path_tracer::geom::{
+    Cone, Csg, Cuboid, Cylinder, Disk, Geom, Instance, Plane, Quad, Sphere, Torus,
+};
+use path_tracer::heightfield::Heightfield;
+use path_tracer::img;
+use path_tracer::light::{DirectionalLight, MeshLight, PointLight, QuadLight, SpotLight};
+use path_tracer::math::{Aabb, Transform, Vec3};
+use path_tracer::medium::{GridMedium, HenyeyGreenstein, HomogeneousMedium};
+use path_tracer::mesh::Mesh;
+use path_tracer::renderer::*;
+use path_tracer::sdf::{self, Mandelbulb, Repeat, Sdf, SmoothSubtraction, SmoothUnion, Twist};
+use path_tracer::spectral::Ior;
+use path_tracer::stats::json_string;
+use path_tracer::texture::{
+    Checkerboard, Gradient, GradientAxis, NoiseTexture, NormalMap, Param, Perlin,
+};
 
 struct BuiltScene(pub Scene<'static>, pub CameraOptions);
 
-fn build_spec_spheres_scene() -> BuiltScene {
-    BuiltScene(
+fn build_spec_spheres_scene() -> path_tracer::Result<BuiltScene> {
+    Ok(BuiltScene(
         Scene::with_primitives(vec![
             Primitive::new(
                 Sphere::new(
@@ -29,7 +44,7 @@ fn build_spec_spheres_scene() -> BuiltScene {
                         z: -6.0,
                     },
                     1.0,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 1.0,
@@ -48,7 +63,7 @@ fn build_spec_spheres_scene() -> BuiltScene {
                         z: -6.0,
                     },
                     0.75,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 0.0,
@@ -67,7 +82,7 @@ fn build_spec_spheres_scene() -> BuiltScene {
                         z: -6.0,
                     },
                     0.75,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 1.0,
@@ -86,7 +101,7 @@ fn build_spec_spheres_scene() -> BuiltScene {
                         z: -6.0,
                     },
                     0.75,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 0.0,
@@ -105,7 +120,7 @@ fn build_spec_spheres_scene() -> BuiltScene {
                         z: -6.0,
                     },
                     0.75,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 0.0,
@@ -124,7 +139,7 @@ fn build_spec_spheres_scene() -> BuiltScene {
                         z: 1.1,
                     },
                     1.0,
-                ),
+                )?,
                 Material::make_light(
                     Vec3 {
                         x: 1.0,
@@ -147,22 +162,24 @@ fn build_spec_spheres_scene() -> BuiltScene {
                 z: 0.0,
             },
             vert_fov: 55.0,
+            motion: None,
+            animation: None,
         },
-    )
+    ))
 }
 
-fn build_mirror_scene() -> BuiltScene {
-    BuiltScene(
+fn build_mirror_scene() -> path_tracer::Result<BuiltScene> {
+    Ok(BuiltScene(
         Scene::with_primitives(vec![
             Primitive::new(
-                Sphere::new(
+                Plane::new(
+                    Vec3::default(),
                     Vec3 {
                         x: 0.0,
-                        y: -100.0,
-                        z: -8.0,
+                        y: 1.0,
+                        z: 0.0,
                     },
-                    100.0,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 0.8,
@@ -181,7 +198,7 @@ fn build_mirror_scene() -> BuiltScene {
                         z: -4.0,
                     },
                     0.75,
-                ),
+                )?,
                 Material::make_reflective(
                     Vec3 {
                         x: 0.7,
@@ -200,7 +217,7 @@ fn build_mirror_scene() -> BuiltScene {
                         z: -4.0,
                     },
                     0.5,
-                ),
+                )?,
                 Material::make_diffuse(Vec3 {
                     x: 0.5,
                     y: 0.0,
@@ -215,7 +232,7 @@ fn build_mirror_scene() -> BuiltScene {
                         z: -4.0,
                     },
                     0.5,
-                ),
+                )?,
                 Material::make_diffuse(Vec3 {
                     x: 0.0,
                     y: 0.5,
@@ -230,7 +247,7 @@ fn build_mirror_scene() -> BuiltScene {
                         z: -4.0,
                     },
                     0.3,
-                ),
+                )?,
                 Material::make_diffuse(Vec3 {
                     x: 0.0,
                     y: 0.0,
@@ -245,7 +262,7 @@ fn build_mirror_scene() -> BuiltScene {
                         z: -3.7,
                     },
                     0.1,
-                ),
+                )?,
                 Material::make_light(
                     Vec3 {
                         x: 1.0,
@@ -262,7 +279,7 @@ fn build_mirror_scene() -> BuiltScene {
                         z: 1.0,
                     },
                     1.0,
-                ),
+                )?,
                 Material::make_light(
                     Vec3 {
                         x: 1.0,
@@ -289,90 +306,1955 @@ fn build_mirror_scene() -> BuiltScene {
                 z: 0.0,
             },
             vert_fov: 55.0,
+            motion: None,
+            animation: None,
         },
-    )
+    ))
 }
 
-fn build_scene(name: &str) -> Option<BuiltScene> {
-    match name {
-        "spec-spheres" => Some(build_spec_spheres_scene()),
-        "mirror" => Some(build_mirror_scene()),
-        _ => None,
-    }
-}
+fn build_instances_scene() -> path_tracer::Result<BuiltScene> {
+    let unit_sphere: Arc<dyn Geom> = Arc::new(Sphere::new(Vec3::default(), 1.0)?);
 
-#[derive(StructOpt)]
-struct CliArgs {
-    /// Width of rendered image, in pixels
-    #[structopt(long, short)]
-    pub width: u32,
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Sphere::new(
+                Vec3 {
+                    x: 0.0,
+                    y: -100.0,
+                    z: -8.0,
+                },
+                100.0,
+            )?,
+            Material::make_diffuse(Vec3 {
+                x: 0.6,
+                y: 0.6,
+                z: 0.6,
+            }),
+        ),
+        Primitive::new(
+            Sphere::new(
+                Vec3 {
+                    x: 0.0,
+                    y: 6.0,
+                    z: -4.0,
+                },
+                1.5,
+            )?,
+            Material::make_light(
+                Vec3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                } * 20.0,
+            ),
+        ),
+    ]);
 
-    /// Height of rendered image, in pixels
-    #[structopt(long, short)]
-    pub height: u32,
+    let count = 8;
+    for i in 0..count {
+        let angle = 360.0 * f64::from(i) / f64::from(count);
+        let transform = Transform::translate(Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: -8.0,
+        }) * Transform::rotate_y(angle)
+            * Transform::translate(Vec3 {
+                x: 3.0,
+                y: 0.8,
+                z: 0.0,
+            })
+            * Transform::rotate_z(30.0)
+            * Transform::scale(Vec3 {
+                x: 0.8,
+                y: 0.4,
+                z: 0.4,
+            })?;
 
-    /// Maximum bounce depth
-    #[structopt(long, default_value = "5")]
-    pub max_depth: u32,
+        let hue = f64::from(i) / f64::from(count);
+        scene.add_primitive(Primitive::new(
+            Instance::new(unit_sphere.clone(), transform),
+            Material::make_reflective(
+                Vec3 {
+                    x: 1.0 - hue,
+                    y: 0.3,
+                    z: hue,
+                },
+                0.3,
+                0.9,
+            ),
+        ));
+    }
 
-    /// Number of samples to gather per pixel
-    #[structopt(long = "spp")]
-    pub samples_per_pixel: u32,
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: Vec3 {
+                x: 0.0,
+                y: 3.0,
+                z: 0.0,
+            },
+            target: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -8.0,
+            },
+            up: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            vert_fov: 55.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
 
-    /// Number of threads to use when rendering in parallel.
-    /// If this argument is 0, the number of cores will be used.
-    #[structopt(short = "j", default_value = "0")]
-    pub threads: u32,
+/// Builds a simple low-poly conifer: a stack of cones, with its base at the origin.
+fn build_tree_mesh() -> path_tracer::Result<Mesh> {
+    let segments = 8;
+    let tiers = [(0.2, 0.45, 0.7), (0.5, 0.35, 0.95), (0.8, 0.25, 1.2)];
 
-    /// Output filename
-    #[structopt(short, default_value = "render.png")]
-    pub output_filename: String,
+    let mut positions = Vec::new();
+    let mut triangles = Vec::new();
 
-    /// Name of the scene to render. Must be one of spec-spheres or mirror.
-    pub scene: String,
-}
+    for &(base_y, radius, apex_y) in tiers.iter() {
+        let base = positions.len() as u32;
+        positions.push(Vec3 {
+            x: 0.0,
+            y: apex_y,
+            z: 0.0,
+        });
+        positions.push(Vec3 {
+            x: 0.0,
+            y: base_y,
+            z: 0.0,
+        });
 
-fn main() -> Result<(), Box<dyn error::Error + 'static>> {
-    let cli = CliArgs::from_args();
+        for i in 0..segments {
+            let angle = 2.0 * f64::consts::PI * f64::from(i) / f64::from(segments);
+            positions.push(Vec3 {
+                x: radius * angle.cos(),
+                y: base_y,
+                z: -radius * angle.sin(),
+            });
 
-    let BuiltScene(scene, camera_options) = match build_scene(&cli.scene) {
-        Some(scene) => scene,
-        None => {
-            eprintln!(
-                "error: Unknown scene '{}'\n\nFor more information try --help",
-                cli.scene
-            );
-            process::exit(1);
+            let cur = base + 2 + i;
+            let next = base + 2 + (i + 1) % segments;
+            triangles.push([base, cur, next]);
+            triangles.push([base + 1, next, cur]);
         }
-    };
-
-    let opts = RenderOptions {
-        camera_options,
+    }
 
-        width: cli.width,
-        height: cli.height,
+    Mesh::new(positions, triangles)
+}
 
-        max_depth: cli.max_depth,
-        samples_per_pixel: cli.samples_per_pixel,
-        threads: cli.threads,
+fn build_forest_scene() -> path_tracer::Result<BuiltScene> {
+    let ground_center = Vec3 {
+        x: 0.0,
+        y: -200.0,
+        z: -30.0,
     };
+    let ground_radius = 200.0;
 
-    println!(
-        "Rendering {} at {}x{} {}spp with max depth {}",
-        cli.scene, opts.width, opts.height, opts.samples_per_pixel, opts.max_depth
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Sphere::new(ground_center, ground_radius)?,
+            Material::make_diffuse(Vec3 {
+                x: 0.35,
+                y: 0.3,
+                z: 0.2,
+            }),
+        ),
+        Primitive::new(
+            Sphere::new(
+                Vec3 {
+                    x: -20.0,
+                    y: 40.0,
+                    z: -10.0,
+                },
+                10.0,
+            )?,
+            Material::make_light(
+                Vec3 {
+                    x: 1.0,
+                    y: 0.95,
+                    z: 0.85,
+                } * 15.0,
+            ),
+        ),
+    ]);
+
+    let tree = Asset::new(
+        build_tree_mesh()?,
+        Material::make_diffuse(Vec3 {
+            x: 0.05,
+            y: 0.35,
+            z: 0.1,
+        }),
     );
+    let autumn = Material::make_diffuse(Vec3 {
+        x: 0.6,
+        y: 0.3,
+        z: 0.05,
+    });
 
-    let start = Instant::now();
-    let pixels = render(&scene, &opts)?;
-    let elapsed = Instant::now() - start;
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    for _ in 0..100_000 {
+        let x = rng.gen_range(-60.0, 60.0);
+        let z = rng.gen_range(-90.0, -2.0);
 
-    println!("Rendered in {}s", elapsed.as_secs_f64());
+        // Place the tree on the surface of the ground sphere.
+        let dx = x - ground_center.x;
+        let dz = z - ground_center.z;
+        let y = ground_center.y + (ground_radius * ground_radius - dx * dx - dz * dz).sqrt();
 
-    let raw_pixels = img::pixels_to_raw_rgb(pixels.as_ref());
+        let transform = Transform::translate(Vec3 { x, y, z })
+            * Transform::rotate_y(rng.gen_range(0.0, 360.0))
+            * Transform::uniform_scale(rng.gen_range(0.6, 1.6))?;
+        let material = if rng.gen::<f64>() < 0.1 {
+            Some(autumn.clone())
+        } else {
+            None
+        };
 
-    let mut png = BufWriter::new(File::create(&cli.output_filename)?);
-    img::write_png(&mut png, raw_pixels.as_ref(), opts.width, opts.height)?;
-    png.flush()?;
+        scene.add_instance(&tree, transform, material);
+    }
 
-    Ok(())
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: Vec3 {
+                x: 0.0,
+                y: 4.0,
+                z: 8.0,
+            },
+            target: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -20.0,
+            },
+            up: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            vert_fov: 55.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_textures_scene() -> path_tracer::Result<BuiltScene> {
+    let floor_size = 20.0;
+    let floor = Mesh::with_uvs(
+        vec![
+            Vec3 {
+                x: -floor_size,
+                y: 0.0,
+                z: floor_size,
+            },
+            Vec3 {
+                x: floor_size,
+                y: 0.0,
+                z: floor_size,
+            },
+            Vec3 {
+                x: floor_size,
+                y: 0.0,
+                z: -floor_size,
+            },
+            Vec3 {
+                x: -floor_size,
+                y: 0.0,
+                z: -floor_size,
+            },
+        ],
+        vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+        vec![[0, 1, 2], [0, 2, 3]],
+    )?;
+
+    let marble = NoiseTexture::new(
+        Vec3 {
+            x: 0.1,
+            y: 0.1,
+            z: 0.15,
+        },
+        Vec3 {
+            x: 0.9,
+            y: 0.9,
+            z: 0.85,
+        },
+        2.0,
+        6,
+        0.5,
+    );
+    let rust_patches = NoiseTexture::new(
+        Vec3::default(),
+        Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        },
+        1.5,
+        4,
+        0.6,
+    );
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(
+                floor,
+                Material::make_diffuse(Param::texture(Checkerboard {
+                    even: Vec3 {
+                        x: 0.8,
+                        y: 0.8,
+                        z: 0.8,
+                    },
+                    odd: Vec3 {
+                        x: 0.2,
+                        y: 0.2,
+                        z: 0.2,
+                    },
+                    frequency: 20.0,
+                })),
+            ),
+            Primitive::new(
+                Sphere::new(
+                    Vec3 {
+                        x: -2.2,
+                        y: 1.0,
+                        z: -6.0,
+                    },
+                    1.0,
+                )?,
+                Material::make_diffuse(Param::texture(marble)),
+            ),
+            Primitive::new(
+                Sphere::new(
+                    Vec3 {
+                        x: 0.0,
+                        y: 1.0,
+                        z: -6.0,
+                    },
+                    1.0,
+                )?,
+                Material::make_reflective(
+                    Vec3 {
+                        x: 0.8,
+                        y: 0.5,
+                        z: 0.3,
+                    },
+                    Param::texture(rust_patches),
+                    0.95,
+                ),
+            ),
+            Primitive::new(
+                Sphere::new(
+                    Vec3 {
+                        x: 2.2,
+                        y: 1.0,
+                        z: -6.0,
+                    },
+                    1.0,
+                )?,
+                Material::make_diffuse(Param::texture(Gradient {
+                    start: Vec3 {
+                        x: 0.1,
+                        y: 0.1,
+                        z: 0.8,
+                    },
+                    end: Vec3 {
+                        x: 0.9,
+                        y: 0.6,
+                        z: 0.1,
+                    },
+                    axis: GradientAxis::V,
+                }))
+                .with_normal_map(NormalMap::Bump {
+                    height: Arc::new(NoiseTexture::new(
+                        Vec3::default(),
+                        Vec3 {
+                            x: 1.0,
+                            y: 1.0,
+                            z: 1.0,
+                        },
+                        6.0,
+                        3,
+                        0.5,
+                    )),
+                    scale: 0.1,
+                }),
+            ),
+            Primitive::new(
+                Sphere::new(
+                    Vec3 {
+                        x: 0.0,
+                        y: 8.0,
+                        z: -2.0,
+                    },
+                    2.0,
+                )?,
+                Material::make_light(
+                    Vec3 {
+                        x: 1.0,
+                        y: 1.0,
+                        z: 1.0,
+                    } * 12.0,
+                ),
+            ),
+        ]),
+        CameraOptions {
+            pos: Vec3 {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            target: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: -6.0,
+            },
+            up: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            vert_fov: 55.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_shapes_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let matte = |x, y, z| Material::make_diffuse(Vec3 { x, y, z });
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(Plane::new(Vec3::default(), up)?, matte(0.7, 0.7, 0.7)),
+            Primitive::new(
+                Cuboid::new(
+                    Vec3 {
+                        x: -3.6,
+                        y: 0.0,
+                        z: -7.0,
+                    },
+                    Vec3 {
+                        x: -2.4,
+                        y: 1.2,
+                        z: -5.8,
+                    },
+                )?,
+                matte(0.8, 0.2, 0.2),
+            ),
+            Primitive::new(
+                Cylinder::new(
+                    Vec3 {
+                        x: -1.0,
+                        y: 0.0,
+                        z: -6.5,
+                    },
+                    Vec3 {
+                        x: -1.0,
+                        y: 1.5,
+                        z: -6.5,
+                    },
+                    0.6,
+                )?,
+                matte(0.2, 0.8, 0.2),
+            ),
+            Primitive::new(
+                Cone::new(
+                    Vec3 {
+                        x: 0.6,
+                        y: 0.0,
+                        z: -6.5,
+                    },
+                    Vec3 {
+                        x: 0.6,
+                        y: 1.8,
+                        z: -6.5,
+                    },
+                    0.7,
+                )?,
+                matte(0.2, 0.2, 0.8),
+            ),
+            Primitive::new(
+                Torus::new(
+                    Vec3 {
+                        x: 2.6,
+                        y: 0.9,
+                        z: -6.5,
+                    },
+                    Vec3 {
+                        x: 0.0,
+                        y: 1.0,
+                        z: 1.5,
+                    },
+                    0.7,
+                    0.25,
+                )?,
+                Material::make_reflective(
+                    Vec3 {
+                        x: 0.9,
+                        y: 0.7,
+                        z: 0.2,
+                    },
+                    0.6,
+                    0.95,
+                ),
+            ),
+            Primitive::new(
+                Disk::new(
+                    Vec3 {
+                        x: 0.0,
+                        y: 6.0,
+                        z: -5.0,
+                    },
+                    -up,
+                    2.5,
+                )?,
+                Material::make_light(
+                    Vec3 {
+                        x: 1.0,
+                        y: 1.0,
+                        z: 1.0,
+                    } * 8.0,
+                ),
+            ),
+        ]),
+        CameraOptions {
+            pos: Vec3 {
+                x: 0.0,
+                y: 2.5,
+                z: 0.0,
+            },
+            target: Vec3 {
+                x: 0.0,
+                y: 0.8,
+                z: -6.5,
+            },
+            up,
+            vert_fov: 50.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_csg_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let center = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: -5.0,
+    };
+
+    // The classic machined part: the intersection of a cube and a sphere, drilled through
+    // along each axis.
+    let half = 0.8;
+    let body = Csg::intersection(
+        Cuboid::new(
+            center
+                - Vec3 {
+                    x: half,
+                    y: half,
+                    z: half,
+                },
+            center
+                + Vec3 {
+                    x: half,
+                    y: half,
+                    z: half,
+                },
+        )?,
+        Sphere::new(center, 1.05)?,
+    )?;
+    let drill = |axis: usize| {
+        let mut offset = Vec3::default();
+        match axis {
+            0 => offset.x = 1.0,
+            1 => offset.y = 1.0,
+            _ => offset.z = 1.0,
+        }
+        Cylinder::new(center - offset, center + offset, 0.45)
+    };
+    let holes = Csg::union(Csg::union(drill(0)?, drill(1)?)?, drill(2)?)?;
+    let part = Instance::new(
+        Arc::new(Csg::difference(body, holes)?),
+        Transform::translate(center)
+            * Transform::rotate_y(35.0)
+            * Transform::rotate_x(-25.0)
+            * Transform::translate(-center),
+    );
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(
+                Plane::new(
+                    Vec3 {
+                        x: 0.0,
+                        y: -0.2,
+                        z: 0.0,
+                    },
+                    up,
+                )?,
+                Material::make_diffuse(Vec3 {
+                    x: 0.6,
+                    y: 0.6,
+                    z: 0.65,
+                }),
+            ),
+            Primitive::new(
+                part,
+                Material::make_reflective(
+                    Vec3 {
+                        x: 0.8,
+                        y: 0.8,
+                        z: 0.85,
+                    },
+                    0.5,
+                    0.9,
+                ),
+            ),
+            Primitive::new(
+                Disk::new(
+                    Vec3 {
+                        x: -2.0,
+                        y: 5.0,
+                        z: -3.0,
+                    },
+                    Vec3 {
+                        x: 0.4,
+                        y: -1.0,
+                        z: -0.4,
+                    },
+                    2.0,
+                )?,
+                Material::make_light(
+                    Vec3 {
+                        x: 1.0,
+                        y: 1.0,
+                        z: 1.0,
+                    } * 10.0,
+                ),
+            ),
+        ]),
+        CameraOptions {
+            pos: Vec3 {
+                x: 0.0,
+                y: 2.2,
+                z: 0.0,
+            },
+            target: center,
+            up,
+            vert_fov: 40.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_sdf_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    let blob = SmoothUnion::new(
+        SmoothUnion::new(
+            sdf::Sphere::new(vec3(-2.4, 0.6, -6.0), 0.6)?,
+            sdf::Sphere::new(vec3(-2.0, 1.3, -6.2), 0.45)?,
+            0.4,
+        )?,
+        sdf::Capsule::new(vec3(-2.9, 0.3, -5.8), vec3(-1.7, 0.3, -5.6), 0.25)?,
+        0.3,
+    )?;
+
+    // Twisted boxes are built around the y axis and then moved into place.
+    let twisted = Instance::new(
+        Arc::new(Sdf::new(Twist::new(
+            SmoothSubtraction::new(
+                sdf::RoundBox::new(vec3(0.0, 0.8, 0.0), vec3(0.4, 0.8, 0.4), 0.05)?,
+                sdf::Torus::new(vec3(0.0, 0.8, 0.0), 0.45, 0.12)?,
+                0.05,
+            )?,
+            60.0,
+        )?)),
+        Transform::translate(vec3(2.2, 0.0, -6.0)),
+    );
+
+    let bulb = Instance::new(
+        Arc::new(Sdf::with_settings(Mandelbulb::new(8.0, 12)?, 512, 1e-4)?),
+        Transform::translate(vec3(0.0, 1.0, -6.5))
+            * Transform::uniform_scale(0.9)?
+            * Transform::rotate_x(-90.0),
+    );
+
+    let pillars = Sdf::new(Repeat::new(
+        sdf::Capsule::new(vec3(0.0, 0.0, -10.0), vec3(0.0, 2.5, -10.0), 0.2)?,
+        vec3(1.5, 0.0, 0.0),
+        [7, 1, 1],
+    )?);
+
+    let matte = |x, y, z| Material::make_diffuse(vec3(x, y, z));
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(Plane::new(Vec3::default(), up)?, matte(0.7, 0.7, 0.7)),
+            Primitive::new(Sdf::new(blob), matte(0.8, 0.3, 0.5)),
+            Primitive::new(twisted, matte(0.3, 0.6, 0.8)),
+            Primitive::new(
+                bulb,
+                Material::make_reflective(vec3(0.9, 0.75, 0.4), 0.3, 0.9),
+            ),
+            Primitive::new(pillars, matte(0.8, 0.8, 0.7)),
+            Primitive::new(
+                Disk::new(vec3(0.0, 6.0, -4.0), -up, 2.5)?,
+                Material::make_light(vec3(1.0, 1.0, 1.0) * 8.0),
+            ),
+        ]),
+        CameraOptions {
+            pos: vec3(0.0, 2.5, 0.0),
+            target: vec3(0.0, 0.8, -6.5),
+            up,
+            vert_fov: 45.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_terrain_scene() -> path_tracer::Result<BuiltScene> {
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    // Ridged noise gives sharper, more mountainous peaks than plain fractal noise.
+    let perlin = Perlin::new(7);
+    let resolution = 512;
+    let mut heights = Vec::with_capacity(resolution * resolution);
+    for row in 0..resolution {
+        for col in 0..resolution {
+            let p = vec3(col as f64, 0.0, row as f64) * (4.0 / resolution as f64);
+            let ridge = 1.0 - perlin.fbm(p, 6, 0.5).abs() * 2.0;
+            heights.push(ridge.max(0.0).powi(2));
+        }
+    }
+    let terrain = Heightfield::new(resolution, resolution, heights, vec3(20.0, 3.0, 20.0))?;
+
+    let ground = NoiseTexture::new(vec3(0.25, 0.35, 0.15), vec3(0.55, 0.45, 0.3), 3.0, 4, 0.5);
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(
+                Instance::new(
+                    Arc::new(terrain),
+                    Transform::translate(vec3(-10.0, 0.0, -22.0)),
+                ),
+                Material::make_diffuse(Param::texture(ground)),
+            ),
+            Primitive::new(
+                Disk::new(vec3(-8.0, 20.0, 0.0), vec3(0.4, -1.0, -0.6), 8.0)?,
+                Material::make_light(vec3(1.0, 0.95, 0.85) * 6.0),
+            ),
+        ]),
+        CameraOptions {
+            pos: vec3(0.0, 5.0, 0.0),
+            target: vec3(0.0, 1.0, -12.0),
+            up: vec3(0.0, 1.0, 0.0),
+            vert_fov: 50.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_fog_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    let fog = HomogeneousMedium::new(
+        vec3(0.005, 0.005, 0.005),
+        vec3(0.04, 0.04, 0.045),
+        HenyeyGreenstein::new(0.6)?,
+    )?;
+    let smoke = HomogeneousMedium::new(
+        vec3(0.3, 0.3, 0.3),
+        vec3(1.5, 1.2, 0.9),
+        HenyeyGreenstein::new(0.2)?,
+    )?;
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(
+                Plane::new(Vec3::default(), up)?,
+                Material::make_diffuse(vec3(0.7, 0.7, 0.7)),
+            ),
+            Primitive::new(
+                Sphere::new(vec3(-1.6, 0.8, -6.0), 0.8)?,
+                Material::make_diffuse(vec3(0.8, 0.3, 0.2)),
+            ),
+            Primitive::new(
+                Sphere::new(vec3(1.6, 0.8, -6.0), 0.8)?,
+                Material::make_reflective(vec3(0.9, 0.9, 0.9), 0.9, 0.95),
+            ),
+            // A column of smoke, bounded by an invisible cylinder
+            Primitive::new(
+                Cylinder::new(vec3(0.0, 0.0, -7.0), vec3(0.0, 3.0, -7.0), 0.7)?,
+                Material::make_interface(),
+            )
+            .with_interior(Arc::new(smoke)),
+            Primitive::new(
+                Disk::new(vec3(0.0, 5.0, -6.0), -up, 1.5)?,
+                Material::make_light(vec3(1.0, 0.95, 0.9) * 15.0),
+            ),
+        ])
+        .with_medium(Arc::new(fog)),
+        CameraOptions {
+            pos: vec3(0.0, 1.5, 0.0),
+            target: vec3(0.0, 1.2, -6.0),
+            up,
+            vert_fov: 45.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_smoke_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    // A rising plume: a noisy column that widens and thins out with height. The box holding it
+    // sits just above the floor, so that the two surfaces never coincide.
+    let perlin = Perlin::new(3);
+    let dims = [48, 96, 48];
+    let mut density = Vec::with_capacity(dims[0] * dims[1] * dims[2]);
+    for z in 0..dims[2] {
+        for y in 0..dims[1] {
+            for x in 0..dims[0] {
+                let p = vec3(
+                    x as f64 / dims[0] as f64 - 0.5,
+                    y as f64 / dims[1] as f64,
+                    z as f64 / dims[2] as f64 - 0.5,
+                );
+                let radius = 0.12 + 0.25 * p.y;
+                let swirl = perlin.fbm(p * 6.0, 4, 0.5);
+                let dist = (p.x * p.x + p.z * p.z).sqrt() / radius + swirl;
+                let value = (1.0 - dist).max(0.0) * (1.0 - p.y);
+                density.push(value as f32);
+            }
+        }
+    }
+
+    let bounds = Aabb {
+        min: vec3(-1.0, 0.01, -7.0),
+        max: vec3(1.0, 4.0, -5.0),
+    };
+    let smoke = GridMedium::new(
+        dims,
+        density,
+        bounds,
+        8.0,
+        vec3(0.9, 0.9, 0.9),
+        HenyeyGreenstein::new(0.3)?,
+    )?;
+
+    Ok(BuiltScene(
+        Scene::with_primitives(vec![
+            Primitive::new(
+                Plane::new(Vec3::default(), up)?,
+                Material::make_diffuse(vec3(0.6, 0.6, 0.6)),
+            ),
+            Primitive::new(
+                Cuboid::new(bounds.min, bounds.max)?,
+                Material::make_interface(),
+            )
+            .with_interior(Arc::new(smoke)),
+            Primitive::new(
+                Disk::new(vec3(-3.0, 6.0, -4.0), vec3(0.5, -1.0, -0.3), 2.0)?,
+                Material::make_light(vec3(1.0, 0.9, 0.8) * 12.0),
+            ),
+            Primitive::new(
+                Disk::new(vec3(4.0, 2.0, -8.0), vec3(-1.0, 0.0, 0.5), 1.0)?,
+                Material::make_light(vec3(0.3, 0.5, 1.0) * 10.0),
+            ),
+        ]),
+        CameraOptions {
+            pos: vec3(0.0, 2.0, 0.0),
+            target: vec3(0.0, 1.8, -6.0),
+            up,
+            vert_fov: 45.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_prism_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    // An upright, shallow triangular prism with its apex pointing away from the camera, cut
+    // from a slab by three half-spaces
+    let base_y = 0.4;
+    let prism = Csg::intersection(
+        Csg::intersection(
+            Plane::new(vec3(0.0, 0.0, -5.0), vec3(0.0, 0.0, 1.0))?,
+            Plane::new(vec3(0.7, 0.0, -5.0), vec3(0.35, 0.0, -0.7))?,
+        )?,
+        Csg::intersection(
+            Plane::new(vec3(-0.7, 0.0, -5.0), vec3(-0.35, 0.0, -0.7))?,
+            Cuboid::new(vec3(-1.0, base_y, -6.5), vec3(1.0, base_y + 1.5, -4.5))?,
+        )?,
+    )?;
+
+    let mut primitives = vec![
+        Primitive::new(
+            Plane::new(Vec3::default(), up)?,
+            Material::make_diffuse(Param::texture(Checkerboard {
+                even: vec3(0.7, 0.7, 0.7),
+                odd: vec3(0.3, 0.3, 0.3),
+                frequency: 1.0,
+            })),
+        ),
+        Primitive::new(
+            Cuboid::new(vec3(-0.9, 0.0, -6.3), vec3(0.9, base_y, -4.9))?,
+            Material::make_diffuse(vec3(0.2, 0.2, 0.2)),
+        ),
+        Primitive::new(
+            prism,
+            Material::make_dielectric(vec3(1.0, 1.0, 1.0), Ior::SF11),
+        ),
+        Primitive::new(
+            Sphere::new(vec3(2.2, 0.7, -5.5), 0.7)?,
+            Material::make_dielectric(vec3(1.0, 1.0, 1.0), Ior::BK7),
+        ),
+        Primitive::new(
+            Disk::new(vec3(0.0, 6.0, -4.0), -up, 2.0)?,
+            Material::make_light(vec3(1.0, 1.0, 1.0) * 4.0),
+        ),
+    ];
+
+    // Thin white bars behind the prism, whose images are split into spectra
+    for bar in 0..7 {
+        let x = -3.0 + bar as f64;
+        primitives.push(Primitive::new(
+            Cuboid::new(vec3(x - 0.04, 0.0, -9.0), vec3(x + 0.04, 3.0, -8.9))?,
+            Material::make_light(vec3(1.0, 1.0, 1.0) * 6.0),
+        ));
+    }
+
+    Ok(BuiltScene(
+        Scene::with_primitives(primitives),
+        CameraOptions {
+            pos: vec3(0.0, 1.1, 0.0),
+            target: vec3(0.0, 1.0, -5.5),
+            up,
+            vert_fov: 40.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_lights_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Plane::new(Vec3::default(), up)?,
+            Material::make_diffuse(vec3(0.75, 0.75, 0.75)),
+        ),
+        Primitive::new(
+            Plane::new(vec3(0.0, 0.0, -10.0), vec3(0.0, 0.0, 1.0))?,
+            Material::make_diffuse(vec3(0.6, 0.6, 0.65)),
+        ),
+        Primitive::new(
+            Sphere::new(vec3(-2.0, 0.8, -6.0), 0.8)?,
+            Material::make_diffuse(vec3(0.8, 0.25, 0.2)),
+        ),
+        Primitive::new(
+            Cuboid::new(vec3(-0.6, 0.0, -7.1), vec3(0.6, 1.2, -5.9))?,
+            Material::make_diffuse(vec3(0.3, 0.7, 0.3)),
+        ),
+        Primitive::new(
+            Sphere::new(vec3(2.0, 0.8, -6.0), 0.8)?,
+            Material::make_reflective(vec3(0.2, 0.3, 0.8), 0.4, 0.9),
+        ),
+    ]);
+
+    // A warm bulb on the left, a spot light picking out the box and a low sun from the right
+    scene.add_light(PointLight::new(
+        vec3(-2.5, 2.5, -4.5),
+        vec3(1.0, 0.8, 0.6) * 6.0,
+    )?);
+    scene.add_light(SpotLight::new(
+        vec3(0.0, 5.0, -4.0),
+        vec3(0.0, 0.0, -6.5),
+        vec3(0.6, 0.8, 1.0) * 40.0,
+        20.0,
+        12.0,
+    )?);
+    scene.add_light(DirectionalLight::new(
+        vec3(-1.0, -0.6, -0.5),
+        vec3(1.0, 0.95, 0.85) * 0.6,
+    )?);
+
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: vec3(0.0, 2.0, 0.0),
+            target: vec3(0.0, 0.8, -6.0),
+            up,
+            vert_fov: 45.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_studio_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Plane::new(Vec3::default(), up)?,
+            Material::make_diffuse(vec3(0.8, 0.8, 0.8)),
+        ),
+        Primitive::new(
+            Plane::new(vec3(0.0, 0.0, -9.0), vec3(0.0, 0.0, 1.0))?,
+            Material::make_diffuse(vec3(0.7, 0.7, 0.75)),
+        ),
+        Primitive::new(
+            Sphere::new(vec3(-1.2, 0.9, -6.0), 0.9)?,
+            Material::make_reflective(vec3(0.8, 0.2, 0.15), 0.3, 0.95),
+        ),
+        Primitive::new(
+            Torus::new(vec3(1.3, 0.3, -5.5), up, 0.8, 0.3)?,
+            Material::make_reflective(vec3(0.9, 0.9, 0.9), 0.9, 0.9),
+        ),
+    ]);
+
+    // A large key softbox to the left, angled down at the subjects, and a dimmer fill on the
+    // right. Both only emit from their fronts.
+    scene.add_area_light(QuadLight::new(
+        vec3(-4.5, 1.0, -3.5),
+        vec3(1.2, 0.0, -1.6),
+        vec3(-0.6, 2.2, -0.45),
+        vec3(1.0, 0.97, 0.9) * 15.0,
+        false,
+    )?);
+    scene.add_area_light(QuadLight::new(
+        vec3(3.5, 0.5, -6.5),
+        vec3(0.0, 0.0, 2.0),
+        vec3(0.0, 2.0, 0.0),
+        vec3(0.85, 0.9, 1.0) * 2.0,
+        false,
+    )?);
+
+    // An overhead ring light shining downwards
+    let segments = 24;
+    let (inner, outer, height) = (1.4, 1.7, 4.0);
+    let mut positions = Vec::new();
+    let mut triangles = Vec::new();
+    for i in 0..segments {
+        let angle = i as f64 / segments as f64 * 2.0 * f64::consts::PI;
+        let (sin, cos) = angle.sin_cos();
+        positions.push(vec3(inner * cos, height, -5.8 + inner * sin));
+        positions.push(vec3(outer * cos, height, -5.8 + outer * sin));
+
+        let (a, b) = (2 * i, 2 * i + 1);
+        let (c, d) = (2 * ((i + 1) % segments), 2 * ((i + 1) % segments) + 1);
+        triangles.push([a, b, c]);
+        triangles.push([b, d, c]);
+    }
+    scene.add_area_light(MeshLight::new(
+        Mesh::new(positions, triangles)?,
+        vec3(1.0, 1.0, 1.0) * 5.0,
+        false,
+    )?);
+
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: vec3(0.0, 1.8, 0.0),
+            target: vec3(0.0, 0.8, -6.0),
+            up,
+            vert_fov: 45.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_lamps_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+    let wall = Material::make_diffuse(vec3(0.75, 0.72, 0.68));
+
+    // A closed room, so that all of the light bounces around inside it
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Plane::new(Vec3::default(), up)?,
+            Material::make_diffuse(vec3(0.5, 0.35, 0.25)),
+        ),
+        Primitive::new(Plane::new(vec3(0.0, 3.0, 0.0), -up)?, wall.clone()),
+        Primitive::new(
+            Plane::new(vec3(-3.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0))?,
+            wall.clone(),
+        ),
+        Primitive::new(
+            Plane::new(vec3(3.0, 0.0, 0.0), vec3(-1.0, 0.0, 0.0))?,
+            wall.clone(),
+        ),
+        Primitive::new(
+            Plane::new(vec3(0.0, 0.0, -7.0), vec3(0.0, 0.0, 1.0))?,
+            wall.clone(),
+        ),
+        Primitive::new(Plane::new(vec3(0.0, 0.0, 1.0), vec3(0.0, 0.0, -1.0))?, wall),
+        Primitive::new(
+            Sphere::new(vec3(-0.8, 0.6, -4.5), 0.6)?,
+            Material::make_reflective(vec3(0.2, 0.4, 0.8), 0.4, 0.9),
+        ),
+        Primitive::new(
+            Cuboid::new(vec3(0.3, 0.0, -5.2), vec3(1.3, 0.9, -4.2))?,
+            Material::make_diffuse(vec3(0.8, 0.8, 0.8)),
+        ),
+    ]);
+
+    // Two uplighters, whose bulbs are hidden inside tall shades so that they only light the
+    // room off the ceiling
+    let shade = Material::make_diffuse(vec3(0.2, 0.2, 0.2));
+    for &(x, z) in &[(-2.2, -6.2), (2.2, -6.2)] {
+        let (half, bottom, top) = (0.25, 1.4, 2.2);
+        let corner = |dx, dz| vec3(x + dx * half, bottom, z + dz * half);
+        let height = vec3(0.0, top - bottom, 0.0);
+        for &((ax, az), (bx, bz)) in &[
+            ((-1.0, -1.0), (1.0, -1.0)),
+            ((1.0, -1.0), (1.0, 1.0)),
+            ((1.0, 1.0), (-1.0, 1.0)),
+            ((-1.0, 1.0), (-1.0, -1.0)),
+        ] {
+            let side = corner(bx, bz) - corner(ax, az);
+            scene.add_primitive(Primitive::new(
+                Quad::new(corner(ax, az), side, height)?,
+                shade.clone(),
+            ));
+        }
+        scene.add_primitive(Primitive::new(
+            Cylinder::new(vec3(x, 0.0, z), vec3(x, bottom, z), 0.04)?,
+            shade.clone(),
+        ));
+        scene.add_area_light(QuadLight::new(
+            corner(-0.9, 0.9),
+            vec3(1.8 * half, 0.0, 0.0),
+            vec3(0.0, 0.0, -1.8 * half),
+            vec3(1.0, 0.85, 0.6) * 250.0,
+            false,
+        )?);
+    }
+
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: vec3(0.0, 1.3, 0.5),
+            target: vec3(0.0, 1.0, -5.0),
+            up,
+            vert_fov: 60.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_city_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    let mut scene = Scene::with_primitives(vec![Primitive::new(
+        Plane::new(Vec3::default(), up)?,
+        Material::make_diffuse(vec3(0.3, 0.3, 0.32)),
+    )]);
+    scene.add_light(DirectionalLight::new(
+        vec3(0.3, -1.0, 0.4),
+        vec3(0.6, 0.7, 1.0) * 0.02,
+    )?);
+
+    // A grid of tower blocks, each lit by thousands of small one-sided windows and separated by
+    // streets lined with lamps
+    let mut rng = StdRng::seed_from_u64(0xc17);
+    let (block, street) = (4.0, 2.0);
+    let (window, floor_height) = (0.3, 0.6);
+    let warm = [
+        vec3(1.0, 0.8, 0.5),
+        vec3(1.0, 0.9, 0.7),
+        vec3(0.8, 0.9, 1.0),
+    ];
+    for i in -3..=3 {
+        for j in 0..6 {
+            let (x0, z0) = (
+                i as f64 * (block + street),
+                -8.0 - j as f64 * (block + street),
+            );
+            let floors = rng.gen_range(4, 16);
+            let height = floors as f64 * floor_height;
+            scene.add_primitive(Primitive::new(
+                Cuboid::new(vec3(x0, 0.0, z0 - block), vec3(x0 + block, height, z0))?,
+                Material::make_diffuse(vec3(0.2, 0.2, 0.22)),
+            ));
+
+            // Each face is given by a corner and the directions along and out of it.
+            let faces = [
+                (vec3(x0, 0.0, z0), vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)),
+                (
+                    vec3(x0 + block, 0.0, z0 - block),
+                    vec3(-1.0, 0.0, 0.0),
+                    vec3(0.0, 0.0, -1.0),
+                ),
+                (
+                    vec3(x0 + block, 0.0, z0),
+                    vec3(0.0, 0.0, -1.0),
+                    vec3(1.0, 0.0, 0.0),
+                ),
+                (
+                    vec3(x0, 0.0, z0 - block),
+                    vec3(0.0, 0.0, 1.0),
+                    vec3(-1.0, 0.0, 0.0),
+                ),
+            ];
+            for &(corner, along, out) in faces.iter() {
+                for floor in 0..floors {
+                    for column in 0..6 {
+                        if rng.gen::<f64>() > 0.35 {
+                            continue;
+                        }
+                        let offset = (column as f64 + 0.5) * block / 6.0 - window / 2.0;
+                        let window_corner = corner
+                            + offset * along
+                            + (floor as f64 * floor_height + 0.2) * up
+                            + 0.01 * out;
+                        let color = warm[rng.gen_range(0, warm.len())] * rng.gen_range(2.0, 6.0);
+                        scene.add_primitive(Primitive::new(
+                            Quad::new(window_corner, window * along, window * up)?,
+                            Material {
+                                two_sided: false,
+                                ..Material::make_light(color)
+                            },
+                        ));
+                    }
+                }
+            }
+
+            // A lamp at the corner of the block
+            scene.add_primitive(Primitive::new(
+                Sphere::new(vec3(x0 - 0.5, 1.5, z0 + 0.5), 0.1)?,
+                Material::make_light(vec3(1.0, 0.7, 0.4) * 60.0),
+            ));
+        }
+    }
+
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: vec3(-3.0, 9.0, 6.0),
+            target: vec3(0.0, 1.0, -18.0),
+            up,
+            vert_fov: 50.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_motion_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Plane::new(Vec3::default(), up)?,
+            Material::make_diffuse(Param::texture(Checkerboard {
+                even: vec3(0.7, 0.7, 0.7),
+                odd: vec3(0.3, 0.3, 0.3),
+                frequency: 1.0,
+            })),
+        ),
+        Primitive::new(
+            Sphere::new(vec3(0.0, 6.0, -3.0), 1.0)?,
+            Material::make_light(vec3(1.0, 0.95, 0.9) * 30.0),
+        ),
+        // A sphere standing still, to compare the others against
+        Primitive::new(
+            Sphere::new(vec3(0.0, 0.5, -6.0), 0.5)?,
+            Material::make_diffuse(vec3(0.8, 0.8, 0.8)),
+        ),
+    ]);
+
+    // A ball rolling quickly across the floor from left to right
+    let ball: Arc<dyn Geom> = Arc::new(Sphere::new(Vec3::default(), 0.5)?);
+    let rolling = AnimatedTransform::new(
+        Transform::identity(),
+        &[
+            Keyframe {
+                time: 0.0,
+                translation: vec3(-2.6, 0.5, -5.0),
+                rotation: (vec3(0.0, 0.0, 1.0).to_unit(), 0.0),
+            },
+            Keyframe {
+                time: 1.0,
+                translation: vec3(-1.55, 0.5, -5.0),
+                rotation: (vec3(0.0, 0.0, 1.0).to_unit(), -120.0),
+            },
+        ],
+    )?;
+    scene.add_primitive(Primitive::new(
+        Instance::animated(ball, rolling),
+        Material::make_reflective(vec3(0.8, 0.2, 0.2), 0.3, 0.8),
+    ));
+
+    // A box spinning half a turn about its vertical axis. Each keyframe is reached by the
+    // shorter way round from the last, so the turn needs one halfway.
+    let cube: Arc<dyn Geom> = Arc::new(Cuboid::new(vec3(-0.4, -0.4, -0.4), vec3(0.4, 0.4, 0.4))?);
+    let spin = |time, degrees| Keyframe {
+        time,
+        translation: vec3(1.8, 0.4, -5.0),
+        rotation: (up.to_unit(), degrees),
+    };
+    let spinning = AnimatedTransform::new(
+        Transform::identity(),
+        &[spin(0.0, 0.0), spin(0.5, 90.0), spin(1.0, 180.0)],
+    )?;
+    scene.add_primitive(Primitive::new(
+        Instance::animated(cube, spinning),
+        Material::make_diffuse(vec3(0.2, 0.4, 0.8)),
+    ));
+
+    // A ball bouncing straight up off the floor behind the others
+    let bouncing = AnimatedTransform::new(
+        Transform::identity(),
+        &[
+            Keyframe::translate(0.0, vec3(0.0, 0.3, -8.0)),
+            Keyframe::translate(0.5, vec3(0.0, 1.5, -8.0)),
+            Keyframe::translate(1.0, vec3(0.0, 1.8, -8.0)),
+        ],
+    )?;
+    scene.add_primitive(Primitive::new(
+        Instance::animated(Arc::new(Sphere::new(Vec3::default(), 0.3)?), bouncing),
+        Material::make_diffuse(vec3(0.9, 0.7, 0.2)),
+    ));
+
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: vec3(0.0, 1.5, 0.0),
+            target: vec3(0.0, 0.6, -6.0),
+            up,
+            vert_fov: 45.0,
+            motion: None,
+            animation: None,
+        },
+    ))
+}
+
+fn build_turntable_scene() -> path_tracer::Result<BuiltScene> {
+    let up = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let vec3 = |x, y, z| Vec3 { x, y, z };
+    let center = vec3(0.0, 0.0, -5.0);
+
+    let mut scene = Scene::with_primitives(vec![
+        Primitive::new(
+            Plane::new(Vec3::default(), up)?,
+            Material::make_diffuse(Param::texture(Checkerboard {
+                even: vec3(0.7, 0.7, 0.7),
+                odd: vec3(0.3, 0.3, 0.3),
+                frequency: 1.0,
+            })),
+        ),
+        Primitive::new(
+            Cylinder::new(center, center + vec3(0.0, 0.4, 0.0), 1.2)?,
+            Material::make_diffuse(vec3(0.2, 0.2, 0.2)),
+        ),
+        Primitive::new(
+            Torus::new(center + vec3(0.0, 0.9, 0.0), vec3(0.3, 1.0, 0.2), 0.5, 0.18)?,
+            Material::make_reflective(vec3(0.9, 0.6, 0.2), 0.6, 0.95),
+        ),
+    ]);
+
+    // A ball bobbing above the pedestal, easing into the top and bottom of each bounce
+    let bob = |time, height| Keyframe::translate(time, center + vec3(0.0, height, 0.0));
+    let bobbing = AnimatedTransform::new(
+        Transform::identity(),
+        &[
+            bob(0.0, 1.9),
+            bob(12.0, 2.4),
+            bob(24.0, 1.9),
+            bob(36.0, 2.4),
+            bob(48.0, 1.9),
+        ],
+    )?
+    .with_interpolation(Interpolation::Smooth);
+    scene.add_primitive(Primitive::new(
+        Instance::animated(Arc::new(Sphere::new(Vec3::default(), 0.25)?), bobbing),
+        Material::make_diffuse(vec3(0.2, 0.4, 0.8)),
+    ));
+
+    // A key light that dims halfway through the turn, and a steady fill light
+    scene.add_light(PointLight::new(
+        vec3(-2.0, 4.0, -3.0),
+        vec3(1.0, 0.9, 0.8) * 60.0,
+    )?);
+    scene.animate_light(
+        0,
+        Track::new(
+            &[(0.0, 1.0), (24.0, 0.3), (48.0, 1.0)],
+            Interpolation::Smooth,
+        )?,
+    )?;
+    scene.add_light(PointLight::new(
+        vec3(3.0, 2.0, -2.0),
+        vec3(0.6, 0.7, 1.0) * 20.0,
+    )?);
+
+    // The camera circles the pedestal once over 48 frames, zooming in as it goes round.
+    let orbit = |frame: f64| Keyframe {
+        time: frame,
+        translation: center,
+        rotation: (up.to_unit(), 7.5 * frame),
+    };
+    let orbit = AnimatedTransform::new(
+        Transform::translate(-center),
+        &[
+            orbit(0.0),
+            orbit(12.0),
+            orbit(24.0),
+            orbit(36.0),
+            orbit(48.0),
+        ],
+    )?;
+    let zoom = Track::new(
+        &[(0.0, 50.0), (24.0, 30.0), (48.0, 50.0)],
+        Interpolation::Smooth,
+    )?;
+
+    Ok(BuiltScene(
+        scene,
+        CameraOptions {
+            pos: vec3(0.0, 2.0, 0.0),
+            target: center + vec3(0.0, 1.0, 0.0),
+            up,
+            vert_fov: 50.0,
+            motion: Some(orbit),
+            animation: Some(CameraAnimation {
+                vert_fov: Some(zoom),
+                ..CameraAnimation::default()
+            }),
+        },
+    ))
+}
+
+fn build_scene(name: &str) -> Option<path_tracer::Result<BuiltScene>> {
+    match name {
+        "spec-spheres" => Some(build_spec_spheres_scene()),
+        "mirror" => Some(build_mirror_scene()),
+        "instances" => Some(build_instances_scene()),
+        "forest" => Some(build_forest_scene()),
+        "textures" => Some(build_textures_scene()),
+        "shapes" => Some(build_shapes_scene()),
+        "csg" => Some(build_csg_scene()),
+        "sdf" => Some(build_sdf_scene()),
+        "terrain" => Some(build_terrain_scene()),
+        "fog" => Some(build_fog_scene()),
+        "smoke" => Some(build_smoke_scene()),
+        "prism" => Some(build_prism_scene()),
+        "lights" => Some(build_lights_scene()),
+        "studio" => Some(build_studio_scene()),
+        "city" => Some(build_city_scene()),
+        "lamps" => Some(build_lamps_scene()),
+        "motion" => Some(build_motion_scene()),
+        "turntable" => Some(build_turntable_scene()),
+        _ => None,
+    }
+}
+
+fn parse_integrator(name: &str) -> Result<Integrator, String> {
+    match name {
+        "path" => Ok(Integrator::Path),
+        "guided" => Ok(Integrator::Guided),
+        "bdpt" => Ok(Integrator::Bidirectional),
+        // The options of these are filled in from their own arguments.
+        "sppm" => Ok(Integrator::PhotonMapping {
+            photons: 0,
+            radius: 0.0,
+        }),
+        "mlt" => Ok(Integrator::Metropolis {
+            bootstrap: 0,
+            chains: 0,
+            large_step: 0.0,
+        }),
+        "debug" => Ok(Integrator::Debug(DebugView::Normal)),
+        _ => Err(format!("unknown integrator '{}'", name)),
+    }
+}
+
+fn parse_debug_view(name: &str) -> Result<DebugView, String> {
+    DebugView::from_name(name).ok_or_else(|| format!("unknown debug view '{}'", name))
+}
+
+/// Parses a frame number or an inclusive range of them, such as `1-48`.
+fn parse_frames(frames: &str) -> Result<(u32, u32), String> {
+    let parse = |number: &str| {
+        number
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| format!("invalid frame number '{}'", number))
+    };
+    let (first, last) = match frames.split_once('-') {
+        Some((first, last)) => (parse(first)?, parse(last)?),
+        None => (parse(frames)?, parse(frames)?),
+    };
+    if last < first {
+        return Err(format!("frame range '{}' ends before it starts", frames));
+    }
+    Ok((first, last))
+}
+
+fn parse_aov(name: &str) -> Result<Aov, String> {
+    Aov::from_name(name).ok_or_else(|| {
+        let names: Vec<_> = Aov::ALL.iter().map(|aov| aov.name()).collect();
+        format!(
+            "unknown output '{}', expected one of {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+#[derive(StructOpt)]
+struct CliArgs {
+    /// Width of rendered image, in pixels
+    #[structopt(long, short)]
+    pub width: u32,
+
+    /// Height of rendered image, in pixels
+    #[structopt(long, short)]
+    pub height: u32,
+
+    /// Maximum bounce depth
+    #[structopt(long, default_value = "5")]
+    pub max_depth: u32,
+
+    /// Number of samples to gather per pixel
+    #[structopt(long = "spp")]
+    pub samples_per_pixel: u32,
+
+    /// Number of threads to use when rendering in parallel.
+    /// If this argument is 0, the number of cores will be used.
+    #[structopt(short = "j", default_value = "0")]
+    pub threads: u32,
+
+    /// Trace individual wavelengths of light rather than RGB, to render dispersion
+    #[structopt(long)]
+    pub spectral: bool,
+
+    /// Rendering algorithm to use: path for path tracing, guided for path tracing guided by
+    /// learned light distributions, bdpt for bidirectional path tracing, sppm for stochastic
+    /// progressive photon mapping, mlt for Metropolis light transport, or debug to view the
+    /// scene's geometry instead of its lighting. bdpt and sppm ignore participating media
+    #[structopt(long, default_value = "path", parse(try_from_str = parse_integrator))]
+    pub integrator: Integrator,
+
+    /// Number of photons to trace per sample when photon mapping
+    #[structopt(long, default_value = "200000")]
+    pub photons: u32,
+
+    /// Initial radius photons are gathered within when photon mapping, in scene units
+    #[structopt(long, default_value = "0.05")]
+    pub gather_radius: f64,
+
+    /// Number of paths traced to estimate the image's brightness with Metropolis light
+    /// transport
+    #[structopt(long, default_value = "100000")]
+    pub bootstrap: u32,
+
+    /// Number of Markov chains to run with Metropolis light transport
+    #[structopt(long, default_value = "1000")]
+    pub chains: u32,
+
+    /// Probability of replacing a path outright rather than perturbing it with Metropolis
+    /// light transport
+    #[structopt(long, default_value = "0.3")]
+    pub large_step: f64,
+
+    /// View of the scene's geometry to render with the debug integrator: normal, depth, uv,
+    /// prim-id, bvh for a heatmap of BVH nodes visited, or ao for ambient occlusion
+    #[structopt(long, default_value = "normal", parse(try_from_str = parse_debug_view))]
+    pub debug_view: DebugView,
+
+    /// Remove noise from the image with a filter guided by the albedo, normals and depth of
+    /// the surfaces seen, also writing the noisy image with .noisy.png in place of the
+    /// output's extension. Only the path and guided integrators support denoising
+    #[structopt(long)]
+    pub denoise: bool,
+
+    /// How strongly to denoise, where larger values blend pixels that differ more in
+    /// brightness
+    #[structopt(long, default_value = "1.0")]
+    pub denoise_strength: f64,
+
+    /// File to write the render's time and ray statistics to, as JSON
+    #[structopt(long)]
+    pub stats_json: Option<String>,
+
+    /// Time the shutter opens, for blurring anything that moves while it's open
+    #[structopt(long, default_value = "0.0")]
+    pub shutter_open: f64,
+
+    /// Time the shutter closes
+    #[structopt(long, default_value = "1.0")]
+    pub shutter_close: f64,
+
+    /// Frame to render, or an inclusive range of frames such as 1-48 to render one after
+    /// another from the same scene. Frame n is exposed from time n plus the shutter open time
+    /// to n plus the shutter close time, so animations are timed in frames
+    #[structopt(long, parse(try_from_str = parse_frames))]
+    pub frames: Option<(u32, u32)>,
+
+    /// Output filename. When rendering frames, a pattern such as %04d in it is replaced by the
+    /// frame number, which is otherwise added before the extension as in render_0001.png. The
+    /// statistics file is named the same way
+    #[structopt(short, default_value = "render.png")]
+    pub output_filename: String,
+
+    /// Auxiliary output to write alongside the image, as a PFM file named after the output
+    /// with the output's name added before the extension. May be repeated. Must be one of
+    /// albedo, normal, depth, prim-id, material-id, direct, indirect or emission. Only the path
+    /// and guided integrators produce auxiliary outputs
+    #[structopt(long = "aov", number_of_values = 1, parse(try_from_str = parse_aov))]
+    pub aovs: Vec<Aov>,
+
+    /// Name of the scene to render. Must be one of spec-spheres, mirror, instances, forest,
+    /// textures, shapes, csg, sdf, terrain, fog, smoke, prism, lights, studio, city, lamps,
+    /// motion or turntable.
+    pub scene: String,
+}
+
+/// Returns the path of a file written alongside `output`, named after it with `suffix` in place
+/// of its extension.
+fn sibling_path(output: &Path, suffix: &str) -> PathBuf {
+    let mut name = output.file_stem().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(suffix);
+    output.with_file_name(name)
+}
+
+/// Returns the path of the file that `frame` is written to, given an output path that may have
+/// a printf-style pattern such as `%04d` where the frame number goes.
+fn frame_path(output: &str, frame: u32) -> PathBuf {
+    if let Some(start) = output.find('%') {
+        let rest = &output[start + 1..];
+        if let Some(end) = rest.find(|c: char| !c.is_ascii_digit()) {
+            if rest[end..].starts_with('d') {
+                let width = rest[..end].parse().unwrap_or(0);
+                let number = if rest.starts_with('0') {
+                    format!("{:01$}", frame, width)
+                } else {
+                    format!("{:1$}", frame, width)
+                };
+                return PathBuf::from(format!(
+                    "{}{}{}",
+                    &output[..start],
+                    number,
+                    &rest[end + 1..]
+                ));
+            }
+        }
+    }
+
+    let output = Path::new(output);
+    let mut name = output.file_stem().unwrap_or_default().to_owned();
+    name.push(format!("_{:04}", frame));
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.with_file_name(name)
+}
+
+fn write_png_file(path: &Path, pixels: &[Vec3], opts: &RenderOptions) -> path_tracer::Result<()> {
+    let raw_pixels = img::pixels_to_raw_rgb(pixels);
+
+    let mut png = BufWriter::new(File::create(path)?);
+    img::write_png(&mut png, raw_pixels.as_ref(), opts.width, opts.height)?;
+    png.flush()?;
+    Ok(())
+}
+
+/// Renders a single image to `output`, along with any auxiliary outputs and statistics asked
+/// for.
+fn render_frame(
+    cli: &CliArgs,
+    scene: &Scene,
+    opts: &RenderOptions,
+    output: &Path,
+    stats_path: Option<&Path>,
+) -> Result<(), Box<dyn error::Error + 'static>> {
+    println!(
+        "Rendering {} at {}x{} {}spp with max depth {}",
+        cli.scene, opts.width, opts.height, opts.samples_per_pixel, opts.max_depth
+    );
+
+    // The denoiser needs the features of the first surfaces seen, whether or not they're
+    // written out.
+    let mut aovs = cli.aovs.clone();
+    if cli.denoise {
+        for aov in &[Aov::Albedo, Aov::Normal, Aov::Depth] {
+            if !aovs.contains(aov) {
+                aovs.push(*aov);
+            }
+        }
+    }
+
+    let start = Instant::now();
+    let layers = render_with_aovs(scene, opts, &aovs)?;
+    let elapsed = Instant::now() - start;
+
+    println!("Rendered in {}s", elapsed.as_secs_f64());
+    println!("{}", layers.stats);
+
+    if let Some(path) = stats_path {
+        let mut json = BufWriter::new(File::create(path)?);
+        writeln!(
+            json,
+            "{{\"scene\": {}, \"width\": {}, \"height\": {}, \"spp\": {}, \
+             \"max_depth\": {}, \"seconds\": {}, \"stats\": {}}}",
+            json_string(&cli.scene),
+            opts.width,
+            opts.height,
+            opts.samples_per_pixel,
+            opts.max_depth,
+            elapsed.as_secs_f64(),
+            layers.stats.to_json()
+        )?;
+        json.flush()?;
+    }
+
+    if cli.denoise {
+        let feature = |wanted| {
+            let idx = aovs.iter().position(|&aov| aov == wanted).unwrap();
+            layers.aovs[idx].as_ref()
+        };
+        let features = Features {
+            albedo: feature(Aov::Albedo),
+            normal: feature(Aov::Normal),
+            depth: feature(Aov::Depth),
+        };
+        let denoised = denoise(
+            &layers.pixels,
+            &features,
+            opts.width,
+            opts.height,
+            cli.denoise_strength,
+        )?;
+        write_png_file(&sibling_path(output, "noisy.png"), &layers.pixels, opts)?;
+        write_png_file(output, &denoised, opts)?;
+    } else {
+        write_png_file(output, &layers.pixels, opts)?;
+    }
+
+    // Only the outputs that were asked for are written, which come first.
+    for (aov, buffer) in cli.aovs.iter().zip(&layers.aovs) {
+        let path = sibling_path(output, &format!("{}.pfm", aov.name()));
+        let mut pfm = BufWriter::new(File::create(path)?);
+        img::write_pfm(&mut pfm, buffer, opts.width, opts.height)?;
+        pfm.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Checks for combinations of arguments that can't work together, which structopt can't catch
+/// on its own.
+fn check_args(cli: &CliArgs) -> Result<(), String> {
+    let path_traced = matches!(cli.integrator, Integrator::Path | Integrator::Guided);
+    if cli.denoise && !path_traced {
+        return Err(
+            "--denoise needs the albedo, normals and depth that only the path and guided \
+             integrators produce"
+                .to_owned(),
+        );
+    }
+    if !cli.aovs.is_empty() && !path_traced {
+        return Err("--aov is only supported by the path and guided integrators".to_owned());
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn error::Error + 'static>> {
+    let cli = CliArgs::from_args();
+    if let Err(message) = check_args(&cli) {
+        eprintln!("error: {}\n\nFor more information try --help", message);
+        process::exit(1);
+    }
+
+    let BuiltScene(scene, camera_options) = match build_scene(&cli.scene) {
+        Some(scene) => scene?,
+        None => {
+            eprintln!(
+                "error: Unknown scene '{}'\n\nFor more information try --help",
+                cli.scene
+            );
+            process::exit(1);
+        }
+    };
+
+    let integrator = match cli.integrator {
+        Integrator::PhotonMapping { .. } => Integrator::PhotonMapping {
+            photons: cli.photons,
+            radius: cli.gather_radius,
+        },
+        Integrator::Metropolis { .. } => Integrator::Metropolis {
+            bootstrap: cli.bootstrap,
+            chains: cli.chains,
+            large_step: cli.large_step,
+        },
+        Integrator::Debug(_) => Integrator::Debug(cli.debug_view),
+        integrator => integrator,
+    };
+
+    let opts = RenderOptions {
+        camera_options,
+
+        width: cli.width,
+        height: cli.height,
+
+        max_depth: cli.max_depth,
+        samples_per_pixel: cli.samples_per_pixel,
+        threads: cli.threads,
+
+        spectral: cli.spectral,
+        integrator,
+
+        shutter_open: cli.shutter_open,
+        shutter_close: cli.shutter_close,
+    };
+
+    let (first, last) = match cli.frames {
+        Some(frames) => frames,
+        None => {
+            let output = Path::new(&cli.output_filename);
+            let stats_path = cli.stats_json.as_ref().map(Path::new);
+            return render_frame(&cli, &scene, &opts, output, stats_path);
+        }
+    };
+
+    // The scene is built once, and every frame is rendered from it at a later time.
+    for frame in first..=last {
+        println!("Frame {}", frame);
+        let frame_opts = RenderOptions {
+            shutter_open: f64::from(frame) + cli.shutter_open,
+            shutter_close: f64::from(frame) + cli.shutter_close,
+            ..opts.clone()
+        };
+        let output = frame_path(&cli.output_filename, frame);
+        let stats_path = cli.stats_json.as_ref().map(|path| frame_path(path, frame));
+        render_frame(&cli, &scene, &frame_opts, &output, stats_path.as_deref())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_paths() {
+        let path = |output, frame| frame_path(output, frame).to_string_lossy().into_owned();
+        assert_eq!(path("render_%04d.png", 7), "render_0007.png");
+        assert_eq!(path("render_%04d.png", 12345), "render_12345.png");
+        assert_eq!(path("%d.png", 12), "12.png");
+        assert_eq!(path("out/f%3d.png", 5), "out/f  5.png");
+
+        // Without a pattern, the frame number goes before the extension.
+        assert_eq!(path("render.png", 3), "render_0003.png");
+        assert_eq!(path("out/render", 3), "out/render_0003");
+        assert_eq!(path("100%.png", 3), "100%_0003.png");
+    }
+
+    #[test]
+    fn denoising_needs_the_path_tracer() {
+        let args = |extra: &[&str]| {
+            let base = ["path-tracer", "-w", "8", "-h", "6", "--spp", "1", "mirror"];
+            CliArgs::from_iter(base.iter().chain(extra))
+        };
+        assert!(check_args(&args(&["--denoise"])).is_ok());
+        assert!(check_args(&args(&["--denoise", "--integrator", "guided"])).is_ok());
+        for integrator in &["bdpt", "sppm", "mlt", "debug"] {
+            let cli = args(&["--denoise", "--integrator", integrator]);
+            assert!(check_args(&cli).is_err(), "{} was accepted", integrator);
+            let cli = args(&["--aov", "albedo", "--integrator", integrator]);
+            assert!(check_args(&cli).is_err(), "{} was accepted", integrator);
+        }
+    }
+
+    #[test]
+    fn frame_ranges() {
+        assert_eq!(parse_frames("5"), Ok((5, 5)));
+        assert_eq!(parse_frames("1-24"), Ok((1, 24)));
+        assert_eq!(parse_frames(" 2 - 4 "), Ok((2, 4)));
+        assert!(parse_frames("5-3").is_err());
+        assert!(parse_frames("-3").is_err());
+        assert!(parse_frames("a").is_err());
+    }
 }
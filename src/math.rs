@@ -1,5 +1,7 @@
 use std::ops::{Add, Div, Mul, Neg, Sub};
 
+use crate::error::{Error, Result};
+
 pub const EPSILON: f64 = 1e-9;
 
 pub fn nearly_equal(a: f64, b: f64) -> bool {
@@ -67,10 +69,54 @@ impl Vec3 {
         }
     }
 
+    pub fn min(self, rhs: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x.min(rhs.x),
+            y: self.y.min(rhs.y),
+            z: self.z.min(rhs.z),
+        }
+    }
+
+    pub fn max(self, rhs: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x.max(rhs.x),
+            y: self.y.max(rhs.y),
+            z: self.z.max(rhs.z),
+        }
+    }
+
+    pub fn recip(self) -> Vec3 {
+        Vec3 {
+            x: 1.0 / self.x,
+            y: 1.0 / self.y,
+            z: 1.0 / self.z,
+        }
+    }
+
+    /// Returns the component along the specified axis (0, 1 or 2 for x, y and z).
+    pub fn axis(self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            2 => self.z,
+            _ => panic!("Invalid axis {}", axis),
+        }
+    }
+
+    /// Normalizes a vector that's known not to be zero, such as a constant direction. Panics
+    /// on zero vectors, so use `try_to_unit` for any vector that could be one, such as one
+    /// given by a caller.
     pub fn to_unit(self) -> Unit3 {
+        self.try_to_unit().expect("Normalizing zero vector")
+    }
+
+    pub fn try_to_unit(self) -> Option<Unit3> {
         let mag = self.mag();
-        assert!(mag > EPSILON, "Normalizing zero vector");
-        Unit3::from_unit_vec3(self / mag)
+        if mag > EPSILON {
+            Some(Unit3::from_unit_vec3(self / mag))
+        } else {
+            None
+        }
     }
 }
 
@@ -160,6 +206,8 @@ impl Div<f64> for Vec3 {
 pub struct Ray {
     pub origin: Vec3,
     pub dir: Unit3,
+    /// The moment the ray is traced at, which decides where moving objects are
+    pub time: f64,
 }
 
 impl Ray {
@@ -167,3 +215,619 @@ impl Ray {
         self.origin + t * Vec3::from(self.dir)
     }
 }
+
+/// An affine transformation, stored along with its inverse.
+///
+/// Transforms compose like matrices: `a * b` applies `b` first and then `a`.
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    mat: [[f64; 4]; 4],
+    inv: [[f64; 4]; 4],
+}
+
+const IDENTITY: [[f64; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mat_mul(a: &[[f64; 4]; 4], b: &[[f64; 4]; 4]) -> [[f64; 4]; 4] {
+    let mut ret = [[0.0; 4]; 4];
+    for (i, row) in ret.iter_mut().enumerate() {
+        for (j, val) in row.iter_mut().enumerate() {
+            *val = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    ret
+}
+
+fn affine_inverse(m: &[[f64; 4]; 4]) -> Option<[[f64; 4]; 4]> {
+    // Cofactors of the upper-left 3x3 block
+    let c00 = m[1][1] * m[2][2] - m[1][2] * m[2][1];
+    let c01 = m[1][2] * m[2][0] - m[1][0] * m[2][2];
+    let c02 = m[1][0] * m[2][1] - m[1][1] * m[2][0];
+
+    let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+    if det.abs() < EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let mut inv = IDENTITY;
+    inv[0][0] = c00 * inv_det;
+    inv[1][0] = c01 * inv_det;
+    inv[2][0] = c02 * inv_det;
+    inv[0][1] = (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det;
+    inv[1][1] = (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det;
+    inv[2][1] = (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det;
+    inv[0][2] = (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det;
+    inv[1][2] = (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det;
+    inv[2][2] = (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det;
+
+    // The inverse translation is -A^-1 * t
+    for row in inv.iter_mut().take(3) {
+        row[3] = -(0..3).map(|k| row[k] * m[k][3]).sum::<f64>();
+    }
+
+    Some(inv)
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            mat: IDENTITY,
+            inv: IDENTITY,
+        }
+    }
+
+    /// Creates a transform from a row-major affine matrix. Fails if the bottom row is not
+    /// `[0, 0, 0, 1]` or the matrix is singular.
+    pub fn from_matrix(mat: [[f64; 4]; 4]) -> Result<Transform> {
+        if mat[3] != IDENTITY[3] {
+            return Err(Error::InvalidParameter("transform matrix is not affine"));
+        }
+        let inv =
+            affine_inverse(&mat).ok_or(Error::InvalidParameter("transform matrix is singular"))?;
+        Ok(Transform { mat, inv })
+    }
+
+    /// Creates a transform from a matrix that only rotates and translates, whose inverse
+    /// rotates back by the transpose after undoing the translation.
+    pub(crate) fn rigid(mat: [[f64; 4]; 4]) -> Transform {
+        let mut inv = IDENTITY;
+        for i in 0..3 {
+            for j in 0..3 {
+                inv[i][j] = mat[j][i];
+            }
+        }
+        for row in inv.iter_mut().take(3) {
+            row[3] = -(0..3).map(|k| row[k] * mat[k][3]).sum::<f64>();
+        }
+        Transform { mat, inv }
+    }
+
+    pub fn translate(offset: Vec3) -> Transform {
+        let mut mat = IDENTITY;
+        let mut inv = IDENTITY;
+        mat[0][3] = offset.x;
+        mat[1][3] = offset.y;
+        mat[2][3] = offset.z;
+        inv[0][3] = -offset.x;
+        inv[1][3] = -offset.y;
+        inv[2][3] = -offset.z;
+        Transform { mat, inv }
+    }
+
+    /// Scales by the specified (nonzero) factors along each axis.
+    pub fn scale(factors: Vec3) -> Result<Transform> {
+        if factors.x.abs() < EPSILON || factors.y.abs() < EPSILON || factors.z.abs() < EPSILON {
+            return Err(Error::InvalidParameter("scale factors must be nonzero"));
+        }
+
+        let mut mat = IDENTITY;
+        let mut inv = IDENTITY;
+        mat[0][0] = factors.x;
+        mat[1][1] = factors.y;
+        mat[2][2] = factors.z;
+        inv[0][0] = 1.0 / factors.x;
+        inv[1][1] = 1.0 / factors.y;
+        inv[2][2] = 1.0 / factors.z;
+        Ok(Transform { mat, inv })
+    }
+
+    pub fn uniform_scale(factor: f64) -> Result<Transform> {
+        Transform::scale(Vec3 {
+            x: factor,
+            y: factor,
+            z: factor,
+        })
+    }
+
+    /// Rotates counterclockwise by `degrees` around `axis` (when looking down the axis towards
+    /// the origin).
+    pub fn rotate(axis: Unit3, degrees: f64) -> Transform {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (x, y, z) = (axis.x(), axis.y(), axis.z());
+
+        let mut mat = IDENTITY;
+        mat[0][0] = cos + x * x * (1.0 - cos);
+        mat[0][1] = x * y * (1.0 - cos) - z * sin;
+        mat[0][2] = x * z * (1.0 - cos) + y * sin;
+        mat[1][0] = y * x * (1.0 - cos) + z * sin;
+        mat[1][1] = cos + y * y * (1.0 - cos);
+        mat[1][2] = y * z * (1.0 - cos) - x * sin;
+        mat[2][0] = z * x * (1.0 - cos) - y * sin;
+        mat[2][1] = z * y * (1.0 - cos) + x * sin;
+        mat[2][2] = cos + z * z * (1.0 - cos);
+
+        // Rotation matrices are orthogonal
+        let mut inv = IDENTITY;
+        for (i, row) in inv.iter_mut().enumerate().take(3) {
+            for (j, val) in row.iter_mut().enumerate().take(3) {
+                *val = mat[j][i];
+            }
+        }
+
+        Transform { mat, inv }
+    }
+
+    pub fn rotate_x(degrees: f64) -> Transform {
+        Transform::rotate(
+            Unit3::from_unit_vec3(Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }),
+            degrees,
+        )
+    }
+
+    pub fn rotate_y(degrees: f64) -> Transform {
+        Transform::rotate(
+            Unit3::from_unit_vec3(Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }),
+            degrees,
+        )
+    }
+
+    pub fn rotate_z(degrees: f64) -> Transform {
+        Transform::rotate(
+            Unit3::from_unit_vec3(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+            degrees,
+        )
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            mat: self.inv,
+            inv: self.mat,
+        }
+    }
+
+    pub fn apply_point(&self, p: Vec3) -> Vec3 {
+        let m = &self.mat;
+        Vec3 {
+            x: m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            y: m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            z: m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        }
+    }
+
+    pub fn apply_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.mat;
+        Vec3 {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+
+    /// Transforms a surface normal, which requires the inverse transpose of the matrix.
+    pub fn apply_normal(&self, n: Unit3) -> Unit3 {
+        let inv = &self.inv;
+        let n = Vec3::from(n);
+        let normal = Vec3 {
+            x: inv[0][0] * n.x + inv[1][0] * n.y + inv[2][0] * n.z,
+            y: inv[0][1] * n.x + inv[1][1] * n.y + inv[2][1] * n.z,
+            z: inv[0][2] * n.x + inv[1][2] * n.y + inv[2][2] * n.z,
+        };
+        // Every transform is invertible, so this is never zero, but it's tiny under large
+        // scales. Dividing by its largest component first keeps it long enough to normalize.
+        let largest = normal.x.abs().max(normal.y.abs()).max(normal.z.abs());
+        (normal / largest).to_unit()
+    }
+
+    /// Returns the factor by which the transform scales lengths, if it scales them equally in
+    /// every direction. That's the case when it only rotates, reflects, translates and scales
+    /// uniformly.
+    pub fn similarity_scale(&self) -> Option<f64> {
+        let axes = [
+            Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        ]
+        .map(|axis| self.apply_vector(axis));
+        let scale_squared = axes[0].mag_squared();
+        let uniform = nearly_equal(axes[1].mag_squared(), scale_squared)
+            && nearly_equal(axes[2].mag_squared(), scale_squared);
+        let orthogonal = [(0, 1), (1, 2), (2, 0)]
+            .iter()
+            .all(|&(i, j)| axes[i].dot(axes[j]).abs() < EPSILON * scale_squared);
+        if uniform && orthogonal {
+            Some(scale_squared.sqrt())
+        } else {
+            None
+        }
+    }
+
+    /// Transforms a ray, returning the new ray along with the factor by which distances along
+    /// it are scaled.
+    pub fn apply_ray(&self, ray: &Ray) -> (Ray, f64) {
+        let dir = self.apply_vector(ray.dir.into());
+        let scale = dir.mag();
+        (
+            Ray {
+                origin: self.apply_point(ray.origin),
+                dir: Unit3::from_unit_vec3(dir / scale),
+                time: ray.time,
+            },
+            scale,
+        )
+    }
+}
+
+impl Mul for Transform {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Transform {
+            mat: mat_mul(&self.mat, &rhs.mat),
+            inv: mat_mul(&rhs.inv, &self.inv),
+        }
+    }
+}
+
+/// An axis-aligned bounding box.
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// Creates an empty box, which is the identity for `union`.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3 {
+                x: f64::INFINITY,
+                y: f64::INFINITY,
+                z: f64::INFINITY,
+            },
+            max: Vec3 {
+                x: f64::NEG_INFINITY,
+                y: f64::NEG_INFINITY,
+                z: f64::NEG_INFINITY,
+            },
+        }
+    }
+
+    /// Creates a box covering all of space, for unbounded geometry.
+    pub fn infinite() -> Aabb {
+        Aabb {
+            min: Aabb::empty().max,
+            max: Aabb::empty().min,
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Vec3>>(points: I) -> Aabb {
+        points
+            .into_iter()
+            .fold(Aabb::empty(), |bounds, p| bounds.include(p))
+    }
+
+    pub fn include(self, p: Vec3) -> Aabb {
+        Aabb {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Returns the box containing only the points in both boxes. The result is empty (with its
+    /// minimum above its maximum on some axis) if the boxes don't overlap.
+    pub fn intersection(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        (0..3).all(|axis| self.min.axis(axis).is_finite() && self.max.axis(axis).is_finite())
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn corners(&self) -> impl Iterator<Item = Vec3> {
+        let (min, max) = (self.min, self.max);
+        (0..8).map(move |i| Vec3 {
+            x: if i & 1 == 0 { min.x } else { max.x },
+            y: if i & 2 == 0 { min.y } else { max.y },
+            z: if i & 4 == 0 { min.z } else { max.z },
+        })
+    }
+
+    /// Computes the bounds of this box after it has been transformed.
+    pub fn transform(&self, transform: &Transform) -> Aabb {
+        if !self.is_finite() {
+            return Aabb::infinite();
+        }
+        Aabb::from_points(self.corners().map(|p| transform.apply_point(p)))
+    }
+
+    /// Returns the parametric range of `ray` inside the box, clipped to `[0, max_dist]`.
+    /// `inv_dir` should be the componentwise reciprocal of the ray direction.
+    pub fn intersect_range(&self, ray: &Ray, inv_dir: Vec3, max_dist: f64) -> Option<(f64, f64)> {
+        let mut t_min = 0.0;
+        let mut t_max = max_dist;
+
+        for axis in 0..3 {
+            let origin = ray.origin.axis(axis);
+            let inv = inv_dir.axis(axis);
+            let mut t0 = (self.min.axis(axis) - origin) * inv;
+            let mut t1 = (self.max.axis(axis) - origin) * inv;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            // Written so that NaNs (from 0 * inf) leave the range unchanged.
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_min > t_max {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+}
+
+/// Solves `a*x^2 + b*x + c = 0`, returning the real roots in increasing order.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a.abs() < EPSILON {
+        if b.abs() < EPSILON {
+            return None;
+        }
+        let root = -c / b;
+        return Some((root, root));
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    // Avoid cancellation by never subtracting nearly equal quantities.
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (r1, r2) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+
+    Some(if r1 < r2 { (r1, r2) } else { (r2, r1) })
+}
+
+/// Returns the largest real root of the monic cubic `x^3 + a*x^2 + b*x + c`.
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    let q = (a * a - 3.0 * b) / 9.0;
+    let r = (2.0 * a * a * a - 9.0 * a * b + 27.0 * c) / 54.0;
+
+    let root = if r * r < q * q * q {
+        let theta = (r / (q * q * q).sqrt()).clamp(-1.0, 1.0).acos();
+        let scale = -2.0 * q.sqrt();
+        (0..3)
+            .map(|k| {
+                scale * ((theta + 2.0 * std::f64::consts::PI * f64::from(k)) / 3.0).cos() - a / 3.0
+            })
+            .fold(f64::NEG_INFINITY, f64::max)
+    } else {
+        let big = -r.signum() * (r.abs() + (r * r - q * q * q).sqrt()).cbrt();
+        let small = if big == 0.0 { 0.0 } else { q / big };
+        big + small - a / 3.0
+    };
+
+    polish_root(
+        root,
+        |x| ((x + a) * x + b) * x + c,
+        |x| (3.0 * x + 2.0 * a) * x + b,
+    )
+}
+
+fn polish_root<F: Fn(f64) -> f64, D: Fn(f64) -> f64>(mut x: f64, f: F, df: D) -> f64 {
+    for _ in 0..2 {
+        let slope = df(x);
+        if slope.abs() < EPSILON {
+            break;
+        }
+        let next = x - f(x) / slope;
+        if !next.is_finite() {
+            break;
+        }
+        x = next;
+    }
+    x
+}
+
+/// Solves the monic quartic `x^4 + a*x^3 + b*x^2 + c*x + d = 0` using Ferrari's method,
+/// returning the finite real roots in increasing order.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // Substitute x = y - a/4 to get y^4 + p*y^2 + q*y + r = 0
+    let a2 = a * a;
+    let p = b - 3.0 * a2 / 8.0;
+    let q = c - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * c / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+
+    let mut roots = Vec::with_capacity(4);
+    let mut push_quadratic = |qa: f64, qb: f64, qc: f64| {
+        if let Some((y1, y2)) = solve_quadratic(qa, qb, qc) {
+            roots.push(y1);
+            roots.push(y2);
+        }
+    };
+
+    if q.abs() < EPSILON {
+        // Biquadratic: solve for y^2
+        if let Some((z1, z2)) = solve_quadratic(1.0, p, r) {
+            for z in [z1, z2].iter().copied().filter(|&z| z >= 0.0) {
+                push_quadratic(1.0, 0.0, -z);
+            }
+        }
+    } else {
+        // Pick m so that the quartic factors into two quadratics, using the resolvent cubic
+        // 8m^3 + 8p*m^2 + (2p^2 - 8r)*m - q^2 = 0. Its largest root is always positive here.
+        let m = largest_cubic_root(p, p * p / 4.0 - r, -q * q / 8.0);
+        if m <= 0.0 {
+            return vec![];
+        }
+        let s = (2.0 * m).sqrt();
+        push_quadratic(1.0, -s, p / 2.0 + m + q / (2.0 * s));
+        push_quadratic(1.0, s, p / 2.0 + m - q / (2.0 * s));
+    }
+
+    let mut roots: Vec<f64> = roots
+        .into_iter()
+        .map(|y| {
+            polish_root(
+                y - a / 4.0,
+                |x| (((x + a) * x + b) * x + c) * x + d,
+                |x| ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c,
+            )
+        })
+        .filter(|x| x.is_finite())
+        .collect();
+    roots.sort_by(f64::total_cmp);
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn sheared() -> Transform {
+        Transform::from_matrix([
+            [1.0, 0.5, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn inverse_undoes_transform() {
+        let transforms = [
+            Transform::translate(vec3(1.0, -2.0, 3.0)) * Transform::rotate_y(30.0),
+            Transform::scale(vec3(2.0, 0.5, 3.0)).unwrap() * Transform::rotate_x(-75.0),
+            sheared() * Transform::translate(vec3(0.0, 4.0, -1.0)),
+        ];
+        for t in transforms.iter() {
+            let product = *t * t.inverse();
+            for (row, expected) in product.mat.iter().zip(IDENTITY.iter()) {
+                for (&actual, &expected) in row.iter().zip(expected.iter()) {
+                    assert!(
+                        (actual - expected).abs() < TOLERANCE,
+                        "expected the identity, got {:?}",
+                        product.mat
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let t = Transform::scale(vec3(4.0, 1.0, 0.25)).unwrap() * Transform::rotate_z(40.0);
+        // Two tangents of a tilted plane, and its normal
+        let u = vec3(1.0, 0.0, -1.0);
+        let v = vec3(0.0, 1.0, 2.0);
+        let n = u.cross(v).to_unit();
+
+        let normal = Vec3::from(t.apply_normal(n));
+        assert!((normal.mag() - 1.0).abs() < TOLERANCE);
+        for &tangent in [u, v].iter() {
+            let tangent = t.apply_vector(tangent);
+            assert!(
+                normal.dot(tangent).abs() < TOLERANCE * tangent.mag(),
+                "{:?} is not perpendicular to {:?}",
+                normal,
+                tangent
+            );
+        }
+    }
+
+    #[test]
+    fn similarity_scale_detects_uniform_scaling() {
+        let similar = Transform::translate(vec3(5.0, 0.0, 0.0))
+            * Transform::uniform_scale(3.0).unwrap()
+            * Transform::rotate(vec3(1.0, 1.0, 0.0).to_unit(), 60.0);
+        let scale = similar.similarity_scale().unwrap();
+        assert!((scale - 3.0).abs() < TOLERANCE);
+
+        assert!(sheared().similarity_scale().is_none());
+        assert!(Transform::scale(vec3(1.0, 2.0, 1.0))
+            .unwrap()
+            .similarity_scale()
+            .is_none());
+    }
+
+    #[test]
+    fn quartic_roots_are_sorted_and_finite() {
+        // (x^2 - 1)(x^2 - 4)
+        let roots = solve_quartic(0.0, -5.0, 0.0, 4.0);
+        assert_eq!(roots.len(), 4);
+        for (&root, &expected) in roots.iter().zip([-2.0, -1.0, 1.0, 2.0].iter()) {
+            assert!((root - expected).abs() < TOLERANCE);
+        }
+
+        assert!(solve_quartic(f64::NAN, 0.0, 0.0, -1.0).is_empty());
+    }
+}
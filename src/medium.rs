@@ -0,0 +1,504 @@
+use std::f64;
+use std::fs;
+use std::path::Path;
+
+use rand::{Rng, RngCore};
+
+use crate::error::{Error, Result};
+use crate::math::*;
+use crate::sample::Basis;
+
+/// The Henyey-Greenstein phase function, describing how light is scattered within a medium.
+#[derive(Debug, Copy, Clone)]
+pub struct HenyeyGreenstein {
+    g: f64,
+}
+
+impl HenyeyGreenstein {
+    /// Creates a phase function with asymmetry `g`, which must lie strictly between -1 and 1.
+    /// Positive values favour forward scattering, negative values backward scattering and zero
+    /// scatters uniformly in all directions.
+    pub fn new(g: f64) -> Result<HenyeyGreenstein> {
+        if g.is_nan() || g <= -1.0 || g >= 1.0 {
+            return Err(Error::InvalidParameter(
+                "phase function asymmetry must be between -1 and 1",
+            ));
+        }
+        Ok(HenyeyGreenstein { g })
+    }
+
+    pub fn g(&self) -> f64 {
+        self.g
+    }
+
+    /// Returns the density of light scattered by angle `theta` from its direction of travel.
+    pub fn eval(&self, cos_theta: f64) -> f64 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * f64::consts::PI * denom * denom.sqrt())
+    }
+
+    /// Samples the new direction of light travelling along `dir`, in proportion to the phase
+    /// function. Since the sampling is exact, no weighting is needed.
+    pub fn sample<R: Rng + ?Sized>(&self, dir: Unit3, rng: &mut R) -> Unit3 {
+        let g = self.g;
+        let u: f64 = rng.gen();
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let term = (1.0 - g * g) / (1.0 + g - 2.0 * g * u);
+            ((1.0 + g * g - term * term) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = rng.gen_range(0.0, 2.0 * f64::consts::PI);
+
+        let basis = Basis::from_normal(dir);
+        Unit3::from_unit_vec3(
+            sin_theta * phi.cos() * basis.x + sin_theta * phi.sin() * basis.y + cos_theta * basis.z,
+        )
+    }
+}
+
+/// The outcome of sampling a medium along a ray.
+#[derive(Debug, Copy, Clone)]
+pub struct MediumSample {
+    /// The distance at which the ray scatters, or `None` if it passes through the medium up to
+    /// the maximum distance.
+    pub scatter: Option<f64>,
+    /// The factor by which light arriving along the sampled path must be scaled.
+    pub weight: Vec3,
+}
+
+/// A volume of absorbing and scattering material, such as fog or smoke.
+pub trait Medium: Send + Sync {
+    /// Samples the point at which `ray` next scatters, if it does so before `max_dist`.
+    fn sample(&self, ray: &Ray, max_dist: f64, rng: &mut dyn RngCore) -> MediumSample;
+
+    /// Returns the fraction of light that passes along `ray` for `dist` without being absorbed
+    /// or scattered. May be a stochastic estimate.
+    fn transmittance(&self, ray: &Ray, dist: f64, rng: &mut dyn RngCore) -> Vec3;
+
+    fn phase(&self) -> &HenyeyGreenstein;
+}
+
+/// Returns `exp(-coeff * dist)` for each channel, treating zero coefficients as fully
+/// transparent even over infinite distances.
+pub(crate) fn beer_lambert(coeff: Vec3, dist: f64) -> Vec3 {
+    let channel = |c: f64| if c > 0.0 { (-c * dist).exp() } else { 1.0 };
+    Vec3 {
+        x: channel(coeff.x),
+        y: channel(coeff.y),
+        z: channel(coeff.z),
+    }
+}
+
+fn average(v: Vec3) -> f64 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+/// A medium with the same properties throughout.
+#[derive(Debug, Copy, Clone)]
+pub struct HomogeneousMedium {
+    absorption: Vec3,
+    scattering: Vec3,
+    phase: HenyeyGreenstein,
+}
+
+impl HomogeneousMedium {
+    /// Creates a medium from its absorption and scattering coefficients, per unit distance.
+    pub fn new(absorption: Vec3, scattering: Vec3, phase: HenyeyGreenstein) -> Result<Self> {
+        let valid = |v: Vec3| (0..3).all(|axis| v.axis(axis) >= 0.0 && v.axis(axis).is_finite());
+        if !valid(absorption) || !valid(scattering) {
+            return Err(Error::InvalidParameter(
+                "medium coefficients must be finite and nonnegative",
+            ));
+        }
+
+        Ok(HomogeneousMedium {
+            absorption,
+            scattering,
+            phase,
+        })
+    }
+
+    fn extinction(&self) -> Vec3 {
+        self.absorption + self.scattering
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample(&self, _ray: &Ray, max_dist: f64, rng: &mut dyn RngCore) -> MediumSample {
+        let extinction = self.extinction();
+
+        // Sample a distance using the extinction of a randomly chosen channel, and weight the
+        // result by the probability averaged over all channels.
+        let channel_extinction = extinction.axis(rng.gen_range(0, 3));
+        let dist = if channel_extinction > 0.0 {
+            -(1.0 - rng.gen::<f64>()).ln() / channel_extinction
+        } else {
+            f64::INFINITY
+        };
+
+        if dist < max_dist {
+            let transmittance = beer_lambert(extinction, dist);
+            let pdf = average(extinction.component_mul(transmittance));
+            MediumSample {
+                scatter: Some(dist),
+                weight: transmittance.component_mul(self.scattering) / pdf,
+            }
+        } else {
+            let transmittance = beer_lambert(extinction, max_dist);
+            let pdf = average(transmittance);
+            MediumSample {
+                scatter: None,
+                weight: if pdf > 0.0 {
+                    transmittance / pdf
+                } else {
+                    Vec3::default()
+                },
+            }
+        }
+    }
+
+    fn transmittance(&self, _ray: &Ray, dist: f64, _rng: &mut dyn RngCore) -> Vec3 {
+        beer_lambert(self.extinction(), dist)
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+/// Returns the number of voxels in a grid, or `None` if it overflows.
+fn voxel_count(dims: [usize; 3]) -> Option<usize> {
+    dims[0].checked_mul(dims[1])?.checked_mul(dims[2])
+}
+
+/// A medium whose density varies through space, given by a grid of voxels filling a box.
+///
+/// The density is interpolated trilinearly between voxel centers, and is zero outside the box.
+/// Extinction is proportional to the density, with a fixed fraction of it being scattering
+/// rather than absorption.
+pub struct GridMedium {
+    dims: [usize; 3],
+    density: Vec<f32>,
+    bounds: Aabb,
+    extinction: f64,
+    albedo: Vec3,
+    /// The largest extinction coefficient anywhere in the grid
+    majorant: f64,
+    phase: HenyeyGreenstein,
+}
+
+impl GridMedium {
+    /// Creates a medium from `dims[0] * dims[1] * dims[2]` densities, with x varying fastest
+    /// and z slowest. The extinction coefficient at a point is its density multiplied by
+    /// `extinction`, and `albedo` is the fraction of extinction that is scattering.
+    pub fn new(
+        dims: [usize; 3],
+        density: Vec<f32>,
+        bounds: Aabb,
+        extinction: f64,
+        albedo: Vec3,
+        phase: HenyeyGreenstein,
+    ) -> Result<GridMedium> {
+        if dims.contains(&0) {
+            return Err(Error::InvalidParameter("grid dimensions must be nonzero"));
+        }
+        let expected =
+            voxel_count(dims).ok_or(Error::InvalidParameter("grid dimensions are too large"))?;
+        if density.len() != expected {
+            return Err(Error::BufferSize {
+                expected,
+                actual: density.len(),
+            });
+        }
+        if density.iter().any(|&d| !d.is_finite() || d < 0.0) {
+            return Err(Error::InvalidParameter(
+                "grid densities must be finite and nonnegative",
+            ));
+        }
+        if !bounds.is_finite() || (0..3).any(|axis| bounds.min.axis(axis) >= bounds.max.axis(axis))
+        {
+            return Err(Error::InvalidParameter("grid bounds must be a finite box"));
+        }
+        if !extinction.is_finite() || extinction < 0.0 {
+            return Err(Error::InvalidParameter(
+                "medium coefficients must be finite and nonnegative",
+            ));
+        }
+        if (0..3).any(|axis| !(0.0..=1.0).contains(&albedo.axis(axis))) {
+            return Err(Error::InvalidParameter(
+                "medium albedo must be between 0 and 1",
+            ));
+        }
+
+        let max_density = density.iter().cloned().fold(0.0, f32::max);
+        let majorant = f64::from(max_density) * extinction;
+        if !majorant.is_finite() {
+            return Err(Error::InvalidParameter(
+                "grid extinction overflows at its densest voxel",
+            ));
+        }
+        Ok(GridMedium {
+            dims,
+            density,
+            bounds,
+            extinction,
+            albedo,
+            majorant,
+            phase,
+        })
+    }
+
+    /// Loads the densities from a file consisting of the three grid dimensions as little-endian
+    /// `u32`s, followed by the densities as little-endian `f32`s in the order expected by `new`.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        bounds: Aabb,
+        extinction: f64,
+        albedo: Vec3,
+        phase: HenyeyGreenstein,
+    ) -> Result<GridMedium> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < 12 {
+            return Err(Error::Format("grid file is missing its header".to_owned()));
+        }
+
+        let word = |idx: usize| {
+            [
+                bytes[4 * idx],
+                bytes[4 * idx + 1],
+                bytes[4 * idx + 2],
+                bytes[4 * idx + 3],
+            ]
+        };
+        let dims = [0, 1, 2].map(|idx| u32::from_le_bytes(word(idx)) as usize);
+
+        let expected = voxel_count(dims)
+            .and_then(|count| count.checked_mul(4))
+            .and_then(|size| size.checked_add(12))
+            .ok_or_else(|| Error::Format("grid dimensions are too large".to_owned()))?;
+        if bytes.len() != expected {
+            return Err(Error::BufferSize {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+
+        let density = (3..bytes.len() / 4)
+            .map(|idx| f32::from_le_bytes(word(idx)))
+            .collect();
+        GridMedium::new(dims, density, bounds, extinction, albedo, phase)
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        f64::from(self.density[(z * self.dims[1] + y) * self.dims[0] + x])
+    }
+
+    /// Returns the interpolated density at `p`.
+    fn density_at(&self, p: Vec3) -> f64 {
+        let size = self.bounds.max - self.bounds.min;
+
+        // Find the surrounding voxel centers along each axis, along with the interpolation
+        // weight of the upper one.
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut frac = [0.0; 3];
+        for axis in 0..3 {
+            let rel = (p.axis(axis) - self.bounds.min.axis(axis)) / size.axis(axis);
+            if !(0.0..=1.0).contains(&rel) {
+                return 0.0;
+            }
+            let last = self.dims[axis] - 1;
+            let coord = (rel * self.dims[axis] as f64 - 0.5).clamp(0.0, last as f64);
+            lower[axis] = coord.floor() as usize;
+            upper[axis] = (lower[axis] + 1).min(last);
+            frac[axis] = coord - lower[axis] as f64;
+        }
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let along_x = |y, z| {
+            lerp(
+                self.voxel(lower[0], y, z),
+                self.voxel(upper[0], y, z),
+                frac[0],
+            )
+        };
+        let along_y = |z| lerp(along_x(lower[1], z), along_x(upper[1], z), frac[1]);
+        lerp(along_y(lower[2]), along_y(upper[2]), frac[2])
+    }
+
+    /// Returns the range of distances along `ray` that lie within the grid, up to `max_dist`.
+    fn clip(&self, ray: &Ray, max_dist: f64) -> Option<(f64, f64)> {
+        self.bounds
+            .intersect_range(ray, Vec3::from(ray.dir).recip(), max_dist)
+    }
+}
+
+impl Medium for GridMedium {
+    fn sample(&self, ray: &Ray, max_dist: f64, rng: &mut dyn RngCore) -> MediumSample {
+        let passed = MediumSample {
+            scatter: None,
+            weight: Vec3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        };
+        let (mut dist, t_max) = match self.clip(ray, max_dist) {
+            Some(range) if self.majorant > 0.0 => range,
+            _ => return passed,
+        };
+
+        // Delta tracking: take steps through a fictitious homogeneous medium with the majorant
+        // extinction, accepting each collision as real in proportion to the true extinction.
+        loop {
+            dist -= (1.0 - rng.gen::<f64>()).ln() / self.majorant;
+            if dist >= t_max {
+                return passed;
+            }
+
+            let extinction = self.density_at(ray.interp(dist)) * self.extinction;
+            if rng.gen::<f64>() * self.majorant < extinction {
+                return MediumSample {
+                    scatter: Some(dist),
+                    weight: self.albedo,
+                };
+            }
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, dist: f64, rng: &mut dyn RngCore) -> Vec3 {
+        let (mut t, t_max) = match self.clip(ray, dist) {
+            Some(range) if self.majorant > 0.0 => range,
+            _ => {
+                return Vec3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                }
+            }
+        };
+
+        // Ratio tracking: the same steps as delta tracking, but attenuating by the probability
+        // of each collision being fictitious instead of stopping.
+        let mut transmittance = 1.0;
+        loop {
+            t -= (1.0 - rng.gen::<f64>()).ln() / self.majorant;
+            if t >= t_max {
+                break;
+            }
+            let extinction = self.density_at(ray.interp(t)) * self.extinction;
+            transmittance *= 1.0 - extinction / self.majorant;
+        }
+
+        Vec3 {
+            x: transmittance,
+            y: transmittance,
+            z: transmittance,
+        }
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    #[test]
+    fn homogeneous_transmittance() {
+        let medium = HomogeneousMedium::new(
+            vec3(0.1, 0.0, 0.0),
+            vec3(0.2, 0.5, 0.0),
+            HenyeyGreenstein::new(0.0).unwrap(),
+        )
+        .unwrap();
+        let ray = Ray {
+            origin: Vec3::default(),
+            dir: vec3(1.0, 0.0, 0.0).to_unit(),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for &dist in &[0.0, 0.5, 3.0, 40.0] {
+            let transmittance = medium.transmittance(&ray, dist, &mut rng);
+            for (axis, &sigma_t) in [0.3f64, 0.5, 0.0].iter().enumerate() {
+                let expected = (-sigma_t * dist).exp();
+                assert!(
+                    (transmittance.axis(axis) - expected).abs() < TOLERANCE,
+                    "expected {}, got {} at distance {}",
+                    expected,
+                    transmittance.axis(axis),
+                    dist
+                );
+            }
+        }
+
+        // A clear channel stays clear all the way to infinity.
+        let transmittance = medium.transmittance(&ray, f64::INFINITY, &mut rng);
+        assert_eq!((transmittance.x, transmittance.z), (0.0, 1.0));
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: vec3(0.0, 0.0, 0.0),
+            max: vec3(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Writes `words` to a temporary grid file and loads it with the given extinction.
+    fn load_grid(name: &str, words: &[[u8; 4]], extinction: f64) -> Result<GridMedium> {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, words.concat()).unwrap();
+        let loaded = GridMedium::load(
+            &path,
+            unit_box(),
+            extinction,
+            vec3(0.5, 0.5, 0.5),
+            HenyeyGreenstein::new(0.0).unwrap(),
+        );
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn oversized_grid_file_is_rejected() {
+        let loaded = load_grid(
+            "path-tracer-oversized-grid.bin",
+            &[u32::MAX.to_le_bytes(); 3],
+            1.0,
+        );
+        assert!(matches!(loaded, Err(Error::Format(_))));
+    }
+
+    #[test]
+    fn non_finite_densities_are_rejected() {
+        let grid = |density: f32| {
+            let mut words = vec![1u32.to_le_bytes(); 3];
+            words.push(density.to_le_bytes());
+            words
+        };
+
+        let loaded = load_grid("path-tracer-infinite-grid.bin", &grid(f32::INFINITY), 1.0);
+        assert!(matches!(loaded, Err(Error::InvalidParameter(_))));
+
+        // Each density is finite, but the densest extinction coefficient isn't.
+        let loaded = load_grid("path-tracer-dense-grid.bin", &grid(f32::MAX), f64::MAX);
+        assert!(matches!(loaded, Err(Error::InvalidParameter(_))));
+
+        let loaded = load_grid("path-tracer-finite-grid.bin", &grid(2.0), 1.5).unwrap();
+        assert_eq!(loaded.majorant, 3.0);
+    }
+}
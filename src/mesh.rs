@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::bvh::Bvh;
+use crate::error::{Error, Result};
+use rand::{Rng, RngCore};
+
+use crate::geom::{Geom, Hit, SurfaceSample};
+use crate::math::*;
+use crate::sample::Basis;
+use crate::texture::Uv;
+
+/// A triangle mesh with its own acceleration structure.
+///
+/// Meshes are intended to be shared between many `Instance`s, so that the triangle data and
+/// hierarchy are only stored once.
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    normals: Option<Vec<Unit3>>,
+    uvs: Option<Vec<Uv>>,
+    triangles: Vec<[u32; 3]>,
+    bvh: Bvh,
+    /// The running total of triangle areas, for picking triangles in proportion to their area
+    cumulative_areas: Vec<f64>,
+}
+
+impl Mesh {
+    /// Creates a mesh from a list of vertex positions and triangles indexing into them.
+    /// Triangles are considered front-facing when their vertices are in counterclockwise order.
+    pub fn new(positions: Vec<Vec3>, triangles: Vec<[u32; 3]>) -> Result<Mesh> {
+        Mesh::with_attributes(positions, None, None, triangles)
+    }
+
+    /// Creates a mesh with per-vertex texture coordinates.
+    pub fn with_uvs(positions: Vec<Vec3>, uvs: Vec<Uv>, triangles: Vec<[u32; 3]>) -> Result<Mesh> {
+        Mesh::with_attributes(positions, None, Some(uvs), triangles)
+    }
+
+    /// Creates a mesh with optional per-vertex normals and texture coordinates.
+    ///
+    /// Vertex normals are interpolated to produce the shading normal, while the geometric normal
+    /// is always that of the triangle. Meshes without texture coordinates use the barycentric
+    /// coordinates of the hit point within its triangle.
+    pub fn with_attributes(
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        uvs: Option<Vec<Uv>>,
+        triangles: Vec<[u32; 3]>,
+    ) -> Result<Mesh> {
+        if triangles.is_empty() {
+            return Err(Error::InvalidParameter("mesh has no triangles"));
+        }
+
+        if triangles
+            .iter()
+            .flatten()
+            .any(|&idx| idx as usize >= positions.len())
+        {
+            return Err(Error::InvalidParameter("mesh vertex index out of range"));
+        }
+
+        let check_len = |len: usize| {
+            if len == positions.len() {
+                Ok(())
+            } else {
+                Err(Error::BufferSize {
+                    expected: positions.len(),
+                    actual: len,
+                })
+            }
+        };
+
+        if let Some(uvs) = &uvs {
+            check_len(uvs.len())?;
+        }
+
+        let normals = match normals {
+            Some(normals) => {
+                check_len(normals.len())?;
+                Some(
+                    normals
+                        .into_iter()
+                        .map(|normal| {
+                            normal
+                                .try_to_unit()
+                                .ok_or(Error::InvalidParameter("zero-length vertex normal"))
+                        })
+                        .collect::<Result<Vec<_>>>()?,
+                )
+            }
+            None => None,
+        };
+
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|tri| Aabb::from_points(tri.iter().map(|&idx| positions[idx as usize])))
+            .collect();
+
+        let mut total_area = 0.0;
+        let cumulative_areas = triangles
+            .iter()
+            .map(|tri| {
+                let [p0, p1, p2] = tri.map(|idx| positions[idx as usize]);
+                total_area += (p1 - p0).cross(p2 - p0).mag() / 2.0;
+                total_area
+            })
+            .collect();
+
+        Ok(Mesh {
+            positions,
+            normals,
+            uvs,
+            triangles,
+            bvh: Bvh::new(&bounds),
+            cumulative_areas,
+        })
+    }
+
+    /// Loads the geometry from a Wavefront OBJ file. Polygonal faces are triangulated as fans.
+    pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Mesh> {
+        Mesh::parse_obj(BufReader::new(File::open(path)?))
+    }
+
+    pub fn parse_obj<R: BufRead>(reader: R) -> Result<Mesh> {
+        let mut obj_positions = Vec::new();
+        let mut obj_normals = Vec::new();
+        let mut obj_uvs = Vec::new();
+
+        // OBJ faces index positions, texture coordinates and normals separately, so every
+        // distinct combination becomes its own vertex.
+        let mut vertex_map = HashMap::new();
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut has_normals = false;
+        let mut has_uvs = false;
+        let mut triangles = Vec::new();
+
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let malformed = |what: &str| Error::Format(format!("line {}: {}", line_idx + 1, what));
+
+            let parse_coords = |tokens: std::str::SplitWhitespace, count: usize| {
+                let coords = tokens
+                    .take(count)
+                    .map(|tok| tok.parse::<f64>())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|_| malformed("invalid coordinate"))?;
+                if coords.len() != count {
+                    return Err(malformed("missing coordinates"));
+                }
+                Ok(coords)
+            };
+
+            let resolve = |idx: &str, count: usize| {
+                let idx: i64 = idx.parse().map_err(|_| malformed("invalid face index"))?;
+                let resolved = if idx < 0 { count as i64 + idx } else { idx - 1 };
+                if resolved < 0 || resolved >= count as i64 {
+                    return Err(malformed("face index out of range"));
+                }
+                Ok(resolved as usize)
+            };
+
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let coords = parse_coords(tokens, 3)?;
+                    obj_positions.push(Vec3 {
+                        x: coords[0],
+                        y: coords[1],
+                        z: coords[2],
+                    });
+                }
+                Some("vn") => {
+                    let coords = parse_coords(tokens, 3)?;
+                    obj_normals.push(Vec3 {
+                        x: coords[0],
+                        y: coords[1],
+                        z: coords[2],
+                    });
+                }
+                Some("vt") => {
+                    let coords = parse_coords(tokens, 2)?;
+                    obj_uvs.push((coords[0], coords[1]));
+                }
+                Some("f") => {
+                    let mut indices = Vec::new();
+                    for tok in tokens {
+                        let mut parts = tok.split('/');
+                        let pos_idx = resolve(parts.next().unwrap_or(""), obj_positions.len())?;
+                        let uv_idx = match parts.next() {
+                            Some(idx) if !idx.is_empty() => Some(resolve(idx, obj_uvs.len())?),
+                            _ => None,
+                        };
+                        let normal_idx = match parts.next() {
+                            Some(idx) if !idx.is_empty() => Some(resolve(idx, obj_normals.len())?),
+                            _ => None,
+                        };
+
+                        let key = (pos_idx, uv_idx, normal_idx);
+                        let vertex = *vertex_map.entry(key).or_insert_with(|| {
+                            positions.push(obj_positions[pos_idx]);
+                            uvs.push(uv_idx.map_or((0.0, 0.0), |idx| obj_uvs[idx]));
+                            normals.push(normal_idx.map(|idx| obj_normals[idx]));
+                            positions.len() as u32 - 1
+                        });
+                        has_uvs |= uv_idx.is_some();
+                        has_normals |= normal_idx.is_some();
+                        indices.push(vertex);
+                    }
+
+                    if indices.len() < 3 {
+                        return Err(malformed("face must have at least 3 vertices"));
+                    }
+                    for i in 1..indices.len() - 1 {
+                        triangles.push([indices[0], indices[i], indices[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let normals = if has_normals {
+            Some(
+                normals
+                    .into_iter()
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| Error::Format("some vertices are missing normals".to_owned()))?,
+            )
+        } else {
+            None
+        };
+
+        Mesh::with_attributes(
+            positions,
+            normals,
+            if has_uvs { Some(uvs) } else { None },
+            triangles,
+        )
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn uvs(&self) -> Option<&[Uv]> {
+        self.uvs.as_deref()
+    }
+
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+
+    fn vertices(&self, tri_idx: usize) -> [Vec3; 3] {
+        let [a, b, c] = self.triangles[tri_idx];
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+}
+
+/// Returns the distance to the intersection of `ray` with a triangle along with its barycentric
+/// coordinates relative to the second and third vertices.
+pub(crate) fn intersect_triangle(ray: &Ray, [p0, p1, p2]: [Vec3; 3]) -> Option<(f64, f64, f64)> {
+    // Möller-Trumbore
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+
+    let dir: Vec3 = ray.dir.into();
+    let pvec = dir.cross(edge2);
+    let det = edge1.dot(pvec);
+    if det.abs() < EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = ray.origin - p0;
+    let u = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let v = dir.dot(qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let dist = edge2.dot(qvec) * inv_det;
+    if dist > EPSILON {
+        Some((dist, u, v))
+    } else {
+        None
+    }
+}
+
+impl Geom for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.bvh
+            .intersect(ray, |tri_idx| {
+                intersect_triangle(ray, self.vertices(tri_idx))
+                    .map(|(dist, b1, b2)| (dist, (tri_idx, b1, b2)))
+            })
+            .map(|(dist, (tri_idx, b1, b2))| {
+                let [a, b, c] = self.triangles[tri_idx];
+                let (a, b, c) = (a as usize, b as usize, c as usize);
+                let b0 = 1.0 - b1 - b2;
+
+                let [p0, p1, p2] = self.vertices(tri_idx);
+                let normal = (p1 - p0).cross(p2 - p0).to_unit();
+
+                let shading_normal = match &self.normals {
+                    Some(normals) => {
+                        let interp = b0 * Vec3::from(normals[a])
+                            + b1 * Vec3::from(normals[b])
+                            + b2 * Vec3::from(normals[c]);
+                        match interp.try_to_unit() {
+                            // Keep the shading normal on the same side as the true normal.
+                            Some(n) if Vec3::from(n).dot(normal.into()) < 0.0 => {
+                                (-Vec3::from(n)).to_unit()
+                            }
+                            Some(n) => n,
+                            None => normal,
+                        }
+                    }
+                    None => normal,
+                };
+
+                let (uv, (uv0, uv1, uv2)) = match &self.uvs {
+                    Some(uvs) => {
+                        let (uv0, uv1, uv2) = (uvs[a], uvs[b], uvs[c]);
+                        (
+                            (
+                                b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+                                b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+                            ),
+                            (uv0, uv1, uv2),
+                        )
+                    }
+                    None => ((b1, b2), ((0.0, 0.0), (1.0, 0.0), (0.0, 1.0))),
+                };
+
+                // Solve for the derivatives of the position with respect to the texture
+                // coordinates along the triangle's edges.
+                let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+                let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+                let det = du1 * dv2 - dv1 * du2;
+                let (dpdu, dpdv) = if det.abs() < EPSILON {
+                    let basis = Basis::from_normal(normal);
+                    (basis.x, basis.y)
+                } else {
+                    let inv_det = 1.0 / det;
+                    let (e1, e2) = (p1 - p0, p2 - p0);
+                    (
+                        (dv2 * e1 - dv1 * e2) * inv_det,
+                        (du1 * e2 - du2 * e1) * inv_det,
+                    )
+                };
+
+                Hit {
+                    dist,
+                    normal,
+                    shading_normal,
+                    uv,
+                    dpdu,
+                    dpdv,
+                }
+            })
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    fn area(&self) -> Option<f64> {
+        self.cumulative_areas.last().copied()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let target = rng.gen::<f64>() * self.area()?;
+        let tri_idx = self
+            .cumulative_areas
+            .partition_point(|&area| area <= target)
+            .min(self.triangles.len() - 1);
+        let [p0, p1, p2] = self.vertices(tri_idx);
+
+        // Uniformly distributed barycentric coordinates
+        let su = rng.gen::<f64>().sqrt();
+        let b1 = su * rng.gen::<f64>();
+        let b2 = 1.0 - su;
+        let b0 = 1.0 - b1 - b2;
+
+        let uv = match &self.uvs {
+            Some(uvs) => {
+                let [a, b, c] = self.triangles[tri_idx].map(|idx| uvs[idx as usize]);
+                (
+                    b0 * a.0 + b1 * b.0 + b2 * c.0,
+                    b0 * a.1 + b1 * b.1 + b2 * c.1,
+                )
+            }
+            None => (b1, b2),
+        };
+
+        Some(SurfaceSample {
+            point: b0 * p0 + b1 * p1 + b2 * p2,
+            normal: (p1 - p0).cross(p2 - p0).try_to_unit()?,
+            uv,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn random_point(rng: &mut StdRng, scale: f64) -> Vec3 {
+        Vec3 {
+            x: rng.gen_range(-scale, scale),
+            y: rng.gen_range(-scale, scale),
+            z: rng.gen_range(-scale, scale),
+        }
+    }
+
+    #[test]
+    fn bvh_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(0xb74);
+        let mut positions = Vec::new();
+        let mut triangles = Vec::new();
+        for tri in 0..200 {
+            let center = random_point(&mut rng, 5.0);
+            for _ in 0..3 {
+                positions.push(center + random_point(&mut rng, 1.5));
+            }
+            let first = 3 * tri;
+            triangles.push([first, first + 1, first + 2]);
+        }
+        let mesh = Mesh::new(positions, triangles).unwrap();
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            // Aim at the cloud of triangles so that most rays hit something.
+            let origin = random_point(&mut rng, 8.0);
+            let ray = Ray {
+                origin,
+                dir: (random_point(&mut rng, 4.0) - origin).to_unit(),
+                time: 0.0,
+            };
+            let expected = (0..mesh.triangles().len())
+                .filter_map(|tri_idx| intersect_triangle(&ray, mesh.vertices(tri_idx)))
+                .map(|(dist, _, _)| dist)
+                .fold(None, |closest: Option<f64>, dist| {
+                    Some(closest.map_or(dist, |closest| closest.min(dist)))
+                });
+            let actual = mesh.intersect(&ray).map(|hit| hit.dist);
+            assert_eq!(actual, expected, "ray {:?}", ray);
+            hits += expected.is_some() as usize;
+        }
+        // Make sure the comparison isn't vacuous.
+        assert!(hits > 500, "only {} rays hit", hits);
+    }
+}
@@ -0,0 +1,323 @@
+use std::f64;
+
+use rand::rngs::StdRng;
+use rand::{Error as RandError, Rng, RngCore, SeedableRng};
+use rayon::prelude::*;
+
+use crate::bdpt::Film;
+use crate::math::*;
+use crate::renderer::{Camera, RenderOptions, Scene};
+
+/// The standard deviation of small steps, as a fraction of the unit interval.
+const SIGMA: f64 = 0.01;
+
+#[derive(Debug, Copy, Clone, Default)]
+struct PrimarySample {
+    value: f64,
+    /// The iteration that last changed the value
+    modified: u64,
+    backup_value: f64,
+    backup_modified: u64,
+}
+
+impl PrimarySample {
+    fn backup(&mut self) {
+        self.backup_value = self.value;
+        self.backup_modified = self.modified;
+    }
+
+    fn restore(&mut self) {
+        self.value = self.backup_value;
+        self.modified = self.backup_modified;
+    }
+}
+
+/// A source of random numbers that replays a vector of uniform samples, which are mutated
+/// between iterations so that paths traced with it explore the space of paths. Samples are
+/// mutated lazily, when they're first used in an iteration. See "A Simple and Robust Mutation
+/// Strategy for the Metropolis Light Transport Algorithm" (Kelemen et al., 2002).
+struct PrimarySampler {
+    /// Draws fresh samples and decides which kind of step to take
+    rng: StdRng,
+    large_step_probability: f64,
+    samples: Vec<PrimarySample>,
+    /// The index of the next sample to be used in the current iteration
+    index: usize,
+    iteration: u64,
+    /// Whether the current iteration replaces every sample rather than perturbing them
+    large_step: bool,
+    last_large_step: u64,
+}
+
+impl PrimarySampler {
+    /// Creates a sampler whose first iteration draws fresh samples from a generator seeded by
+    /// `seed`, so that it traces the same path as any other sampler with the same seed.
+    fn new(seed: u64, large_step_probability: f64) -> PrimarySampler {
+        PrimarySampler {
+            rng: StdRng::seed_from_u64(seed),
+            large_step_probability,
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+        }
+    }
+
+    /// Mutates the samples for the next proposed path.
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    /// Keeps the samples of the proposed path.
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Returns to the samples from before the proposed path.
+    fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.modified == self.iteration {
+                sample.restore();
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next_sample(&mut self) -> f64 {
+        if self.index >= self.samples.len() {
+            self.samples
+                .resize(self.index + 1, PrimarySample::default());
+        }
+
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+
+        // Catch up on the large step that a sample unused since then missed.
+        if sample.modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.modified = self.last_large_step;
+        }
+
+        sample.backup();
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // Apply every small step the sample missed at once, as a single wider one.
+            let steps = (self.iteration - sample.modified) as f64;
+            let normal = standard_normal(&mut self.rng);
+            sample.value += SIGMA * steps.sqrt() * normal;
+
+            // Wrap around the unit interval, where rounding can land exactly on 1.
+            sample.value = (sample.value - sample.value.floor()) % 1.0;
+        }
+        sample.modified = self.iteration;
+        sample.value
+    }
+}
+
+impl RngCore for PrimarySampler {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_sample() * 2f64.powi(32)) as u32
+    }
+
+    // Generated floats take their top 53 bits, which keeps them equal to the samples.
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_sample() * 2f64.powi(53)) as u64) << 11
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), RandError> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+fn standard_normal<R: Rng + ?Sized>(rng: &mut R) -> f64 {
+    let radius = (-2.0 * (1.0 - rng.gen::<f64>()).ln()).sqrt();
+    radius * (2.0 * f64::consts::PI * rng.gen::<f64>()).cos()
+}
+
+struct Tracer<'s> {
+    scene: &'s Scene<'s>,
+    camera: &'s Camera,
+    width: u32,
+    height: u32,
+    max_depth: u32,
+}
+
+impl Tracer<'_> {
+    /// Traces a path through a point on the image chosen by the sampler, returning the point
+    /// and the radiance found.
+    fn evaluate(&self, sampler: &mut PrimarySampler) -> ((f64, f64), Vec3) {
+        let x = sampler.gen::<f64>() * f64::from(self.width);
+        let y = sampler.gen::<f64>() * f64::from(self.height);
+        let time = self.camera.sample_time(sampler.gen());
+        let ray = self.camera.cast_ray(x, y, time);
+        let radiance = self.scene.trace_ray(&ray, sampler, 0, self.max_depth);
+        ((x, y), radiance)
+    }
+
+    /// Runs a Markov chain for `mutations` steps, starting from the path traced with `seed`,
+    /// which must carry some light, and splatting the expected contribution of every step onto
+    /// `film`.
+    fn run_chain(&self, seed: u64, mutations: u64, large_step: f64, film: &Film) {
+        let mut rng = rand::thread_rng();
+        let mut sampler = PrimarySampler::new(seed, large_step);
+        let (mut point, mut radiance) = self.evaluate(&mut sampler);
+        let mut contribution = importance(radiance);
+
+        for _ in 0..mutations {
+            sampler.start_iteration();
+            let (proposed_point, proposed) = self.evaluate(&mut sampler);
+            let proposed_contribution = importance(proposed);
+            let accept = (proposed_contribution / contribution).min(1.0);
+
+            // Splat both paths in proportion to how likely each is to be kept, which lowers
+            // the variance compared to splatting only the one that is.
+            if accept > 0.0 {
+                film.add_splat(proposed_point, accept / proposed_contribution * proposed);
+            }
+            if accept < 1.0 {
+                film.add_splat(point, (1.0 - accept) / contribution * radiance);
+            }
+
+            if rng.gen::<f64>() < accept {
+                point = proposed_point;
+                radiance = proposed;
+                contribution = proposed_contribution;
+                sampler.accept();
+            } else {
+                sampler.reject();
+            }
+        }
+    }
+}
+
+/// Returns the scalar that mutations are distributed in proportion to. Paths with no light
+/// are never accepted once a chain has found one with some.
+fn importance(radiance: Vec3) -> f64 {
+    let value = (radiance.x + radiance.y + radiance.z) / 3.0;
+    if value.is_finite() {
+        value.max(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// Renders with primary sample space Metropolis light transport, normalizing with the mean
+/// brightness of `bootstrap` independent paths and then running `chains` Markov chains that
+/// between them mutate paths `samples_per_pixel` times for every pixel.
+pub(crate) fn render_to(
+    scene: &Scene,
+    camera: &Camera,
+    pixels: &mut [Vec3],
+    opts: &RenderOptions,
+    bootstrap: u32,
+    chains: u32,
+    large_step: f64,
+) {
+    let tracer = Tracer {
+        scene,
+        camera,
+        width: opts.width,
+        height: opts.height,
+        max_depth: opts.max_depth,
+    };
+
+    // Each bootstrap path is seeded by its index, so that chains can start from it.
+    let weights: Vec<_> = (0..bootstrap)
+        .into_par_iter()
+        .map(|seed| {
+            let mut sampler = PrimarySampler::new(u64::from(seed), large_step);
+            importance(tracer.evaluate(&mut sampler).1)
+        })
+        .collect();
+    let mut total = 0.0;
+    let cdf: Vec<_> = weights
+        .iter()
+        .map(|weight| {
+            total += weight;
+            total
+        })
+        .collect();
+    let brightness = total / f64::from(bootstrap);
+
+    let film = Film::new(opts.width, opts.height);
+    if total > 0.0 {
+        let mutations = u64::from(opts.samples_per_pixel) * pixels.len() as u64;
+        let chains = u64::from(chains);
+        (0..chains).into_par_iter().for_each(|chain| {
+            let u = rand::thread_rng().gen::<f64>() * total;
+            let seed = cdf.partition_point(|&value| value <= u).min(cdf.len() - 1);
+            let count = mutations * (chain + 1) / chains - mutations * chain / chains;
+            tracer.run_chain(seed as u64, count, large_step, &film);
+        });
+    }
+
+    let scale = brightness / f64::from(opts.samples_per_pixel);
+    for (idx, pixel) in pixels.iter_mut().enumerate() {
+        *pixel = scale * film.get(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(sampler: &mut PrimarySampler, count: usize) -> Vec<f64> {
+        (0..count).map(|_| sampler.next_sample()).collect()
+    }
+
+    #[test]
+    fn same_seed_replays_same_samples() {
+        let mut first = PrimarySampler::new(7, 0.3);
+        let mut second = PrimarySampler::new(7, 0.3);
+        let values = draw(&mut first, 16);
+        assert_eq!(values, draw(&mut second, 16));
+        assert!(values.iter().all(|value| (0.0..1.0).contains(value)));
+
+        // Floats generated from the sampler are the samples themselves.
+        let mut third = PrimarySampler::new(7, 0.3);
+        let generated: Vec<f64> = (0..16).map(|_| third.gen::<f64>()).collect();
+        assert_eq!(values, generated);
+
+        let mut other = PrimarySampler::new(8, 0.3);
+        assert_ne!(values, draw(&mut other, 16));
+    }
+
+    #[test]
+    fn reject_restores_samples() {
+        for &large_step in &[0.0, 1.0] {
+            let mut sampler = PrimarySampler::new(3, large_step);
+            let original = draw(&mut sampler, 16);
+
+            sampler.start_iteration();
+            let mutated = draw(&mut sampler, 16);
+            assert_ne!(original, mutated);
+            assert!(mutated.iter().all(|value| (0.0..1.0).contains(value)));
+
+            sampler.reject();
+            let restored: Vec<f64> = sampler.samples.iter().map(|sample| sample.value).collect();
+            assert_eq!(original, restored);
+            assert!(sampler.samples.iter().all(|sample| sample.modified == 0));
+
+            // Accepted mutations stick.
+            sampler.start_iteration();
+            let mutated = draw(&mut sampler, 16);
+            sampler.accept();
+            let kept: Vec<f64> = sampler.samples.iter().map(|sample| sample.value).collect();
+            assert_eq!(mutated, kept);
+        }
+    }
+}
@@ -1,22 +1,46 @@
-use std::error;
 use std::f64;
+use std::iter;
+use std::ops::Add;
+use std::sync::{Arc, OnceLock};
 
-use rand::Rng;
+use rand::{Rng, RngCore};
 use rayon::prelude::*;
 
+use crate::animation::{AnimatedTransform, CameraAnimation, Track};
+use crate::bdpt;
+use crate::bsdf::{Bsdf, BsdfSample};
+use crate::bvh::Bvh;
+use crate::debug::{self, DebugView};
+use crate::error::{Error, Result};
 use crate::geom::*;
+use crate::guiding::Guide;
+use crate::img::pixel_count;
+use crate::light::{
+    area_to_solid_angle, sample_surface_emission, surface_emission_pdf, surface_power, AreaLight,
+    EmissionSample, Light, LightBounds, LightSample, LightTree,
+};
 use crate::math::*;
-use crate::sample::*;
+use crate::medium::Medium;
+use crate::mlt;
+use crate::sample::power_heuristic;
+use crate::spectral::{self, Ior};
+use crate::sppm;
+use crate::stats::{self, Collector, Counter, RenderStats};
+use crate::texture::{NormalMap, Param, Uv};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct CameraOptions {
     pub pos: Vec3,
     pub target: Vec3,
     pub up: Vec3,
     pub vert_fov: f64,
+    /// Moves the camera over time, transforming it in world space like an animated instance
+    pub motion: Option<AnimatedTransform>,
+    /// Changes where the camera is, where it looks and how wide, over time
+    pub animation: Option<CameraAnimation>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct RenderOptions {
     pub camera_options: CameraOptions,
     pub width: u32,
@@ -24,42 +48,305 @@ pub struct RenderOptions {
     pub samples_per_pixel: u32,
     pub max_depth: u32,
     pub threads: u32,
+    /// Whether to trace individual wavelengths rather than RGB, so that dispersion is
+    /// reproduced. Material colors are converted to spectra, while media keep using their RGB
+    /// coefficients.
+    pub spectral: bool,
+    pub integrator: Integrator,
+    /// The times the shutter opens and closes. Each ray is traced at a time picked uniformly
+    /// between them, so that anything moving meanwhile is blurred.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+}
+
+/// The algorithm used to estimate the light reaching the camera.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Integrator {
+    /// Traces paths from the camera, sampling lights directly at each bounce.
+    Path,
+    /// Traces paths like `Path`, but learns where light arrives from throughout the scene over
+    /// passes that double in samples per pixel, and samples half of the bounces off
+    /// non-specular surfaces from what it has learned nearby. Finds light that arrives through
+    /// narrow gaps far more often. See "Practical Path Guiding for Efficient Light-Transport
+    /// Simulation" (Müller et al., 2017).
+    Guided,
+    /// Traces paths from both the camera and the lights and connects them, which finds light
+    /// that reaches the camera through small openings or off bright surfaces far more often.
+    /// Participating media are ignored, and spectral rendering isn't supported.
+    Bidirectional,
+    /// Traces photons from the lights and estimates the light they leave around the first
+    /// non-specular surface each camera ray hits, shrinking the gather radius over passes so
+    /// that the estimate converges. Finds caustics seen directly or in mirrors far faster than
+    /// the other integrators. Each sample per pixel is a pass tracing `photons` photons,
+    /// gathered within `radius` at first. Participating media are ignored, spectral rendering
+    /// isn't supported, and light from lights infinitely far away only bounces off the
+    /// scene's finite bounds. See "Stochastic Progressive Photon Mapping" (Hachisuka and
+    /// Jensen, 2009).
+    PhotonMapping { photons: u32, radius: f64 },
+    /// Mutates the random numbers the path tracer draws, so that once a path carrying light is
+    /// found, similar paths are explored around it. Suited to scenes where light only reaches
+    /// the camera along a few hard-to-find paths. The image's brightness is estimated from
+    /// `bootstrap` independent paths, and then `chains` Markov chains mutate paths as many
+    /// times in total as the path tracer would trace them, replacing all the random numbers
+    /// with probability `large_step` and perturbing them otherwise. See "A Simple and Robust
+    /// Mutation Strategy for the Metropolis Light Transport Algorithm" (Kelemen et al., 2002).
+    Metropolis {
+        bootstrap: u32,
+        chains: u32,
+        large_step: f64,
+    },
+    /// Renders a view of the scene's geometry instead of its lighting, for inspecting it.
+    Debug(DebugView),
 }
 
+/// An auxiliary image rendered alongside the image itself, such as for compositing or as
+/// input to a denoiser. Values are averaged over each pixel's samples, so they blend along
+/// edges just as the image does. Depth is averaged over only the samples that hit a surface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aov {
+    /// The albedo of the first surface hit
+    Albedo,
+    /// The shading normal of the first surface hit, facing the camera
+    Normal,
+    /// The distance from the camera to the first surface hit, or infinity if there's none
+    Depth,
+    /// The index of the first primitive hit within the scene, or -1 if there's none
+    PrimitiveId,
+    /// The `id` of the first surface's material, or -1 if there's none
+    MaterialId,
+    /// Light that scattered once on its way from a light to the camera
+    Direct,
+    /// Light that scattered more than once on its way from a light to the camera
+    Indirect,
+    /// Light emitted straight towards the camera
+    Emission,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::PrimitiveId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+    ];
+
+    /// Returns a short lowercase name for the output, such as for naming files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::PrimitiveId => "prim-id",
+            Aov::MaterialId => "material-id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+        }
+    }
+
+    /// Looks up an output by the name `name` returns.
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().copied().find(|aov| aov.name() == name)
+    }
+}
+
+#[derive(Clone)]
 pub struct Camera {
     pos: Vec3,
     u: Unit3,
     v: Unit3,
+    n: Unit3,
+    plane_dist: f64,
     n_with_plane_dist: Vec3,
     aspect_ratio: f64,
     inv_width: f64,
     inv_height: f64,
+    /// The options the camera was made from, if it moves over time
+    moving: Option<Arc<CameraOptions>>,
+    shutter_open: f64,
+    shutter_close: f64,
 }
 
 impl Camera {
-    pub fn new(options: &CameraOptions, width: u32, height: u32) -> Camera {
-        let n = (options.target - options.pos).to_unit();
-        let u = options.up.cross(n.into()).to_unit();
+    pub fn new(options: &CameraOptions, width: u32, height: u32) -> Result<Camera> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidParameter("image dimensions must be nonzero"));
+        }
+
+        let fovs = options
+            .animation
+            .iter()
+            .flat_map(|animation| animation.vert_fov.iter())
+            .flat_map(Track::values);
+        if iter::once(options.vert_fov)
+            .chain(fovs)
+            .any(|fov| fov.is_nan() || fov <= 0.0 || fov >= 180.0)
+        {
+            return Err(Error::InvalidParameter(
+                "vertical field of view must be between 0 and 180 degrees",
+            ));
+        }
+
+        let camera = Camera::look_at(
+            options.pos,
+            options.target,
+            options.up,
+            options.vert_fov,
+            f64::from(width) / f64::from(height),
+        )?;
+        // Keyframes only rotate and translate, which can't squash the view, so checking the
+        // camera at one time covers the whole motion apart from any animation.
+        if let Some(motion) = &options.motion {
+            camera
+                .transformed(&motion.at(0.0))
+                .ok_or(Error::DegenerateCamera(
+                    "motion transform squashes the view",
+                ))?;
+        }
+        let moves = options.motion.is_some() || options.animation.is_some();
+        Ok(Camera {
+            inv_width: 1.0 / f64::from(width),
+            inv_height: 1.0 / f64::from(height),
+            moving: if moves {
+                Some(Arc::new(options.clone()))
+            } else {
+                None
+            },
+            ..camera
+        })
+    }
+
+    /// Places a still camera with a 1x1 image, which `new` resizes.
+    fn look_at(
+        pos: Vec3,
+        target: Vec3,
+        up: Vec3,
+        vert_fov: f64,
+        aspect_ratio: f64,
+    ) -> Result<Camera> {
+        let n = (target - pos)
+            .try_to_unit()
+            .ok_or(Error::DegenerateCamera("target coincides with position"))?;
+        let u = up
+            .cross(n.into())
+            .try_to_unit()
+            .ok_or(Error::DegenerateCamera(
+                "up vector is parallel to view direction",
+            ))?;
         let v = Unit3::from_unit_vec3(Vec3::from(n).cross(u.into()));
 
         // cot(vert_fov/2)
-        let plane_dist = 1.0 / (options.vert_fov * f64::consts::PI / 360.0).tan();
-
-        let fwidth = f64::from(width);
-        let fheight = f64::from(height);
+        let plane_dist = 1.0 / (vert_fov * f64::consts::PI / 360.0).tan();
 
-        Camera {
-            pos: options.pos,
+        Ok(Camera {
+            pos,
             u,
             v,
+            n,
+            plane_dist,
             n_with_plane_dist: plane_dist * Vec3::from(n),
-            aspect_ratio: fwidth / fheight,
-            inv_width: 1.0 / fwidth,
-            inv_height: 1.0 / fheight,
+            aspect_ratio,
+            inv_width: 1.0,
+            inv_height: 1.0,
+            moving: None,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        })
+    }
+
+    /// Sets the times the shutter opens and closes, which `sample_time` picks between.
+    pub fn with_shutter(self, open: f64, close: f64) -> Camera {
+        Camera {
+            shutter_open: open,
+            shutter_close: close,
+            ..self
+        }
+    }
+
+    /// Maps `u` in `[0, 1)` uniformly to a time while the shutter is open.
+    pub fn sample_time(&self, u: f64) -> f64 {
+        self.shutter_open + u * (self.shutter_close - self.shutter_open)
+    }
+
+    /// Returns the time halfway through the exposure.
+    pub fn mid_exposure(&self) -> f64 {
+        self.sample_time(0.5)
+    }
+
+    /// Returns the camera as it is at `time`, standing still there. Where the animation
+    /// places the camera degenerately, such as looking at its own position, the camera stays
+    /// where its options place it instead, and likewise where the motion squashes its view.
+    pub fn at(&self, time: f64) -> Camera {
+        let options = match &self.moving {
+            Some(options) => options,
+            None => return self.clone(),
+        };
+
+        let mut camera = Camera {
+            moving: None,
+            ..self.clone()
+        };
+        if let Some(animation) = &options.animation {
+            let track = |track: &Option<Track<Vec3>>, value| {
+                track.as_ref().map_or(value, |track| track.at(time))
+            };
+            let vert_fov = animation
+                .vert_fov
+                .as_ref()
+                .map_or(options.vert_fov, |track| track.at(time));
+            let placed = Camera::look_at(
+                track(&animation.pos, options.pos),
+                track(&animation.target, options.target),
+                options.up,
+                vert_fov,
+                self.aspect_ratio,
+            );
+            if let Ok(placed) = placed {
+                camera = Camera {
+                    inv_width: self.inv_width,
+                    inv_height: self.inv_height,
+                    shutter_open: self.shutter_open,
+                    shutter_close: self.shutter_close,
+                    ..placed
+                };
+            }
         }
+
+        match &options.motion {
+            Some(motion) => camera.transformed(&motion.at(time)).unwrap_or(camera),
+            None => camera,
+        }
+    }
+
+    /// Moves a still camera by `transform`, failing if it squashes the view so far that the
+    /// camera's axes can't be recovered.
+    fn transformed(&self, transform: &Transform) -> Option<Camera> {
+        // Keep the axes orthonormal even if the transform scales or shears.
+        let n = transform.apply_vector(self.n.into()).try_to_unit()?;
+        let u = transform.apply_vector(self.u.into());
+        let u = (u - u.dot(n.into()) * Vec3::from(n)).try_to_unit()?;
+        let v = Unit3::from_unit_vec3(Vec3::from(n).cross(u.into()));
+        Some(Camera {
+            pos: transform.apply_point(self.pos),
+            u,
+            v,
+            n,
+            n_with_plane_dist: self.plane_dist * Vec3::from(n),
+            ..self.clone()
+        })
     }
 
-    pub fn cast_ray(&self, pixel_x: f64, pixel_y: f64) -> Ray {
+    /// Casts a ray at `time` through the point `(pixel_x, pixel_y)` on the image.
+    pub fn cast_ray(&self, pixel_x: f64, pixel_y: f64, time: f64) -> Ray {
+        if self.moving.is_some() {
+            return self.at(time).cast_ray(pixel_x, pixel_y, time);
+        }
+        stats::add(Counter::CameraRays, 1);
+
         // Map to [-1, 1]
         let ndc_x = 2.0 * (pixel_x * self.inv_width) - 1.0;
         let ndc_y = 2.0 * (pixel_y * self.inv_height) - 1.0;
@@ -71,50 +358,167 @@ impl Camera {
         Ray {
             origin: self.pos,
             dir,
+            time,
         }
     }
+
+    pub fn position(&self) -> Vec3 {
+        self.pos
+    }
+
+    /// Returns the direction the camera looks in.
+    pub fn direction(&self) -> Unit3 {
+        self.n
+    }
+
+    /// Returns the area of the image plane at unit distance from the camera.
+    fn image_area(&self) -> f64 {
+        4.0 * self.aspect_ratio / (self.plane_dist * self.plane_dist)
+    }
+
+    /// Returns the pixel coordinates that a ray leaving the camera along `dir` passes through,
+    /// or `None` if it's outside the image.
+    pub fn project(&self, dir: Unit3) -> Option<(f64, f64)> {
+        let dir = Vec3::from(dir);
+        let cos_theta = dir.dot(self.n.into());
+        if cos_theta <= 0.0 {
+            return None;
+        }
+
+        let scale = self.plane_dist / cos_theta;
+        let ndc_x = -dir.dot(self.u.into()) * scale / self.aspect_ratio;
+        let ndc_y = -dir.dot(self.v.into()) * scale;
+        if ndc_x.abs() > 1.0 || ndc_y.abs() > 1.0 {
+            return None;
+        }
+
+        Some((
+            (ndc_x + 1.0) / (2.0 * self.inv_width),
+            (ndc_y + 1.0) / (2.0 * self.inv_height),
+        ))
+    }
+
+    /// Returns the probability density, with respect to solid angle, of `cast_ray` producing a
+    /// ray along `dir` when given a uniformly random point on the image.
+    pub fn pdf(&self, dir: Unit3) -> f64 {
+        if self.project(dir).is_none() {
+            return 0.0;
+        }
+        let cos_theta = Vec3::from(dir).dot(self.n.into());
+        1.0 / (self.image_area() * cos_theta * cos_theta * cos_theta)
+    }
+
+    /// Returns the camera's sensitivity to light arriving back along `dir`. This is normalized
+    /// over the whole image, so light traced to the camera from the scene adds to a pixel in
+    /// proportion to the number of pixels.
+    pub fn importance(&self, dir: Unit3) -> f64 {
+        let cos_theta = Vec3::from(dir).dot(self.n.into());
+        self.pdf(dir) / cos_theta
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Material {
-    pub emittance: Vec3,
-    pub albedo: Vec3,
-    pub reflectance: f64,
-    pub gloss: f64,
+    pub emittance: Param<Vec3>,
+    pub albedo: Param<Vec3>,
+    pub reflectance: Param<f64>,
+    pub gloss: Param<f64>,
+    pub normal_map: Option<NormalMap>,
+    /// Whether the surface only marks the boundary of a medium, letting light pass straight
+    /// through it.
+    pub interface: bool,
+    /// Makes the surface a smooth dielectric such as glass, which reflects or refracts light
+    /// tinted by `albedo`.
+    pub dielectric: Option<Ior>,
+    /// Whether the surface emits light from its back as well as its front. Hits from inside a
+    /// solid count as hitting its back.
+    pub two_sided: bool,
+    /// Identifies the material in the material ID output, for telling surfaces apart when
+    /// compositing.
+    pub id: u32,
 }
 
 impl Material {
-    pub fn make_light(color: Vec3) -> Material {
+    pub fn make_light<C: Into<Param<Vec3>>>(color: C) -> Material {
+        Material {
+            emittance: color.into(),
+            albedo: Vec3::default().into(),
+            reflectance: 0.0.into(),
+            gloss: 0.0.into(),
+            normal_map: None,
+            interface: false,
+            dielectric: None,
+            two_sided: true,
+            id: 0,
+        }
+    }
+
+    pub fn make_diffuse<C: Into<Param<Vec3>>>(color: C) -> Material {
+        Material {
+            emittance: Vec3::default().into(),
+            albedo: color.into(),
+            reflectance: 0.0.into(),
+            gloss: 0.0.into(),
+            normal_map: None,
+            interface: false,
+            dielectric: None,
+            two_sided: true,
+            id: 0,
+        }
+    }
+
+    pub fn make_reflective<C, R, G>(color: C, reflectance: R, gloss: G) -> Material
+    where
+        C: Into<Param<Vec3>>,
+        R: Into<Param<f64>>,
+        G: Into<Param<f64>>,
+    {
+        Material {
+            emittance: Vec3::default().into(),
+            albedo: color.into(),
+            reflectance: reflectance.into(),
+            gloss: gloss.into(),
+            normal_map: None,
+            interface: false,
+            dielectric: None,
+            two_sided: true,
+            id: 0,
+        }
+    }
+
+    pub fn make_dielectric<C: Into<Param<Vec3>>>(color: C, ior: Ior) -> Material {
         Material {
-            emittance: color,
-            albedo: Vec3::default(),
-            reflectance: 0.0,
-            gloss: 0.0,
+            dielectric: Some(ior),
+            ..Material::make_diffuse(color)
         }
     }
 
-    pub fn make_diffuse(color: Vec3) -> Material {
+    /// Creates an invisible material for primitives that only serve to contain a medium.
+    pub fn make_interface() -> Material {
         Material {
-            emittance: Vec3::default(),
-            albedo: color,
-            reflectance: 0.0,
-            gloss: 0.0,
+            interface: true,
+            ..Material::make_diffuse(Vec3::default())
         }
     }
 
-    pub fn make_reflective(color: Vec3, reflectance: f64, gloss: f64) -> Material {
+    pub fn with_normal_map(self, normal_map: NormalMap) -> Material {
         Material {
-            emittance: Vec3::default(),
-            albedo: color,
-            reflectance,
-            gloss,
+            normal_map: Some(normal_map),
+            ..self
         }
     }
+
+    pub fn with_id(self, id: u32) -> Material {
+        Material { id, ..self }
+    }
 }
 
 pub struct Primitive<'a> {
     geom: Box<dyn Geom + 'a>,
     material: Material,
+    interior: Option<Arc<dyn Medium + 'a>>,
+    /// The index of the area light the primitive is the surface of, within the scene's lights
+    light: Option<usize>,
 }
 
 impl<'a> Primitive<'a> {
@@ -122,6 +526,17 @@ impl<'a> Primitive<'a> {
         Primitive {
             geom: Box::new(geom),
             material,
+            interior: None,
+            light: None,
+        }
+    }
+
+    /// Fills the primitive with a medium, which rays enter when they pass through its surface.
+    /// The geometry should be a closed solid, and the material usually an interface.
+    pub fn with_interior(self, medium: Arc<dyn Medium + 'a>) -> Primitive<'a> {
+        Primitive {
+            interior: Some(medium),
+            ..self
         }
     }
 
@@ -132,134 +547,1010 @@ impl<'a> Primitive<'a> {
     pub fn material(&self) -> &Material {
         &self.material
     }
+
+    pub fn interior(&self) -> Option<&dyn Medium> {
+        self.interior.as_deref()
+    }
+}
+
+/// Geometry along with a default material, which can be placed in a scene many times without
+/// duplicating the geometry.
+#[derive(Clone)]
+pub struct Asset<'a> {
+    geom: Arc<dyn Geom + 'a>,
+    material: Material,
+}
+
+impl<'a> Asset<'a> {
+    pub fn new<G: Geom + 'a>(geom: G, material: Material) -> Asset<'a> {
+        Asset {
+            geom: Arc::new(geom),
+            material,
+        }
+    }
+
+    pub fn geom(&self) -> &Arc<dyn Geom + 'a> {
+        &self.geom
+    }
+
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+
+    /// Creates a primitive placing the asset with `transform`, optionally overriding its
+    /// material.
+    pub fn instantiate(&self, transform: Transform, material: Option<Material>) -> Primitive<'a> {
+        Primitive::new(
+            Instance::new(self.geom.clone(), transform),
+            material.unwrap_or_else(|| self.material.clone()),
+        )
+    }
+}
+
+/// What the three components of the radiance carried along a path represent.
+#[derive(Debug, Copy, Clone)]
+enum Channels {
+    Rgb,
+    /// Radiance at three wavelengths, with the hero wavelength first. Once dispersion has split
+    /// the path, only the hero wavelength carries light.
+    Spectral {
+        wavelengths: Vec3,
+        hero_only: bool,
+    },
+}
+
+impl Channels {
+    /// Converts a color from a material into the values it takes in each channel.
+    fn color(self, rgb: Vec3) -> Vec3 {
+        match self {
+            Channels::Rgb => rgb,
+            Channels::Spectral { wavelengths, .. } => spectral::uplift(rgb, wavelengths),
+        }
+    }
+
+    /// Returns the index of refraction that light follows through a dielectric, along with the
+    /// weight and channels for the rest of the path.
+    fn refract(self, ior: &Ior) -> (f64, Vec3, Channels) {
+        let unit = Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        match self {
+            Channels::Rgb => (ior.eval(spectral::REFERENCE_WAVELENGTH), unit, self),
+            Channels::Spectral {
+                wavelengths,
+                hero_only,
+            } => {
+                let n = ior.eval(wavelengths.x);
+                if hero_only || !ior.is_dispersive() {
+                    (n, unit, self)
+                } else {
+                    // The other wavelengths would refract in other directions, so drop them and
+                    // let the hero stand in for all three.
+                    let weight = Vec3 {
+                        x: 3.0,
+                        y: 0.0,
+                        z: 0.0,
+                    };
+                    let channels = Channels::Spectral {
+                        wavelengths,
+                        hero_only: true,
+                    };
+                    (n, weight, channels)
+                }
+            }
+        }
+    }
 }
 
-struct IntersectionInfo<'a> {
+/// The state carried along a path as it is traced through the scene.
+#[derive(Copy, Clone)]
+struct PathState<'m> {
+    /// The medium the path is currently travelling through
+    medium: Option<&'m dyn Medium>,
+    channels: Channels,
+    /// The number of bounces so far
+    depth: u32,
+    max_depth: u32,
+    /// Where the path last scattered, if the direction it left in was sampled from a
+    /// non-specular lobe. Used to weight light found by hitting an area light against sampling
+    /// it directly.
+    last_scatter: Option<Scatter>,
+    /// Learns where light arrives from, and guides bounces towards it
+    guide: Option<&'m Guide>,
+    /// The time the path is traced at, shared by every ray along it
+    time: f64,
+}
+
+#[derive(Copy, Clone)]
+struct Scatter {
+    point: Vec3,
+    /// The surface normal, or `None` in a medium
+    normal: Option<Unit3>,
+    /// The probability density of the direction the path left in
+    pdf: f64,
+}
+
+impl<'m> PathState<'m> {
+    /// Returns the state after another bounce.
+    fn deeper(self) -> PathState<'m> {
+        PathState {
+            depth: self.depth + 1,
+            ..self
+        }
+    }
+}
+
+/// Radiance found along a path, split by how many times the light scattered before reaching
+/// the start of the path.
+#[derive(Debug, Copy, Clone, Default)]
+struct PathRadiance {
+    emitted: Vec3,
+    /// Light that scattered once
+    direct: Vec3,
+    /// Light that scattered more than once
+    indirect: Vec3,
+    /// The first surface the path reached, if it started at the camera and didn't scatter in
+    /// a medium before reaching one
+    first_hit: Option<FirstHit>,
+}
+
+/// The first surface along a camera path, for auxiliary outputs.
+#[derive(Debug, Copy, Clone)]
+struct FirstHit {
+    albedo: Vec3,
+    /// The shading normal, facing the camera
+    normal: Vec3,
+    /// The distance from the camera
+    depth: f64,
+    prim_index: usize,
+    material_id: u32,
+}
+
+impl PathRadiance {
+    /// Returns `value` found by a path that has bounced `depth` times since its start.
+    fn found(depth: u32, value: Vec3) -> PathRadiance {
+        match depth {
+            0 => PathRadiance {
+                emitted: value,
+                ..PathRadiance::default()
+            },
+            1 => PathRadiance {
+                direct: value,
+                ..PathRadiance::default()
+            },
+            _ => PathRadiance {
+                indirect: value,
+                ..PathRadiance::default()
+            },
+        }
+    }
+
+    fn total(&self) -> Vec3 {
+        self.emitted + self.direct + self.indirect
+    }
+
+    fn scaled(self, weight: Vec3) -> PathRadiance {
+        self.map(|value| weight.component_mul(value))
+    }
+
+    fn map<F: Fn(Vec3) -> Vec3>(self, f: F) -> PathRadiance {
+        PathRadiance {
+            emitted: f(self.emitted),
+            direct: f(self.direct),
+            indirect: f(self.indirect),
+            first_hit: self.first_hit,
+        }
+    }
+}
+
+impl Add for PathRadiance {
+    type Output = PathRadiance;
+
+    fn add(self, rhs: PathRadiance) -> PathRadiance {
+        PathRadiance {
+            emitted: self.emitted + rhs.emitted,
+            direct: self.direct + rhs.direct,
+            indirect: self.indirect + rhs.indirect,
+            first_hit: self.first_hit.or(rhs.first_hit),
+        }
+    }
+}
+
+/// Counts a path from the camera ending for `reason` after scattering `depth` times.
+fn end_path(reason: Counter, depth: u32) {
+    stats::add(reason, 1);
+    stats::add(Counter::PathLength, u64::from(depth));
+}
+
+/// The fraction of bounces that follow a direction sampled from what a guide has learned,
+/// rather than from the BSDF.
+const GUIDE_FRACTION: f64 = 0.5;
+
+/// The fraction of the distance to a sampled light that shadow rays stop short by.
+pub(crate) const SHADOW_TOLERANCE: f64 = 1e-6;
+
+pub struct IntersectionInfo<'a> {
     pub prim: &'a Primitive<'a>,
+    /// The index of `prim` within the scene
+    pub prim_index: usize,
+    pub dist: f64,
     pub point: Vec3,
+    /// The geometric normal, facing the incoming ray
     pub normal: Unit3,
+    /// The normal used for shading, on the same side of the surface as `normal`
+    pub shading_normal: Unit3,
+    pub uv: Uv,
     pub inside: bool,
+    /// The time of the ray that hit the surface
+    pub time: f64,
+}
+
+/// A primitive with an emissive material, which is sampled as a light by picking points
+/// uniformly over its surface. Primitives whose geometry doesn't support sampling, such as
+/// cuboids and CSG solids, aren't lights.
+pub(crate) struct Emitter<'s> {
+    geom: &'s dyn Geom,
+    emittance: &'s Param<Vec3>,
+    two_sided: bool,
+    area: f64,
+}
+
+impl<'s> Emitter<'s> {
+    /// Returns the primitive as a light, if its material emits light and points can be sampled
+    /// on its surface.
+    fn new(prim: &'s Primitive) -> Option<Emitter<'s>> {
+        let material = prim.material();
+        let emits = match &material.emittance {
+            Param::Const(emittance) => emittance.mag_squared() > 0.0,
+            Param::Texture(_) => true,
+        };
+        if !emits || material.interface {
+            return None;
+        }
+
+        let area = prim.geom().area().filter(|&area| area > 0.0)?;
+        Some(Emitter {
+            geom: prim.geom(),
+            emittance: &material.emittance,
+            two_sided: material.two_sided,
+            area,
+        })
+    }
+}
+
+impl<'s> Light for Emitter<'s> {
+    fn sample(&self, point: Vec3, _time: f64, rng: &mut dyn RngCore) -> Option<LightSample> {
+        let surface = self.geom.sample_surface(rng)?;
+        let offset = surface.point - point;
+        let dist = offset.mag();
+        let dir = offset.try_to_unit()?;
+        let pdf = area_to_solid_angle(dir, dist, surface.normal, self.area, self.two_sided);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            dir,
+            dist,
+            radiance: self.emittance.eval(surface.uv, surface.point) / pdf,
+            pdf,
+            delta: false,
+            normal: Some(surface.normal),
+        })
+    }
+
+    fn pdf(&self, point: Vec3, dir: Unit3, time: f64) -> f64 {
+        self.geom
+            .intersect(&Ray {
+                origin: point,
+                dir,
+                time,
+            })
+            .map_or(0.0, |hit| {
+                area_to_solid_angle(dir, hit.dist, hit.normal, self.area, self.two_sided)
+            })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        // Textured emitters are estimated from their emittance at a single point.
+        let bounds = self.geom.bounds();
+        let emittance = self.emittance.eval((0.5, 0.5), bounds.centroid());
+        Some(LightBounds {
+            bounds,
+            axis: Unit3::from_unit_vec3(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+            normal_angle: f64::consts::PI,
+            emission_angle: f64::consts::FRAC_PI_2,
+            power: surface_power(emittance, self.area, self.two_sided),
+            two_sided: self.two_sided,
+        })
+    }
+
+    fn sample_emission(&self, _: &Aabb, rng: &mut dyn RngCore) -> Option<EmissionSample> {
+        let surface = self.geom.sample_surface(rng)?;
+        let radiance = self.emittance.eval(surface.uv, surface.point);
+        sample_surface_emission(&surface, radiance, self.area, self.two_sided, rng)
+    }
+
+    fn pdf_emission(&self, _: Vec3, normal: Option<Unit3>, dir: Unit3, _: &Aabb) -> (f64, f64) {
+        normal.map_or((0.0, 0.0), |normal| {
+            surface_emission_pdf(normal, dir, self.area, self.two_sided)
+        })
+    }
 }
 
+/// One of the lights sampled in a scene: either one added explicitly, or an emissive primitive.
+pub(crate) enum SceneLight<'s> {
+    Explicit(&'s dyn Light),
+    Emitter(Emitter<'s>),
+}
+
+impl<'s> SceneLight<'s> {
+    pub(crate) fn get(&self) -> &dyn Light {
+        match self {
+            SceneLight::Explicit(light) => *light,
+            SceneLight::Emitter(emitter) => emitter,
+        }
+    }
+}
+
+/// Returns the BSDF at a surface hit, along with the weight and channels for the rest of the path
+/// as given by `Channels::refract` for dielectrics.
+fn surface_bsdf(info: &IntersectionInfo, channels: Channels) -> (Bsdf, Vec3, Channels) {
+    let material = info.prim.material();
+    let albedo = material.albedo.eval(info.uv, info.point);
+
+    match &material.dielectric {
+        Some(ior) => {
+            let (n, weight, channels) = channels.refract(ior);
+            // The ratio of the index of refraction on the incident side to that on the far
+            // side
+            let eta = if info.inside { n } else { 1.0 / n };
+            let bsdf = Bsdf::dielectric(
+                info.normal,
+                info.shading_normal,
+                eta,
+                channels.color(albedo),
+            );
+            (bsdf, weight, channels)
+        }
+        None => {
+            let bsdf = Bsdf::standard(
+                info.normal,
+                info.shading_normal,
+                channels.color(albedo),
+                material.reflectance.eval(info.uv, info.point),
+                material.gloss.eval(info.uv, info.point),
+            );
+            let unit = Vec3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            };
+            (bsdf, unit, channels)
+        }
+    }
+}
+
+/// Returns the BSDF at a surface hit, for light traced in RGB.
+pub(crate) fn rgb_bsdf(info: &IntersectionInfo) -> Bsdf {
+    surface_bsdf(info, Channels::Rgb).0
+}
+
+/// Top-level acceleration structures over the primitives and lights in a scene.
+struct SceneAccel {
+    bvh: Bvh,
+    /// Indices of the primitives in the hierarchy, in the order they were given to it
+    bounded: Vec<usize>,
+    /// Indices of primitives with infinite bounds, which are always tested
+    unbounded: Vec<usize>,
+    /// Indices of emissive primitives that are sampled as lights, which are numbered after the
+    /// scene's explicit lights
+    emitters: Vec<usize>,
+    /// The light that each primitive is the surface of, if any
+    prim_lights: Vec<Option<usize>>,
+    light_tree: LightTree,
+}
+
+impl SceneAccel {
+    fn new(primitives: &[Primitive], lights: &[Box<dyn Light + '_>]) -> SceneAccel {
+        let mut bounds = Vec::new();
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+
+        for (idx, prim) in primitives.iter().enumerate() {
+            let prim_bounds = prim.geom().bounds();
+            if prim_bounds.is_finite() {
+                bounds.push(prim_bounds);
+                bounded.push(idx);
+            } else {
+                unbounded.push(idx);
+            }
+        }
+
+        let mut light_bounds: Vec<_> = lights.iter().map(|light| light.bounds()).collect();
+        let mut emitters = Vec::new();
+        let mut prim_lights = Vec::with_capacity(primitives.len());
+        for (idx, prim) in primitives.iter().enumerate() {
+            if prim.light.is_some() {
+                prim_lights.push(prim.light);
+            } else if let Some(emitter) = Emitter::new(prim) {
+                prim_lights.push(Some(light_bounds.len()));
+                light_bounds.push(emitter.bounds());
+                emitters.push(idx);
+            } else {
+                prim_lights.push(None);
+            }
+        }
+
+        SceneAccel {
+            bvh: Bvh::new(&bounds),
+            bounded,
+            unbounded,
+            emitters,
+            prim_lights,
+            light_tree: LightTree::new(&light_bounds),
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Scene<'a> {
     primitives: Vec<Primitive<'a>>,
+    /// The medium filling the space outside of all primitives
+    medium: Option<Arc<dyn Medium + 'a>>,
+    /// Lights that are sampled directly rather than being hit by rays
+    lights: Vec<Box<dyn Light + 'a>>,
+    /// How the brightness of each of `lights` changes over time, if it does
+    light_intensities: Vec<Option<Track<f64>>>,
+    // Built lazily on first use, as primitives may be added one at a time
+    accel: OnceLock<SceneAccel>,
 }
 
 impl<'a> Scene<'a> {
     pub fn new() -> Scene<'a> {
-        Scene { primitives: vec![] }
+        Scene::default()
     }
 
     pub fn with_primitives(primitives: Vec<Primitive<'a>>) -> Scene<'a> {
-        Scene { primitives }
+        Scene {
+            primitives,
+            medium: None,
+            lights: Vec::new(),
+            light_intensities: Vec::new(),
+            accel: OnceLock::new(),
+        }
+    }
+
+    /// Fills the space between primitives with a medium, such as fog.
+    pub fn with_medium(self, medium: Arc<dyn Medium + 'a>) -> Scene<'a> {
+        Scene {
+            medium: Some(medium),
+            ..self
+        }
+    }
+
+    pub fn medium(&self) -> Option<&dyn Medium> {
+        self.medium.as_deref()
     }
 
-    pub fn primitives(&self) -> &[Primitive] {
+    /// Adds a light to the scene, which illuminates surfaces and media through shadow rays.
+    ///
+    /// Primitives with emissive materials are also sampled as lights, as long as their
+    /// geometry supports sampling points on its surface.
+    pub fn add_light<L: Light + 'a>(&mut self, light: L) {
+        self.lights.push(Box::new(light));
+        self.light_intensities.push(None);
+        self.accel.take();
+    }
+
+    /// Scales the brightness of the light with the given index in `lights` by `intensity`
+    /// over time. Area lights are scaled along with their surfaces.
+    pub fn animate_light(&mut self, idx: usize, intensity: Track<f64>) -> Result<()> {
+        if intensity
+            .values()
+            .any(|value| !(value >= 0.0 && value.is_finite()))
+        {
+            return Err(Error::InvalidParameter(
+                "light intensity must be nonnegative and finite",
+            ));
+        }
+        match self.light_intensities.get_mut(idx) {
+            Some(slot) => {
+                *slot = Some(intensity);
+                Ok(())
+            }
+            None => Err(Error::InvalidParameter("no light with that index")),
+        }
+    }
+
+    /// Adds a light to the scene, as with `add_light`.
+    pub fn with_light<L: Light + 'a>(mut self, light: L) -> Scene<'a> {
+        self.add_light(light);
+        self
+    }
+
+    /// Adds an area light to the scene, along with a primitive for its emitting surface.
+    pub fn add_area_light<L: AreaLight + 'a>(&mut self, light: L) {
+        let material = Material {
+            two_sided: light.is_two_sided(),
+            ..Material::make_light(light.radiance())
+        };
+        let primitive = Primitive {
+            light: Some(self.lights.len()),
+            ..Primitive::new(light.geom(), material)
+        };
+        self.add_primitive(primitive);
+        self.add_light(light);
+    }
+
+    /// Adds an area light to the scene, as with `add_area_light`.
+    pub fn with_area_light<L: AreaLight + 'a>(mut self, light: L) -> Scene<'a> {
+        self.add_area_light(light);
+        self
+    }
+
+    pub fn lights(&self) -> &[Box<dyn Light + 'a>] {
+        self.lights.as_slice()
+    }
+
+    pub fn primitives(&self) -> &[Primitive<'a>] {
         self.primitives.as_slice()
     }
 
     pub fn add_primitive(&mut self, primitive: Primitive<'a>) {
         self.primitives.push(primitive);
+        self.accel.take();
     }
 
-    fn intersect(&'a self, ray: &Ray) -> Option<IntersectionInfo<'a>> {
-        let mut intersected = None;
-        for prim in self.primitives() {
-            if let Some(dist) = prim.geom().intersect(ray) {
-                match intersected {
-                    Some((_, min_dist)) => {
-                        if dist < min_dist {
-                            intersected = Some((prim, dist));
-                        }
-                    }
-                    None => {
-                        intersected = Some((prim, dist));
-                    }
+    /// Places `asset` in the scene with the specified transform, optionally overriding its
+    /// material.
+    pub fn add_instance(
+        &mut self,
+        asset: &Asset<'a>,
+        transform: Transform,
+        material: Option<Material>,
+    ) {
+        self.add_primitive(asset.instantiate(transform, material));
+    }
+
+    fn accel(&self) -> &SceneAccel {
+        self.accel
+            .get_or_init(|| SceneAccel::new(&self.primitives, &self.lights))
+    }
+
+    /// Returns the number of lights sampled in the scene, including emissive primitives.
+    pub(crate) fn light_count(&self) -> usize {
+        self.lights.len() + self.accel().emitters.len()
+    }
+
+    /// Returns the light with the given index, counting the scene's explicit lights followed
+    /// by its emitters.
+    pub(crate) fn light(&self, idx: usize) -> SceneLight<'_> {
+        match self.lights.get(idx) {
+            Some(light) => SceneLight::Explicit(light.as_ref()),
+            None => {
+                let prim = &self.primitives[self.accel().emitters[idx - self.lights.len()]];
+                SceneLight::Emitter(Emitter::new(prim).unwrap())
+            }
+        }
+    }
+
+    /// Returns how much the light with the given index is scaled by its animation at `time`.
+    pub(crate) fn light_scale(&self, idx: usize, time: f64) -> f64 {
+        self.light_intensities
+            .get(idx)
+            .and_then(Option::as_ref)
+            .map_or(1.0, |intensity| intensity.at(time))
+    }
+
+    /// Returns how much the light emitted from a surface hit is scaled by the animation of the
+    /// light it's the surface of.
+    pub(crate) fn emission_scale(&self, info: &IntersectionInfo) -> f64 {
+        self.prim_light(info.prim_index)
+            .map_or(1.0, |idx| self.light_scale(idx, info.time))
+    }
+
+    /// Returns the index of the light that the primitive with the given index is the surface
+    /// of, if any.
+    pub(crate) fn prim_light(&self, prim_index: usize) -> Option<usize> {
+        self.accel().prim_lights[prim_index]
+    }
+
+    /// Returns the bounds of all primitives with finite bounds.
+    pub(crate) fn finite_bounds(&self) -> Aabb {
+        let accel = self.accel();
+        accel.bounded.iter().fold(Aabb::empty(), |bounds, &idx| {
+            bounds.union(self.primitives[idx].geom().bounds())
+        })
+    }
+
+    /// Finds the closest surface along `ray` that isn't an interface.
+    pub(crate) fn intersect_surface(&'a self, ray: &Ray) -> Option<IntersectionInfo<'a>> {
+        let mut ray = *ray;
+        loop {
+            let info = self.intersect(&ray)?;
+            if !info.prim.material().interface {
+                return Some(info);
+            }
+            ray.origin = info.point;
+        }
+    }
+
+    pub(crate) fn intersect(&'a self, ray: &Ray) -> Option<IntersectionInfo<'a>> {
+        let accel = self.accel();
+
+        let mut closest = accel.bvh.intersect(ray, |idx| {
+            let prim_index = accel.bounded[idx];
+            let prim = &self.primitives[prim_index];
+            prim.geom()
+                .intersect(ray)
+                .map(|hit| (hit.dist, (prim_index, hit)))
+        });
+
+        stats::add(Counter::IntersectionTests, accel.unbounded.len() as u64);
+        for &idx in &accel.unbounded {
+            let prim = &self.primitives[idx];
+            if let Some(hit) = prim.geom().intersect(ray) {
+                if closest
+                    .as_ref()
+                    .is_none_or(|(min_dist, _)| hit.dist < *min_dist)
+                {
+                    closest = Some((hit.dist, (idx, hit)));
                 }
             }
         }
-        intersected.map(|(prim, dist)| {
-            let point = ray.interp(dist);
-            let normal = prim.geom().normal_at(point);
+
+        closest.map(|(_, (prim_index, hit))| {
+            let prim = &self.primitives[prim_index];
+            let point = ray.interp(hit.dist);
+            let normal = hit.normal;
             // Note: == 0 means tangent, still outside.
             let inside = Vec3::from(normal).dot(ray.dir.into()) > 0.0;
+
+            let mut shading_normal = match &prim.material().normal_map {
+                Some(map) => map.perturb(hit.shading_normal, hit.dpdu, hit.dpdv, hit.uv, point),
+                None => hit.shading_normal,
+            };
+
+            let flip = |n: Unit3| {
+                if inside {
+                    (-Vec3::from(n)).to_unit()
+                } else {
+                    n
+                }
+            };
+            let normal = flip(normal);
+            shading_normal = flip(shading_normal);
+
+            // A shading normal facing away from the viewer can't be shaded sensibly, so fall
+            // back to the true normal.
+            if Vec3::from(shading_normal).dot(ray.dir.into()) >= 0.0 {
+                shading_normal = normal;
+            }
+
             IntersectionInfo {
                 prim,
+                prim_index,
+                dist: hit.dist,
                 point,
-                normal: if inside {
-                    (-Vec3::from(normal)).to_unit()
-                } else {
-                    normal
-                },
+                normal,
+                shading_normal,
+                uv: hit.uv,
                 inside,
+                time: ray.time,
             }
         })
     }
 
+    /// Shades a non-emissive surface hit, adding light sampled directly from the scene's lights
+    /// to the light found by continuing the path in a direction sampled from the BSDF.
     fn trace_reflection<R: Rng + ?Sized>(
         &self,
         ray: &Ray,
         info: &IntersectionInfo,
+        path: PathState,
         rng: &mut R,
-        depth: u32,
-        max_depth: u32,
-    ) -> Vec3 {
-        let material = info.prim.material();
+    ) -> PathRadiance {
+        let (bsdf, weight, channels) = surface_bsdf(info, path.channels);
+        let path = PathState { channels, ..path };
 
-        if material.reflectance > 0.0 && rng.gen::<f64>() < material.reflectance {
-            let alpha = (1.0 - material.gloss) * f64::consts::FRAC_PI_2;
-            let cos_alpha = alpha.cos();
+        let wo = (-Vec3::from(ray.dir)).to_unit();
 
-            let ray_dir: Vec3 = ray.dir.into();
-            let normal: Vec3 = info.normal.into();
+        // Guided directions are mixed with the BSDF's non-specular lobes, so their combined
+        // density weighs them against sampling the lights.
+        let region = path
+            .guide
+            .filter(|_| !bsdf.is_specular())
+            .map(|guide| guide.region(info.point));
+        let guided = region.filter(|region| region.is_trained());
+        let scatter_pdf = |wi| match guided {
+            Some(region) => {
+                GUIDE_FRACTION * region.pdf(wi) + (1.0 - GUIDE_FRACTION) * bsdf.pdf(wo, wi)
+            }
+            None => bsdf.pdf(wo, wi),
+        };
 
-            let reflection_dir = ray_dir - 2.0 * ray_dir.dot(normal) * normal;
+        let direct = if bsdf.is_specular() {
+            Vec3::default()
+        } else {
+            self.sample_lights(
+                info.point,
+                Some(info.normal),
+                path,
+                |wi| (bsdf.eval(wo, wi), scatter_pdf(wi)),
+                rng,
+            )
+        };
+        let radiance = PathRadiance::found(path.depth, direct);
 
-            let dir = sample_uniform_cone(Unit3::from_unit_vec3(reflection_dir), alpha, rng);
-            let cos_theta = Vec3::from(dir).dot(normal);
+        let sample = match guided {
+            Some(region) if rng.gen::<f64>() < GUIDE_FRACTION => {
+                let dir = region.sample(rng);
+                let pdf = scatter_pdf(dir);
+                let value = bsdf.eval(wo, dir);
+                Some(BsdfSample {
+                    dir,
+                    weight: value / pdf,
+                    pdf,
+                    specular: false,
+                    transmitted: false,
+                })
+                .filter(|_| pdf > 0.0 && value.mag_squared() > 0.0)
+            }
+            Some(_) => bsdf.sample(wo, rng).map(|sample| {
+                if sample.specular {
+                    // Only the BSDF can choose specular lobes.
+                    BsdfSample {
+                        weight: sample.weight / (1.0 - GUIDE_FRACTION),
+                        ..sample
+                    }
+                } else {
+                    let pdf = scatter_pdf(sample.dir);
+                    BsdfSample {
+                        weight: bsdf.eval(wo, sample.dir) / pdf,
+                        pdf,
+                        ..sample
+                    }
+                }
+            }),
+            None => bsdf.sample(wo, rng),
+        };
 
-            if cos_theta < 0.0 {
-                // TODO: take into account in PDF, BDRF?
-                return Vec3::default();
+        let sample = match sample {
+            Some(sample) => sample,
+            None => {
+                end_path(Counter::Absorbed, path.depth);
+                return radiance.scaled(weight);
             }
+        };
 
-            let incoming = self.trace_ray(
-                &Ray {
-                    origin: info.point,
-                    dir,
-                },
-                rng,
-                depth,
-                max_depth,
-            );
+        let medium = if sample.transmitted {
+            self.medium_across(info, path.medium)
+        } else {
+            path.medium
+        };
+        let last_scatter = if sample.specular {
+            None
+        } else {
+            Some(Scatter {
+                point: info.point,
+                normal: Some(info.normal),
+                pdf: sample.pdf,
+            })
+        };
+        stats::add(Counter::BounceRays, 1);
+        let incoming = self.trace_path(
+            &Ray {
+                origin: info.point,
+                dir: sample.dir,
+                time: path.time,
+            },
+            PathState {
+                medium,
+                last_scatter,
+                ..path
+            },
+            rng,
+        );
+        if let Some(region) = region.filter(|_| !sample.specular) {
+            region.record(sample.dir, incoming.total(), sample.pdf);
+        }
+        (radiance + incoming.scaled(sample.weight)).scaled(weight)
+    }
+
+    /// Estimates the light from the scene's lights scattered at `point` by a path that has
+    /// already bounced there, where `scatter` gives the fraction of light arriving from a
+    /// direction that is scattered along the path, and the probability density of the path
+    /// having been continued in that direction instead. `normal` is the surface normal at
+    /// `point`, if it's on a surface.
+    ///
+    /// Lights without bounds are all sampled, while a single light is picked from the rest in
+    /// proportion to an estimate of its contribution.
+    fn sample_lights<R, F>(
+        &self,
+        point: Vec3,
+        normal: Option<Unit3>,
+        path: PathState,
+        scatter: F,
+        rng: &mut R,
+    ) -> Vec3
+    where
+        R: Rng + ?Sized,
+        F: Fn(Unit3) -> (Vec3, f64),
+    {
+        if path.depth >= path.max_depth {
+            return Vec3::default();
+        }
+
+        let tree = &self.accel().light_tree;
+        let picked = tree.sample(point, normal, rng.gen());
+        let unbounded = tree.unbounded().iter().map(|&idx| (idx, 1.0));
+
+        let mut radiance = Vec3::default();
+        for (idx, prob) in unbounded.chain(picked) {
+            let light = self.light(idx);
+            let sample = match light.get().sample(point, path.time, &mut &mut *rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+
+            let (value, scatter_pdf) = scatter(sample.dir);
+            if value.mag_squared() <= 0.0 {
+                continue;
+            }
+
+            // Stop just short of the light, so that area lights don't shadow themselves.
+            let ray = Ray {
+                origin: point,
+                dir: sample.dir,
+                time: path.time,
+            };
+            let dist = (1.0 - SHADOW_TOLERANCE) * sample.dist;
+            let transmittance = self.transmittance(&ray, dist, path.medium, rng);
 
-            let coeff = if cos_alpha < EPSILON {
+            let mis_weight = if sample.delta {
                 1.0
             } else {
-                // PDF = 1 / (2pi * (1 - cos(alpha))), BDRF = 1 / (pi * (1 - cos^2(alpha)))
-                2.0 * (1.0 - cos_alpha) / (1.0 - cos_alpha * cos_alpha)
+                power_heuristic(prob * sample.pdf, scatter_pdf)
             };
+            let scale = self.light_scale(idx, path.time);
+            radiance = radiance
+                + mis_weight / prob
+                    * path
+                        .channels
+                        .color(scale * sample.radiance)
+                        .component_mul(value)
+                        .component_mul(transmittance);
+        }
+
+        radiance
+    }
 
-            return coeff * cos_theta * incoming;
+    /// Estimates the light from the scene's lights that `bsdf` reflects towards `wo` at the
+    /// surface described by `info`, ignoring participating media. Lights are both sampled
+    /// directly and found by following a direction sampled from the BSDF, for integrators that
+    /// don't otherwise look for them.
+    pub(crate) fn sample_direct<R: Rng + ?Sized>(
+        &self,
+        info: &IntersectionInfo,
+        bsdf: &Bsdf,
+        wo: Unit3,
+        rng: &mut R,
+    ) -> Vec3 {
+        let path = PathState {
+            medium: None,
+            channels: Channels::Rgb,
+            depth: 0,
+            max_depth: 1,
+            last_scatter: None,
+            guide: None,
+            time: info.time,
+        };
+        let radiance = self.sample_lights(
+            info.point,
+            Some(info.normal),
+            path,
+            |wi| (bsdf.eval(wo, wi), bsdf.pdf(wo, wi)),
+            rng,
+        );
+
+        let sample = match bsdf.sample(wo, rng) {
+            Some(sample) if !sample.specular => sample,
+            _ => return radiance,
+        };
+        stats::add(Counter::BounceRays, 1);
+        let ray = Ray {
+            origin: info.point,
+            dir: sample.dir,
+            time: info.time,
+        };
+        let hit = match self.intersect_surface(&ray) {
+            Some(hit) => hit,
+            None => return radiance,
+        };
+        let idx = match self.accel().prim_lights[hit.prim_index] {
+            Some(idx) => idx,
+            None => return radiance,
+        };
+        let material = hit.prim.material();
+        if !material.two_sided && hit.inside {
+            return radiance;
         }
 
-        if material.albedo.mag_squared() > EPSILON {
-            let dir = sample_cos_weighted_hemisphere(info.normal, rng);
-            let incoming = self.trace_ray(
-                &Ray {
-                    origin: info.point,
-                    dir,
-                },
-                rng,
-                depth,
-                max_depth,
-            );
-            return material.albedo.component_mul(incoming);
+        let prob = self
+            .accel()
+            .light_tree
+            .pmf(info.point, Some(info.normal), idx);
+        let light_pdf = prob * self.light(idx).get().pdf(info.point, sample.dir, info.time);
+        let emitted = self.light_scale(idx, hit.time) * material.emittance.eval(hit.uv, hit.point);
+        radiance + power_heuristic(sample.pdf, light_pdf) * sample.weight.component_mul(emitted)
+    }
+
+    /// Returns the fraction of light that travels along a shadow ray for `dist`, starting in
+    /// `medium`. Interfaces let light through into the medium beyond them, while any other
+    /// surface blocks it.
+    pub(crate) fn transmittance<R: Rng + ?Sized>(
+        &self,
+        ray: &Ray,
+        dist: f64,
+        medium: Option<&dyn Medium>,
+        rng: &mut R,
+    ) -> Vec3 {
+        stats::add(Counter::ShadowRays, 1);
+
+        let mut ray = *ray;
+        let mut remaining = dist;
+        let mut medium = medium;
+        let mut transmittance = Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        loop {
+            let info = self.intersect(&ray).filter(|info| info.dist < remaining);
+            let segment = info.as_ref().map_or(remaining, |info| info.dist);
+            if let Some(medium) = medium {
+                transmittance = transmittance.component_mul(medium.transmittance(
+                    &ray,
+                    segment,
+                    &mut &mut *rng,
+                ));
+            }
+
+            let info = match info {
+                None => return transmittance,
+                Some(info) if info.prim.material().interface => info,
+                Some(_) => return Vec3::default(),
+            };
+
+            medium = self.medium_across(&info, medium);
+            ray.origin = info.point;
+            remaining -= info.dist;
         }
+    }
 
-        Vec3::default()
+    /// Returns the medium on the other side of the surface described by `info`, for light
+    /// passing through it from `medium`.
+    fn medium_across<'b>(
+        &'b self,
+        info: &IntersectionInfo<'b>,
+        medium: Option<&'b dyn Medium>,
+    ) -> Option<&'b dyn Medium> {
+        match info.prim.interior() {
+            Some(interior) if !info.inside => Some(interior),
+            Some(_) => self.medium(),
+            None => medium,
+        }
     }
 
+    /// Traces a ray starting in the scene's medium.
     pub fn trace_ray<R: Rng + ?Sized>(
         &self,
         ray: &Ray,
@@ -267,65 +1558,673 @@ impl<'a> Scene<'a> {
         depth: u32,
         max_depth: u32,
     ) -> Vec3 {
-        if depth >= max_depth {
-            return Vec3::default();
+        let path = PathState {
+            medium: self.medium(),
+            channels: Channels::Rgb,
+            depth,
+            max_depth,
+            last_scatter: None,
+            guide: None,
+            time: ray.time,
+        };
+        self.trace_path(ray, path, rng).total()
+    }
+
+    fn trace_path<R: Rng + ?Sized>(&self, ray: &Ray, path: PathState, rng: &mut R) -> PathRadiance {
+        if path.depth >= path.max_depth {
+            end_path(Counter::MaxDepth, path.depth);
+            return PathRadiance::default();
+        }
+
+        let info = self.intersect(ray);
+
+        let mut weight = Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        if let Some(medium) = path.medium {
+            let max_dist = info.as_ref().map_or(f64::INFINITY, |info| info.dist);
+            let sample = medium.sample(ray, max_dist, &mut &mut *rng);
+
+            if let Some(dist) = sample.scatter {
+                let point = ray.interp(dist);
+                let phase = medium.phase();
+                let direct = self.sample_lights(
+                    point,
+                    None,
+                    path.deeper(),
+                    |wi| {
+                        let value = phase.eval(Vec3::from(ray.dir).dot(wi.into()));
+                        let value3 = Vec3 {
+                            x: value,
+                            y: value,
+                            z: value,
+                        };
+                        (value3, value)
+                    },
+                    rng,
+                );
+
+                let dir = phase.sample(ray.dir, rng);
+                let pdf = phase.eval(Vec3::from(ray.dir).dot(dir.into()));
+                stats::add(Counter::BounceRays, 1);
+                let incoming = self.trace_path(
+                    &Ray {
+                        origin: point,
+                        dir,
+                        time: path.time,
+                    },
+                    PathState {
+                        last_scatter: Some(Scatter {
+                            point,
+                            normal: None,
+                            pdf,
+                        }),
+                        ..path.deeper()
+                    },
+                    rng,
+                );
+                let direct = PathRadiance::found(path.depth + 1, direct);
+                return (direct + incoming).scaled(sample.weight);
+            }
+
+            weight = sample.weight;
         }
 
-        let info = match self.intersect(ray) {
+        let info = match info {
             None => {
-                return Vec3::default();
+                end_path(Counter::Escaped, path.depth);
+                return PathRadiance::default();
             }
             Some(info) => info,
         };
 
         let material = info.prim.material();
 
-        material.emittance + self.trace_reflection(ray, &info, rng, depth + 1, max_depth)
+        // Interfaces don't interact with light, so carry on into the medium beyond them.
+        if material.interface {
+            let mut incoming = self.trace_path(
+                &Ray {
+                    origin: info.point,
+                    dir: ray.dir,
+                    time: ray.time,
+                },
+                PathState {
+                    medium: self.medium_across(&info, path.medium),
+                    ..path
+                },
+                rng,
+            );
+            if let Some(first_hit) = &mut incoming.first_hit {
+                first_hit.depth += info.dist;
+            }
+            return incoming.scaled(weight);
+        }
+
+        let mut emitted = Vec3::default();
+        if material.two_sided || !info.inside {
+            emitted = path
+                .channels
+                .color(self.emission_scale(&info) * material.emittance.eval(info.uv, info.point));
+
+            // The light could also have been found by sampling it directly.
+            let light = self.accel().prim_lights[info.prim_index];
+            if let (Some(idx), Some(scatter)) = (light, path.last_scatter) {
+                let prob = self
+                    .accel()
+                    .light_tree
+                    .pmf(scatter.point, scatter.normal, idx);
+                let light_pdf = prob * self.light(idx).get().pdf(scatter.point, ray.dir, ray.time);
+                emitted = power_heuristic(scatter.pdf, light_pdf) * emitted;
+            }
+        }
+
+        let emitted = PathRadiance::found(path.depth, emitted);
+        let mut radiance =
+            (emitted + self.trace_reflection(ray, &info, path.deeper(), rng)).scaled(weight);
+        if path.depth == 0 {
+            radiance.first_hit = Some(FirstHit {
+                albedo: material.albedo.eval(info.uv, info.point),
+                normal: info.shading_normal.into(),
+                depth: info.dist,
+                prim_index: info.prim_index,
+                material_id: material.id,
+            });
+        }
+        radiance
     }
 }
 
-pub fn render_to(
+/// Renders into `pixels`, returning counts of the work it took.
+pub fn render_to(scene: &Scene, pixels: &mut [Vec3], opts: &RenderOptions) -> Result<RenderStats> {
+    render_aovs_to(scene, pixels, &mut [], opts)
+}
+
+/// Renders into `pixels` as `render_to` does, also filling the buffer paired with each
+/// auxiliary output in `aovs`. Only the path tracer produces auxiliary outputs.
+pub fn render_aovs_to(
     scene: &Scene,
     pixels: &mut [Vec3],
+    aovs: &mut [(Aov, &mut [Vec3])],
     opts: &RenderOptions,
-) -> Result<(), Box<dyn error::Error + 'static>> {
-    assert_eq!(pixels.len(), (opts.width * opts.height) as usize);
+) -> Result<RenderStats> {
+    let expected = pixel_count(opts.width, opts.height)?;
+    let lengths = aovs.iter().map(|(_, buffer)| buffer.len());
+    if let Some(actual) = iter::once(pixels.len())
+        .chain(lengths)
+        .find(|&len| len != expected)
+    {
+        return Err(Error::BufferSize { expected, actual });
+    }
+
+    if opts.samples_per_pixel == 0 {
+        return Err(Error::InvalidParameter("samples per pixel must be nonzero"));
+    }
+
+    if !(opts.shutter_open.is_finite() && opts.shutter_close.is_finite()) {
+        return Err(Error::InvalidParameter("shutter times must be finite"));
+    }
+    if opts.shutter_close < opts.shutter_open {
+        return Err(Error::InvalidParameter(
+            "shutter must close no earlier than it opens",
+        ));
+    }
+
+    if opts.spectral && !matches!(opts.integrator, Integrator::Path | Integrator::Guided) {
+        return Err(Error::InvalidParameter(
+            "spectral rendering is only supported by the path tracer",
+        ));
+    }
+
+    if !aovs.is_empty() && !matches!(opts.integrator, Integrator::Path | Integrator::Guided) {
+        return Err(Error::InvalidParameter(
+            "auxiliary outputs are only supported by the path tracer",
+        ));
+    }
+
+    if let Integrator::PhotonMapping { photons, radius } = opts.integrator {
+        if photons == 0 {
+            return Err(Error::InvalidParameter("photon count must be nonzero"));
+        }
+        if !(radius > 0.0 && radius.is_finite()) {
+            return Err(Error::InvalidParameter("gather radius must be positive"));
+        }
+    }
+
+    if let Integrator::Metropolis {
+        bootstrap,
+        chains,
+        large_step,
+    } = opts.integrator
+    {
+        if bootstrap == 0 {
+            return Err(Error::InvalidParameter(
+                "bootstrap path count must be nonzero",
+            ));
+        }
+        if chains == 0 {
+            return Err(Error::InvalidParameter(
+                "Markov chain count must be nonzero",
+            ));
+        }
+        if !(0.0..=1.0).contains(&large_step) {
+            return Err(Error::InvalidParameter(
+                "large step probability must be between 0 and 1",
+            ));
+        }
+    }
+
+    let cam = Camera::new(&opts.camera_options, opts.width, opts.height)?
+        .with_shutter(opts.shutter_open, opts.shutter_close);
 
+    // Every thread counts its own work, which is summed once the image is done.
+    let collector = Collector::default();
+    let attach = collector.clone();
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(opts.threads as usize)
+        .start_handler(move |_| attach.attach())
         .build()?;
 
-    let cam = Camera::new(&opts.camera_options, opts.width, opts.height);
+    match opts.integrator {
+        Integrator::Path | Integrator::Guided => {}
+        Integrator::Bidirectional => {
+            pool.install(|| bdpt::render_to(scene, &cam, pixels, opts));
+            return Ok(collector.stats());
+        }
+        Integrator::Debug(view) => {
+            pool.install(|| debug::render_to(scene, &cam, pixels, opts, view));
+            return Ok(collector.stats());
+        }
+        Integrator::PhotonMapping { photons, radius } => {
+            pool.install(|| sppm::render_to(scene, &cam, pixels, opts, photons, radius));
+            return Ok(collector.stats());
+        }
+        Integrator::Metropolis {
+            bootstrap,
+            chains,
+            large_step,
+        } => {
+            pool.install(|| {
+                mlt::render_to(scene, &cam, pixels, opts, bootstrap, chains, large_step)
+            });
+            return Ok(collector.stats());
+        }
+    }
+
+    let mut sums = vec![PixelSums::default(); pixels.len()];
+    pool.install(|| match opts.integrator {
+        Integrator::Guided => render_guided(scene, &cam, &mut sums, opts),
+        _ => trace_samples(scene, &cam, &mut sums, opts, None, opts.samples_per_pixel),
+    });
+
+    let samples = f64::from(opts.samples_per_pixel);
+    for (pixel, sums) in pixels.iter_mut().zip(&sums) {
+        *pixel = sums.light.total() / samples;
+    }
+    for (aov, buffer) in aovs.iter_mut() {
+        let aov = *aov;
+        pool.install(|| {
+            buffer.par_iter_mut().enumerate().for_each(|(idx, value)| {
+                let sums = &sums[idx];
+                *value = match aov {
+                    Aov::Albedo => sums.albedo / samples,
+                    Aov::Normal => sums.normal / samples,
+                    Aov::Emission => sums.light.emitted / samples,
+                    Aov::Direct => sums.light.direct / samples,
+                    Aov::Indirect => sums.light.indirect / samples,
+                    Aov::Depth => gray(if sums.hits > 0 {
+                        sums.depth / f64::from(sums.hits)
+                    } else {
+                        f64::INFINITY
+                    }),
+                    Aov::PrimitiveId => gray(sums.prim_id / samples),
+                    Aov::MaterialId => gray(sums.material_id / samples),
+                };
+            })
+        });
+    }
 
-    pool.install(|| {
-        pixels.par_iter_mut().enumerate().for_each(|(idx, pixel)| {
-            let x = (idx as u32) % opts.width;
-            let y = (idx as u32) / opts.width;
+    Ok(collector.stats())
+}
 
-            let mut rng = rand::thread_rng();
+/// What's found at a pixel, summed over its samples.
+#[derive(Debug, Copy, Clone, Default)]
+struct PixelSums {
+    light: PathRadiance,
+    albedo: Vec3,
+    normal: Vec3,
+    /// The depths of the samples that hit a surface, and how many of them there were
+    depth: f64,
+    hits: u32,
+    /// IDs of the first surfaces hit, with -1 for samples that hit nothing
+    prim_id: f64,
+    material_id: f64,
+}
 
-            let total_sampled = (0..opts.samples_per_pixel)
-                .map(|_| {
-                    let ray = cam.cast_ray(
-                        f64::from(x) + rng.gen::<f64>(),
-                        f64::from(y) + rng.gen::<f64>(),
-                    );
-                    scene.trace_ray(&ray, &mut rng, 0, opts.max_depth)
-                })
-                .fold(Vec3::default(), |a, b| a + b);
+impl PixelSums {
+    /// Returns the sums for a single sample that found `light`.
+    fn new(light: PathRadiance) -> PixelSums {
+        match light.first_hit {
+            Some(hit) => PixelSums {
+                light,
+                albedo: hit.albedo,
+                normal: hit.normal,
+                depth: hit.depth,
+                hits: 1,
+                prim_id: hit.prim_index as f64,
+                material_id: f64::from(hit.material_id),
+            },
+            None => PixelSums {
+                light,
+                prim_id: -1.0,
+                material_id: -1.0,
+                ..PixelSums::default()
+            },
+        }
+    }
+}
 
-            *pixel = total_sampled / f64::from(opts.samples_per_pixel);
-        })
-    });
+impl Add for PixelSums {
+    type Output = PixelSums;
 
-    Ok(())
+    fn add(self, rhs: PixelSums) -> PixelSums {
+        PixelSums {
+            light: self.light + rhs.light,
+            albedo: self.albedo + rhs.albedo,
+            normal: self.normal + rhs.normal,
+            depth: self.depth + rhs.depth,
+            hits: self.hits + rhs.hits,
+            prim_id: self.prim_id + rhs.prim_id,
+            material_id: self.material_id + rhs.material_id,
+        }
+    }
 }
 
-pub fn render(
+/// Traces a path through a random point within the pixel at `(x, y)`.
+fn sample_pixel<R: Rng + ?Sized>(
     scene: &Scene,
+    cam: &Camera,
     opts: &RenderOptions,
-) -> Result<Box<[Vec3]>, Box<dyn error::Error + 'static>> {
-    let mut pixels = vec![Vec3::default(); (opts.width * opts.height) as usize].into_boxed_slice();
+    guide: Option<&Guide>,
+    (x, y): (u32, u32),
+    rng: &mut R,
+) -> PixelSums {
+    let ray = cam.cast_ray(
+        f64::from(x) + rng.gen::<f64>(),
+        f64::from(y) + rng.gen::<f64>(),
+        cam.sample_time(rng.gen()),
+    );
+
+    let path = PathState {
+        medium: scene.medium(),
+        channels: Channels::Rgb,
+        depth: 0,
+        max_depth: opts.max_depth,
+        last_scatter: None,
+        guide,
+        time: ray.time,
+    };
+    let light = if opts.spectral {
+        let wavelengths = spectral::sample_wavelengths(rng.gen());
+        let path = PathState {
+            channels: Channels::Spectral {
+                wavelengths,
+                hero_only: false,
+            },
+            ..path
+        };
+        let radiance = scene.trace_path(&ray, path, rng);
+        radiance.map(|value| spectral::to_rgb(value, wavelengths))
+    } else {
+        scene.trace_path(&ray, path, rng)
+    };
+    PixelSums::new(light)
+}
+
+/// Adds `samples` samples to every pixel's sums.
+fn trace_samples(
+    scene: &Scene,
+    cam: &Camera,
+    sums: &mut [PixelSums],
+    opts: &RenderOptions,
+    guide: Option<&Guide>,
+    samples: u32,
+) {
+    sums.par_iter_mut().enumerate().for_each(|(idx, sums)| {
+        let coords = ((idx as u32) % opts.width, (idx as u32) / opts.width);
+
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..samples {
+            *sums = *sums + sample_pixel(scene, cam, opts, guide, coords, &mut rng);
+        }
+    });
+}
+
+/// Renders with path guiding, in passes that each trace twice as many samples per pixel as
+/// the last, refining the guide between them. Every pass contributes to the image.
+fn render_guided(scene: &Scene, cam: &Camera, sums: &mut [PixelSums], opts: &RenderOptions) {
+    let mut guide = Guide::new(scene.finite_bounds());
+    let mut traced = 0;
+    let mut pass_samples = 1u32;
+    while traced < opts.samples_per_pixel {
+        let samples = pass_samples.min(opts.samples_per_pixel - traced);
+        trace_samples(scene, cam, sums, opts, Some(&guide), samples);
+
+        traced += samples;
+        if traced < opts.samples_per_pixel {
+            guide = guide.refined(samples);
+        }
+        pass_samples = pass_samples.saturating_mul(2);
+    }
+}
+
+/// Returns a gray pixel with every channel set to `value`.
+fn gray(value: f64) -> Vec3 {
+    Vec3 {
+        x: value,
+        y: value,
+        z: value,
+    }
+}
+
+pub fn render(scene: &Scene, opts: &RenderOptions) -> Result<Box<[Vec3]>> {
+    let mut pixels =
+        vec![Vec3::default(); pixel_count(opts.width, opts.height)?].into_boxed_slice();
     render_to(scene, &mut pixels, opts)?;
     Ok(pixels)
 }
+
+/// An image rendered along with auxiliary outputs.
+pub struct Layers {
+    pub pixels: Box<[Vec3]>,
+    /// The buffer of each requested output, in the order they were requested
+    pub aovs: Vec<Box<[Vec3]>>,
+    pub stats: RenderStats,
+}
+
+/// Renders the image along with each of `aovs`.
+pub fn render_with_aovs(scene: &Scene, opts: &RenderOptions, aovs: &[Aov]) -> Result<Layers> {
+    let len = pixel_count(opts.width, opts.height)?;
+    let mut pixels = vec![Vec3::default(); len].into_boxed_slice();
+    let mut buffers: Vec<Box<[Vec3]>> = aovs
+        .iter()
+        .map(|_| vec![Vec3::default(); len].into_boxed_slice())
+        .collect();
+    let mut targets: Vec<_> = aovs
+        .iter()
+        .copied()
+        .zip(buffers.iter_mut().map(|buffer| &mut **buffer))
+        .collect();
+    let stats = render_aovs_to(scene, &mut pixels, &mut targets, opts)?;
+    Ok(Layers {
+        pixels,
+        aovs: buffers,
+        stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::Keyframe;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn glowing<G: Geom + 'static>(geom: G) -> Primitive<'static> {
+        Primitive::new(geom, Material::make_light(vec3(1.0, 1.0, 1.0)))
+    }
+
+    /// A camera looking down the z axis at the origin.
+    fn camera_options() -> CameraOptions {
+        CameraOptions {
+            pos: vec3(0.0, 0.0, 5.0),
+            target: vec3(0.0, 0.0, 0.0),
+            up: vec3(0.0, 1.0, 0.0),
+            vert_fov: 40.0,
+            motion: None,
+            animation: None,
+        }
+    }
+
+    fn render_options() -> RenderOptions {
+        RenderOptions {
+            camera_options: camera_options(),
+            width: 8,
+            height: 6,
+            samples_per_pixel: 1,
+            max_depth: 4,
+            threads: 1,
+            spectral: false,
+            integrator: Integrator::Path,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+
+    #[test]
+    fn degenerate_cameras_are_rejected() {
+        let new = |options: CameraOptions| Camera::new(&options, 8, 6);
+        assert!(new(camera_options()).is_ok());
+
+        let looking_up = CameraOptions {
+            up: vec3(0.0, 0.0, -2.0),
+            ..camera_options()
+        };
+        assert!(matches!(new(looking_up), Err(Error::DegenerateCamera(_))));
+        let looking_at_itself = CameraOptions {
+            target: vec3(0.0, 0.0, 5.0),
+            ..camera_options()
+        };
+        assert!(matches!(
+            new(looking_at_itself),
+            Err(Error::DegenerateCamera(_))
+        ));
+
+        // Each scale is allowed alone, but together they shrink the view to nothing.
+        let shrink = Transform::uniform_scale(1e-5).unwrap();
+        let squashed = CameraOptions {
+            motion: Some(
+                AnimatedTransform::new(
+                    shrink * shrink,
+                    &[Keyframe::translate(0.0, vec3(0.0, 0.0, 0.0))],
+                )
+                .unwrap(),
+            ),
+            ..camera_options()
+        };
+        assert!(matches!(new(squashed), Err(Error::DegenerateCamera(_))));
+    }
+
+    #[test]
+    fn mismatched_buffers_are_rejected() {
+        let scene = Scene::with_primitives(vec![glowing(
+            Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap(),
+        )]);
+        let opts = render_options();
+
+        let mut pixels = vec![Vec3::default(); 47];
+        assert!(matches!(
+            render_to(&scene, &mut pixels, &opts),
+            Err(Error::BufferSize {
+                expected: 48,
+                actual: 47
+            })
+        ));
+
+        let mut pixels = vec![Vec3::default(); 48];
+        let mut depth = vec![Vec3::default(); 49];
+        let mut aovs = [(Aov::Depth, depth.as_mut_slice())];
+        assert!(matches!(
+            render_aovs_to(&scene, &mut pixels, &mut aovs, &opts),
+            Err(Error::BufferSize {
+                expected: 48,
+                actual: 49
+            })
+        ));
+
+        let huge = RenderOptions {
+            width: 1 << 16,
+            height: 1 << 16,
+            ..render_options()
+        };
+        assert!(matches!(
+            render(&scene, &huge),
+            Err(Error::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn emitters_need_sampleable_surfaces() {
+        let sphere: Arc<dyn Geom> = Arc::new(Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap());
+        let instanced = glowing(Instance::new(
+            sphere.clone(),
+            Transform::translate(vec3(0.0, 2.0, 0.0)) * Transform::uniform_scale(3.0).unwrap(),
+        ));
+        let emitter = Emitter::new(&instanced).expect("instanced sphere should be a light");
+        assert!((emitter.area - 36.0 * f64::consts::PI).abs() < 1e-9);
+
+        // Geometry that can't be sampled is deliberately left out of light sampling.
+        let stretched = glowing(Instance::new(
+            sphere,
+            Transform::scale(vec3(1.0, 3.0, 1.0)).unwrap(),
+        ));
+        assert!(Emitter::new(&stretched).is_none());
+        let cuboid = glowing(Cuboid::new(vec3(0.0, 0.0, 0.0), vec3(1.0, 1.0, 1.0)).unwrap());
+        assert!(Emitter::new(&cuboid).is_none());
+    }
+
+    #[test]
+    fn counts_one_camera_ray_per_sample() {
+        let scene = Scene::with_primitives(vec![
+            Primitive::new(
+                Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap(),
+                Material::make_diffuse(vec3(0.5, 0.5, 0.5)),
+            ),
+            glowing(Sphere::new(vec3(0.0, 4.0, 0.0), 0.5).unwrap()),
+        ]);
+        let opts = render_options();
+        let count = 48;
+
+        let mut pixels = vec![Vec3::default(); count];
+        let stats = render_to(&scene, &mut pixels, &opts).unwrap();
+        assert_eq!(stats.camera_rays, count as u64);
+
+        // Auxiliary outputs come from the camera paths, without tracing any rays of their own.
+        let mut buffers = vec![vec![Vec3::default(); count]; 4];
+        let mut aovs: Vec<(Aov, &mut [Vec3])> =
+            [Aov::Albedo, Aov::Normal, Aov::Depth, Aov::PrimitiveId]
+                .iter()
+                .copied()
+                .zip(buffers.iter_mut().map(|buffer| buffer.as_mut_slice()))
+                .collect();
+        let stats = render_aovs_to(&scene, &mut pixels, &mut aovs, &opts).unwrap();
+        assert_eq!(stats.camera_rays, count as u64);
+    }
+
+    #[test]
+    fn aovs_describe_first_surface_hit() {
+        // A diffuse sphere inside a larger interface, which paths pass straight through.
+        let mut diffuse = Material::make_diffuse(vec3(0.2, 0.4, 0.6));
+        diffuse.id = 7;
+        let scene = Scene::with_primitives(vec![
+            Primitive::new(
+                Sphere::new(vec3(0.0, 0.0, 0.0), 2.0).unwrap(),
+                Material::make_interface(),
+            ),
+            Primitive::new(Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap(), diffuse),
+            glowing(Sphere::new(vec3(0.0, 0.0, 8.0), 0.5).unwrap()),
+        ]);
+        let opts = RenderOptions {
+            width: 64,
+            height: 48,
+            samples_per_pixel: 4,
+            ..render_options()
+        };
+        let aovs = [
+            Aov::Albedo,
+            Aov::Normal,
+            Aov::Depth,
+            Aov::PrimitiveId,
+            Aov::MaterialId,
+        ];
+        let layers = render_with_aovs(&scene, &opts, &aovs).unwrap();
+
+        let center = 24 * 64 + 32;
+        let value = |aov: usize| layers.aovs[aov][center];
+        assert!((value(0) - vec3(0.2, 0.4, 0.6)).mag() < 1e-9);
+        assert!(value(1).z > 0.99);
+        // Depth is measured from the camera, not from the interface.
+        assert!((value(2).x - 4.0).abs() < 0.01, "depth {}", value(2).x);
+        assert_eq!(value(3).x, 1.0);
+        assert_eq!(value(4).x, 7.0);
+
+        // The corners see nothing at all.
+        assert_eq!(layers.aovs[2][0].x, f64::INFINITY);
+        assert_eq!(layers.aovs[3][0].x, -1.0);
+        assert_eq!(layers.aovs[0][0].mag_squared(), 0.0);
+    }
+}
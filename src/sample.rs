@@ -4,7 +4,8 @@ use rand::Rng;
 
 use crate::math::{Unit3, Vec3};
 
-struct Basis {
+/// An orthonormal basis whose z axis is a given normal.
+pub struct Basis {
     pub x: Vec3,
     pub y: Vec3,
     pub z: Vec3,
@@ -63,3 +64,15 @@ pub fn sample_uniform_cone<R: Rng + ?Sized>(normal: Unit3, alpha: f64, rng: &mut
 
     Unit3::from_unit_vec3(x * basis.x + y * basis.y + z * basis.z)
 }
+
+/// Weights a sample taken with one of two sampling strategies, given the probability densities
+/// of choosing it with that strategy and with the other, using Veach's power heuristic.
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
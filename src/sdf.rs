@@ -0,0 +1,660 @@
+use std::f64;
+
+use crate::error::{Error, Result};
+use crate::geom::{Geom, Hit};
+use crate::math::*;
+use crate::sample::Basis;
+
+/// A signed distance function, giving the distance from a point to the nearest surface of a
+/// solid. The distance is negative inside the solid.
+///
+/// The distance may be underestimated (making sphere tracing slower) but must never be
+/// overestimated, or rays will step through thin features.
+pub trait Distance: Send + Sync {
+    fn distance(&self, p: Vec3) -> f64;
+
+    /// Returns a box containing the whole solid.
+    fn bounds(&self) -> Aabb;
+}
+
+/// A distance function defined by a closure.
+pub struct DistanceFn<F> {
+    f: F,
+    bounds: Aabb,
+}
+
+impl<F: Fn(Vec3) -> f64 + Send + Sync> DistanceFn<F> {
+    pub fn new(bounds: Aabb, f: F) -> DistanceFn<F> {
+        DistanceFn { f, bounds }
+    }
+}
+
+impl<F: Fn(Vec3) -> f64 + Send + Sync> Distance for DistanceFn<F> {
+    fn distance(&self, p: Vec3) -> f64 {
+        (self.f)(p)
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+fn abs(v: Vec3) -> Vec3 {
+    Vec3 {
+        x: v.x.abs(),
+        y: v.y.abs(),
+        z: v.z.abs(),
+    }
+}
+
+fn splat(s: f64) -> Vec3 {
+    Vec3 { x: s, y: s, z: s }
+}
+
+fn check_positive(value: f64, message: &'static str) -> Result<()> {
+    if value.is_nan() || value <= 0.0 {
+        Err(Error::InvalidParameter(message))
+    } else {
+        Ok(())
+    }
+}
+
+pub struct Sphere {
+    center: Vec3,
+    radius: f64,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f64) -> Result<Sphere> {
+        check_positive(radius, "sphere radius must be positive")?;
+        Ok(Sphere { center, radius })
+    }
+}
+
+impl Distance for Sphere {
+    fn distance(&self, p: Vec3) -> f64 {
+        (p - self.center).mag() - self.radius
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.center - splat(self.radius),
+            max: self.center + splat(self.radius),
+        }
+    }
+}
+
+/// An axis-aligned box whose edges are rounded off with the given radius.
+pub struct RoundBox {
+    center: Vec3,
+    half_extents: Vec3,
+    rounding: f64,
+}
+
+impl RoundBox {
+    pub fn new(center: Vec3, half_extents: Vec3, rounding: f64) -> Result<RoundBox> {
+        if (0..3).any(|axis| half_extents.axis(axis).is_nan() || half_extents.axis(axis) <= 0.0) {
+            return Err(Error::InvalidParameter("box extents must be positive"));
+        }
+        let max_rounding = half_extents.x.min(half_extents.y).min(half_extents.z);
+        if rounding.is_nan() || rounding < 0.0 || rounding > max_rounding {
+            return Err(Error::InvalidParameter(
+                "box rounding must be between zero and the smallest half extent",
+            ));
+        }
+
+        Ok(RoundBox {
+            center,
+            half_extents,
+            rounding,
+        })
+    }
+}
+
+impl Distance for RoundBox {
+    fn distance(&self, p: Vec3) -> f64 {
+        let q = abs(p - self.center) - self.half_extents + splat(self.rounding);
+        q.max(Vec3::default()).mag() + q.x.max(q.y).max(q.z).min(0.0) - self.rounding
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.center - self.half_extents,
+            max: self.center + self.half_extents,
+        }
+    }
+}
+
+/// A torus lying in the xz plane.
+pub struct Torus {
+    center: Vec3,
+    major_radius: f64,
+    minor_radius: f64,
+}
+
+impl Torus {
+    pub fn new(center: Vec3, major_radius: f64, minor_radius: f64) -> Result<Torus> {
+        check_positive(minor_radius, "torus minor radius must be positive")?;
+        if major_radius.is_nan() || major_radius < minor_radius {
+            return Err(Error::InvalidParameter(
+                "torus major radius must be at least its minor radius",
+            ));
+        }
+
+        Ok(Torus {
+            center,
+            major_radius,
+            minor_radius,
+        })
+    }
+}
+
+impl Distance for Torus {
+    fn distance(&self, p: Vec3) -> f64 {
+        let p = p - self.center;
+        let radial = (p.x * p.x + p.z * p.z).sqrt() - self.major_radius;
+        (radial * radial + p.y * p.y).sqrt() - self.minor_radius
+    }
+
+    fn bounds(&self) -> Aabb {
+        let outer = self.major_radius + self.minor_radius;
+        let extents = Vec3 {
+            x: outer,
+            y: self.minor_radius,
+            z: outer,
+        };
+        Aabb {
+            min: self.center - extents,
+            max: self.center + extents,
+        }
+    }
+}
+
+/// The set of points within `radius` of the segment between two points.
+pub struct Capsule {
+    a: Vec3,
+    b: Vec3,
+    radius: f64,
+}
+
+impl Capsule {
+    pub fn new(a: Vec3, b: Vec3, radius: f64) -> Result<Capsule> {
+        check_positive(radius, "capsule radius must be positive")?;
+        Ok(Capsule { a, b, radius })
+    }
+}
+
+impl Distance for Capsule {
+    fn distance(&self, p: Vec3) -> f64 {
+        let pa = p - self.a;
+        let ba = self.b - self.a;
+        let len_squared = ba.mag_squared();
+        let h = if len_squared > 0.0 {
+            (pa.dot(ba) / len_squared).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (pa - h * ba).mag() - self.radius
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.a.min(self.b) - splat(self.radius),
+            max: self.a.max(self.b) + splat(self.radius),
+        }
+    }
+}
+
+/// Blends two solids together, filling in the creases where they meet. `smoothness` is roughly
+/// the distance over which the blend takes place.
+pub struct SmoothUnion<A, B> {
+    a: A,
+    b: B,
+    smoothness: f64,
+}
+
+impl<A: Distance, B: Distance> SmoothUnion<A, B> {
+    pub fn new(a: A, b: B, smoothness: f64) -> Result<SmoothUnion<A, B>> {
+        check_positive(smoothness, "smoothness must be positive")?;
+        Ok(SmoothUnion { a, b, smoothness })
+    }
+}
+
+impl<A: Distance, B: Distance> Distance for SmoothUnion<A, B> {
+    fn distance(&self, p: Vec3) -> f64 {
+        let (da, db) = (self.a.distance(p), self.b.distance(p));
+        let k = self.smoothness;
+        let h = (0.5 + 0.5 * (db - da) / k).clamp(0.0, 1.0);
+        db + (da - db) * h - k * h * (1.0 - h)
+    }
+
+    fn bounds(&self) -> Aabb {
+        // The blend can swell the surface by up to a quarter of the smoothness.
+        let bounds = self.a.bounds().union(self.b.bounds());
+        Aabb {
+            min: bounds.min - splat(self.smoothness / 4.0),
+            max: bounds.max + splat(self.smoothness / 4.0),
+        }
+    }
+}
+
+/// Carves the second solid out of the first, rounding off the edges of the cut.
+pub struct SmoothSubtraction<A, B> {
+    a: A,
+    b: B,
+    smoothness: f64,
+}
+
+impl<A: Distance, B: Distance> SmoothSubtraction<A, B> {
+    pub fn new(a: A, b: B, smoothness: f64) -> Result<SmoothSubtraction<A, B>> {
+        check_positive(smoothness, "smoothness must be positive")?;
+        Ok(SmoothSubtraction { a, b, smoothness })
+    }
+}
+
+impl<A: Distance, B: Distance> Distance for SmoothSubtraction<A, B> {
+    fn distance(&self, p: Vec3) -> f64 {
+        let (da, db) = (self.a.distance(p), self.b.distance(p));
+        let k = self.smoothness;
+        let h = (0.5 - 0.5 * (da + db) / k).clamp(0.0, 1.0);
+        da - (da + db) * h + k * h * (1.0 - h)
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.a.bounds()
+    }
+}
+
+/// Repeats a solid on a grid, with `copies` instances along each axis centered on the origin.
+///
+/// Only the copy in the nearest grid cell is evaluated, so the solid should fit within a single
+/// cell for the distance to remain a lower bound.
+pub struct Repeat<D> {
+    inner: D,
+    spacing: Vec3,
+    copies: [u32; 3],
+}
+
+impl<D: Distance> Repeat<D> {
+    pub fn new(inner: D, spacing: Vec3, copies: [u32; 3]) -> Result<Repeat<D>> {
+        for (axis, &count) in copies.iter().enumerate() {
+            if count == 0 {
+                return Err(Error::InvalidParameter("repeat count must be positive"));
+            }
+            if count > 1 {
+                check_positive(spacing.axis(axis), "repeat spacing must be positive")?;
+            }
+        }
+
+        Ok(Repeat {
+            inner,
+            spacing,
+            copies,
+        })
+    }
+
+    /// Returns the offset of the copy furthest along each positive axis.
+    fn max_offset(&self) -> Vec3 {
+        let half_span =
+            |axis: usize| (self.copies[axis] - 1) as f64 / 2.0 * self.spacing.axis(axis);
+        Vec3 {
+            x: half_span(0),
+            y: half_span(1),
+            z: half_span(2),
+        }
+    }
+}
+
+impl<D: Distance> Distance for Repeat<D> {
+    fn distance(&self, p: Vec3) -> f64 {
+        let cell_offset = |axis: usize| {
+            let count = self.copies[axis];
+            if count == 1 {
+                return 0.0;
+            }
+            let spacing = self.spacing.axis(axis);
+            let first = (count - 1) as f64 / 2.0;
+            let cell = (p.axis(axis) / spacing + first)
+                .round()
+                .clamp(0.0, (count - 1) as f64);
+            (cell - first) * spacing
+        };
+
+        self.inner.distance(
+            p - Vec3 {
+                x: cell_offset(0),
+                y: cell_offset(1),
+                z: cell_offset(2),
+            },
+        )
+    }
+
+    fn bounds(&self) -> Aabb {
+        let bounds = self.inner.bounds();
+        Aabb {
+            min: bounds.min - self.max_offset(),
+            max: bounds.max + self.max_offset(),
+        }
+    }
+}
+
+/// Twists a solid around the y axis, rotating each horizontal slice by an angle proportional to
+/// its height.
+pub struct Twist<D> {
+    inner: D,
+    rate: f64,
+    radius: f64,
+}
+
+impl<D: Distance> Twist<D> {
+    /// Creates a twist turning by `degrees_per_unit` for each unit of height.
+    pub fn new(inner: D, degrees_per_unit: f64) -> Result<Twist<D>> {
+        if !degrees_per_unit.is_finite() {
+            return Err(Error::InvalidParameter("twist rate must be finite"));
+        }
+
+        // The largest distance from the axis that any part of the solid can reach.
+        let radius = inner
+            .bounds()
+            .corners()
+            .map(|c| (c.x * c.x + c.z * c.z).sqrt())
+            .fold(0.0, f64::max);
+        if !radius.is_finite() {
+            return Err(Error::InvalidParameter("twisted solid must be bounded"));
+        }
+
+        Ok(Twist {
+            inner,
+            rate: degrees_per_unit.to_radians(),
+            radius,
+        })
+    }
+}
+
+impl<D: Distance> Distance for Twist<D> {
+    fn distance(&self, p: Vec3) -> f64 {
+        let (sin, cos) = (-self.rate * p.y).sin_cos();
+        let untwisted = Vec3 {
+            x: cos * p.x - sin * p.z,
+            y: p.y,
+            z: sin * p.x + cos * p.z,
+        };
+
+        // Twisting stretches space by up to this factor at the edge of the solid, so scale the
+        // distance down to keep it a lower bound.
+        let stretch = (1.0 + (self.rate * self.radius).powi(2)).sqrt();
+        self.inner.distance(untwisted) / stretch
+    }
+
+    fn bounds(&self) -> Aabb {
+        let bounds = self.inner.bounds();
+        Aabb {
+            min: Vec3 {
+                x: -self.radius,
+                y: bounds.min.y,
+                z: -self.radius,
+            },
+            max: Vec3 {
+                x: self.radius,
+                y: bounds.max.y,
+                z: self.radius,
+            },
+        }
+    }
+}
+
+/// The Mandelbulb fractal, centered on the origin with the bulb's pole along the z axis.
+pub struct Mandelbulb {
+    power: f64,
+    iterations: u32,
+}
+
+impl Mandelbulb {
+    /// The radius beyond which points are known to escape.
+    const BAILOUT: f64 = 2.0;
+
+    /// Creates a Mandelbulb of the given power, evaluated to the given number of iterations.
+    /// The classic shape has power 8; more iterations give finer detail.
+    pub fn new(power: f64, iterations: u32) -> Result<Mandelbulb> {
+        if power.is_nan() || power < 2.0 {
+            return Err(Error::InvalidParameter(
+                "Mandelbulb power must be at least 2",
+            ));
+        }
+        if iterations == 0 {
+            return Err(Error::InvalidParameter(
+                "Mandelbulb must have at least one iteration",
+            ));
+        }
+
+        Ok(Mandelbulb { power, iterations })
+    }
+}
+
+impl Distance for Mandelbulb {
+    fn distance(&self, p: Vec3) -> f64 {
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = z.mag();
+
+        for _ in 0..self.iterations {
+            if r > Mandelbulb::BAILOUT {
+                break;
+            }
+
+            // Raise z to the given power in spherical coordinates, tracking the derivative.
+            let theta = if r > 0.0 { (z.z / r).acos() } else { 0.0 };
+            let phi = z.y.atan2(z.x);
+            dr = self.power * r.powf(self.power - 1.0) * dr + 1.0;
+
+            let zr = r.powf(self.power);
+            let (sin_theta, cos_theta) = (theta * self.power).sin_cos();
+            let (sin_phi, cos_phi) = (phi * self.power).sin_cos();
+            z =
+                zr * Vec3 {
+                    x: sin_theta * cos_phi,
+                    y: sin_theta * sin_phi,
+                    z: cos_theta,
+                } + p;
+            r = z.mag();
+        }
+
+        if r > 0.0 {
+            0.5 * r.ln() * r / dr
+        } else {
+            0.0
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: splat(-Mandelbulb::BAILOUT),
+            max: splat(Mandelbulb::BAILOUT),
+        }
+    }
+}
+
+/// An object whose surface is the zero set of a signed distance function, rendered by sphere
+/// tracing.
+///
+/// Distance fields have no natural parameterization, so all hits have zero texture coordinates;
+/// textures should be evaluated in terms of the hit point instead.
+pub struct Sdf<D> {
+    distance: D,
+    max_steps: u32,
+    tolerance: f64,
+}
+
+impl<D: Distance> Sdf<D> {
+    const DEFAULT_MAX_STEPS: u32 = 256;
+    const DEFAULT_TOLERANCE: f64 = 1e-4;
+
+    pub fn new(distance: D) -> Sdf<D> {
+        Sdf {
+            distance,
+            max_steps: Self::DEFAULT_MAX_STEPS,
+            tolerance: Self::DEFAULT_TOLERANCE,
+        }
+    }
+
+    /// Creates an object that is marched for at most `max_steps`, and whose surface is
+    /// considered hit once the distance to it falls below `tolerance`. Fractals and other
+    /// finely detailed fields may need more steps and a smaller tolerance than the defaults.
+    pub fn with_settings(distance: D, max_steps: u32, tolerance: f64) -> Result<Sdf<D>> {
+        if max_steps == 0 {
+            return Err(Error::InvalidParameter("SDF step count must be positive"));
+        }
+        check_positive(tolerance, "SDF tolerance must be positive")?;
+
+        Ok(Sdf {
+            distance,
+            max_steps,
+            tolerance,
+        })
+    }
+
+    pub fn distance(&self) -> &D {
+        &self.distance
+    }
+
+    /// Estimates the outward normal at `p` from the gradient of the distance, sampled at the
+    /// vertices of a tetrahedron.
+    fn normal_at(&self, p: Vec3) -> Option<Unit3> {
+        let h = self.tolerance;
+        let offsets = [
+            Vec3 {
+                x: 1.0,
+                y: -1.0,
+                z: -1.0,
+            },
+            Vec3 {
+                x: -1.0,
+                y: -1.0,
+                z: 1.0,
+            },
+            Vec3 {
+                x: -1.0,
+                y: 1.0,
+                z: -1.0,
+            },
+            Vec3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        ];
+
+        offsets
+            .iter()
+            .fold(Vec3::default(), |gradient, &k| {
+                gradient + self.distance.distance(p + h * k) * k
+            })
+            .try_to_unit()
+    }
+}
+
+impl<D: Distance> Geom for Sdf<D> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (t_min, t_max) = self.distance.bounds().intersect_range(
+            ray,
+            Vec3::from(ray.dir).recip(),
+            f64::INFINITY,
+        )?;
+
+        let mut t = t_min;
+        let mut d = self.distance.distance(ray.interp(t));
+
+        // Rays leaving a surface start within the tolerance of it, so first step away until the
+        // surface is behind us. The side we end up on decides whether we're looking for the
+        // surface from the outside or the inside.
+        let mut steps = 0;
+        while d.abs() < self.tolerance {
+            t += self.tolerance;
+            d = self.distance.distance(ray.interp(t));
+            steps += 1;
+            if t > t_max || steps >= self.max_steps {
+                return None;
+            }
+        }
+        let sign = d.signum();
+
+        while steps < self.max_steps {
+            let d = sign * self.distance.distance(ray.interp(t));
+            if d < self.tolerance {
+                let point = ray.interp(t);
+                let normal = self.normal_at(point)?;
+                let basis = Basis::from_normal(normal);
+                return Some(Hit::new(t, normal, (0.0, 0.0), basis.x, basis.y));
+            }
+
+            t += d;
+            if t > t_max {
+                return None;
+            }
+            steps += 1;
+        }
+
+        None
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.distance.bounds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sphere tracing stops within the surface tolerance of the surface.
+    const TOLERANCE: f64 = Sdf::<Sphere>::DEFAULT_TOLERANCE;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn ray(origin: Vec3, dir: Vec3) -> Ray {
+        Ray {
+            origin,
+            dir: dir.to_unit(),
+            time: 0.0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn sphere_hits_at_analytic_distance() {
+        let sdf = Sdf::new(Sphere::new(vec3(0.0, 0.0, 0.0), 1.0).unwrap());
+
+        let offset = 0.3f64;
+        let hit = sdf
+            .intersect(&ray(vec3(-5.0, offset, 0.0), vec3(1.0, 0.0, 0.0)))
+            .expect("expected a hit");
+        let depth = (1.0 - offset * offset).sqrt();
+        assert_close(hit.dist, 5.0 - depth);
+        let normal = Vec3::from(hit.normal);
+        assert_close(normal.x, -depth);
+        assert_close(normal.y, offset);
+
+        // From the inside, the march finds the far side.
+        let hit = sdf
+            .intersect(&ray(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)))
+            .expect("expected a hit");
+        assert_close(hit.dist, 1.0);
+
+        assert!(sdf
+            .intersect(&ray(vec3(-5.0, 1.5, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_none());
+        assert!(sdf
+            .intersect(&ray(vec3(-5.0, 0.0, 0.0), vec3(-1.0, 0.0, 0.0)))
+            .is_none());
+    }
+}
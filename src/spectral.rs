@@ -0,0 +1,253 @@
+use std::f64;
+use std::sync::OnceLock;
+
+use crate::math::Vec3;
+
+/// The shortest wavelength sampled in spectral mode, in nanometers.
+pub const LAMBDA_MIN: f64 = 380.0;
+/// The longest wavelength sampled in spectral mode, in nanometers.
+pub const LAMBDA_MAX: f64 = 720.0;
+/// The wavelength at which dispersive materials are evaluated when rendering in RGB, the
+/// Fraunhofer d line.
+pub const REFERENCE_WAVELENGTH: f64 = 587.6;
+
+/// Picks three wavelengths spread evenly over the visible range, starting from a hero
+/// wavelength chosen by `u` in `[0, 1)`. The hero wavelength is the x component.
+pub fn sample_wavelengths(u: f64) -> Vec3 {
+    let rotate = |offset: f64| LAMBDA_MIN + (u + offset).fract() * (LAMBDA_MAX - LAMBDA_MIN);
+    Vec3 {
+        x: rotate(0.0),
+        y: rotate(1.0 / 3.0),
+        z: rotate(2.0 / 3.0),
+    }
+}
+
+// Smits' basis spectra for converting RGB reflectances, in 10 bins evenly spaced over the
+// sampled range. See "An RGB to Spectrum Conversion for Reflectances" (Smits, 1999).
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Linearly interpolates a basis spectrum between the centers of its bins.
+fn eval_basis(basis: &[f64; 10], lambda: f64) -> f64 {
+    let bin_width = (LAMBDA_MAX - LAMBDA_MIN) / basis.len() as f64;
+    let pos = ((lambda - LAMBDA_MIN) / bin_width - 0.5).clamp(0.0, (basis.len() - 1) as f64);
+    let lower = pos.floor() as usize;
+    let upper = (lower + 1).min(basis.len() - 1);
+    let t = pos - lower as f64;
+    basis[lower] * (1.0 - t) + basis[upper] * t
+}
+
+/// Evaluates a smooth spectrum matching the linear RGB color `rgb` at a single wavelength.
+fn uplift_one(rgb: Vec3, lambda: f64) -> f64 {
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let basis = |spectrum: &[f64; 10]| eval_basis(spectrum, lambda);
+
+    // Build the spectrum from white plus the secondary and primary colors that remain.
+    let value = if r <= g && r <= b {
+        r * basis(&SMITS_WHITE)
+            + if g <= b {
+                (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+            } else {
+                (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * basis(&SMITS_WHITE)
+            + if r <= b {
+                (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+            } else {
+                (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+            }
+    } else {
+        b * basis(&SMITS_WHITE)
+            + if r <= g {
+                (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+            } else {
+                (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+            }
+    };
+
+    value.max(0.0)
+}
+
+/// Converts a linear RGB color into the values of a matching spectrum at each of the given
+/// wavelengths.
+pub fn uplift(rgb: Vec3, wavelengths: Vec3) -> Vec3 {
+    Vec3 {
+        x: uplift_one(rgb, wavelengths.x),
+        y: uplift_one(rgb, wavelengths.y),
+        z: uplift_one(rgb, wavelengths.z),
+    }
+}
+
+/// Evaluates the CIE 1931 color matching functions at a wavelength, using the multi-lobe fit
+/// from "Simple Analytic Approximations to the CIE XYZ Color Matching Functions" (Wyman et al.,
+/// 2013).
+fn color_matching(lambda: f64) -> Vec3 {
+    let lobe = |mean: f64, below: f64, above: f64| {
+        let t = (lambda - mean) / if lambda < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+
+    Vec3 {
+        x: 1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        y: 0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        z: 1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    }
+}
+
+fn xyz_to_linear_srgb(xyz: Vec3) -> Vec3 {
+    Vec3 {
+        x: 3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        y: -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        z: 0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    }
+}
+
+/// The integral of the color matching functions over the sampled range.
+fn white_xyz() -> Vec3 {
+    static WHITE: OnceLock<Vec3> = OnceLock::new();
+    *WHITE.get_or_init(|| {
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        (0..steps)
+            .map(|step| color_matching(LAMBDA_MIN + step as f64 + 0.5))
+            .fold(Vec3::default(), |a, b| a + b)
+    })
+}
+
+/// Converts radiance sampled at the given wavelengths into linear sRGB.
+///
+/// Colors are balanced so that a constant spectrum of one maps to white, which is also what
+/// `uplift` produces for white.
+pub fn to_rgb(values: Vec3, wavelengths: Vec3) -> Vec3 {
+    let white = white_xyz();
+
+    // Monte Carlo estimate of the integral of radiance against the color matching functions,
+    // with uniformly sampled wavelengths.
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let xyz = (values.x * color_matching(wavelengths.x)
+        + values.y * color_matching(wavelengths.y)
+        + values.z * color_matching(wavelengths.z))
+        * (range / 3.0 / white.y);
+
+    let rgb = xyz_to_linear_srgb(xyz);
+    let white_rgb = xyz_to_linear_srgb(white / white.y);
+    Vec3 {
+        x: rgb.x / white_rgb.x,
+        y: rgb.y / white_rgb.y,
+        z: rgb.z / white_rgb.z,
+    }
+}
+
+/// The index of refraction of a dielectric, possibly varying with wavelength.
+#[derive(Debug, Copy, Clone)]
+pub enum Ior {
+    Constant(f64),
+    /// Cauchy's equation, `n = a + b / λ²` with `λ` in micrometers.
+    Cauchy {
+        a: f64,
+        b: f64,
+    },
+    /// The Sellmeier equation, `n² = 1 + Σ bᵢλ² / (λ² - cᵢ)` with `λ` in micrometers.
+    Sellmeier {
+        b: [f64; 3],
+        c: [f64; 3],
+    },
+}
+
+impl Ior {
+    /// Borosilicate crown glass, a common optical glass with low dispersion.
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+        c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+    };
+
+    /// Dense flint glass, which disperses light strongly.
+    pub const SF11: Ior = Ior::Sellmeier {
+        b: [1.737_596_95, 0.313_747_346, 1.898_781_01],
+        c: [0.013_188_707, 0.062_306_814_2, 155.236_29],
+    };
+
+    /// Returns the index of refraction at a wavelength in nanometers.
+    pub fn eval(&self, lambda: f64) -> f64 {
+        let micrometers = lambda / 1000.0;
+        let l2 = micrometers * micrometers;
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => {
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+
+    /// Returns whether the index of refraction varies with wavelength.
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Averages the color estimated for `rgb` over evenly spread hero wavelengths.
+    fn round_trip(rgb: Vec3) -> Vec3 {
+        let samples = 1000;
+        (0..samples)
+            .map(|idx| {
+                let wavelengths = sample_wavelengths((idx as f64 + 0.5) / samples as f64);
+                to_rgb(uplift(rgb, wavelengths), wavelengths)
+            })
+            .fold(Vec3::default(), |a, b| a + b)
+            / samples as f64
+    }
+
+    #[test]
+    fn white_round_trips() {
+        for &level in &[1.0, 0.25] {
+            let rgb = round_trip(Vec3 {
+                x: level,
+                y: level,
+                z: level,
+            });
+            for axis in 0..3 {
+                assert!(
+                    (rgb.axis(axis) - level).abs() < 1e-3,
+                    "expected gray {}, got {:?}",
+                    level,
+                    rgb
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn wavelengths_cover_visible_range() {
+        for &u in &[0.0, 0.4, 0.999] {
+            let wavelengths = sample_wavelengths(u);
+            for axis in 0..3 {
+                assert!((LAMBDA_MIN..LAMBDA_MAX).contains(&wavelengths.axis(axis)));
+            }
+        }
+    }
+}
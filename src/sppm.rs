@@ -0,0 +1,301 @@
+use std::f64;
+
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::bdpt::{shading_correction, LightDistribution};
+use crate::bsdf::Bsdf;
+use crate::kdtree::KdTree;
+use crate::math::*;
+use crate::renderer::{rgb_bsdf, Camera, RenderOptions, Scene};
+use crate::stats::{self, Counter};
+
+/// The fraction of newly gathered photons kept in each pixel's estimate, which trades how fast
+/// the gather radius shrinks against how much noise remains.
+const ALPHA: f64 = 2.0 / 3.0;
+
+/// Light left by a photon where it hit a non-specular surface.
+struct Photon {
+    /// The direction the photon arrived from, pointing away from the surface
+    wi: Unit3,
+    power: Vec3,
+    /// The number of segments the photon travelled from its light
+    edges: u32,
+}
+
+/// The first non-specular surface a camera ray hits in a pass, where photons are gathered.
+struct VisiblePoint {
+    point: Vec3,
+    wo: Unit3,
+    bsdf: Bsdf,
+    /// The fraction of light leaving the point that reaches the camera
+    beta: Vec3,
+    /// The number of segments between the point and the camera
+    edges: u32,
+}
+
+/// The progressive estimate for a pixel, refined after every pass.
+struct PixelState {
+    radius: f64,
+    /// The number of photons the estimate is made from, discounted as the radius shrinks
+    count: f64,
+    /// The power of those photons, scaled to the current radius and weighted by the BSDFs
+    /// they were gathered at
+    flux: Vec3,
+    /// The light emitted towards the camera or sampled directly from lights, summed over passes
+    direct: Vec3,
+    visible: Option<VisiblePoint>,
+}
+
+struct Tracer<'s> {
+    scene: &'s Scene<'s>,
+    max_depth: u32,
+    scene_bounds: Aabb,
+    lights: LightDistribution,
+}
+
+impl<'s> Tracer<'s> {
+    /// Follows `ray` through specular bounces until it hits a non-specular surface, returning
+    /// the light found along the way and the point where photons should be gathered.
+    fn trace_camera<R: Rng + ?Sized>(
+        &self,
+        ray: &Ray,
+        rng: &mut R,
+    ) -> (Vec3, Option<VisiblePoint>) {
+        let mut ray = *ray;
+        let mut beta = Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let mut radiance = Vec3::default();
+
+        for edges in 1..=self.max_depth {
+            let info = match self.scene.intersect_surface(&ray) {
+                Some(info) => info,
+                None => break,
+            };
+            let wo = (-Vec3::from(ray.dir)).to_unit();
+            let material = info.prim.material();
+            if material.two_sided || !info.inside {
+                let emitted =
+                    self.scene.emission_scale(&info) * material.emittance.eval(info.uv, info.point);
+                radiance = radiance + beta.component_mul(emitted);
+            }
+
+            // Pick between the specular lobes, which are followed, and the rest, which are
+            // estimated from the photons nearby.
+            let bsdf = rgb_bsdf(&info);
+            let sample = match bsdf.sample(wo, rng) {
+                Some(sample) if sample.specular => sample,
+                _ if bsdf.is_specular() => break,
+                _ => {
+                    beta = beta / (1.0 - bsdf.specular_probability());
+                    if edges < self.max_depth {
+                        let direct = self.scene.sample_direct(&info, &bsdf, wo, rng);
+                        radiance = radiance + beta.component_mul(direct);
+                    }
+                    let visible = VisiblePoint {
+                        point: info.point,
+                        wo,
+                        bsdf,
+                        beta,
+                        edges,
+                    };
+                    return (radiance, Some(visible));
+                }
+            };
+
+            beta = beta.component_mul(sample.weight);
+            if beta.mag_squared() <= 0.0 {
+                break;
+            }
+            stats::add(Counter::BounceRays, 1);
+            ray = Ray {
+                origin: info.point,
+                dir: sample.dir,
+                time: ray.time,
+            };
+        }
+
+        (radiance, None)
+    }
+
+    /// Traces a photon from a light at `time`, leaving it at each non-specular surface it
+    /// reaches after bouncing at least once. Light arriving straight from the lights is sampled
+    /// directly instead.
+    fn trace_photon<R: Rng + ?Sized>(
+        &self,
+        photons: &mut Vec<(Vec3, Photon)>,
+        time: f64,
+        rng: &mut R,
+    ) {
+        let (idx, prob) = match self.lights.sample(rng.gen()) {
+            Some(picked) => picked,
+            None => return,
+        };
+        let emission = match self
+            .scene
+            .light(idx)
+            .get()
+            .sample_emission(&self.scene_bounds, &mut &mut *rng)
+        {
+            Some(emission) => emission,
+            None => return,
+        };
+        if emission.pdf_pos <= 0.0 || emission.pdf_dir <= 0.0 {
+            return;
+        }
+
+        let cos_theta = emission.normal.map_or(1.0, |normal| {
+            Vec3::from(normal).dot(emission.ray.dir.into()).abs()
+        });
+        let radiance = self.scene.light_scale(idx, time) * emission.radiance;
+        let mut power = cos_theta / (prob * emission.pdf_pos * emission.pdf_dir) * radiance;
+        let mut ray = Ray {
+            time,
+            ..emission.ray
+        };
+
+        // Every photon needs at least one more segment to reach the camera.
+        for edges in 1..self.max_depth {
+            if power.mag_squared() <= 0.0 {
+                return;
+            }
+            let info = match self.scene.intersect_surface(&ray) {
+                Some(info) => info,
+                None => return,
+            };
+            let wi = (-Vec3::from(ray.dir)).to_unit();
+            let bsdf = rgb_bsdf(&info);
+            if edges > 1 && !bsdf.is_specular() {
+                photons.push((info.point, Photon { wi, power, edges }));
+            }
+
+            let sample = match bsdf.sample(wi, rng) {
+                Some(sample) => sample,
+                None => return,
+            };
+            let correction = shading_correction(info.normal, info.shading_normal, wi, sample.dir);
+            let scattered = correction * power.component_mul(sample.weight);
+
+            // Keep the power of surviving photons roughly constant, so that a few bright
+            // photons don't dominate the estimates.
+            let survival = (average(scattered) / average(power)).min(1.0);
+            if rng.gen::<f64>() >= survival {
+                return;
+            }
+            power = scattered / survival;
+            stats::add(Counter::BounceRays, 1);
+            ray = Ray {
+                origin: info.point,
+                dir: sample.dir,
+                time: ray.time,
+            };
+        }
+    }
+}
+
+impl PixelState {
+    /// Adds the photons around the pixel's visible point to its estimate, shrinking the radius
+    /// to keep only a fraction `ALPHA` of the new ones.
+    fn gather(&mut self, photons: &KdTree<Photon>, max_depth: u32) {
+        let visible = match self.visible.take() {
+            Some(visible) => visible,
+            None => return,
+        };
+
+        let mut found = 0.0;
+        let mut flux = Vec3::default();
+        photons.for_each_within(visible.point, self.radius, |_, photon| {
+            if photon.edges + visible.edges > max_depth {
+                return;
+            }
+            let value = visible.bsdf.f(visible.wo, photon.wi);
+            flux = flux + value.component_mul(photon.power);
+            found += 1.0;
+        });
+        if found <= 0.0 {
+            return;
+        }
+
+        let count = self.count + ALPHA * found;
+        let radius = self.radius * (count / (self.count + found)).sqrt();
+        let scale = (radius / self.radius).powi(2);
+        self.flux = scale * (self.flux + visible.beta.component_mul(flux));
+        self.count = count;
+        self.radius = radius;
+    }
+}
+
+/// Renders with stochastic progressive photon mapping, running a pass of `photons` photons for
+/// each sample per pixel and starting with a gather radius of `radius`.
+pub(crate) fn render_to(
+    scene: &Scene,
+    camera: &Camera,
+    pixels: &mut [Vec3],
+    opts: &RenderOptions,
+    photons: u32,
+    radius: f64,
+) {
+    let tracer = Tracer {
+        scene,
+        max_depth: opts.max_depth,
+        scene_bounds: scene.finite_bounds(),
+        lights: LightDistribution::new(scene),
+    };
+
+    let mut states: Vec<_> = (0..pixels.len())
+        .map(|_| PixelState {
+            radius,
+            count: 0.0,
+            flux: Vec3::default(),
+            direct: Vec3::default(),
+            visible: None,
+        })
+        .collect();
+
+    for _ in 0..opts.samples_per_pixel {
+        states.par_iter_mut().enumerate().for_each(|(idx, state)| {
+            let x = (idx as u32) % opts.width;
+            let y = (idx as u32) / opts.width;
+
+            let mut rng = rand::thread_rng();
+            let ray = camera.cast_ray(
+                f64::from(x) + rng.gen::<f64>(),
+                f64::from(y) + rng.gen::<f64>(),
+                camera.sample_time(rng.gen()),
+            );
+            let (direct, visible) = tracer.trace_camera(&ray, &mut rng);
+            state.direct = state.direct + direct;
+            state.visible = visible;
+        });
+
+        let traced: Vec<_> = (0..photons)
+            .into_par_iter()
+            .fold(Vec::new, |mut traced, _| {
+                let mut rng = rand::thread_rng();
+                tracer.trace_photon(&mut traced, camera.sample_time(rng.gen()), &mut rng);
+                traced
+            })
+            .flatten()
+            .collect();
+        let tree = KdTree::new(traced);
+
+        states
+            .par_iter_mut()
+            .for_each(|state| state.gather(&tree, opts.max_depth));
+    }
+
+    // Every pass's photons share the lights' power between them.
+    let passes = f64::from(opts.samples_per_pixel);
+    let emitted = passes * f64::from(photons);
+    for (pixel, state) in pixels.iter_mut().zip(&states) {
+        let area = f64::consts::PI * state.radius * state.radius;
+        *pixel = state.direct / passes + state.flux / (emitted * area);
+    }
+}
+
+fn average(color: Vec3) -> f64 {
+    (color.x + color.y + color.z) / 3.0
+}
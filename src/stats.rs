@@ -0,0 +1,254 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A count of some kind of work done while rendering.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Counter {
+    CameraRays,
+    BounceRays,
+    ShadowRays,
+    IntersectionTests,
+    NodesVisited,
+    /// Paths from the camera that left the scene
+    Escaped,
+    /// Paths from the camera that reached a surface that scattered no light
+    Absorbed,
+    /// Paths from the camera that were cut off at the maximum depth
+    MaxDepth,
+    /// The number of times paths from the camera scattered, summed over the paths that ended
+    PathLength,
+}
+
+const COUNTER_COUNT: usize = 9;
+
+/// The counters of a single thread. Only that thread writes to them, so they're updated
+/// without read-modify-write operations.
+#[derive(Default)]
+struct Counters([AtomicU64; COUNTER_COUNT]);
+
+impl Counters {
+    fn get(&self, counter: Counter) -> u64 {
+        self.0[counter as usize].load(Ordering::Relaxed)
+    }
+}
+
+thread_local! {
+    static COUNTERS: RefCell<Option<Arc<Counters>>> = const { RefCell::new(None) };
+}
+
+/// Adds `amount` to one of the current thread's counters, if it has any.
+pub(crate) fn add(counter: Counter, amount: u64) {
+    COUNTERS.with(|counters| {
+        if let Some(counters) = &*counters.borrow() {
+            let value = &counters.0[counter as usize];
+            value.store(value.load(Ordering::Relaxed) + amount, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Returns one of the current thread's counters, or 0 if it has none.
+pub(crate) fn get(counter: Counter) -> u64 {
+    COUNTERS.with(|counters| {
+        counters
+            .borrow()
+            .as_ref()
+            .map_or(0, |counters| counters.get(counter))
+    })
+}
+
+/// Gathers the counters of the threads that render an image.
+#[derive(Clone, Default)]
+pub(crate) struct Collector(Arc<Mutex<Vec<Arc<Counters>>>>);
+
+impl Collector {
+    /// Gives the current thread counters of its own, replacing any it had, which are summed
+    /// into the collector's totals.
+    pub(crate) fn attach(&self) {
+        let counters = Arc::new(Counters::default());
+        self.0.lock().unwrap().push(Arc::clone(&counters));
+        COUNTERS.with(|current| *current.borrow_mut() = Some(counters));
+    }
+
+    /// Sums the counters of every thread attached so far.
+    pub(crate) fn stats(&self) -> RenderStats {
+        let threads = self.0.lock().unwrap();
+        let total = |counter| threads.iter().map(|counters| counters.get(counter)).sum();
+        RenderStats {
+            camera_rays: total(Counter::CameraRays),
+            bounce_rays: total(Counter::BounceRays),
+            shadow_rays: total(Counter::ShadowRays),
+            intersection_tests: total(Counter::IntersectionTests),
+            nodes_visited: total(Counter::NodesVisited),
+            escaped: total(Counter::Escaped),
+            absorbed: total(Counter::Absorbed),
+            max_depth: total(Counter::MaxDepth),
+            path_length: total(Counter::PathLength),
+        }
+    }
+}
+
+/// Counts of the work done to render an image, summed over all of the threads that rendered
+/// it.
+///
+/// Rays are counted by every integrator, while paths are only counted where they're traced
+/// from the camera one bounce at a time, by the path tracer and Metropolis light transport.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /// Rays cast through pixels
+    pub camera_rays: u64,
+    /// Rays leaving a point where light scattered, to find where the light came from
+    pub bounce_rays: u64,
+    /// Rays testing whether two points can see each other
+    pub shadow_rays: u64,
+    /// Tests of a ray against a single primitive or triangle
+    pub intersection_tests: u64,
+    /// BVH nodes whose bounds a ray was tested against
+    pub nodes_visited: u64,
+    /// Paths that left the scene
+    pub escaped: u64,
+    /// Paths that reached a surface that scattered no light back
+    pub absorbed: u64,
+    /// Paths that were cut off at the maximum depth
+    pub max_depth: u64,
+    /// The number of times light scattered, summed over all paths
+    pub path_length: u64,
+}
+
+impl RenderStats {
+    pub fn rays(&self) -> u64 {
+        self.camera_rays + self.bounce_rays + self.shadow_rays
+    }
+
+    pub fn paths(&self) -> u64 {
+        self.escaped + self.absorbed + self.max_depth
+    }
+
+    /// Returns the mean number of times light scattered along a path, or 0 if no paths were
+    /// counted.
+    pub fn average_path_length(&self) -> f64 {
+        if self.paths() == 0 {
+            0.0
+        } else {
+            self.path_length as f64 / self.paths() as f64
+        }
+    }
+
+    /// Returns the statistics as a JSON object, for reading by other tools.
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("camera_rays", self.camera_rays),
+            ("bounce_rays", self.bounce_rays),
+            ("shadow_rays", self.shadow_rays),
+            ("intersection_tests", self.intersection_tests),
+            ("nodes_visited", self.nodes_visited),
+            ("paths", self.paths()),
+            ("escaped", self.escaped),
+            ("absorbed", self.absorbed),
+            ("max_depth", self.max_depth),
+        ];
+        let mut json = String::from("{");
+        for (name, value) in &fields {
+            json.push_str(&format!("\"{}\": {}, ", name, value));
+        }
+        json.push_str(&format!(
+            "\"average_path_length\": {}}}",
+            self.average_path_length()
+        ));
+        json
+    }
+}
+
+/// Returns `value` as a quoted JSON string, escaping the characters JSON doesn't allow in one.
+pub fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let per_ray = |count: u64| {
+            if self.rays() == 0 {
+                0.0
+            } else {
+                count as f64 / self.rays() as f64
+            }
+        };
+        let share = |count: u64| {
+            if self.paths() == 0 {
+                0.0
+            } else {
+                100.0 * count as f64 / self.paths() as f64
+            }
+        };
+
+        writeln!(f, "Rays:               {}", self.rays())?;
+        writeln!(f, "  camera:           {}", self.camera_rays)?;
+        writeln!(f, "  bounce:           {}", self.bounce_rays)?;
+        writeln!(f, "  shadow:           {}", self.shadow_rays)?;
+        writeln!(
+            f,
+            "Intersection tests: {} ({:.1} per ray)",
+            self.intersection_tests,
+            per_ray(self.intersection_tests)
+        )?;
+        writeln!(
+            f,
+            "BVH nodes visited:  {} ({:.1} per ray)",
+            self.nodes_visited,
+            per_ray(self.nodes_visited)
+        )?;
+        writeln!(
+            f,
+            "Paths:              {} (average length {:.2})",
+            self.paths(),
+            self.average_path_length()
+        )?;
+        writeln!(
+            f,
+            "  escaped:          {} ({:.1}%)",
+            self.escaped,
+            share(self.escaped)
+        )?;
+        writeln!(
+            f,
+            "  absorbed:         {} ({:.1}%)",
+            self.absorbed,
+            share(self.absorbed)
+        )?;
+        write!(
+            f,
+            "  max depth:        {} ({:.1}%)",
+            self.max_depth,
+            share(self.max_depth)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("mirror"), r#""mirror""#);
+        assert_eq!(json_string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(
+            json_string("tab\tnew\nline\u{1}"),
+            r#""tab\tnew\nline\u0001""#
+        );
+    }
+}
@@ -0,0 +1,598 @@
+use std::f64;
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::error::{Error, Result};
+use crate::img;
+use crate::math::*;
+use crate::sample::Basis;
+
+/// A texture coordinate pair.
+pub type Uv = (f64, f64);
+
+/// A spatially varying color, evaluated at surface hit points.
+pub trait Texture: Send + Sync {
+    /// Evaluates the texture at the specified texture coordinates and world-space point.
+    fn eval(&self, uv: Uv, point: Vec3) -> Vec3;
+}
+
+/// A material parameter which is either constant or read from a texture.
+///
+/// Scalar parameters read from a texture use the average of its channels.
+#[derive(Clone)]
+pub enum Param<T> {
+    Const(T),
+    Texture(Arc<dyn Texture>),
+}
+
+impl<T> Param<T> {
+    pub fn texture<X: Texture + 'static>(texture: X) -> Param<T> {
+        Param::Texture(Arc::new(texture))
+    }
+}
+
+impl<T> From<T> for Param<T> {
+    fn from(val: T) -> Param<T> {
+        Param::Const(val)
+    }
+}
+
+impl Param<Vec3> {
+    pub fn eval(&self, uv: Uv, point: Vec3) -> Vec3 {
+        match self {
+            Param::Const(val) => *val,
+            Param::Texture(texture) => texture.eval(uv, point),
+        }
+    }
+}
+
+impl Param<f64> {
+    pub fn eval(&self, uv: Uv, point: Vec3) -> f64 {
+        match self {
+            Param::Const(val) => *val,
+            Param::Texture(texture) => {
+                let color = texture.eval(uv, point);
+                (color.x + color.y + color.z) / 3.0
+            }
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Param<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Param::Const(val) => f.debug_tuple("Const").field(val).finish(),
+            Param::Texture(_) => f.write_str("Texture"),
+        }
+    }
+}
+
+/// How texture coordinates outside of `[0, 1]` are mapped back into the image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WrapMode {
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+impl WrapMode {
+    fn apply(self, idx: i64, size: usize) -> usize {
+        let size = size as i64;
+        let wrapped = match self {
+            WrapMode::Repeat => idx.rem_euclid(size),
+            WrapMode::Mirror => {
+                let period = idx.rem_euclid(2 * size);
+                if period < size {
+                    period
+                } else {
+                    2 * size - 1 - period
+                }
+            }
+            WrapMode::Clamp => idx.clamp(0, size - 1),
+        };
+        wrapped as usize
+    }
+}
+
+fn srgb_to_linear(val: f64) -> f64 {
+    if val <= 0.04045 {
+        val / 12.92
+    } else {
+        ((val + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A bilinearly filtered texture backed by an image.
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    texels: Vec<[f32; 3]>,
+    wrap: WrapMode,
+}
+
+impl ImageTexture {
+    /// Creates a texture from linear RGB texels, stored in row-major order from the top row.
+    pub fn new(width: usize, height: usize, texels: Vec<[f32; 3]>, wrap: WrapMode) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidParameter(
+                "texture dimensions must be nonzero",
+            ));
+        }
+        if texels.len() != width * height {
+            return Err(Error::BufferSize {
+                expected: width * height,
+                actual: texels.len(),
+            });
+        }
+        Ok(ImageTexture {
+            width,
+            height,
+            texels,
+            wrap,
+        })
+    }
+
+    /// Loads a PNG image. Color images should normally be decoded as sRGB, while images holding
+    /// other data (such as gloss maps) should be loaded with `srgb` set to `false`.
+    pub fn load_png<P: AsRef<Path>>(path: P, wrap: WrapMode, srgb: bool) -> Result<Self> {
+        let image = img::read_png(path)?;
+
+        let decode = |val: f64| if srgb { srgb_to_linear(val) } else { val };
+        let texels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.rgb();
+                [decode(r) as f32, decode(g) as f32, decode(b) as f32]
+            })
+            .collect();
+
+        ImageTexture::new(image.width, image.height, texels, wrap)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        let [r, g, b] = self.texels[y * self.width + x];
+        Vec3 {
+            x: f64::from(r),
+            y: f64::from(g),
+            z: f64::from(b),
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn eval(&self, (u, v): Uv, _point: Vec3) -> Vec3 {
+        // Texel centers lie at half-integer coordinates, and v points up the image.
+        let x = u * self.width as f64 - 0.5;
+        let y = (1.0 - v) * self.height as f64 - 0.5;
+
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        (1.0 - ty) * ((1.0 - tx) * self.texel(x0, y0) + tx * self.texel(x0 + 1, y0))
+            + ty * ((1.0 - tx) * self.texel(x0, y0 + 1) + tx * self.texel(x0 + 1, y0 + 1))
+    }
+}
+
+/// Alternates between two colors in a checkerboard pattern in texture space.
+pub struct Checkerboard {
+    pub even: Vec3,
+    pub odd: Vec3,
+    /// Number of squares along each texture axis
+    pub frequency: f64,
+}
+
+impl Texture for Checkerboard {
+    fn eval(&self, (u, v): Uv, _point: Vec3) -> Vec3 {
+        let parity = ((u * self.frequency).floor() + (v * self.frequency).floor()) as i64;
+        if parity.rem_euclid(2) == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+/// Which texture coordinate a `Gradient` varies along.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GradientAxis {
+    U,
+    V,
+}
+
+/// Linearly interpolates between two colors along one of the texture axes.
+pub struct Gradient {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub axis: GradientAxis,
+}
+
+impl Texture for Gradient {
+    fn eval(&self, (u, v): Uv, _point: Vec3) -> Vec3 {
+        let t = match self.axis {
+            GradientAxis::U => u,
+            GradientAxis::V => v,
+        }
+        .clamp(0.0, 1.0);
+        (1.0 - t) * self.start + t * self.end
+    }
+}
+
+/// Improved Perlin gradient noise in three dimensions.
+pub struct Perlin {
+    perm: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut base: Vec<u8> = (0..=255).collect();
+        base.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        let mut perm = [0; 512];
+        for (i, val) in perm.iter_mut().enumerate() {
+            *val = base[i % 256];
+        }
+        Perlin { perm }
+    }
+
+    /// Evaluates the noise at `p`, returning a value in approximately `[-1, 1]`.
+    pub fn noise(&self, p: Vec3) -> f64 {
+        let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+        let xi = (fx as i64 & 255) as usize;
+        let yi = (fy as i64 & 255) as usize;
+        let zi = (fz as i64 & 255) as usize;
+
+        let perm = &self.perm;
+        let hash = |dx: usize, dy: usize, dz: usize| {
+            perm[perm[perm[xi + dx] as usize + yi + dy] as usize + zi + dz]
+        };
+
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(
+                    u,
+                    grad(hash(0, 0, 0), x, y, z),
+                    grad(hash(1, 0, 0), x - 1.0, y, z),
+                ),
+                lerp(
+                    u,
+                    grad(hash(0, 1, 0), x, y - 1.0, z),
+                    grad(hash(1, 1, 0), x - 1.0, y - 1.0, z),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    grad(hash(0, 0, 1), x, y, z - 1.0),
+                    grad(hash(1, 0, 1), x - 1.0, y, z - 1.0),
+                ),
+                lerp(
+                    u,
+                    grad(hash(0, 1, 1), x, y - 1.0, z - 1.0),
+                    grad(hash(1, 1, 1), x - 1.0, y - 1.0, z - 1.0),
+                ),
+            ),
+        )
+    }
+
+    /// Sums `octaves` layers of noise, each with double the frequency of the last and its
+    /// amplitude scaled by `persistence`.
+    pub fn fbm(&self, p: Vec3, octaves: u32, persistence: f64) -> f64 {
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let mut max = 0.0;
+
+        for _ in 0..octaves {
+            total += amplitude * self.noise(frequency * p);
+            max += amplitude;
+            amplitude *= persistence;
+            frequency *= 2.0;
+        }
+
+        if max > 0.0 {
+            total / max
+        } else {
+            0.0
+        }
+    }
+}
+
+fn grad(hash: u8, x: f64, y: f64, z: f64) -> f64 {
+    // Dot product with one of 12 edge directions of a cube
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// A solid texture blending between two colors according to fractal Perlin noise evaluated at
+/// the world-space hit point.
+pub struct NoiseTexture {
+    perlin: Perlin,
+    pub low: Vec3,
+    pub high: Vec3,
+    /// Frequency of the first octave, in world units
+    pub scale: f64,
+    pub octaves: u32,
+    pub persistence: f64,
+}
+
+impl NoiseTexture {
+    pub fn new(low: Vec3, high: Vec3, scale: f64, octaves: u32, persistence: f64) -> Self {
+        NoiseTexture {
+            perlin: Perlin::new(0),
+            low,
+            high,
+            scale,
+            octaves,
+            persistence,
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn eval(&self, _uv: Uv, point: Vec3) -> Vec3 {
+        let noise = self
+            .perlin
+            .fbm(self.scale * point, self.octaves, self.persistence);
+        let t = (0.5 * (noise + 1.0)).clamp(0.0, 1.0);
+        (1.0 - t) * self.low + t * self.high
+    }
+}
+
+/// Offset in texture space used to estimate the slope of bump maps.
+const BUMP_DELTA: f64 = 1e-3;
+
+/// Perturbs the shading normal of a surface to add fine detail without changing its geometry.
+#[derive(Clone)]
+pub enum NormalMap {
+    /// A tangent-space normal map, with each component remapped from `[-1, 1]` to `[0, 1]`.
+    /// Textures holding normal maps should not be decoded as sRGB.
+    Tangent(Arc<dyn Texture>),
+    /// A height map displacing the surface along its normal, with heights multiplied by
+    /// `scale`.
+    Bump {
+        height: Arc<dyn Texture>,
+        scale: f64,
+    },
+}
+
+impl NormalMap {
+    /// Computes the perturbed normal at a surface point with the specified shading normal and
+    /// partial derivatives.
+    pub fn perturb(&self, normal: Unit3, dpdu: Vec3, dpdv: Vec3, uv: Uv, point: Vec3) -> Unit3 {
+        let n: Vec3 = normal.into();
+
+        let perturbed = match self {
+            NormalMap::Tangent(texture) => {
+                let encoded = texture.eval(uv, point);
+
+                // Build an orthonormal tangent frame around the shading normal, keeping the
+                // handedness implied by the texture coordinates.
+                let basis = Basis::from_normal(normal);
+                let tangent = (dpdu - n.dot(dpdu) * n)
+                    .try_to_unit()
+                    .map_or(basis.x, Vec3::from);
+                let mut bitangent = n.cross(tangent);
+                if bitangent.dot(dpdv) < 0.0 {
+                    bitangent = -bitangent;
+                }
+
+                (2.0 * encoded.x - 1.0) * tangent
+                    + (2.0 * encoded.y - 1.0) * bitangent
+                    + (2.0 * encoded.z - 1.0) * n
+            }
+            NormalMap::Bump { height, scale } => {
+                let height_at = |uv: Uv, point: Vec3| {
+                    let val = height.eval(uv, point);
+                    scale * (val.x + val.y + val.z) / 3.0
+                };
+
+                let (u, v) = uv;
+                let base = height_at(uv, point);
+                let du =
+                    (height_at((u + BUMP_DELTA, v), point + BUMP_DELTA * dpdu) - base) / BUMP_DELTA;
+                let dv =
+                    (height_at((u, v + BUMP_DELTA), point + BUMP_DELTA * dpdv) - base) / BUMP_DELTA;
+
+                // Derivatives of the displaced surface, ignoring the change in the normal itself
+                let displaced = (dpdu + du * n).cross(dpdv + dv * n);
+                if displaced.dot(n) < 0.0 {
+                    -displaced
+                } else {
+                    displaced
+                }
+            }
+        };
+
+        perturbed.try_to_unit().unwrap_or(normal)
+    }
+}
+
+impl std::fmt::Debug for NormalMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NormalMap::Tangent(_) => f.write_str("Tangent"),
+            NormalMap::Bump { scale, .. } => f.debug_struct("Bump").field("scale", scale).finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-6;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    /// A texture two texels wide and one tall, black on the left and white on the right.
+    fn ramp(wrap: WrapMode) -> ImageTexture {
+        ImageTexture::new(2, 1, vec![[0.0; 3], [1.0; 3]], wrap).unwrap()
+    }
+
+    #[test]
+    fn wrap_modes() {
+        let wrap = |mode: WrapMode| (-5..9).map(|idx| mode.apply(idx, 4)).collect::<Vec<_>>();
+        assert_eq!(
+            wrap(WrapMode::Repeat),
+            [3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0]
+        );
+        assert_eq!(
+            wrap(WrapMode::Mirror),
+            [3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0]
+        );
+        assert_eq!(
+            wrap(WrapMode::Clamp),
+            [0, 0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3, 3]
+        );
+    }
+
+    #[test]
+    fn bilinear_lookup() {
+        let texture = ramp(WrapMode::Clamp);
+        let eval = |u: f64, v: f64| texture.eval((u, v), Vec3::default());
+
+        // Texel centers return the texel exactly, whatever the vertical position.
+        assert_close(eval(0.25, 0.5).x, 0.0);
+        assert_close(eval(0.75, 0.1).x, 1.0);
+        // Between centers the texels are blended linearly.
+        assert_close(eval(0.5, 0.5).x, 0.5);
+        assert_close(eval(0.375, 0.5).x, 0.25);
+        // Channels are filtered independently of each other.
+        assert_close(eval(0.625, 0.5).z, 0.75);
+    }
+
+    #[test]
+    fn bilinear_lookup_wraps_at_edges() {
+        // Past the outer texel centers, the blend depends on how the image wraps.
+        let at_edge = |wrap: WrapMode| ramp(wrap).eval((0.0, 0.5), Vec3::default()).x;
+        assert_close(at_edge(WrapMode::Clamp), 0.0);
+        assert_close(at_edge(WrapMode::Mirror), 0.0);
+        assert_close(at_edge(WrapMode::Repeat), 0.5);
+    }
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn assert_vec_close(actual: Unit3, expected: Vec3) {
+        let actual = Vec3::from(actual);
+        assert!(
+            (actual - expected).mag() < TOLERANCE,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    fn constant(color: Vec3) -> Arc<dyn Texture> {
+        Arc::new(Gradient {
+            start: color,
+            end: color,
+            axis: GradientAxis::U,
+        })
+    }
+
+    /// Perturbs the normal of the plane z = 0, with u along x and v along `dpdv`.
+    fn perturb_plane(map: &NormalMap, dpdu: Vec3, dpdv: Vec3) -> Unit3 {
+        let normal = vec3(0.0, 0.0, 1.0).to_unit();
+        map.perturb(normal, dpdu, dpdv, (0.5, 0.5), Vec3::default())
+    }
+
+    #[test]
+    fn tangent_normal_map() {
+        let (dpdu, dpdv) = (vec3(2.0, 0.0, 0.0), vec3(0.0, 3.0, 0.0));
+        let tangent_map = |encoded: Vec3| NormalMap::Tangent(constant(encoded));
+
+        // A flat map leaves the normal alone.
+        let flat = tangent_map(vec3(0.5, 0.5, 1.0));
+        assert_vec_close(perturb_plane(&flat, dpdu, dpdv), vec3(0.0, 0.0, 1.0));
+
+        // The first two channels point along the tangent and bitangent.
+        let along_u = tangent_map(vec3(1.0, 0.5, 0.5));
+        assert_vec_close(perturb_plane(&along_u, dpdu, dpdv), vec3(1.0, 0.0, 0.0));
+        let along_v = tangent_map(vec3(0.5, 1.0, 0.5));
+        assert_vec_close(perturb_plane(&along_v, dpdu, dpdv), vec3(0.0, 1.0, 0.0));
+
+        // Mirrored texture coordinates flip the bitangent.
+        assert_vec_close(perturb_plane(&along_v, dpdu, -dpdv), vec3(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn tangent_normal_map_fallbacks() {
+        // Without a usable tangent, the frame is built from the normal alone.
+        let along_u = NormalMap::Tangent(constant(vec3(1.0, 0.5, 0.5)));
+        let normal = perturb_plane(&along_u, vec3(0.0, 0.0, 1.0), vec3(0.0, 1.0, 0.0));
+        assert_close(normal.z(), 0.0);
+        assert_close(Vec3::from(normal).mag(), 1.0);
+
+        // A map which encodes no direction at all keeps the geometric normal.
+        let empty = NormalMap::Tangent(constant(vec3(0.5, 0.5, 0.5)));
+        let normal = perturb_plane(&empty, vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        assert_vec_close(normal, vec3(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn bump_map() {
+        let (dpdu, dpdv) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+
+        // A constant height doesn't change the slope of the surface.
+        let level = NormalMap::Bump {
+            height: constant(vec3(0.7, 0.7, 0.7)),
+            scale: 2.0,
+        };
+        assert_vec_close(perturb_plane(&level, dpdu, dpdv), vec3(0.0, 0.0, 1.0));
+
+        // Heights rising by one per unit of u tilt the normal 45 degrees back towards -u.
+        let ramp = NormalMap::Bump {
+            height: Arc::new(Gradient {
+                start: Vec3::default(),
+                end: vec3(0.5, 0.5, 0.5),
+                axis: GradientAxis::U,
+            }),
+            scale: 2.0,
+        };
+        let tilted = vec3(-1.0, 0.0, 1.0) / 2f64.sqrt();
+        assert_vec_close(perturb_plane(&ramp, dpdu, dpdv), tilted);
+    }
+}